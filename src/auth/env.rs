@@ -0,0 +1,61 @@
+//! Read access tokens from environment variables
+use std::env;
+
+use crate::auth::{AccessToken, ProvidesAccessToken, TokenError};
+
+/// The environment variable read by `EnvAccessTokenProvider::default()`
+pub const DEFAULT_TOKEN_ENV_VAR: &str = "NAKADION_ACCESS_TOKEN";
+
+/// Provides the `AccessToken` contained in an environment variable.
+///
+/// The variable is read on every call to `get_token` so that
+/// changes of the variable take effect immediately.
+#[derive(Debug, Clone)]
+pub struct EnvAccessTokenProvider {
+    var_name: String,
+}
+
+impl EnvAccessTokenProvider {
+    /// Create a new `EnvAccessTokenProvider` reading the
+    /// token from the variable `var_name`.
+    pub fn new<T: Into<String>>(var_name: T) -> EnvAccessTokenProvider {
+        EnvAccessTokenProvider {
+            var_name: var_name.into(),
+        }
+    }
+}
+
+impl Default for EnvAccessTokenProvider {
+    fn default() -> EnvAccessTokenProvider {
+        EnvAccessTokenProvider::new(DEFAULT_TOKEN_ENV_VAR)
+    }
+}
+
+impl ProvidesAccessToken for EnvAccessTokenProvider {
+    fn get_token(&self) -> Result<Option<AccessToken>, TokenError> {
+        match env::var(&self.var_name) {
+            Ok(ref token) if !token.trim().is_empty() => Ok(Some(AccessToken::new(token.trim()))),
+            Ok(_) => Err(TokenError::Client {
+                message: format!("Environment variable '{}' is empty", self.var_name),
+            }),
+            Err(err) => Err(TokenError::Client {
+                message: format!(
+                    "Could not read environment variable '{}': {}",
+                    self.var_name, err
+                ),
+            }),
+        }
+    }
+}
+
+#[test]
+fn reads_the_token_from_the_variable() {
+    let var_name = format!("NAKADION_TEST_TOKEN_{}", uuid::Uuid::new_v4().to_simple());
+    let provider = EnvAccessTokenProvider::new(var_name.as_str());
+
+    assert!(provider.get_token().is_err());
+
+    env::set_var(&var_name, "token");
+    assert_eq!(provider.get_token().unwrap().unwrap().0, "token");
+    env::remove_var(&var_name);
+}
@@ -0,0 +1,132 @@
+//! Read access tokens from files
+use std::fs;
+use std::io::{Error as IoError, ErrorKind};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::auth::{AccessToken, ProvidesAccessToken, TokenError};
+
+/// Provides the `AccessToken` contained in a file.
+///
+/// This is useful if tokens are mounted as files and rotated
+/// by the platform.
+///
+/// The token is cached and the file is read again if it
+/// changed or once the cached token is older than the TTL.
+/// Leading and trailing whitespace is removed from the token.
+pub struct FileAccessTokenProvider {
+    path: PathBuf,
+    ttl: Duration,
+    cached: Mutex<Option<CachedToken>>,
+}
+
+struct CachedToken {
+    token: AccessToken,
+    version: FileVersion,
+    read_at: Instant,
+}
+
+/// Identifies the contents of a file without reading it
+#[derive(PartialEq, Eq)]
+struct FileVersion {
+    modified: Option<SystemTime>,
+    len: u64,
+}
+
+impl FileAccessTokenProvider {
+    /// Create a new `FileAccessTokenProvider` reading the file
+    /// at `path` at least every 60 seconds.
+    pub fn new<P: Into<PathBuf>>(path: P) -> FileAccessTokenProvider {
+        FileAccessTokenProvider::with_ttl(path, Duration::from_secs(60))
+    }
+
+    /// Create a new `FileAccessTokenProvider` reading the file
+    /// at `path` at least every `ttl`.
+    pub fn with_ttl<P: Into<PathBuf>>(path: P, ttl: Duration) -> FileAccessTokenProvider {
+        FileAccessTokenProvider {
+            path: path.into(),
+            ttl,
+            cached: Mutex::new(None),
+        }
+    }
+
+    fn version(&self) -> Result<FileVersion, TokenError> {
+        let metadata = fs::metadata(&self.path).map_err(|err| self.io_error(err))?;
+        Ok(FileVersion {
+            modified: metadata.modified().ok(),
+            len: metadata.len(),
+        })
+    }
+
+    fn read_token(&self) -> Result<AccessToken, TokenError> {
+        let contents = fs::read_to_string(&self.path).map_err(|err| self.io_error(err))?;
+        let token = contents.trim();
+        if token.is_empty() {
+            return Err(TokenError::Client {
+                message: format!("Token file '{}' is empty", self.path.display()),
+            });
+        }
+        Ok(AccessToken::new(token))
+    }
+
+    fn io_error(&self, err: IoError) -> TokenError {
+        let message = format!(
+            "Could not read token file '{}': {}",
+            self.path.display(),
+            err
+        );
+        match err.kind() {
+            // Retrying will not help unless the setup is fixed
+            ErrorKind::NotFound | ErrorKind::PermissionDenied | ErrorKind::InvalidData => {
+                TokenError::Client { message }
+            }
+            _ => TokenError::Other { message },
+        }
+    }
+}
+
+impl ProvidesAccessToken for FileAccessTokenProvider {
+    fn get_token(&self) -> Result<Option<AccessToken>, TokenError> {
+        let mut cached = self.cached.lock().unwrap();
+
+        let version = self.version()?;
+
+        if let Some(ref cached) = *cached {
+            if cached.version == version && cached.read_at.elapsed() < self.ttl {
+                return Ok(Some(cached.token.clone()));
+            }
+        }
+
+        let token = self.read_token()?;
+        *cached = Some(CachedToken {
+            token: token.clone(),
+            version,
+            read_at: Instant::now(),
+        });
+
+        Ok(Some(token))
+    }
+
+    fn invalidate_token(&self) {
+        *self.cached.lock().unwrap() = None;
+    }
+}
+
+#[test]
+fn reads_the_token_again_once_the_file_changed() {
+    let path = ::std::env::temp_dir().join(format!("nakadion-token-{}", uuid::Uuid::new_v4()));
+    fs::write(&path, "first\n").unwrap();
+
+    let provider = FileAccessTokenProvider::new(&path);
+    assert_eq!(provider.get_token().unwrap().unwrap().0, "first");
+
+    fs::write(&path, "second\n").unwrap();
+    assert_eq!(provider.get_token().unwrap().unwrap().0, "second");
+
+    fs::remove_file(&path).unwrap();
+    match provider.get_token() {
+        Err(TokenError::Client { .. }) => {}
+        other => panic!("Expected a client error but got {:?}", other),
+    }
+}
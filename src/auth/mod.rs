@@ -1,6 +1,16 @@
 //! Optional OAUTH authorization for connecting to Nakadi
 use std::fmt;
 
+mod env;
+mod file;
+#[cfg(feature = "blocking")]
+mod oauth2;
+
+pub use self::env::{EnvAccessTokenProvider, DEFAULT_TOKEN_ENV_VAR};
+pub use self::file::FileAccessTokenProvider;
+#[cfg(feature = "blocking")]
+pub use self::oauth2::{OAuth2AccessTokenProvider, OAuth2Config};
+
 /// A token used for authentication against `Nakadi`.
 #[derive(Clone, Debug)]
 pub struct AccessToken(pub String);
@@ -24,6 +34,46 @@ impl fmt::Display for AccessToken {
 pub trait ProvidesAccessToken {
     /// Get a new `Token`. Return `None` to disable authentication.
     fn get_token(&self) -> Result<Option<AccessToken>, TokenError>;
+
+    /// Called when Nakadi rejected the last token with `401 Unauthorized`.
+    ///
+    /// Implementors caching tokens should discard the cached token
+    /// so that the following call to `get_token` returns a fresh one.
+    /// The clients retry a rejected request once right after calling this
+    /// method if `get_token` returns a token. Otherwise the request fails
+    /// and is retried by the caller, e.g. the retries of a commit or the
+    /// reconnects of the consumer, once a fresh token is available.
+    ///
+    /// This is also called from within async executors and therefore
+    /// must not block, e.g. by requesting a new token. Such requests
+    /// should be made in the background.
+    ///
+    /// The default implementation does nothing.
+    fn invalidate_token(&self) {}
+}
+
+/// Invalidates a token rejected on `operation`.
+///
+/// Returns `true` if a fresh token is available right away so
+/// that the rejected request can be sent again.
+pub(crate) fn renew_rejected_token<P>(token_provider: &P, operation: &str) -> bool
+where
+    P: ProvidesAccessToken + ?Sized,
+{
+    token_provider.invalidate_token();
+    if token_provider.get_token().is_ok() {
+        warn!(
+            "The access token was rejected on {}. Retrying with a fresh token.",
+            operation
+        );
+        true
+    } else {
+        warn!(
+            "The access token was rejected on {}. No fresh token available yet.",
+            operation
+        );
+        false
+    }
 }
 
 /// Using this access token provider disables OAUTH.
@@ -0,0 +1,449 @@
+//! Obtain access tokens via the OAuth2 client credentials grant
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use failure::*;
+use reqwest::blocking::Client as HttpClient;
+
+use crate::auth::{AccessToken, ProvidesAccessToken, TokenError};
+
+/// Settings for obtaining tokens from an OAuth2 token endpoint
+#[derive(Debug, Clone)]
+pub struct OAuth2Config {
+    /// The URL of the token endpoint
+    pub token_url: String,
+    pub client_id: String,
+    pub client_secret: String,
+    /// The scopes to request. No scopes are requested if empty.
+    pub scopes: Vec<String>,
+    /// Refresh a token this long before it expires
+    pub refresh_before_expiry: Duration,
+    /// Keep serving an expired token for this long
+    /// while refreshing it fails
+    pub grace_period: Duration,
+    /// Wait this long before retrying a failed refresh
+    pub retry_interval: Duration,
+    /// The lifetime of a token if the token endpoint
+    /// does not send `expires_in`
+    pub default_expires_in: Duration,
+    /// The timeout for requests to the token endpoint
+    pub request_timeout: Duration,
+}
+
+impl OAuth2Config {
+    /// Create a new `OAuth2Config` with defaults for all optional settings
+    pub fn new<U, I, S>(token_url: U, client_id: I, client_secret: S) -> OAuth2Config
+    where
+        U: Into<String>,
+        I: Into<String>,
+        S: Into<String>,
+    {
+        OAuth2Config {
+            token_url: token_url.into(),
+            client_id: client_id.into(),
+            client_secret: client_secret.into(),
+            scopes: Vec::new(),
+            refresh_before_expiry: Duration::from_secs(60),
+            grace_period: Duration::from_secs(30),
+            retry_interval: Duration::from_secs(5),
+            default_expires_in: Duration::from_secs(3600),
+            request_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Provides `AccessToken`s obtained from an OAuth2 token endpoint
+/// using the client credentials grant.
+///
+/// Tokens are cached and refreshed from a background thread before
+/// they expire so that `get_token` never waits for the network.
+/// If refreshing fails the last token is still served until it
+/// is expired for longer than the grace period.
+///
+/// The first token is requested before `new` returns. The background
+/// thread stops once the `OAuth2AccessTokenProvider` is dropped.
+///
+/// Invalidating a token discards it and wakes the background thread
+/// to request a new token. `invalidate_token` does not wait for the new
+/// token and `get_token` fails until it has been obtained. Concurrent
+/// invalidations are served by a single request.
+///
+/// This is only available with the `blocking` feature enabled.
+pub struct OAuth2AccessTokenProvider {
+    state: Arc<RwLock<TokenState>>,
+    grace_period: Duration,
+    // Wakes the refresh thread. Dropping the sender stops the refresh thread.
+    wake: Mutex<mpsc::Sender<()>>,
+}
+
+impl OAuth2AccessTokenProvider {
+    /// Create a new `OAuth2AccessTokenProvider` and start refreshing tokens.
+    ///
+    /// Failing to obtain the first token is not an error. It is retried
+    /// in the background and `get_token` fails until a token was obtained.
+    ///
+    /// # Errors
+    ///
+    /// The HTTP client or the background thread could not be created.
+    pub fn new(config: OAuth2Config) -> Result<OAuth2AccessTokenProvider, Error> {
+        let http_client = HttpClient::builder()
+            .timeout(config.request_timeout)
+            .build()
+            .context("Could not create HTTP client")?;
+
+        let grace_period = config.grace_period;
+        let fetcher = Arc::new(TokenFetcher {
+            http_client,
+            config,
+        });
+
+        let state = Arc::new(RwLock::new(TokenState::default()));
+        let first_wait = refresh(&fetcher, &state);
+
+        let (wake, woken) = mpsc::channel();
+        {
+            let state = state.clone();
+            thread::Builder::new()
+                .name("nakadion-oauth2".to_string())
+                .spawn(move || refresh_loop(fetcher, state, woken, first_wait))
+                .context("Could not start token refresh thread")?;
+        }
+
+        Ok(OAuth2AccessTokenProvider {
+            state,
+            grace_period,
+            wake: Mutex::new(wake),
+        })
+    }
+}
+
+impl ProvidesAccessToken for OAuth2AccessTokenProvider {
+    fn get_token(&self) -> Result<Option<AccessToken>, TokenError> {
+        self.state
+            .read()
+            .unwrap()
+            .current(Instant::now(), self.grace_period)
+            .map(Some)
+    }
+
+    fn invalidate_token(&self) {
+        let mut state = self.state.write().unwrap();
+        state.token = None;
+        if !state.refresh_requested {
+            state.refresh_requested = true;
+            // The refresh thread only stops once we are dropped
+            let _ = self.wake.lock().unwrap().send(());
+        }
+    }
+}
+
+#[derive(Default)]
+struct TokenState {
+    token: Option<(AccessToken, Instant)>,
+    last_error: Option<TokenError>,
+    /// The token was invalidated and the refresh thread has been woken
+    refresh_requested: bool,
+}
+
+impl TokenState {
+    fn current(&self, now: Instant, grace_period: Duration) -> Result<AccessToken, TokenError> {
+        let last_error = self
+            .last_error
+            .as_ref()
+            .map(ToString::to_string)
+            .unwrap_or_else(|| "<none>".to_string());
+
+        match self.token {
+            Some((ref token, expires_at)) if now < expires_at + grace_period => Ok(token.clone()),
+            Some(_) => Err(TokenError::Other {
+                message: format!(
+                    "The access token expired and could not be refreshed. Last error: {}",
+                    last_error
+                ),
+            }),
+            None if self.refresh_requested => Err(TokenError::Other {
+                message: format!(
+                    "The access token was rejected and is being refreshed. Last error: {}",
+                    last_error
+                ),
+            }),
+            None => Err(TokenError::Other {
+                message: format!("No access token obtained yet. Last error: {}", last_error),
+            }),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: Option<u64>,
+}
+
+struct TokenFetcher {
+    http_client: HttpClient,
+    config: OAuth2Config,
+}
+
+impl TokenFetcher {
+    fn fetch(&self) -> Result<(AccessToken, Duration), TokenError> {
+        let mut form = vec![("grant_type", "client_credentials".to_string())];
+        if !self.config.scopes.is_empty() {
+            form.push(("scope", self.config.scopes.join(" ")));
+        }
+
+        let response = self
+            .http_client
+            .post(&self.config.token_url)
+            .basic_auth(&self.config.client_id, Some(&self.config.client_secret))
+            .form(&form)
+            .send()
+            .map_err(|err| TokenError::Other {
+                message: format!("Could not request token: {}", err),
+            })?;
+
+        let status = response.status();
+        if status.is_success() {
+            let response: TokenResponse = response.json().map_err(|err| TokenError::Server {
+                message: format!("Invalid token response: {}", err),
+            })?;
+            let expires_in = response
+                .expires_in
+                .map(Duration::from_secs)
+                .unwrap_or(self.config.default_expires_in);
+            Ok((AccessToken(response.access_token), expires_in))
+        } else {
+            let message = format!(
+                "{}: {}",
+                status,
+                response
+                    .text()
+                    .unwrap_or_else(|_| "<Nakadion: Could not read body.>".to_string())
+            );
+            if status.is_client_error() {
+                Err(TokenError::Client { message })
+            } else if status.is_server_error() {
+                Err(TokenError::Server { message })
+            } else {
+                Err(TokenError::Other { message })
+            }
+        }
+    }
+}
+
+/// Fetches a token and returns the time to wait until the next refresh
+fn refresh(fetcher: &TokenFetcher, state: &RwLock<TokenState>) -> Duration {
+    match fetcher.fetch() {
+        Ok((token, expires_in)) => {
+            let mut state = state.write().unwrap();
+            state.token = Some((token, Instant::now() + expires_in));
+            state.last_error = None;
+            state.refresh_requested = false;
+            expires_in
+                .checked_sub(fetcher.config.refresh_before_expiry)
+                .unwrap_or(expires_in / 2)
+        }
+        Err(err) => {
+            warn!("Could not obtain an access token: {}", err);
+            let mut state = state.write().unwrap();
+            state.last_error = Some(err);
+            state.refresh_requested = false;
+            fetcher.config.retry_interval
+        }
+    }
+}
+
+/// Refreshes the token when it is due or when woken
+/// because the token was invalidated
+fn refresh_loop(
+    fetcher: Arc<TokenFetcher>,
+    state: Arc<RwLock<TokenState>>,
+    woken: mpsc::Receiver<()>,
+    first_wait: Duration,
+) {
+    let mut wait = first_wait;
+    while let Ok(()) | Err(mpsc::RecvTimeoutError::Timeout) = woken.recv_timeout(wait) {
+        wait = refresh(&fetcher, &state);
+    }
+    debug!("Token refresh thread stopped");
+}
+
+#[test]
+fn serves_expired_tokens_within_the_grace_period() {
+    let now = Instant::now();
+    let grace_period = Duration::from_secs(30);
+
+    let state = TokenState::default();
+    assert!(state.current(now, grace_period).is_err());
+
+    let state = TokenState {
+        token: Some((AccessToken::new("token"), now)),
+        last_error: Some(TokenError::Server {
+            message: "unavailable".to_string(),
+        }),
+        refresh_requested: false,
+    };
+    assert_eq!(
+        state
+            .current(now + Duration::from_secs(10), grace_period)
+            .unwrap()
+            .0,
+        "token"
+    );
+    assert!(state
+        .current(now + Duration::from_secs(31), grace_period)
+        .is_err());
+}
+
+#[test]
+fn obtains_a_token_from_the_token_endpoint() {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let token_url = format!("http://{}/oauth2/token", listener.local_addr().unwrap());
+    let server = thread::spawn(move || {
+        let (mut connection, _) = listener.accept().unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0; 1024];
+        while !String::from_utf8_lossy(&request).contains("grant_type=client_credentials") {
+            let n = connection.read(&mut buf).unwrap();
+            request.extend_from_slice(&buf[..n]);
+        }
+        let body = r#"{"access_token":"secret","token_type":"Bearer","expires_in":3600}"#;
+        write!(
+            connection,
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+             Connection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+        .unwrap();
+        String::from_utf8_lossy(&request).to_string()
+    });
+
+    let provider =
+        OAuth2AccessTokenProvider::new(OAuth2Config::new(token_url, "id", "pw")).unwrap();
+
+    let request = server.join().unwrap();
+    assert!(request.starts_with("POST /oauth2/token"));
+    assert!(request.to_lowercase().contains("authorization: basic"));
+    assert_eq!(provider.get_token().unwrap().unwrap().0, "secret");
+}
+
+/// Serves tokens `token-1`, `token-2`, ... and counts the token requests.
+///
+/// All but the first request are answered after `refresh_delay`.
+#[cfg(test)]
+fn start_token_server(refresh_delay: Duration) -> (String, Arc<::std::sync::atomic::AtomicUsize>) {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let token_url = format!("http://{}/oauth2/token", listener.local_addr().unwrap());
+    let requests = Arc::new(AtomicUsize::new(0));
+    {
+        let requests = requests.clone();
+        thread::spawn(move || {
+            for connection in listener.incoming() {
+                let mut connection = connection.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !String::from_utf8_lossy(&request).contains("grant_type=client_credentials") {
+                    let n = connection.read(&mut buf).unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let n = requests.fetch_add(1, Ordering::SeqCst) + 1;
+                if n > 1 {
+                    thread::sleep(refresh_delay);
+                }
+                let body = format!(
+                    r#"{{"access_token":"token-{}","token_type":"Bearer","expires_in":3600}}"#,
+                    n
+                );
+                write!(
+                    connection,
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+                     Connection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+                .unwrap();
+            }
+        });
+    }
+    (token_url, requests)
+}
+
+#[test]
+fn invalidating_a_token_refreshes_it_in_the_background() {
+    use std::sync::atomic::Ordering;
+
+    let (token_url, requests) = start_token_server(Duration::from_millis(300));
+
+    let provider =
+        OAuth2AccessTokenProvider::new(OAuth2Config::new(token_url, "id", "pw")).unwrap();
+    assert_eq!(provider.get_token().unwrap().unwrap().0, "token-1");
+
+    let started = Instant::now();
+    provider.invalidate_token();
+    provider.invalidate_token();
+    provider.invalidate_token();
+    assert!(started.elapsed() < Duration::from_millis(200));
+    assert!(provider.get_token().is_err());
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let token = loop {
+        if let Ok(Some(token)) = provider.get_token() {
+            break token;
+        }
+        assert!(Instant::now() < deadline, "no fresh token obtained");
+        thread::sleep(Duration::from_millis(10));
+    };
+    assert_eq!(token.0, "token-2");
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(requests.load(Ordering::SeqCst), 2);
+}
+
+#[cfg(all(feature = "async", feature = "test-support"))]
+#[test]
+fn async_clients_publish_with_a_refreshed_token_when_unauthorized() {
+    use std::sync::atomic::Ordering;
+
+    use crate::nakadi::asynchronous::AsyncNakadiPublisher;
+    use crate::nakadi::publisher::PublishStatus;
+    use crate::test_support::{Endpoint, FakeNakadi, Fault};
+
+    let (token_url, token_requests) = start_token_server(Duration::from_millis(100));
+    let provider =
+        OAuth2AccessTokenProvider::new(OAuth2Config::new(token_url, "id", "pw")).unwrap();
+
+    let nakadi = FakeNakadi::start().unwrap();
+    nakadi.create_event_type("test_event", 1);
+    nakadi.inject_fault(
+        Endpoint::Publish,
+        Fault::Status(401, "Token revoked".to_string()),
+    );
+
+    let publisher = AsyncNakadiPublisher::new(nakadi.nakadi_host(), false, provider);
+    // A single thread would be stalled by a blocking token request
+    let mut runtime = tokio::runtime::Builder::new()
+        .basic_scheduler()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    let status = runtime
+        .block_on(publisher.publish_events("test_event", &[1, 2], None, Duration::from_secs(5)))
+        .unwrap();
+
+    match status {
+        PublishStatus::AllEventsPublished => {}
+        other => panic!("Expected all events to be published but got {:?}", other),
+    }
+    assert_eq!(token_requests.load(Ordering::SeqCst), 2);
+    assert_eq!(nakadi.published_events("test_event").len(), 2);
+}
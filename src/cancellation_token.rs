@@ -1,11 +1,77 @@
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Condvar, Mutex};
+#[cfg(feature = "blocking")]
+use std::time::Duration;
+
+#[cfg(feature = "async")]
+use tokio::sync::watch;
 
 use crate::nakadi::metrics::*;
 
+/// A flag which can be waited for until it is set
+struct Flag {
+    is_set: AtomicBool,
+    lock: Mutex<()>,
+    condvar: Condvar,
+    #[cfg(feature = "async")]
+    sender: watch::Sender<bool>,
+    #[cfg(feature = "async")]
+    receiver: watch::Receiver<bool>,
+}
+
+impl Flag {
+    fn new() -> Flag {
+        #[cfg(feature = "async")]
+        let (sender, receiver) = watch::channel(false);
+        Flag {
+            is_set: AtomicBool::new(false),
+            lock: Mutex::new(()),
+            condvar: Condvar::new(),
+            #[cfg(feature = "async")]
+            sender,
+            #[cfg(feature = "async")]
+            receiver,
+        }
+    }
+
+    fn set(&self) {
+        {
+            let _guard = self.lock.lock().unwrap();
+            self.is_set.store(true, Ordering::Relaxed);
+            self.condvar.notify_all();
+        }
+        #[cfg(feature = "async")]
+        let _ = self.sender.broadcast(true);
+    }
+
+    fn is_set(&self) -> bool {
+        self.is_set.load(Ordering::Relaxed)
+    }
+
+    /// Returns `true` if the flag was set before the timeout elapsed.
+    #[cfg(feature = "blocking")]
+    fn wait_timeout(&self, timeout: Duration) -> bool {
+        let guard = self.lock.lock().unwrap();
+        let _ = self
+            .condvar
+            .wait_timeout_while(guard, timeout, |_| !self.is_set())
+            .unwrap();
+        self.is_set()
+    }
+
+    #[cfg(feature = "async")]
+    async fn wait(&self) {
+        let mut receiver = self.receiver.clone();
+        // The first value received is the current one
+        while let Some(false) = receiver.recv().await {}
+    }
+}
+
 pub struct CancellationTokenSource {
-    cancellation_requested: Arc<AtomicBool>,
-    cancelled: Arc<AtomicBool>,
+    cancellation_requested: Arc<Flag>,
+    #[cfg(feature = "blocking")]
+    drain_requested: Arc<AtomicBool>,
+    cancelled: Arc<Flag>,
     metrics_collector: Arc<dyn MetricsCollector + Sync + Send + 'static>,
 }
 
@@ -28,23 +94,39 @@ impl CancellationTokenSource {
         M: MetricsCollector + Sync + Send + 'static,
     {
         CancellationTokenSource {
-            cancellation_requested: Arc::new(AtomicBool::new(false)),
-            cancelled: Arc::new(AtomicBool::new(false)),
+            cancellation_requested: Arc::new(Flag::new()),
+            #[cfg(feature = "blocking")]
+            drain_requested: Arc::new(AtomicBool::new(false)),
+            cancelled: Arc::new(Flag::new()),
             metrics_collector,
         }
     }
 
     pub fn request_cancellation(&self) {
-        self.cancellation_requested.store(true, Ordering::Relaxed);
+        self.cancellation_requested.set();
+    }
+
+    /// Request to finish the work already accepted and then stop.
+    #[cfg(feature = "blocking")]
+    pub fn request_drain(&self) {
+        self.drain_requested.store(true, Ordering::Relaxed);
     }
 
     pub fn is_any_cancelled(&self) -> bool {
-        self.cancelled.load(Ordering::Relaxed)
+        self.cancelled.is_set()
+    }
+
+    /// Resolves once any of the tokens got cancelled.
+    #[cfg(feature = "async")]
+    pub async fn any_cancelled(&self) {
+        self.cancelled.wait().await
     }
 
     pub fn auto_token(&self) -> AutoCancellationToken {
         AutoCancellationToken {
             cancellation_requested: self.cancellation_requested.clone(),
+            #[cfg(feature = "blocking")]
+            drain_requested: self.drain_requested.clone(),
             cancelled: self.cancelled.clone(),
             metrics_collector: self.metrics_collector.clone(),
         }
@@ -54,8 +136,10 @@ impl CancellationTokenSource {
 impl Default for CancellationTokenSource {
     fn default() -> Self {
         CancellationTokenSource {
-            cancellation_requested: Arc::new(AtomicBool::new(false)),
-            cancelled: Arc::new(AtomicBool::new(false)),
+            cancellation_requested: Arc::new(Flag::new()),
+            #[cfg(feature = "blocking")]
+            drain_requested: Arc::new(AtomicBool::new(false)),
+            cancelled: Arc::new(Flag::new()),
             metrics_collector: Arc::new(DevNullMetricsCollector),
         }
     }
@@ -63,16 +147,36 @@ impl Default for CancellationTokenSource {
 
 pub trait CancellationToken {
     fn cancellation_requested(&self) -> bool;
+    #[cfg(feature = "blocking")]
+    fn drain_requested(&self) -> bool;
     fn cancelled(&self);
 }
 
 #[derive(Clone)]
 pub struct AutoCancellationToken {
-    cancellation_requested: Arc<AtomicBool>,
-    cancelled: Arc<AtomicBool>,
+    cancellation_requested: Arc<Flag>,
+    #[cfg(feature = "blocking")]
+    drain_requested: Arc<AtomicBool>,
+    cancelled: Arc<Flag>,
     metrics_collector: Arc<dyn MetricsCollector + Send + Sync + 'static>,
 }
 
+impl AutoCancellationToken {
+    /// Wait at most `timeout` for a cancellation to be requested.
+    ///
+    /// Returns `true` if a cancellation was requested.
+    #[cfg(feature = "blocking")]
+    pub fn wait_for_cancellation_request(&self, timeout: Duration) -> bool {
+        self.cancellation_requested.wait_timeout(timeout)
+    }
+
+    /// Resolves once a cancellation was requested.
+    #[cfg(feature = "async")]
+    pub async fn cancellation_request(&self) {
+        self.cancellation_requested.wait().await
+    }
+}
+
 impl Drop for AutoCancellationToken {
     fn drop(&mut self) {
         self.cancelled();
@@ -88,10 +192,65 @@ impl Drop for AutoCancellationToken {
 
 impl CancellationToken for AutoCancellationToken {
     fn cancellation_requested(&self) -> bool {
-        self.cancellation_requested.load(Ordering::Relaxed)
+        self.cancellation_requested.is_set()
+    }
+
+    #[cfg(feature = "blocking")]
+    fn drain_requested(&self) -> bool {
+        self.drain_requested.load(Ordering::Relaxed)
     }
 
     fn cancelled(&self) {
-        self.cancelled.store(true, Ordering::Relaxed)
+        self.cancelled.set()
     }
 }
+
+#[cfg(feature = "blocking")]
+#[test]
+fn a_cancellation_request_wakes_up_a_waiting_token() {
+    use std::thread;
+    use std::time::Instant;
+
+    let source = CancellationTokenSource::default();
+    let token = source.auto_token();
+
+    assert!(!token.wait_for_cancellation_request(Duration::from_millis(10)));
+
+    let start = Instant::now();
+    let handle =
+        thread::spawn(move || token.wait_for_cancellation_request(Duration::from_secs(10)));
+    thread::sleep(Duration::from_millis(50));
+    source.request_cancellation();
+
+    assert!(handle.join().unwrap());
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn a_cancellation_request_resolves_the_waiting_futures() {
+    use std::time::{Duration, Instant};
+
+    let mut runtime = tokio::runtime::Builder::new()
+        .basic_scheduler()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    let source = CancellationTokenSource::default();
+    let token = source.auto_token();
+
+    let start = Instant::now();
+    runtime.block_on(async {
+        let waiting = tokio::spawn(async move {
+            token.cancellation_request().await;
+            // Dropping the token marks it as cancelled
+        });
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+        source.request_cancellation();
+        waiting.await.unwrap();
+        source.any_cancelled().await;
+    });
+    assert!(source.is_any_cancelled());
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
@@ -150,6 +150,7 @@
 //! # let handler_factory = MyHandlerFactory;
 //!
 //! // Start Nakadion
+//! # #[cfg(feature = "blocking")]
 //! let nakadion = builder.build_and_start(handler_factory,
 //! token_provider).unwrap();
 //!
@@ -248,6 +249,7 @@ extern crate failure;
 extern crate log;
 #[macro_use]
 extern crate serde;
+#[cfg(feature = "blocking")]
 extern crate backoff;
 extern crate chrono;
 extern crate reqwest;
@@ -257,24 +259,43 @@ extern crate uuid;
 
 #[cfg(feature = "metrix")]
 extern crate metrix;
+#[cfg(feature = "prometheus")]
+extern crate prometheus;
 
 pub mod auth;
 
 mod nakadi;
 
 pub use crate::nakadi::api;
+#[cfg(feature = "async")]
+pub use crate::nakadi::asynchronous;
+#[cfg(feature = "blocking")]
+pub use crate::nakadi::buffered_publisher;
+#[cfg(feature = "blocking")]
 pub use crate::nakadi::consumer;
+#[cfg(feature = "blocking")]
+pub use crate::nakadi::consumer::{ShutdownReport, StopReason};
+pub use crate::nakadi::dead_letter;
 pub use crate::nakadi::handler::*;
+pub use crate::nakadi::lifecycle::LifecycleListener;
 pub use crate::nakadi::metrics;
 pub use crate::nakadi::model::{EventType, FlowId, PartitionId, StreamId, SubscriptionId};
 pub use crate::nakadi::streaming_client;
+#[cfg(feature = "blocking")]
+pub use crate::nakadi::Nakadion;
 pub use crate::nakadi::{
-    CommitStrategy, Nakadion, NakadionBuilder, NakadionConfig, SubscriptionDiscovery,
+    CommitStrategy, ConflictBehaviour, ConsumerSettings, HandlerRetryPolicy, NakadionBuilder,
+    NakadionConfig, ReconnectBackoff, ReconnectPolicy, SubscriptionDiscovery,
 };
 
 pub use crate::nakadi::publisher;
 
 pub use crate::nakadi::events;
 
+#[cfg(feature = "test-support")]
+pub mod test_support;
+
 pub(crate) mod cancellation_token;
+#[cfg(feature = "blocking")]
+pub(crate) mod thread_handle;
 //pub(crate) mod custom_headers;
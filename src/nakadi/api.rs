@@ -7,23 +7,36 @@
 //! * Delete an existing event type
 //! * Create a new Subscription or get an exiting subscription
 //! * Delete an existing subscription
+//! * Get the statistics of a subscription
+//! * Get and reset the committed cursors of a subscription
 use std::env;
+#[cfg(feature = "blocking")]
 use std::io::Read;
+#[cfg(feature = "blocking")]
 use std::sync::Arc;
 use std::time::Duration;
 
 use serde::{self, Deserialize, Deserializer, Serialize, Serializer};
 use serde_json;
 
+#[cfg(feature = "blocking")]
 use backoff::{Error as BackoffError, ExponentialBackoff, Operation};
 use failure::*;
-use reqwest::header::{HeaderMap, CONTENT_TYPE};
 use reqwest::StatusCode;
-use reqwest::blocking::{Client as HttpClient, ClientBuilder as HttpClientBuilder, Response};
-
-use crate::auth::{AccessToken, ProvidesAccessToken, TokenError};
+#[cfg(feature = "blocking")]
+use reqwest::{
+    blocking::{Client as HttpClient, ClientBuilder as HttpClientBuilder, Response},
+    header::{HeaderMap, CONTENT_TYPE},
+};
+
+use crate::auth::TokenError;
+#[cfg(feature = "blocking")]
+use crate::auth::{renew_rejected_token, AccessToken, ProvidesAccessToken};
+use crate::nakadi::handler::SubscriptionCursor;
 use crate::nakadi::model::{FlowId, StreamId, SubscriptionId};
 
+use self::stats::SubscriptionStats;
+
 /// A REST client for the Nakadi API.
 ///
 /// This accesses the REST API only and does not provide
@@ -113,6 +126,52 @@ pub trait ApiClient {
     ///
     /// The subscription could not be deleted.
     fn delete_subscription(&self, id: &SubscriptionId) -> Result<(), DeleteSubscriptionError>;
+
+    /// Get the statistics of a `Subscription` identified by a `SubscriptionId`.
+    ///
+    /// The statistics contain the state of each partition, the stream
+    /// a partition is assigned to and the number of unconsumed events.
+    ///
+    /// If `show_time_lag` is `true` Nakadi will also calculate the consumer
+    /// lag for each partition which is a more expensive operation.
+    ///
+    /// # Errors
+    ///
+    /// The statistics could not be retrieved.
+    fn subscription_stats(
+        &self,
+        id: &SubscriptionId,
+        show_time_lag: bool,
+    ) -> Result<SubscriptionStats, StatsError>;
+
+    /// Get the currently committed cursors of a `Subscription`
+    /// identified by a `SubscriptionId`.
+    ///
+    /// # Errors
+    ///
+    /// The cursors could not be retrieved.
+    fn get_committed_cursors(
+        &self,
+        subscription_id: &SubscriptionId,
+        flow_id: FlowId,
+    ) -> Result<Vec<SubscriptionCursor>, GetCursorsError>;
+
+    /// Reset the cursors of a `Subscription` identified by a `SubscriptionId`
+    /// to the given cursors.
+    ///
+    /// This will close all currently open streams of the subscription.
+    /// Consumption will start with the events following the given cursors
+    /// once clients reconnect.
+    ///
+    /// # Errors
+    ///
+    /// The cursors could not be reset.
+    fn reset_cursors(
+        &self,
+        subscription_id: &SubscriptionId,
+        cursors: &[SubscriptionCursor],
+        flow_id: FlowId,
+    ) -> Result<(), ResetCursorsError>;
 }
 
 /// Settings for establishing a connection to `Nakadi`.
@@ -168,7 +227,7 @@ impl ConfigBuilder {
     ///
     /// * NAKADION_NAKADI_HOST: Host address of Nakadi. The host is mandatory.
     /// * NAKADION_REQUEST_TIMEOUT_MS: Timeout in ms after which a connection to the REST API
-    /// is aborted. This is optional and defaults to 1 second.
+    ///   is aborted. This is optional and defaults to 1 second.
     ///
     /// # Errors
     ///
@@ -224,6 +283,7 @@ impl ConfigBuilder {
     /// # Errors
     ///
     /// Fails if this builder is in an invalid state.
+    #[cfg(feature = "blocking")]
     pub fn build_client<T>(self, token_provider: T) -> Result<NakadiApiClient, Error>
     where
         T: ProvidesAccessToken + Send + Sync + 'static,
@@ -238,6 +298,7 @@ impl ConfigBuilder {
     /// # Errors
     ///
     /// Fails if this builder is in an invalid state.
+    #[cfg(feature = "blocking")]
     pub fn build_client_with_shared_access_token_provider(
         self,
         token_provider: Arc<dyn ProvidesAccessToken + Send + Sync + 'static>,
@@ -252,6 +313,7 @@ impl ConfigBuilder {
 ///
 /// This accesses the REST API only and does not provide
 /// functionality for streaming.
+#[cfg(feature = "blocking")]
 #[derive(Clone)]
 pub struct NakadiApiClient {
     nakadi_host: String,
@@ -259,6 +321,7 @@ pub struct NakadiApiClient {
     token_provider: Arc<dyn ProvidesAccessToken + Send + Sync + 'static>,
 }
 
+#[cfg(feature = "blocking")]
 impl NakadiApiClient {
     /// Build a new client with an owned access token provider.
     ///
@@ -313,96 +376,44 @@ impl NakadiApiClient {
 
         let body = make_cursors_body(cursors);
 
-        let request_builder = self.http_client.post(url).headers(headers).body(body);
+        let send = || -> ::std::result::Result<Response, CommitError> {
+            let request_builder = self
+                .http_client
+                .post(url)
+                .headers(headers.clone())
+                .body(body.clone());
 
-        let request_builder = if let Some(AccessToken(token)) = self.token_provider.get_token()? {
-            request_builder.bearer_auth(token)
-        } else {
-            request_builder
-        };
+            let request_builder =
+                if let Some(AccessToken(token)) = self.token_provider.get_token()? {
+                    request_builder.bearer_auth(token)
+                } else {
+                    request_builder
+                };
 
-        let mut response = request_builder.send()?;
+            Ok(request_builder.send()?)
+        };
 
-        match response.status() {
-            // All cursors committed but at least one did not increase an offset.
-            StatusCode::OK => Ok(CommitStatus::NotAllOffsetsIncreased),
-            // All cursors committed and all increased the offset.
-            StatusCode::NO_CONTENT => Ok(CommitStatus::AllOffsetsIncreased),
-            StatusCode::NOT_FOUND => Err(CommitError::SubscriptionNotFound(
-                format!(
-                    "{}: {}",
-                    StatusCode::NOT_FOUND,
-                    read_response_body(&mut response)
-                ),
-                flow_id,
-            )),
-            StatusCode::UNPROCESSABLE_ENTITY => Err(CommitError::UnprocessableEntity(
-                format!(
-                    "{}: {}",
-                    StatusCode::UNPROCESSABLE_ENTITY,
-                    read_response_body(&mut response)
-                ),
-                flow_id,
-            )),
-            StatusCode::FORBIDDEN => Err(CommitError::Client(
-                format!(
-                    "{}: {}",
-                    StatusCode::FORBIDDEN,
-                    "<Nakadion: Nakadi said forbidden.>"
-                ),
-                flow_id,
-            )),
-            other_status if other_status.is_client_error() => Err(CommitError::Client(
-                format!("{}: {}", other_status, read_response_body(&mut response)),
-                flow_id,
-            )),
-            other_status if other_status.is_server_error() => Err(CommitError::Server(
-                format!("{}: {}", other_status, read_response_body(&mut response)),
-                flow_id,
-            )),
-            other_status => Err(CommitError::Other(
-                format!("{}: {}", other_status, read_response_body(&mut response)),
-                flow_id,
-            )),
+        let mut response = send()?;
+        if response.status() == StatusCode::UNAUTHORIZED
+            && renew_rejected_token(&*self.token_provider, "commit")
+        {
+            response = send()?;
         }
+        let status = response.status();
+
+        commit_outcome(status, read_response_body(&mut response), flow_id)
+    }
+
+    fn cursors_url(&self, subscription_id: &SubscriptionId) -> String {
+        format!(
+            "{}/subscriptions/{}/cursors",
+            self.nakadi_host, subscription_id.0
+        )
     }
 }
 
+#[cfg(feature = "blocking")]
 impl ApiClient for NakadiApiClient {
-    /*    fn stats(&self) -> ::std::result::Result<SubscriptionStats, StatsError> {
-        let mut headers = Headers::new();
-        if let Some(token) = self.token_provider.get_token()? {
-            headers.set(Authorization(Bearer { token: token.0 }));
-        };
-
-        let mut response = self.http_client
-            .get(&self.stats_url)
-            .headers(headers)
-            .send()?;
-        match response.status() {
-            StatusCode::Ok => {
-                let parsed = serde_json::from_reader(response)?;
-                Ok(parsed)
-            }
-            StatusCode::Forbidden => Err(StatsError::Client {
-                message: format!(
-                    "{}: {}",
-                    StatusCode::Forbidden,
-                    "<Nakadion: Nakadi said forbidden.>"
-                ),
-            }),
-            other_status if other_status.is_client_error() => Err(StatsError::Client {
-                message: format!("{}: {}", other_status, read_response_body(&mut response)),
-            }),
-            other_status if other_status.is_server_error() => Err(StatsError::Server {
-                message: format!("{}: {}", other_status, read_response_body(&mut response)),
-            }),
-            other_status => Err(StatsError::Other {
-                message: format!("{}: {}", other_status, read_response_body(&mut response)),
-            }),
-        }
-    }*/
-
     fn commit_cursors_budgeted<T: AsRef<[u8]>>(
         &self,
         subscription_id: &SubscriptionId,
@@ -415,16 +426,14 @@ impl ApiClient for NakadiApiClient {
             return Ok(CommitStatus::NothingToCommit);
         }
 
-        let url = format!(
-            "{}/subscriptions/{}/cursors",
-            self.nakadi_host, subscription_id.0
-        );
+        let url = self.cursors_url(subscription_id);
 
         let mut op = || {
             self.attempt_commit(&url, stream_id.clone(), cursors, flow_id.clone())
                 .map_err(|err| match err {
                     err @ CommitError::UnprocessableEntity { .. } => BackoffError::Permanent(err),
-                    err @ CommitError::Client { .. } => BackoffError::Permanent(err),
+                    err @ CommitError::Forbidden { .. } => BackoffError::Permanent(err),
+                    err @ CommitError::SubscriptionNotFound { .. } => BackoffError::Permanent(err),
                     err => BackoffError::Transient(err),
                 })
         };
@@ -439,10 +448,12 @@ impl ApiClient for NakadiApiClient {
             );
         };
 
-        let mut backoff = ExponentialBackoff::default();
-        backoff.max_elapsed_time = Some(budget);
-        backoff.initial_interval = Duration::from_millis(50);
-        backoff.multiplier = 1.5;
+        let mut backoff = ExponentialBackoff {
+            max_elapsed_time: Some(budget),
+            initial_interval: Duration::from_millis(50),
+            multiplier: 1.5,
+            ..ExponentialBackoff::default()
+        };
 
         match op.retry_notify(&mut backoff, notify) {
             Ok(x) => Ok(x),
@@ -469,10 +480,12 @@ impl ApiClient for NakadiApiClient {
             warn!("Delete event type error happened {:?}: {}", dur, err);
         };
 
-        let mut backoff = ExponentialBackoff::default();
-        backoff.max_elapsed_time = Some(Duration::from_secs(5));
-        backoff.initial_interval = Duration::from_millis(100);
-        backoff.multiplier = 1.5;
+        let mut backoff = ExponentialBackoff {
+            max_elapsed_time: Some(Duration::from_secs(5)),
+            initial_interval: Duration::from_millis(100),
+            multiplier: 1.5,
+            ..ExponentialBackoff::default()
+        };
 
         match op.retry_notify(&mut backoff, notify) {
             Ok(_) => Ok(()),
@@ -507,10 +520,12 @@ impl ApiClient for NakadiApiClient {
             warn!("Create event type error happened {:?}: {}", dur, err);
         };
 
-        let mut backoff = ExponentialBackoff::default();
-        backoff.max_elapsed_time = Some(Duration::from_secs(5));
-        backoff.initial_interval = Duration::from_millis(100);
-        backoff.multiplier = 1.5;
+        let mut backoff = ExponentialBackoff {
+            max_elapsed_time: Some(Duration::from_secs(5)),
+            initial_interval: Duration::from_millis(100),
+            multiplier: 1.5,
+            ..ExponentialBackoff::default()
+        };
 
         match op.retry_notify(&mut backoff, notify) {
             Ok(_) => Ok(()),
@@ -531,9 +546,198 @@ impl ApiClient for NakadiApiClient {
         let url = format!("{}/subscriptions/{}", self.nakadi_host, id.0);
         delete_subscription(&self.http_client, &url, &*self.token_provider)
     }
+
+    fn subscription_stats(
+        &self,
+        id: &SubscriptionId,
+        show_time_lag: bool,
+    ) -> Result<SubscriptionStats, StatsError> {
+        let url = format!(
+            "{}/subscriptions/{}/stats?show_time_lag={}",
+            self.nakadi_host, id.0, show_time_lag
+        );
+        get_subscription_stats(&self.http_client, &url, &*self.token_provider)
+    }
+
+    fn get_committed_cursors(
+        &self,
+        subscription_id: &SubscriptionId,
+        flow_id: FlowId,
+    ) -> Result<Vec<SubscriptionCursor>, GetCursorsError> {
+        let mut headers = HeaderMap::new();
+
+        headers.insert("X-Flow-Id", flow_id.0.parse().unwrap());
+
+        let request_builder = self
+            .http_client
+            .get(&self.cursors_url(subscription_id))
+            .headers(headers);
+
+        let request_builder = if let Some(AccessToken(token)) = self.token_provider.get_token()? {
+            request_builder.bearer_auth(token)
+        } else {
+            request_builder
+        };
+
+        let mut response = request_builder.send()?;
+
+        match response.status() {
+            StatusCode::OK => {
+                let body = read_response_body(&mut response);
+                match serde_json::from_str::<SubscriptionCursorsResponse>(&body) {
+                    Ok(cursors) => Ok(cursors.items),
+                    Err(err) => Err(GetCursorsError::Parse(err.to_string(), flow_id)),
+                }
+            }
+            StatusCode::NOT_FOUND => Err(GetCursorsError::SubscriptionNotFound(
+                format!(
+                    "{}: {}",
+                    StatusCode::NOT_FOUND,
+                    read_response_body(&mut response)
+                ),
+                flow_id,
+            )),
+            StatusCode::FORBIDDEN => Err(GetCursorsError::Client(
+                format!(
+                    "{}: {}",
+                    StatusCode::FORBIDDEN,
+                    "<Nakadion: Nakadi said forbidden.>"
+                ),
+                flow_id,
+            )),
+            other_status if other_status.is_client_error() => Err(GetCursorsError::Client(
+                format!("{}: {}", other_status, read_response_body(&mut response)),
+                flow_id,
+            )),
+            other_status if other_status.is_server_error() => Err(GetCursorsError::Server(
+                format!("{}: {}", other_status, read_response_body(&mut response)),
+                flow_id,
+            )),
+            other_status => Err(GetCursorsError::Other(
+                format!("{}: {}", other_status, read_response_body(&mut response)),
+                flow_id,
+            )),
+        }
+    }
+
+    fn reset_cursors(
+        &self,
+        subscription_id: &SubscriptionId,
+        cursors: &[SubscriptionCursor],
+        flow_id: FlowId,
+    ) -> Result<(), ResetCursorsError> {
+        let mut headers = HeaderMap::new();
+
+        headers.insert("X-Flow-Id", flow_id.0.parse().unwrap());
+
+        let request_builder = self
+            .http_client
+            .patch(&self.cursors_url(subscription_id))
+            .headers(headers)
+            .json(&SubscriptionCursorsRequest { items: cursors });
+
+        let request_builder = if let Some(AccessToken(token)) = self.token_provider.get_token()? {
+            request_builder.bearer_auth(token)
+        } else {
+            request_builder
+        };
+
+        let mut response = request_builder.send()?;
+
+        match response.status() {
+            StatusCode::NO_CONTENT => Ok(()),
+            StatusCode::NOT_FOUND => Err(ResetCursorsError::SubscriptionNotFound(
+                format!(
+                    "{}: {}",
+                    StatusCode::NOT_FOUND,
+                    read_response_body(&mut response)
+                ),
+                flow_id,
+            )),
+            StatusCode::CONFLICT => Err(ResetCursorsError::Conflict(
+                format!(
+                    "{}: {}",
+                    StatusCode::CONFLICT,
+                    read_response_body(&mut response)
+                ),
+                flow_id,
+            )),
+            StatusCode::UNPROCESSABLE_ENTITY => Err(ResetCursorsError::UnprocessableEntity(
+                format!(
+                    "{}: {}",
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    read_response_body(&mut response)
+                ),
+                flow_id,
+            )),
+            StatusCode::FORBIDDEN => Err(ResetCursorsError::Client(
+                format!(
+                    "{}: {}",
+                    StatusCode::FORBIDDEN,
+                    "<Nakadion: Nakadi said forbidden.>"
+                ),
+                flow_id,
+            )),
+            other_status if other_status.is_client_error() => Err(ResetCursorsError::Client(
+                format!("{}: {}", other_status, read_response_body(&mut response)),
+                flow_id,
+            )),
+            other_status if other_status.is_server_error() => Err(ResetCursorsError::Server(
+                format!("{}: {}", other_status, read_response_body(&mut response)),
+                flow_id,
+            )),
+            other_status => Err(ResetCursorsError::Other(
+                format!("{}: {}", other_status, read_response_body(&mut response)),
+                flow_id,
+            )),
+        }
+    }
+}
+
+/// Maps the status and body of a response to a commit
+/// request to the outcome of the commit.
+pub(crate) fn commit_outcome(
+    status: StatusCode,
+    body: String,
+    flow_id: FlowId,
+) -> Result<CommitStatus, CommitError> {
+    match status {
+        // All cursors committed but at least one did not increase an offset.
+        StatusCode::OK => Ok(CommitStatus::NotAllOffsetsIncreased),
+        // All cursors committed and all increased the offset.
+        StatusCode::NO_CONTENT => Ok(CommitStatus::AllOffsetsIncreased),
+        StatusCode::NOT_FOUND => Err(CommitError::SubscriptionNotFound(
+            format!("{}: {}", StatusCode::NOT_FOUND, body),
+            flow_id,
+        )),
+        StatusCode::UNPROCESSABLE_ENTITY => Err(CommitError::UnprocessableEntity(
+            format!("{}: {}", StatusCode::UNPROCESSABLE_ENTITY, body),
+            flow_id,
+        )),
+        StatusCode::FORBIDDEN => Err(CommitError::Forbidden(
+            format!(
+                "{}: {}",
+                StatusCode::FORBIDDEN,
+                "<Nakadion: Nakadi said forbidden.>"
+            ),
+            flow_id,
+        )),
+        other_status if other_status.is_client_error() => Err(CommitError::Client(
+            format!("{}: {}", other_status, body),
+            flow_id,
+        )),
+        other_status if other_status.is_server_error() => Err(CommitError::Server(
+            format!("{}: {}", other_status, body),
+            flow_id,
+        )),
+        other_status => Err(CommitError::Other(
+            format!("{}: {}", other_status, body),
+            flow_id,
+        )),
+    }
 }
 
-fn make_cursors_body<T: AsRef<[u8]>>(cursors: &[T]) -> Vec<u8> {
+pub(crate) fn make_cursors_body<T: AsRef<[u8]>>(cursors: &[T]) -> Vec<u8> {
     let bytes_required: usize = cursors.iter().map(|c| c.as_ref().len()).sum();
     let mut body = Vec::with_capacity(bytes_required + 20);
     body.extend(b"{\"items\":[");
@@ -547,6 +751,118 @@ fn make_cursors_body<T: AsRef<[u8]>>(cursors: &[T]) -> Vec<u8> {
     body
 }
 
+/// The body of a request to reset the cursors of a subscription
+#[cfg(feature = "blocking")]
+#[derive(Serialize)]
+struct SubscriptionCursorsRequest<'a> {
+    items: &'a [SubscriptionCursor],
+}
+
+/// The committed cursors of a subscription as returned by Nakadi
+#[cfg(feature = "blocking")]
+#[derive(Deserialize)]
+struct SubscriptionCursorsResponse {
+    items: Vec<SubscriptionCursor>,
+}
+
+#[cfg(feature = "blocking")]
+#[test]
+fn subscription_cursors_request_serialize() {
+    use crate::nakadi::model::PartitionId;
+
+    let cursors = vec![SubscriptionCursor {
+        partition: PartitionId::new("6"),
+        offset: "543".to_string(),
+        event_type: "order.ORDER_RECEIVED".to_string(),
+    }];
+
+    let json_str = serde_json::to_string(&SubscriptionCursorsRequest { items: &cursors }).unwrap();
+
+    assert_eq!(
+        &json_str,
+        "{\"items\":[{\"partition\":\"6\",\"offset\":\"543\",\
+         \"event_type\":\"order.ORDER_RECEIVED\"}]}"
+    );
+}
+
+#[cfg(feature = "blocking")]
+#[test]
+fn subscription_cursors_response_deserialize() {
+    use crate::nakadi::model::PartitionId;
+
+    let json_str = r#"{"items":[{"partition":"6","offset":"543","#.to_owned()
+        + r#""event_type":"order.ORDER_RECEIVED","cursor_token":"#
+        + r#""b75c3102-98a4-4385-a5fd-b96f1d7872f2"}]}"#;
+
+    let parsed: SubscriptionCursorsResponse = serde_json::from_str(&json_str).unwrap();
+
+    assert_eq!(
+        parsed.items,
+        vec![SubscriptionCursor {
+            partition: PartitionId::new("6"),
+            offset: "543".to_string(),
+            event_type: "order.ORDER_RECEIVED".to_string(),
+        }]
+    );
+}
+
+#[cfg(all(feature = "blocking", feature = "test-support"))]
+#[test]
+fn retries_throttled_and_unauthorized_commits() {
+    use crate::auth::NoAuthAccessTokenProvider;
+    use crate::metrics::DevNullMetricsCollector;
+    use crate::streaming_client::{ConfigBuilder as StreamingConfigBuilder, StreamingClient};
+    use crate::test_support::{Endpoint, FakeNakadi, Fault};
+
+    let nakadi = FakeNakadi::start().unwrap();
+    nakadi.create_event_type("test_event", 1);
+    let subscription_id = nakadi.create_subscription("test_app", &["test_event"]);
+    nakadi.publish("test_event", &[serde_json::json!({"a": 1})]);
+
+    let streaming_client = StreamingConfigBuilder::default()
+        .nakadi_host(nakadi.nakadi_host())
+        .build_client(NoAuthAccessTokenProvider, DevNullMetricsCollector)
+        .unwrap();
+    let api_client = ConfigBuilder::default()
+        .nakadi_host(nakadi.nakadi_host())
+        .build_client(NoAuthAccessTokenProvider)
+        .unwrap();
+
+    let (stream_id, mut lines) = streaming_client
+        .connect(&subscription_id, FlowId::default())
+        .unwrap();
+    let cursor = loop {
+        let line: serde_json::Value =
+            serde_json::from_slice(&lines.next().unwrap().unwrap().bytes).unwrap();
+        if line["events"].is_array() {
+            break serde_json::to_vec(&line["cursor"]).unwrap();
+        }
+    };
+
+    nakadi.inject_fault(
+        Endpoint::Commit,
+        Fault::Status(429, "Throttled".to_string()),
+    );
+    nakadi.inject_fault(
+        Endpoint::Commit,
+        Fault::Status(401, "Unauthorized".to_string()),
+    );
+
+    let status = api_client.commit_cursors_budgeted(
+        &subscription_id,
+        &stream_id,
+        &[cursor],
+        FlowId::default(),
+        Duration::from_secs(5),
+    );
+
+    match status {
+        Ok(CommitStatus::AllOffsetsIncreased) => {}
+        other => panic!("Expected the commit to be retried but got {:?}", other),
+    }
+    assert_eq!(nakadi.commits().len(), 1);
+}
+
 /// A commit attempt can result in multiple statuses
 #[derive(Debug)]
 pub enum CommitStatus {
@@ -562,7 +878,7 @@ pub enum CommitStatus {
     NothingToCommit,
 }
 
-#[derive(Fail, Debug)]
+#[derive(Fail, Debug, Clone, PartialEq, Eq)]
 pub enum CommitError {
     #[fail(display = "Token Error on commit: {}", _0)]
     TokenError(String),
@@ -574,6 +890,66 @@ pub enum CommitError {
     UnprocessableEntity(String, FlowId),
     #[fail(display = "Server Error(FlowId: {}): {}", _1, _0)]
     Server(String, FlowId),
+    #[fail(display = "Forbidden(FlowId: {}): {}", _1, _0)]
+    Forbidden(String, FlowId),
+    #[fail(display = "Client Error(FlowId: {}): {}", _1, _0)]
+    Client(String, FlowId),
+    #[fail(display = "Other Error(FlowId: {}): {}", _1, _0)]
+    Other(String, FlowId),
+}
+
+impl CommitError {
+    /// Returns true if this error can most possibly not
+    /// be mitigated by reconnecting to the stream.
+    ///
+    /// Only a missing subscription and missing permissions are permanent.
+    /// Other client errors like `401` or `429` are not.
+    pub fn is_permanent(&self) -> bool {
+        matches!(
+            *self,
+            CommitError::SubscriptionNotFound(_, _) | CommitError::Forbidden(_, _)
+        )
+    }
+}
+
+/// Errors that can happen when retrieving the committed
+/// cursors of a subscription.
+#[derive(Fail, Debug)]
+pub enum GetCursorsError {
+    #[fail(display = "Token Error on getting cursors: {}", _0)]
+    TokenError(String),
+    #[fail(display = "Connection Error: {}", _0)]
+    Connection(String),
+    #[fail(display = "Subscription not found(FlowId: {}): {}", _1, _0)]
+    SubscriptionNotFound(String, FlowId),
+    #[fail(display = "Parse Error(FlowId: {}): {}", _1, _0)]
+    Parse(String, FlowId),
+    #[fail(display = "Server Error(FlowId: {}): {}", _1, _0)]
+    Server(String, FlowId),
+    #[fail(display = "Client Error(FlowId: {}): {}", _1, _0)]
+    Client(String, FlowId),
+    #[fail(display = "Other Error(FlowId: {}): {}", _1, _0)]
+    Other(String, FlowId),
+}
+
+/// Errors that can happen when resetting the
+/// cursors of a subscription.
+#[derive(Fail, Debug)]
+pub enum ResetCursorsError {
+    #[fail(display = "Token Error on cursor reset: {}", _0)]
+    TokenError(String),
+    #[fail(display = "Connection Error: {}", _0)]
+    Connection(String),
+    #[fail(display = "Subscription not found(FlowId: {}): {}", _1, _0)]
+    SubscriptionNotFound(String, FlowId),
+    /// A cursor reset is already in progress for the subscription
+    #[fail(display = "Conflict(FlowId: {}): {}", _1, _0)]
+    Conflict(String, FlowId),
+    /// At least one of the cursors is invalid
+    #[fail(display = "Unprocessable Entity(FlowId: {}): {}", _1, _0)]
+    UnprocessableEntity(String, FlowId),
+    #[fail(display = "Server Error(FlowId: {}): {}", _1, _0)]
+    Server(String, FlowId),
     #[fail(display = "Client Error(FlowId: {}): {}", _1, _0)]
     Client(String, FlowId),
     #[fail(display = "Other Error(FlowId: {}): {}", _1, _0)]
@@ -608,6 +984,30 @@ impl From<::reqwest::Error> for CommitError {
     }
 }
 
+impl From<TokenError> for GetCursorsError {
+    fn from(e: TokenError) -> GetCursorsError {
+        GetCursorsError::TokenError(format!("{}", e))
+    }
+}
+
+impl From<::reqwest::Error> for GetCursorsError {
+    fn from(e: ::reqwest::Error) -> GetCursorsError {
+        GetCursorsError::Connection(format!("{}", e))
+    }
+}
+
+impl From<TokenError> for ResetCursorsError {
+    fn from(e: TokenError) -> ResetCursorsError {
+        ResetCursorsError::TokenError(format!("{}", e))
+    }
+}
+
+impl From<::reqwest::Error> for ResetCursorsError {
+    fn from(e: ::reqwest::Error) -> ResetCursorsError {
+        ResetCursorsError::Connection(format!("{}", e))
+    }
+}
+
 impl From<TokenError> for StatsError {
     fn from(e: TokenError) -> StatsError {
         StatsError::TokenError(format!("{}", e))
@@ -626,6 +1026,7 @@ impl From<::reqwest::Error> for StatsError {
     }
 }
 
+#[cfg(feature = "blocking")]
 fn create_event_type(
     client: &HttpClient,
     url: &str,
@@ -668,6 +1069,7 @@ fn create_event_type(
     }
 }
 
+#[cfg(feature = "blocking")]
 fn delete_event_type(
     client: &HttpClient,
     url: &str,
@@ -701,6 +1103,7 @@ fn delete_event_type(
     }
 }
 
+#[cfg(feature = "blocking")]
 fn delete_subscription(
     client: &HttpClient,
     url: &str,
@@ -738,6 +1141,51 @@ fn delete_subscription(
     }
 }
 
+#[cfg(feature = "blocking")]
+fn get_subscription_stats(
+    client: &HttpClient,
+    url: &str,
+    token_provider: &dyn ProvidesAccessToken,
+) -> Result<SubscriptionStats, StatsError> {
+    let request_builder = client.get(url);
+
+    let request_builder = if let Some(AccessToken(token)) = token_provider.get_token()? {
+        request_builder.bearer_auth(token)
+    } else {
+        request_builder
+    };
+
+    let mut response = request_builder.send()?;
+
+    match response.status() {
+        StatusCode::OK => {
+            let body = read_response_body(&mut response);
+            Ok(serde_json::from_str(&body)?)
+        }
+        StatusCode::FORBIDDEN => Err(StatsError::Client(format!(
+            "{}: {}",
+            StatusCode::FORBIDDEN,
+            "<Nakadion: Nakadi said forbidden.>"
+        ))),
+        other_status if other_status.is_client_error() => Err(StatsError::Client(format!(
+            "{}: {}",
+            other_status,
+            read_response_body(&mut response)
+        ))),
+        other_status if other_status.is_server_error() => Err(StatsError::Server(format!(
+            "{}: {}",
+            other_status,
+            read_response_body(&mut response)
+        ))),
+        other_status => Err(StatsError::Other(format!(
+            "{}: {}",
+            other_status,
+            read_response_body(&mut response)
+        ))),
+    }
+}
+
+#[cfg(feature = "blocking")]
 fn read_response_body(response: &mut Response) -> String {
     let mut buf = String::new();
     response
@@ -746,6 +1194,7 @@ fn read_response_body(response: &mut Response) -> String {
         .unwrap_or_else(|_| "<Could not read body.>".to_string())
 }
 
+#[cfg(feature = "blocking")]
 fn create_subscription(
     client: &HttpClient,
     url: &str,
@@ -765,36 +1214,36 @@ fn create_subscription(
     };
 
     match request_builder.json(request).send() {
-        Ok(ref mut response) => match response.status() {
-            StatusCode::OK => match serde_json::from_reader(response) {
-                Ok(sub) => Ok(CreateSubscriptionStatus::AlreadyExists(sub)),
-                Err(err) => Err(CreateSubscriptionError::Other(err.to_string())),
-            },
-            StatusCode::CREATED => match serde_json::from_reader(response) {
-                Ok(sub) => Ok(CreateSubscriptionStatus::Created(sub)),
-                Err(err) => Err(CreateSubscriptionError::Other(err.to_string())),
-            },
-            StatusCode::UNAUTHORIZED => {
-                let msg = read_response_body(response);
-                Err(CreateSubscriptionError::Unauthorized(msg))
-            }
-            StatusCode::UNPROCESSABLE_ENTITY => {
-                let msg = read_response_body(response);
-                Err(CreateSubscriptionError::UnprocessableEntity(msg))
-            }
-            StatusCode::BAD_REQUEST => {
-                let msg = read_response_body(response);
-                Err(CreateSubscriptionError::BadRequest(msg))
-            }
-            _ => {
-                let msg = read_response_body(response);
-                Err(CreateSubscriptionError::Other(msg))
-            }
-        },
+        Ok(ref mut response) => {
+            let status = response.status();
+            create_subscription_outcome(status, read_response_body(response))
+        }
         Err(err) => Err(CreateSubscriptionError::Other(format!("{}", err))),
     }
 }
 
+/// Maps the status and body of a response to a request
+/// for creating a subscription to the outcome of the request.
+pub(crate) fn create_subscription_outcome(
+    status: StatusCode,
+    body: String,
+) -> Result<CreateSubscriptionStatus, CreateSubscriptionError> {
+    match status {
+        StatusCode::OK => match serde_json::from_str(&body) {
+            Ok(sub) => Ok(CreateSubscriptionStatus::AlreadyExists(sub)),
+            Err(err) => Err(CreateSubscriptionError::Other(err.to_string())),
+        },
+        StatusCode::CREATED => match serde_json::from_str(&body) {
+            Ok(sub) => Ok(CreateSubscriptionStatus::Created(sub)),
+            Err(err) => Err(CreateSubscriptionError::Other(err.to_string())),
+        },
+        StatusCode::UNAUTHORIZED => Err(CreateSubscriptionError::Unauthorized(body)),
+        StatusCode::UNPROCESSABLE_ENTITY => Err(CreateSubscriptionError::UnprocessableEntity(body)),
+        StatusCode::BAD_REQUEST => Err(CreateSubscriptionError::BadRequest(body)),
+        _ => Err(CreateSubscriptionError::Other(body)),
+    }
+}
+
 /// A request describing the subscription to be created.
 ///
 /// The fields are described in more detail in
@@ -1226,16 +1675,80 @@ pub struct EventTypeStatistics {
 }
 
 pub mod stats {
+    //! Statistics on a subscription
+    //!
+    //! See [Subscription Stats](http://nakadi.io/manual.html#/subscriptions/subscription_id/stats_get)
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use crate::nakadi::model::{PartitionId, StreamId};
+
+    /// The state of a partition within a subscription.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum PartitionState {
+        /// The partition is currently not assigned to any client
+        Unassigned,
+        /// The partition is currently reassigned from one client to another
+        Reassigning,
+        /// The partition is assigned to a client
+        Assigned,
+    }
+
+    impl Serialize for PartitionState {
+        fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+        {
+            match *self {
+                PartitionState::Unassigned => serializer.serialize_str("unassigned"),
+                PartitionState::Reassigning => serializer.serialize_str("reassigning"),
+                PartitionState::Assigned => serializer.serialize_str("assigned"),
+            }
+        }
+    }
+
+    impl<'de> Deserialize<'de> for PartitionState {
+        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            let tag: String = Deserialize::deserialize(deserializer)?;
+            match tag.as_ref() {
+                "unassigned" => Ok(PartitionState::Unassigned),
+                "reassigning" => Ok(PartitionState::Reassigning),
+                "assigned" => Ok(PartitionState::Assigned),
+                other => Err(serde::de::Error::custom(format!(
+                    "not a partition state: {}",
+                    other
+                ))),
+            }
+        }
+    }
+
     /// Information on a partition
-    #[derive(Debug, Deserialize)]
+    #[derive(Debug, Clone, Deserialize)]
     pub struct PartitionInfo {
-        pub partition: String,
-        pub stream_id: String,
-        pub unconsumed_events: usize,
+        pub partition: PartitionId,
+        pub state: PartitionState,
+        /// The stream the partition is assigned to.
+        ///
+        /// Not present if the partition is unassigned.
+        #[serde(default)]
+        pub stream_id: Option<StreamId>,
+        /// The number of events in this partition which have
+        /// not been committed yet.
+        ///
+        /// Not present if nothing has been committed so far.
+        #[serde(default)]
+        pub unconsumed_events: Option<usize>,
+        /// The age of the oldest uncommitted event in seconds.
+        ///
+        /// Only present if the time lag was requested.
+        #[serde(default)]
+        pub consumer_lag_seconds: Option<u64>,
     }
 
     /// An `EventType` can be published on multiple partitions.
-    #[derive(Debug, Deserialize)]
+    #[derive(Debug, Clone, Deserialize)]
     pub struct EventTypeInfo {
         pub event_type: String,
         pub partitions: Vec<PartitionInfo>,
@@ -1247,11 +1760,19 @@ pub mod stats {
         pub fn num_partitions(&self) -> usize {
             self.partitions.len()
         }
+
+        /// Returns the sum of all unconsumed events over all partitions.
+        pub fn unconsumed_events(&self) -> usize {
+            self.partitions
+                .iter()
+                .filter_map(|p| p.unconsumed_events)
+                .sum()
+        }
     }
 
     /// A stream can provide multiple `EventTypes` where each of them can have
     /// its own partitioning setup.
-    #[derive(Debug, Deserialize, Default)]
+    #[derive(Debug, Clone, Deserialize, Default)]
     pub struct SubscriptionStats {
         #[serde(rename = "items")]
         pub event_types: Vec<EventTypeInfo>,
@@ -1267,5 +1788,47 @@ pub mod stats {
                 .max()
                 .unwrap_or(0)
         }
+
+        /// Returns the sum of all unconsumed events over all `EventType`s.
+        pub fn unconsumed_events(&self) -> usize {
+            self.event_types
+                .iter()
+                .map(|et| et.unconsumed_events())
+                .sum()
+        }
+
+        /// Returns the highest consumer lag of all partitions
+        /// if the time lag was requested.
+        pub fn max_consumer_lag_seconds(&self) -> Option<u64> {
+            self.event_types
+                .iter()
+                .flat_map(|et| et.partitions.iter())
+                .filter_map(|p| p.consumer_lag_seconds)
+                .max()
+        }
+    }
+
+    #[test]
+    fn deserialize_subscription_stats() {
+        let json = r#"{"items":[{"event_type":"order.ORDER_RECEIVED","partitions":["#.to_owned()
+            + r#"{"partition":"0","state":"assigned","unconsumed_events":5,"#
+            + r#""consumer_lag_seconds":12,"stream_id":"b75c3102","assignment_type":"auto"},"#
+            + r#"{"partition":"1","state":"unassigned"}]}]}"#;
+
+        let stats: SubscriptionStats = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(stats.max_partitions(), 2);
+        assert_eq!(stats.unconsumed_events(), 5);
+        assert_eq!(stats.max_consumer_lag_seconds(), Some(12));
+
+        let assigned = &stats.event_types[0].partitions[0];
+        assert_eq!(assigned.partition, PartitionId::new("0"));
+        assert_eq!(assigned.state, PartitionState::Assigned);
+        assert_eq!(assigned.stream_id.as_ref().unwrap().0, "b75c3102");
+
+        let unassigned = &stats.event_types[0].partitions[1];
+        assert_eq!(unassigned.state, PartitionState::Unassigned);
+        assert!(unassigned.stream_id.is_none());
+        assert!(unassigned.unconsumed_events.is_none());
     }
 }
@@ -0,0 +1,255 @@
+//! Access to the REST API of Nakadi without blocking
+//!
+//! # Functionality
+//!
+//! * Commit cursors
+//! * Create a new Subscription or get an exiting subscription
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use failure::*;
+use futures::future::{BoxFuture, FutureExt};
+use reqwest::{
+    header::{HeaderMap, CONTENT_TYPE},
+    Client as HttpClient, ClientBuilder as HttpClientBuilder, StatusCode,
+};
+use tokio::time::delay_for;
+
+use crate::auth::{renew_rejected_token, AccessToken, ProvidesAccessToken};
+use crate::nakadi::api::{
+    commit_outcome, create_subscription_outcome, make_cursors_body, CommitError, CommitStatus,
+    Config, CreateSubscriptionError, CreateSubscriptionStatus, SubscriptionRequest,
+};
+use crate::nakadi::model::{FlowId, StreamId, SubscriptionId};
+
+/// Access to the Nakadi REST API without blocking
+pub trait AsyncApiClient {
+    /// Commit the cursors encoded in the given
+    /// bytes.
+    ///
+    /// A commit can only be done on a valid stream identified by
+    /// its id.
+    ///
+    /// This method will retry for 500ms in case of a failure.
+    ///
+    /// # Errors
+    ///
+    /// The cursors could not be committed.
+    fn commit_cursors<'a, T: AsRef<[u8]> + Sync>(
+        &'a self,
+        subscription_id: &'a SubscriptionId,
+        stream_id: &'a StreamId,
+        cursors: &'a [T],
+        flow_id: FlowId,
+    ) -> BoxFuture<'a, Result<CommitStatus, CommitError>> {
+        self.commit_cursors_budgeted(
+            subscription_id,
+            stream_id,
+            cursors,
+            flow_id,
+            Duration::from_millis(500),
+        )
+    }
+
+    /// Commit the cursors encoded in the given
+    /// bytes.
+    ///
+    /// A commit can only be done on a valid stream identified by
+    /// its id.
+    ///
+    /// This method will retry for the `Duration`
+    /// defined by `budget` in case of a failure.
+    ///
+    /// # Errors
+    ///
+    /// The cursors could not be committed.
+    fn commit_cursors_budgeted<'a, T: AsRef<[u8]> + Sync>(
+        &'a self,
+        subscription_id: &'a SubscriptionId,
+        stream_id: &'a StreamId,
+        cursors: &'a [T],
+        flow_id: FlowId,
+        budget: Duration,
+    ) -> BoxFuture<'a, Result<CommitStatus, CommitError>>;
+
+    /// Creates an new subscription defined by a `SubscriptionRequest`.
+    ///
+    /// Trying to create a `Subscription` that already existed is not
+    /// considered a failure. See `CreateSubscriptionStatus` for mor details.
+    ///
+    /// # Errors
+    ///
+    /// The subscription could not be created.
+    fn create_subscription<'a>(
+        &'a self,
+        request: &'a SubscriptionRequest,
+    ) -> BoxFuture<'a, Result<CreateSubscriptionStatus, CreateSubscriptionError>>;
+}
+
+/// A REST client for the Nakadi API that does not block.
+///
+/// This accesses the REST API only and does not provide
+/// functionality for streaming.
+#[derive(Clone)]
+pub struct AsyncNakadiApiClient {
+    nakadi_host: String,
+    http_client: HttpClient,
+    token_provider: Arc<dyn ProvidesAccessToken + Send + Sync + 'static>,
+}
+
+impl AsyncNakadiApiClient {
+    /// Build a new client with the given access token provider.
+    pub fn new<P>(config: Config, token_provider: P) -> Result<AsyncNakadiApiClient, Error>
+    where
+        P: ProvidesAccessToken + Send + Sync + 'static,
+    {
+        AsyncNakadiApiClient::with_shared_access_token_provider(config, Arc::new(token_provider))
+    }
+
+    /// Build a new client with a shared access token provider.
+    pub fn with_shared_access_token_provider(
+        config: Config,
+        token_provider: Arc<dyn ProvidesAccessToken + Send + Sync + 'static>,
+    ) -> Result<AsyncNakadiApiClient, Error> {
+        let http_client = HttpClientBuilder::new()
+            .timeout(config.request_timeout)
+            .build()
+            .context("Could not build HTTP client")?;
+
+        Ok(AsyncNakadiApiClient {
+            nakadi_host: config.nakadi_host,
+            http_client,
+            token_provider,
+        })
+    }
+
+    async fn attempt_commit<T: AsRef<[u8]>>(
+        &self,
+        url: &str,
+        stream_id: &StreamId,
+        cursors: &[T],
+        flow_id: FlowId,
+    ) -> Result<CommitStatus, CommitError> {
+        let mut headers = HeaderMap::new();
+
+        headers.insert("X-Flow-Id", flow_id.0.parse().unwrap());
+        headers.insert("X-Nakadi-StreamId", stream_id.0.parse().unwrap());
+        headers.insert(CONTENT_TYPE, "application/json".parse().unwrap());
+
+        let body = make_cursors_body(cursors);
+
+        let send = || async {
+            let request_builder = self
+                .http_client
+                .post(url)
+                .headers(headers.clone())
+                .body(body.clone());
+
+            let request_builder =
+                if let Some(AccessToken(token)) = self.token_provider.get_token()? {
+                    request_builder.bearer_auth(token)
+                } else {
+                    request_builder
+                };
+
+            Ok::<_, CommitError>(request_builder.send().await?)
+        };
+
+        let mut response = send().await?;
+        if response.status() == StatusCode::UNAUTHORIZED
+            && renew_rejected_token(&*self.token_provider, "commit")
+        {
+            response = send().await?;
+        }
+        let status = response.status();
+
+        commit_outcome(status, read_response_body(response).await, flow_id)
+    }
+}
+
+impl AsyncApiClient for AsyncNakadiApiClient {
+    fn commit_cursors_budgeted<'a, T: AsRef<[u8]> + Sync>(
+        &'a self,
+        subscription_id: &'a SubscriptionId,
+        stream_id: &'a StreamId,
+        cursors: &'a [T],
+        flow_id: FlowId,
+        budget: Duration,
+    ) -> BoxFuture<'a, Result<CommitStatus, CommitError>> {
+        async move {
+            if cursors.is_empty() {
+                return Ok(CommitStatus::NothingToCommit);
+            }
+
+            let url = format!(
+                "{}/subscriptions/{}/cursors",
+                self.nakadi_host, subscription_id.0
+            );
+
+            let deadline = Instant::now() + budget;
+            let mut delay = Duration::from_millis(50);
+            loop {
+                let err = match self
+                    .attempt_commit(&url, stream_id, cursors, flow_id.clone())
+                    .await
+                {
+                    Ok(status) => return Ok(status),
+                    Err(err @ CommitError::UnprocessableEntity { .. }) => return Err(err),
+                    Err(err @ CommitError::Forbidden { .. }) => return Err(err),
+                    Err(err @ CommitError::SubscriptionNotFound { .. }) => return Err(err),
+                    Err(err) => err,
+                };
+
+                if Instant::now() + delay > deadline {
+                    return Err(err);
+                }
+
+                warn!(
+                    "[API-Client, stream={}, flow_id={}] - Retry notification. \
+                     Commit error happened. Retrying in {:?}: {}",
+                    stream_id, flow_id, delay, err
+                );
+
+                delay_for(delay).await;
+                delay = delay.mul_f64(1.5);
+            }
+        }
+        .boxed()
+    }
+
+    fn create_subscription<'a>(
+        &'a self,
+        request: &'a SubscriptionRequest,
+    ) -> BoxFuture<'a, Result<CreateSubscriptionStatus, CreateSubscriptionError>> {
+        async move {
+            let url = format!("{}/subscriptions", self.nakadi_host);
+
+            let request_builder = self
+                .http_client
+                .post(&url)
+                .header(CONTENT_TYPE, "application/json");
+
+            let request_builder = match self.token_provider.get_token() {
+                Ok(Some(AccessToken(token))) => request_builder.bearer_auth(token),
+                Ok(None) => request_builder,
+                Err(err) => return Err(CreateSubscriptionError::Other(err.to_string())),
+            };
+
+            match request_builder.json(request).send().await {
+                Ok(response) => {
+                    let status = response.status();
+                    create_subscription_outcome(status, read_response_body(response).await)
+                }
+                Err(err) => Err(CreateSubscriptionError::Other(format!("{}", err))),
+            }
+        }
+        .boxed()
+    }
+}
+
+async fn read_response_body(response: reqwest::Response) -> String {
+    response
+        .text()
+        .await
+        .unwrap_or_else(|_| "<Could not read body.>".to_string())
+}
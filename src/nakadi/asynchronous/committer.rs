@@ -0,0 +1,254 @@
+//! Keeping track of cursors and committing them from a task
+use std::time::{Duration, Instant};
+
+use failure::*;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+
+use crate::cancellation_token::AutoCancellationToken;
+use crate::nakadi::api::{CommitError, CommitStatus};
+use crate::nakadi::asynchronous::api::AsyncApiClient;
+use crate::nakadi::batch::Batch;
+use crate::nakadi::committer::{add_commit_entry, due_cursors, CommitEntries};
+use crate::nakadi::metrics::{MetricsCollector, MetricsContext};
+use crate::nakadi::model::{FlowId, StreamId, SubscriptionId};
+use crate::nakadi::CommitStrategy;
+
+/// Receives the cursors of processed batches and commits
+/// them according to a `CommitStrategy`.
+///
+/// The committer stops once all of its clones have been dropped
+/// after it committed all remaining cursors.
+#[derive(Clone)]
+pub(crate) struct Committer {
+    sender: mpsc::UnboundedSender<(Batch, Option<usize>)>,
+    subscription_id: SubscriptionId,
+    stream_id: StreamId,
+}
+
+impl Committer {
+    /// Spawn the committer task on the current runtime.
+    pub fn start<A, M>(
+        client: A,
+        strategy: CommitStrategy,
+        subscription_id: SubscriptionId,
+        stream_id: StreamId,
+        lifecycle: AutoCancellationToken,
+        metrics_collector: M,
+    ) -> (Committer, JoinHandle<()>)
+    where
+        A: AsyncApiClient + Send + Sync + 'static,
+        M: MetricsCollector + Send + Sync + 'static,
+    {
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        let handle = tokio::spawn(commit_loop(
+            receiver,
+            strategy,
+            subscription_id.clone(),
+            stream_id.clone(),
+            client,
+            lifecycle,
+            metrics_collector,
+        ));
+
+        (
+            Committer {
+                sender,
+                subscription_id,
+                stream_id,
+            },
+            handle,
+        )
+    }
+
+    pub fn request_commit(
+        &self,
+        batch: Batch,
+        num_events_hint: Option<usize>,
+    ) -> Result<(), Error> {
+        self.sender.send((batch, num_events_hint)).map_err(|_| {
+            format_err!(
+                "[Committer, stream={}] Could not accept commit request. Channel disconnected.",
+                self.stream_id
+            )
+        })
+    }
+
+    pub fn stream_id(&self) -> &StreamId {
+        &self.stream_id
+    }
+
+    pub fn subscription_id(&self) -> &SubscriptionId {
+        &self.subscription_id
+    }
+}
+
+/// Commits cursors until the channel closes or a commit fails.
+///
+/// `_lifecycle` is only held to signal that the committer
+/// stopped once it gets dropped.
+async fn commit_loop<A, M>(
+    mut receiver: mpsc::UnboundedReceiver<(Batch, Option<usize>)>,
+    strategy: CommitStrategy,
+    subscription_id: SubscriptionId,
+    stream_id: StreamId,
+    client: A,
+    _lifecycle: AutoCancellationToken,
+    metrics_collector: M,
+) where
+    A: AsyncApiClient,
+    M: MetricsCollector,
+{
+    let mut cursors = CommitEntries::new();
+    loop {
+        match timeout(Duration::from_millis(50), receiver.recv()).await {
+            Ok(Some((next_batch, num_events_hint))) => {
+                metrics_collector.committer_batch_received(
+                    &MetricsContext::for_batch(&subscription_id, &next_batch.batch_line),
+                    next_batch.received_at,
+                );
+                add_commit_entry(&mut cursors, next_batch, strategy, num_events_hint);
+            }
+            Ok(None) => {
+                info!(
+                    "[Committer, subscription={}, stream={}] Commit channel closed. \
+                     Flushing all cursors before stopping.",
+                    subscription_id, stream_id
+                );
+                flush_all_cursors(cursors, &subscription_id, &stream_id, &client).await;
+                break;
+            }
+            Err(_elapsed) => (),
+        }
+
+        match flush_if_due(
+            &mut cursors,
+            &subscription_id,
+            &stream_id,
+            &client,
+            strategy,
+            &metrics_collector,
+        )
+        .await
+        {
+            Ok(CommitStatus::NotAllOffsetsIncreased) => info!(
+                "[Committer, subscription={}, stream={}] Not all cursors were increased.",
+                subscription_id, stream_id
+            ),
+            Err(err) => {
+                error!(
+                    "[Committer, subscription={}, stream={}] Aborting. Failed to commit cursors: {}",
+                    subscription_id, stream_id, err
+                );
+                break;
+            }
+            _ => {}
+        }
+    }
+
+    info!(
+        "[Committer, subscription={}, stream={}] Committer stopped.",
+        subscription_id, stream_id
+    );
+}
+
+async fn flush_all_cursors<A>(
+    all_cursors: CommitEntries,
+    subscription_id: &SubscriptionId,
+    stream_id: &StreamId,
+    client: &A,
+) where
+    A: AsyncApiClient,
+{
+    if all_cursors.is_empty() {
+        info!(
+            "[Committer, subscription={}, stream={}] No cursors to finally commit.",
+            subscription_id, stream_id
+        );
+        return;
+    }
+
+    let cursors_to_commit: Vec<_> = all_cursors.values().map(|v| v.cursor()).collect();
+
+    let flow_id = FlowId::default();
+
+    match client
+        .commit_cursors(
+            subscription_id,
+            stream_id,
+            &cursors_to_commit,
+            flow_id.clone(),
+        )
+        .await
+    {
+        Ok(CommitStatus::AllOffsetsIncreased) => info!(
+            "[Committer, subscription={}, stream={}, flow id={}] All remaining offsets\
+             increased.",
+            subscription_id, stream_id, flow_id
+        ),
+        Ok(CommitStatus::NotAllOffsetsIncreased) => info!(
+            "[Committer, subscription={}, stream={}, flow id={}] Not all remaining\
+             offsets increased.",
+            subscription_id, stream_id, flow_id
+        ),
+        Ok(CommitStatus::NothingToCommit) => info!(
+            "[Committer, subscription={}, stream={}, flow id={}] There was nothing\
+             to be finally committed.",
+            subscription_id, stream_id, flow_id
+        ),
+        Err(err) => error!(
+            "[Committer, subscription={}, stream={}, flow id={}] Failed to commit all\
+             remaining cursors: {}",
+            subscription_id, stream_id, flow_id, err
+        ),
+    }
+}
+
+async fn flush_if_due<A, M>(
+    all_cursors: &mut CommitEntries,
+    subscription_id: &SubscriptionId,
+    stream_id: &StreamId,
+    client: &A,
+    strategy: CommitStrategy,
+    metrics_collector: &M,
+) -> Result<CommitStatus, CommitError>
+where
+    A: AsyncApiClient,
+    M: MetricsCollector,
+{
+    let due =
+        if let Some(due) = due_cursors(all_cursors, subscription_id, strategy, metrics_collector) {
+            due
+        } else {
+            return Ok(CommitStatus::NothingToCommit);
+        };
+
+    let ctx = MetricsContext::for_subscription(subscription_id);
+    let start = Instant::now();
+    match client
+        .commit_cursors_budgeted(
+            subscription_id,
+            stream_id,
+            &due.cursors,
+            FlowId::default(),
+            Duration::from_secs(5),
+        )
+        .await
+    {
+        Ok(status) => {
+            metrics_collector.committer_cursor_commit_attempt(&ctx, start);
+            metrics_collector.committer_cursor_committed(&ctx, start);
+            metrics_collector.committer_batches_committed(&ctx, due.num_batches);
+            metrics_collector.committer_events_committed(&ctx, due.num_events);
+            all_cursors.clear();
+            Ok(status)
+        }
+        Err(err) => {
+            metrics_collector.committer_cursor_commit_attempt(&ctx, start);
+            metrics_collector.committer_cursor_commit_failed(&ctx, start);
+            Err(err)
+        }
+    }
+}
@@ -0,0 +1,452 @@
+//! The consumer iterates over batches of events on a task.
+use std::time::Instant;
+
+use failure::*;
+use futures::future::{self, Either, FutureExt};
+use futures::pin_mut;
+use futures::stream::StreamExt;
+use tokio::time::delay_for;
+
+use crate::cancellation_token::{
+    AutoCancellationToken, CancellationToken, CancellationTokenSource,
+};
+
+use crate::nakadi::asynchronous::api::AsyncApiClient;
+use crate::nakadi::asynchronous::committer::Committer;
+use crate::nakadi::asynchronous::handler::AsyncHandlerFactory;
+use crate::nakadi::asynchronous::streaming_client::{AsyncStreamingClient, LineStream};
+use crate::nakadi::asynchronous::worker::Worker;
+use crate::nakadi::batch::{Batch, BatchLine};
+use crate::nakadi::metrics::{MetricsCollector, MetricsContext};
+use crate::nakadi::model::*;
+use crate::nakadi::streaming_client::{ConnectError, RawLine};
+use crate::nakadi::{CommitStrategy, HandlerRetryPolicy, ReconnectPolicy};
+
+pub(crate) struct ConsumerLoopSettings<C, A, HF, M> {
+    pub streaming_client: C,
+    pub api_client: A,
+    pub handler_factory: HF,
+    pub commit_strategy: CommitStrategy,
+    pub subscription_id: SubscriptionId,
+    pub lifecycle: AutoCancellationToken,
+    pub metrics_collector: M,
+    pub handler_retry_policy: HandlerRetryPolicy,
+    pub reconnect_policy: ReconnectPolicy,
+}
+
+/// Connects to the stream and consumes it until a stop
+/// is requested or a permanent error occurs.
+///
+/// Reconnects once a stream ends.
+pub(crate) async fn consumer_loop<C, A, HF, M>(
+    consumer_loop_settings: ConsumerLoopSettings<C, A, HF, M>,
+) where
+    C: AsyncStreamingClient + Send + Sync + 'static,
+    A: AsyncApiClient + Clone + Send + Sync + 'static,
+    HF: AsyncHandlerFactory + 'static,
+    M: MetricsCollector + Clone + Send + Sync + 'static,
+{
+    let ConsumerLoopSettings {
+        streaming_client,
+        api_client,
+        handler_factory,
+        commit_strategy,
+        subscription_id,
+        lifecycle,
+        metrics_collector,
+        handler_retry_policy,
+        reconnect_policy,
+    } = consumer_loop_settings;
+
+    loop {
+        if lifecycle.cancellation_requested() {
+            info!(
+                "[Consumer, subscription={}] Abort requested",
+                subscription_id
+            );
+            break;
+        }
+
+        info!(
+            "[Consumer, subscription={}] Connecting to stream",
+            subscription_id
+        );
+        let start = Instant::now();
+        let (stream_id, line_stream) = match connect(
+            &streaming_client,
+            &subscription_id,
+            &reconnect_policy,
+            &lifecycle,
+        )
+        .await
+        {
+            Ok(v) => {
+                metrics_collector.consumer_connected(start);
+                v
+            }
+            Err(err) => {
+                if !lifecycle.cancellation_requested() {
+                    error!(
+                        "[Consumer, subscription={}] Giving up to connect: {}",
+                        subscription_id, err
+                    );
+                }
+                break;
+            }
+        };
+
+        info!(
+            "[Consumer, subscription={}, stream={}] Connected to a new stream.",
+            subscription_id, stream_id
+        );
+        let connected_since = Instant::now();
+
+        let components = CancellationTokenSource::new(metrics_collector.clone());
+
+        let (committer, committer_handle) = Committer::start(
+            api_client.clone(),
+            commit_strategy,
+            subscription_id.clone(),
+            stream_id.clone(),
+            components.auto_token(),
+            metrics_collector.clone(),
+        );
+
+        let mut dispatcher = Dispatcher {
+            handler_factory: &handler_factory,
+            committer,
+            components: &components,
+            workers: Vec::new(),
+            metrics_collector: &metrics_collector,
+            handler_retry_policy,
+        };
+
+        let stream_ended = consume(
+            line_stream,
+            &subscription_id,
+            &stream_id,
+            &mut dispatcher,
+            &lifecycle,
+            &metrics_collector,
+        )
+        .await;
+
+        info!(
+            "[Consumer, subscription={}, stream={}] Stream consumption ended or aborted. \
+             Stopping workers.",
+            subscription_id, stream_id
+        );
+
+        if stream_ended {
+            dispatcher.end_of_stream();
+        }
+        components.request_cancellation();
+        dispatcher.stop().await;
+
+        if let Err(err) = committer_handle.await {
+            error!(
+                "[Consumer, subscription={}, stream={}] Committer task failed: {}",
+                subscription_id, stream_id, err
+            );
+        }
+
+        info!(
+            "[Consumer, subscription={}, stream={}] Committer stopped.",
+            subscription_id, stream_id
+        );
+
+        metrics_collector.consumer_connection_lifetime(connected_since);
+    }
+
+    info!(
+        "[Consumer, subscription={}] Nakadi consumer stopped.  Exiting",
+        subscription_id
+    );
+}
+
+async fn consume<HF, M>(
+    mut line_stream: LineStream,
+    subscription_id: &SubscriptionId,
+    stream_id: &StreamId,
+    dispatcher: &mut Dispatcher<'_, HF, M>,
+    lifecycle: &AutoCancellationToken,
+    metrics_collector: &M,
+) -> bool
+where
+    HF: AsyncHandlerFactory,
+    M: MetricsCollector + Clone + Send + Sync + 'static,
+{
+    loop {
+        if lifecycle.cancellation_requested() {
+            info!(
+                "[Consumer, subscription={}, stream={}] Abort requested",
+                subscription_id, stream_id
+            );
+            return false;
+        }
+
+        if dispatcher.components.is_any_cancelled() {
+            error!(
+                "[Consumer, subscription={}, stream={}] A worker or the committer \
+                 is gone. Aborting.",
+                subscription_id, stream_id
+            );
+            return false;
+        }
+
+        let line_result = {
+            let stop_requested = future::select(
+                lifecycle.cancellation_request().boxed(),
+                dispatcher.components.any_cancelled().boxed(),
+            );
+            match future::select(line_stream.next(), stop_requested).await {
+                Either::Left((Some(line_result), _)) => line_result,
+                // The stream ended
+                Either::Left((None, _)) => break,
+                // Checked at the beginning of the loop
+                Either::Right(_) => continue,
+            }
+        };
+
+        match line_result {
+            Ok(raw_line) => {
+                if let Err(err) = process_batch_line(
+                    dispatcher,
+                    subscription_id,
+                    stream_id,
+                    raw_line,
+                    metrics_collector,
+                )
+                .await
+                {
+                    error!(
+                        "[Consumer, subscription={}, stream={}] Could not process batch line: \
+                         {}",
+                        subscription_id, stream_id, err
+                    );
+                    return false;
+                }
+            }
+            Err(err) => {
+                error!(
+                    "[Consumer, subscription={}, stream={}] The streaming connection broke: {}",
+                    subscription_id, stream_id, err
+                );
+                break;
+            }
+        }
+    }
+
+    // The stream ended or the connection broke
+    true
+}
+
+async fn process_batch_line<HF, M>(
+    dispatcher: &mut Dispatcher<'_, HF, M>,
+    subscription_id: &SubscriptionId,
+    stream_id: &StreamId,
+    raw_line: RawLine,
+    metrics_collector: &M,
+) -> Result<(), Error>
+where
+    HF: AsyncHandlerFactory,
+    M: MetricsCollector + Clone + Send + Sync + 'static,
+{
+    let num_bytes = raw_line.bytes.len();
+    metrics_collector.consumer_line_received(num_bytes);
+
+    let batch_line = BatchLine::new(raw_line.bytes)?;
+
+    if let Some(info) = batch_line.info() {
+        match ::std::str::from_utf8(info) {
+            Ok(info) => {
+                metrics_collector.consumer_info_line_received(info.len());
+                info!(
+                    "[Consumer, subscription={}, stream={}] Received info: {}",
+                    subscription_id, stream_id, info
+                );
+            }
+            Err(err) => warn!(
+                "[Consumer, subscription={}, stream={}] Received info line \
+                 which is not readable: {}",
+                subscription_id, stream_id, err
+            ),
+        };
+    }
+
+    if batch_line.is_keep_alive_line() {
+        debug!("Keep alive!");
+        metrics_collector.consumer_keep_alive_line_received(num_bytes);
+        Ok(())
+    } else {
+        metrics_collector.consumer_batch_received(
+            &MetricsContext::for_batch(subscription_id, &batch_line),
+            raw_line.received_at,
+        );
+        metrics_collector.consumer_batch_line_received(num_bytes);
+        dispatcher
+            .dispatch(Batch {
+                batch_line,
+                received_at: raw_line.received_at,
+            })
+            .await
+            .map_err(|err| {
+                err.context(format!(
+                    "[Consumer, subscription={}, stream={}] \
+                     Dispatcher did not process batch",
+                    subscription_id, stream_id
+                ))
+                .into()
+            })
+    }
+}
+
+/// Passes batches to the workers and creates a worker
+/// for each partition not seen before on the current stream.
+struct Dispatcher<'a, HF, M> {
+    handler_factory: &'a HF,
+    committer: Committer,
+    components: &'a CancellationTokenSource,
+    workers: Vec<Worker>,
+    metrics_collector: &'a M,
+    handler_retry_policy: HandlerRetryPolicy,
+}
+
+impl<'a, HF, M> Dispatcher<'a, HF, M>
+where
+    HF: AsyncHandlerFactory,
+    M: MetricsCollector + Clone + Send + Sync + 'static,
+{
+    async fn dispatch(&mut self, batch: Batch) -> Result<(), Error> {
+        self.metrics_collector.dispatcher_batch_received(
+            &MetricsContext::for_batch(self.committer.subscription_id(), &batch.batch_line),
+            batch.received_at,
+        );
+
+        let partition = PartitionId(batch.batch_line.partition_str()?.into());
+
+        let worker_idx = self
+            .workers
+            .iter()
+            .position(|w| w.partition() == &partition);
+
+        let worker_idx = if let Some(idx) = worker_idx {
+            idx
+        } else {
+            let stream_id = self.committer.stream_id();
+            info!(
+                "[Dispatcher, stream={}, partition={}] Creating new handler",
+                stream_id, partition
+            );
+
+            let handler = self
+                .handler_factory
+                .create_handler(&partition)
+                .await
+                .context(format!(
+                    "[Dispatcher, stream={}, partition={}] Handler factory failed",
+                    stream_id, partition
+                ))?;
+
+            info!(
+                "[Dispatcher, stream={}, partition={}] New handler created. Starting worker.",
+                stream_id, partition
+            );
+
+            let worker = Worker::start(
+                handler,
+                self.committer.clone(),
+                partition.clone(),
+                self.components.auto_token(),
+                self.metrics_collector.clone(),
+                self.handler_retry_policy,
+            );
+
+            self.workers.push(worker);
+            self.metrics_collector
+                .dispatcher_current_workers(self.workers.len());
+            self.workers.len() - 1
+        };
+
+        self.workers[worker_idx].process(batch)
+    }
+
+    /// Tell the handlers of all workers that the stream ended.
+    fn end_of_stream(&self) {
+        self.workers.iter().for_each(Worker::end_of_stream);
+    }
+
+    /// Stop all workers and wait for them to finish.
+    ///
+    /// The committer stops after the workers since
+    /// they share the channel to the committer.
+    async fn stop(self) {
+        for worker in self.workers {
+            worker.join().await;
+        }
+        self.metrics_collector.dispatcher_current_workers(0);
+    }
+}
+
+/// Tries to connect until successful or until the `ReconnectPolicy`
+/// gives up.
+async fn connect<C: AsyncStreamingClient>(
+    client: &C,
+    subscription_id: &SubscriptionId,
+    reconnect_policy: &ReconnectPolicy,
+    lifecycle: &AutoCancellationToken,
+) -> Result<(StreamId, LineStream), ConnectError> {
+    let started = Instant::now();
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let flow_id = FlowId::default();
+        match client.connect(subscription_id, flow_id.clone()).await {
+            Ok(it) => {
+                return Ok(it);
+            }
+            Err(err) => {
+                if reconnect_policy.gives_up_on(&err) {
+                    return Err(err);
+                } else if reconnect_policy.deadline_exceeded(started) {
+                    return Err(ConnectError::Other(
+                        format!(
+                            "Failed to connect to Nakadi after {} attempts. Last error: {}",
+                            attempt, err
+                        ),
+                        flow_id,
+                    ));
+                } else if lifecycle.cancellation_requested() {
+                    return Err(ConnectError::Other(
+                        format!(
+                            "Failed to connect to Nakadi after {} attempts. Abort requested",
+                            attempt
+                        ),
+                        flow_id,
+                    ));
+                } else {
+                    let delay = reconnect_policy.delay_after(attempt, &err);
+                    warn!(
+                        "Failed to connect(attempt {}) to Nakadi(retry in {}ms): {}",
+                        attempt,
+                        delay.as_millis(),
+                        err
+                    );
+                    let cancellation_request = lifecycle.cancellation_request();
+                    pin_mut!(cancellation_request);
+                    if let Either::Right(_) =
+                        future::select(delay_for(delay), cancellation_request).await
+                    {
+                        return Err(ConnectError::Other(
+                            format!(
+                                "Failed to connect to Nakadi after {} attempts. Abort requested",
+                                attempt
+                            ),
+                            flow_id,
+                        ));
+                    }
+                }
+            }
+        }
+    }
+}
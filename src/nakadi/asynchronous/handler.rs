@@ -0,0 +1,83 @@
+//! Handlers for processing batches on an async runtime
+use futures::future::{self, BoxFuture, FutureExt};
+
+use crate::nakadi::handler::{
+    CreateHandlerError, HandlerStopReason, ProcessingStatus, SubscriptionCursor,
+};
+use crate::nakadi::model::PartitionId;
+
+/// A handler that contains batch processing logic and
+/// returns a future of a `ProcessingStatus`.
+///
+/// This is the async counterpart of `BatchHandler`. A handler
+/// is only ever driven by one task at a time and the next batch
+/// of the same partition will only be passed to the handler once
+/// the returned future completed.
+///
+/// # Example
+///
+/// ```rust
+/// use futures::future::{BoxFuture, FutureExt};
+/// use nakadion::asynchronous::AsyncBatchHandler;
+/// use nakadion::{ProcessingStatus, SubscriptionCursor};
+///
+/// // Use a struct to maintain state
+/// struct MyHandler {
+///     pub count: i32,
+/// }
+///
+/// impl AsyncBatchHandler for MyHandler {
+///     fn handle<'a>(
+///         &'a mut self,
+///         _cursor: &'a SubscriptionCursor,
+///         _events: &'a [u8],
+///     ) -> BoxFuture<'a, ProcessingStatus> {
+///         async move {
+///             self.count += 1;
+///             ProcessingStatus::processed_no_hint()
+///         }
+///         .boxed()
+///     }
+/// }
+///
+/// // Handler creation will be done by `AsyncHandlerFactory`
+/// let mut handler = MyHandler { count: 0 };
+/// ```
+pub trait AsyncBatchHandler: Send {
+    /// Handle the events.
+    ///
+    /// The `events` slice always contains a JSON encoded array of events.
+    fn handle<'a>(
+        &'a mut self,
+        cursor: &'a SubscriptionCursor,
+        events: &'a [u8],
+    ) -> BoxFuture<'a, ProcessingStatus>;
+
+    /// Called once before the worker stops and the handler gets dropped.
+    ///
+    /// This is the async counterpart of `BatchHandler::on_stop`.
+    /// `last_processed_cursor` is the cursor of the last batch the handler
+    /// processed or `None` if it did not process any batch.
+    ///
+    /// The default implementation does nothing.
+    fn on_stop<'a>(
+        &'a mut self,
+        _reason: &'a HandlerStopReason,
+        _last_processed_cursor: Option<&'a SubscriptionCursor>,
+    ) -> BoxFuture<'a, ()> {
+        future::ready(()).boxed()
+    }
+}
+
+/// A factory that creates `AsyncBatchHandler`s.
+///
+/// A handler is created for each partition
+/// when the first batch of that partition arrives on a stream.
+pub trait AsyncHandlerFactory: Send + Sync {
+    type Handler: AsyncBatchHandler + 'static;
+    /// Create a new handler for the given partition.
+    fn create_handler<'a>(
+        &'a self,
+        partition: &'a PartitionId,
+    ) -> BoxFuture<'a, Result<Self::Handler, CreateHandlerError>>;
+}
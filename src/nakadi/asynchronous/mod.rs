@@ -0,0 +1,340 @@
+//! Consuming events on a `tokio` runtime
+//!
+//! The components in here are the async counterparts of the
+//! thread based components. Streaming, dispatching, processing
+//! and committing are all done by tasks spawned on the runtime
+//! `AsyncNakadion` was started on.
+//!
+//! This module is only available with the `async` feature enabled.
+use std::sync::Arc;
+
+use failure::*;
+
+use crate::auth::ProvidesAccessToken;
+use crate::cancellation_token::CancellationTokenSource;
+use crate::nakadi::api::{Config as ApiConfig, CreateSubscriptionStatus};
+use crate::nakadi::metrics::MetricsCollector;
+use crate::nakadi::model::SubscriptionId;
+use crate::nakadi::streaming_client::Config as StreamingClientConfig;
+use crate::nakadi::{ConsumerSettings, NakadionConfig, SubscriptionDiscovery};
+
+pub mod api;
+mod committer;
+mod consumer;
+pub mod handler;
+pub mod publisher;
+pub mod streaming_client;
+mod worker;
+
+pub use self::api::{AsyncApiClient, AsyncNakadiApiClient};
+pub use self::handler::{AsyncBatchHandler, AsyncHandlerFactory};
+pub use self::publisher::AsyncNakadiPublisher;
+pub use self::streaming_client::{AsyncNakadiStreamingClient, AsyncStreamingClient, LineStream};
+
+use self::consumer::{consumer_loop, ConsumerLoopSettings};
+
+/// This struct represents a `Nakadion` running on a
+/// `tokio` runtime.
+///
+/// Once instantiated it can only be used
+/// query its running state or to stop
+/// consuming events. Consuming stops once
+/// `AsyncNakadion` is dropped.
+///
+/// Unlike `Nakadion` it does not support the following
+/// `ConsumerSettings` and refuses to start if they are set:
+///
+/// * `min_idle_worker_lifetime`: Workers are kept until the stream ends
+/// * `dead_letter_sink`: Batches which failed after all retries stop
+///   the worker
+/// * `lifecycle_listener`: Connects, partition assignments and info lines
+///   are only logged
+pub struct AsyncNakadion {
+    lifecycle: CancellationTokenSource,
+}
+
+impl AsyncNakadion {
+    /// Start with manually created components and parameter
+    ///
+    /// The `SubscriptionId` must be already known.
+    ///
+    /// This must be called from within a `tokio` runtime.
+    ///
+    /// The `min_idle_worker_lifetime`, the `dead_letter_sink` and the
+    /// `lifecycle_listener` of the settings are not supported and
+    /// must not be set.
+    ///
+    /// # Errors
+    ///
+    /// Nakadion could not be started. This is also the case if
+    /// unsupported settings or an invalid `HandlerRetryPolicy` were given.
+    pub fn start_with<HF, C, A, M>(
+        subscription_id: SubscriptionId,
+        streaming_client: C,
+        api_client: A,
+        handler_factory: HF,
+        metrics_collector: M,
+        consumer_settings: ConsumerSettings,
+    ) -> Result<AsyncNakadion, Error>
+    where
+        C: AsyncStreamingClient + Send + Sync + 'static,
+        A: AsyncApiClient + Clone + Send + Sync + 'static,
+        HF: AsyncHandlerFactory + 'static,
+        M: MetricsCollector + Clone + Send + Sync + 'static,
+    {
+        check_supported(&consumer_settings)?;
+        consumer_settings.handler_retry_policy.validate()?;
+
+        let lifecycle = CancellationTokenSource::new(metrics_collector.clone());
+
+        tokio::spawn(consumer_loop(ConsumerLoopSettings {
+            streaming_client,
+            api_client,
+            handler_factory,
+            commit_strategy: consumer_settings.commit_strategy,
+            subscription_id,
+            lifecycle: lifecycle.auto_token(),
+            metrics_collector,
+            handler_retry_policy: consumer_settings.handler_retry_policy,
+            reconnect_policy: consumer_settings.reconnect_policy,
+        }));
+
+        Ok(AsyncNakadion { lifecycle })
+    }
+
+    /// Start with the given configuration
+    /// and create all internally required components from the configuration
+    ///
+    /// This must be called from within a `tokio` runtime.
+    ///
+    /// The `min_idle_worker_lifetime`, the `dead_letter_sink` and the
+    /// `lifecycle_listener` of the configuration are not supported and
+    /// must not be set.
+    ///
+    /// # Errors
+    ///
+    /// Nakadion could not be started. This might be due to an
+    /// invalid configuration or unsupported settings
+    pub async fn start<HF, P, M>(
+        config: NakadionConfig,
+        handler_factory: HF,
+        access_token_provider: P,
+        metrics_collector: M,
+    ) -> Result<AsyncNakadion, Error>
+    where
+        HF: AsyncHandlerFactory + 'static,
+        P: ProvidesAccessToken + Send + Sync + 'static,
+        M: MetricsCollector + Clone + Send + Sync + 'static,
+    {
+        let access_token_provider = Arc::new(access_token_provider);
+        let consumer_settings = config.consumer_settings();
+        check_supported(&consumer_settings)?;
+
+        let api_client = AsyncNakadiApiClient::with_shared_access_token_provider(
+            ApiConfig {
+                nakadi_host: config.nakadi_host.clone(),
+                request_timeout: config.request_timeout,
+            },
+            access_token_provider.clone(),
+        )?;
+
+        info!(
+            "Discovering subscription with {}",
+            config.subscription_discovery
+        );
+
+        let subscription_id = match config.subscription_discovery {
+            SubscriptionDiscovery::ExistingId(id) => id,
+            SubscriptionDiscovery::Application(request) => {
+                match api_client.create_subscription(&request).await? {
+                    CreateSubscriptionStatus::Created(subscription) => {
+                        info!("Created new subscription {}", subscription.id);
+                        subscription.id
+                    }
+                    CreateSubscriptionStatus::AlreadyExists(subscription) => {
+                        info!("Using already existing subscription {}", subscription.id);
+                        subscription.id
+                    }
+                }
+            }
+        };
+
+        let streaming_client_config = StreamingClientConfig {
+            stream_keep_alive_limit: config.stream_keep_alive_limit,
+            stream_limit: config.stream_limit,
+            stream_timeout: config.stream_timeout,
+            batch_flush_timeout: config.batch_flush_timeout,
+            batch_limit: config.batch_limit,
+            max_uncommitted_events: config.max_uncommitted_events,
+            nakadi_host: config.nakadi_host,
+            partitions: config.partitions,
+        };
+
+        let streaming_client = AsyncNakadiStreamingClient::with_shared_access_token_provider(
+            streaming_client_config,
+            access_token_provider,
+            metrics_collector.clone(),
+        )?;
+
+        info!("Commit strategy is {:?}", config.commit_strategy);
+        info!("Handler retry policy is {:?}", config.handler_retry_policy);
+        info!("Reconnect policy is {:?}", config.reconnect_policy);
+
+        AsyncNakadion::start_with(
+            subscription_id,
+            streaming_client,
+            api_client,
+            handler_factory,
+            metrics_collector,
+            consumer_settings,
+        )
+    }
+
+    /// Returns true if Nakadion is running
+    pub fn running(&self) -> bool {
+        !self.lifecycle.is_any_cancelled()
+    }
+
+    /// Stops Nakadion
+    pub fn stop(&self) {
+        self.lifecycle.request_cancellation()
+    }
+
+    /// Resolves once Nakadion has stopped
+    pub async fn stopped(&self) {
+        self.lifecycle.any_cancelled().await
+    }
+}
+
+/// Fails if `consumer_settings` contain settings `AsyncNakadion` does not support
+fn check_supported(consumer_settings: &ConsumerSettings) -> Result<(), Error> {
+    if consumer_settings.min_idle_worker_lifetime.is_some() {
+        bail!("A minimum idle worker lifetime is not supported by AsyncNakadion");
+    }
+    if consumer_settings.dead_letter_sink.is_some() {
+        bail!("A dead letter sink is not supported by AsyncNakadion");
+    }
+    if consumer_settings.lifecycle_listener.is_some() {
+        bail!("A lifecycle listener is not supported by AsyncNakadion");
+    }
+    Ok(())
+}
+
+#[test]
+fn rejects_unsupported_consumer_settings() {
+    use std::time::Duration;
+
+    assert!(check_supported(&ConsumerSettings::default()).is_ok());
+
+    let settings = ConsumerSettings {
+        min_idle_worker_lifetime: Some(Duration::from_secs(60)),
+        ..ConsumerSettings::default()
+    };
+    assert!(check_supported(&settings).is_err());
+}
+
+#[cfg(feature = "test-support")]
+#[test]
+fn tells_async_handlers_why_they_stopped() {
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+
+    use futures::future::{self, BoxFuture, FutureExt};
+    use serde_json::json;
+
+    use crate::auth::NoAuthAccessTokenProvider;
+    use crate::nakadi::handler::{
+        CreateHandlerError, HandlerStopReason, ProcessingStatus, SubscriptionCursor,
+    };
+    use crate::nakadi::metrics::DevNullMetricsCollector;
+    use crate::nakadi::model::PartitionId;
+    use crate::nakadi::NakadionBuilder;
+    use crate::test_support::{FakeNakadi, FakeNakadiConfig};
+
+    type Stops = Arc<Mutex<Vec<(HandlerStopReason, Option<String>)>>>;
+
+    struct Handler(Stops);
+
+    impl AsyncBatchHandler for Handler {
+        fn handle<'a>(
+            &'a mut self,
+            _cursor: &'a SubscriptionCursor,
+            _events: &'a [u8],
+        ) -> BoxFuture<'a, ProcessingStatus> {
+            future::ready(ProcessingStatus::processed_no_hint()).boxed()
+        }
+
+        fn on_stop<'a>(
+            &'a mut self,
+            reason: &'a HandlerStopReason,
+            last_processed_cursor: Option<&'a SubscriptionCursor>,
+        ) -> BoxFuture<'a, ()> {
+            let offset = last_processed_cursor.map(|c| c.offset.clone());
+            self.0.lock().unwrap().push((reason.clone(), offset));
+            future::ready(()).boxed()
+        }
+    }
+
+    struct Factory(Stops);
+
+    impl AsyncHandlerFactory for Factory {
+        type Handler = Handler;
+
+        fn create_handler<'a>(
+            &'a self,
+            _partition: &'a PartitionId,
+        ) -> BoxFuture<'a, Result<Handler, CreateHandlerError>> {
+            future::ready(Ok(Handler(self.0.clone()))).boxed()
+        }
+    }
+
+    // Stopping must not wait for the next keep alive line
+    let nakadi = FakeNakadi::start_with(FakeNakadiConfig {
+        keep_alive_interval: Duration::from_secs(60),
+        ..FakeNakadiConfig::default()
+    })
+    .unwrap();
+    nakadi.create_event_type("test_event", 1);
+    let subscription_id = nakadi.create_subscription("test_app", &["test_event"]);
+    nakadi.publish("test_event", &[json!({"a": 1})]);
+
+    let config = NakadionBuilder::default()
+        .nakadi_host(nakadi.nakadi_host())
+        .subscription_discovery(SubscriptionDiscovery::ExistingId(subscription_id.clone()))
+        .build_config()
+        .unwrap();
+    let stops = Stops::default();
+    let mut runtime = tokio::runtime::Builder::new()
+        .basic_scheduler()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    runtime.block_on(async {
+        let nakadion = AsyncNakadion::start(
+            config,
+            Factory(stops.clone()),
+            NoAuthAccessTokenProvider,
+            DevNullMetricsCollector,
+        )
+        .await
+        .unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while nakadi.commits().is_empty() {
+            assert!(Instant::now() < deadline, "Cursors were not committed");
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+        nakadion.stop();
+        tokio::time::timeout(Duration::from_secs(5), nakadion.stopped())
+            .await
+            .expect("Nakadion did not stop");
+        assert!(!nakadion.running());
+    });
+
+    let offset = nakadi.commits()[0].cursors[0].offset.clone();
+    assert_eq!(
+        stops.lock().unwrap().clone(),
+        vec![(HandlerStopReason::Cancelled, Some(offset))]
+    );
+}
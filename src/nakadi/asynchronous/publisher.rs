@@ -0,0 +1,325 @@
+//! Publish events to Nakadi without blocking
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use backoff::backoff::Backoff;
+use reqwest::{
+    header::{HeaderMap, CONTENT_TYPE},
+    Client as HttpClient, StatusCode,
+};
+use serde::Serialize;
+use serde_json;
+use tokio::time::delay_for;
+
+use crate::auth::{renew_rejected_token, AccessToken, ProvidesAccessToken};
+use crate::nakadi::metrics::{DevNullMetricsCollector, PublisherMetricsCollector};
+use crate::nakadi::model::FlowId;
+use crate::nakadi::publisher::{
+    collect_outcome_metrics, publish_backoff, publish_outcome, PendingEvents, PublishError,
+    PublishStatus,
+};
+
+/// Publishes events to `Nakadi` without blocking
+///
+/// This is the async counterpart of `NakadiPublisher`
+/// and has the same semantics.
+///
+/// Constructors take a parameter `retry_on_partial_success`.
+/// If set to `true` partial successes on publishing events will be retried.
+/// Otherwise not.
+///
+/// No metrics are collected unless a `PublisherMetricsCollector`
+/// is set with `metrics_collector`.
+#[derive(Clone)]
+pub struct AsyncNakadiPublisher {
+    nakadi_base_url: Arc<String>,
+    http_client: HttpClient,
+    token_provider: Arc<dyn ProvidesAccessToken + Sync + Send + 'static>,
+    retry_on_partial_success: bool,
+    metrics_collector: Arc<dyn PublisherMetricsCollector + Sync + Send + 'static>,
+}
+
+impl AsyncNakadiPublisher {
+    /// Create a new `AsyncNakadiPublisher`
+    pub fn new<U: Into<String>, T: ProvidesAccessToken + Sync + Send + 'static>(
+        nakadi_base_url: U,
+        retry_on_partial_success: bool,
+        token_provider: T,
+    ) -> AsyncNakadiPublisher {
+        AsyncNakadiPublisher::with_shared_access_token_provider(
+            nakadi_base_url,
+            retry_on_partial_success,
+            Arc::new(token_provider),
+        )
+    }
+
+    /// Create a new `AsyncNakadiPublisher`
+    pub fn with_shared_access_token_provider<U: Into<String>>(
+        nakadi_base_url: U,
+        retry_on_partial_success: bool,
+        token_provider: Arc<dyn ProvidesAccessToken + Sync + Send + 'static>,
+    ) -> AsyncNakadiPublisher {
+        AsyncNakadiPublisher {
+            nakadi_base_url: Arc::new(nakadi_base_url.into()),
+            http_client: HttpClient::new(),
+            token_provider,
+            retry_on_partial_success,
+            metrics_collector: Arc::new(DevNullMetricsCollector),
+        }
+    }
+
+    /// Report publishing to the given `PublisherMetricsCollector`
+    pub fn metrics_collector<M>(mut self, metrics_collector: M) -> AsyncNakadiPublisher
+    where
+        M: PublisherMetricsCollector + Sync + Send + 'static,
+    {
+        self.metrics_collector = Arc::new(metrics_collector);
+        self
+    }
+
+    /// Publish events packed into a vector of bytes.
+    ///
+    /// The events must be encoded in a way that `Nakadi`
+    /// can understand and pass it`s validation mechanism
+    /// especially regarding schemas.
+    ///
+    /// The bytes are only parsed as a JSON array of events if
+    /// partial successes are retried. Otherwise they are sent unchanged.
+    ///
+    /// The `budget` is the maximum `Duration` publishing events
+    /// is retried. This can lead to events being published multiple
+    /// times.
+    pub async fn publish_raw(
+        &self,
+        event_type: &str,
+        bytes: Vec<u8>,
+        flow_id: Option<FlowId>,
+        budget: Duration,
+    ) -> Result<PublishStatus, PublishError> {
+        self.publish_bytes(event_type, &bytes, None, flow_id, budget)
+            .await
+    }
+
+    /// Publish events packed into a vector of bytes
+    /// reporting `num_events` to the metrics if known.
+    async fn publish_bytes(
+        &self,
+        event_type: &str,
+        bytes: &[u8],
+        num_events: Option<usize>,
+        flow_id: Option<FlowId>,
+        budget: Duration,
+    ) -> Result<PublishStatus, PublishError> {
+        let url = format!("{}/event-types/{}/events", self.nakadi_base_url, event_type);
+
+        let flow_id = flow_id.unwrap_or_default();
+
+        let mut pending = PendingEvents::new(bytes, self.retry_on_partial_success, num_events)?;
+
+        let mut backoff = publish_backoff(budget);
+        loop {
+            let err = match self
+                .attempt_publish(&url, event_type, &pending, &flow_id)
+                .await
+                .and_then(|publish_status| pending.update(publish_status, &flow_id))
+            {
+                Ok(PublishStatus::NotAllEventsPublished(_)) if self.retry_on_partial_success => {
+                    PublishError::Other(
+                        "Partial success. Retry enabled.".to_string(),
+                        flow_id.clone(),
+                    )
+                }
+                Ok(publish_status) => return Ok(publish_status),
+                Err(err) => err,
+            };
+
+            let delay = match backoff.next_backoff() {
+                Some(delay) if err.is_retry_suggested() => delay,
+                _ => return pending.give_up(err),
+            };
+
+            warn!("Publish error happened {:?}: {}", delay, err);
+            self.metrics_collector.publisher_retry(event_type);
+
+            delay_for(delay).await;
+        }
+    }
+
+    /// Publish the given events to `Nakadi`
+    ///
+    /// The `budget` is the maximum `Duration` publishing events
+    /// is retried. This can lead to events being published multiple
+    /// times.
+    pub async fn publish_events<T: Serialize>(
+        &self,
+        event_type: &str,
+        events: &[T],
+        flow_id: Option<FlowId>,
+        budget: Duration,
+    ) -> Result<PublishStatus, PublishError> {
+        let bytes = match serde_json::to_vec(events) {
+            Ok(bytes) => bytes,
+            Err(err) => return Err(PublishError::Serialization(err.to_string())),
+        };
+        self.publish_bytes(event_type, &bytes, Some(events.len()), flow_id, budget)
+            .await
+    }
+
+    async fn attempt_publish(
+        &self,
+        url: &str,
+        event_type: &str,
+        pending: &PendingEvents<'_>,
+        flow_id: &FlowId,
+    ) -> Result<PublishStatus, PublishError> {
+        let bytes = pending.body();
+        let num_events = pending.num_pending();
+        self.metrics_collector
+            .publisher_attempt(event_type, num_events, bytes.len());
+
+        let mut headers = HeaderMap::new();
+
+        headers.insert("X-Flow-Id", flow_id.0.parse().unwrap());
+        headers.insert(CONTENT_TYPE, "application/json".parse().unwrap());
+
+        let send = || async {
+            let request_builder = self.http_client.post(url).headers(headers.clone());
+
+            let request_builder = match self.token_provider.get_token() {
+                Ok(Some(AccessToken(token))) => request_builder.bearer_auth(token),
+                Ok(None) => request_builder,
+                Err(err) => return Err(PublishError::Token(err.to_string())),
+            };
+
+            request_builder
+                .body(bytes.clone())
+                .send()
+                .await
+                .map_err(|err| PublishError::Other(format!("{}", err), flow_id.clone()))
+        };
+
+        let start = Instant::now();
+        let mut sent = send().await;
+        if let Ok(ref response) = sent {
+            if response.status() == StatusCode::UNAUTHORIZED
+                && renew_rejected_token(&*self.token_provider, "publish")
+            {
+                sent = send().await;
+            }
+        }
+        let response = match sent {
+            Ok(response) => response,
+            Err(err) => {
+                self.metrics_collector.publisher_failed(event_type, None);
+                return Err(err);
+            }
+        };
+        self.metrics_collector
+            .publisher_responded(event_type, start);
+
+        let status = response.status();
+        let body = response
+            .text()
+            .await
+            .unwrap_or_else(|_| "<Could not read body>".to_string());
+        let outcome = publish_outcome(status, body, flow_id);
+        collect_outcome_metrics(
+            &*self.metrics_collector,
+            event_type,
+            num_events,
+            status,
+            &outcome,
+        );
+        outcome
+    }
+}
+
+/// The body of a `207 Multi-Status` response to a publish request
+#[cfg(all(test, feature = "test-support"))]
+fn partial_success(submitted: &[bool]) -> String {
+    let items: Vec<_> = submitted
+        .iter()
+        .map(|&submitted| {
+            if submitted {
+                serde_json::json!({"publishing_status": "submitted", "step": "none"})
+            } else {
+                serde_json::json!({"publishing_status": "failed", "step": "publishing"})
+            }
+        })
+        .collect();
+    serde_json::to_string(&items).unwrap()
+}
+
+#[cfg(feature = "test-support")]
+#[test]
+fn retries_only_the_failed_events_of_a_partial_success() {
+    use crate::auth::NoAuthAccessTokenProvider;
+    use crate::test_support::{Endpoint, FakeNakadi, Fault};
+
+    let nakadi = FakeNakadi::start().unwrap();
+    nakadi.create_event_type("test_event", 1);
+    nakadi.inject_fault(
+        Endpoint::Publish,
+        Fault::Status(207, partial_success(&[true, false, true])),
+    );
+
+    let publisher =
+        AsyncNakadiPublisher::new(nakadi.nakadi_host(), true, NoAuthAccessTokenProvider);
+    let mut runtime = tokio::runtime::Builder::new()
+        .basic_scheduler()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    let status = runtime
+        .block_on(publisher.publish_events("test_event", &[1, 2, 3], None, Duration::from_secs(5)))
+        .unwrap();
+
+    match status {
+        PublishStatus::AllEventsPublished => {}
+        other => panic!("Expected all events to be published but got {:?}", other),
+    }
+    assert_eq!(
+        nakadi.published_events("test_event"),
+        vec![serde_json::json!(2)]
+    );
+}
+
+#[cfg(feature = "test-support")]
+#[test]
+fn does_not_retry_a_partial_success_if_disabled() {
+    use crate::auth::NoAuthAccessTokenProvider;
+    use crate::nakadi::publisher::PublishingStatus;
+    use crate::test_support::{Endpoint, FakeNakadi, Fault};
+
+    let nakadi = FakeNakadi::start().unwrap();
+    nakadi.create_event_type("test_event", 1);
+    nakadi.inject_fault(
+        Endpoint::Publish,
+        Fault::Status(207, partial_success(&[true, false])),
+    );
+
+    let publisher =
+        AsyncNakadiPublisher::new(nakadi.nakadi_host(), false, NoAuthAccessTokenProvider);
+    let mut runtime = tokio::runtime::Builder::new()
+        .basic_scheduler()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    let status = runtime
+        .block_on(publisher.publish_events("test_event", &[1, 2], None, Duration::from_secs(5)))
+        .unwrap();
+
+    match status {
+        PublishStatus::NotAllEventsPublished(items) => {
+            let statuses: Vec<_> = items.into_iter().map(|i| i.publishing_status).collect();
+            assert_eq!(
+                statuses,
+                vec![PublishingStatus::Submitted, PublishingStatus::Failed]
+            );
+        }
+        other => panic!("Expected a partial success but got {:?}", other),
+    }
+    assert!(nakadi.published_events("test_event").is_empty());
+}
@@ -0,0 +1,278 @@
+//! Connect to a stream and consume lines asynchronously
+use std::io::Error as IoError;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use bytes::Bytes;
+use failure::*;
+use futures::future::{BoxFuture, FutureExt};
+use futures::stream::{BoxStream, Stream, StreamExt, TryStreamExt};
+use reqwest::StatusCode;
+use reqwest::{
+    header::HeaderMap, Client as HttpClient, ClientBuilder as HttpClientBuilder, Response,
+};
+
+use crate::auth::{renew_rejected_token, AccessToken, ProvidesAccessToken};
+use crate::nakadi::metrics::{DevNullMetricsCollector, MetricsCollector};
+use crate::nakadi::model::{FlowId, StreamId, SubscriptionId};
+use crate::nakadi::streaming_client::{
+    connect_error, create_connect_url, create_events_url, stream_id_from_headers, Config,
+    ConnectError, ConnectRequestBody, LineResult, RawLine, LINE_SPLIT_BYTE,
+};
+
+/// A stream of lines received from Nakadi.
+pub type LineStream = BoxStream<'static, LineResult>;
+
+/// Connects to a stream without blocking the current thread.
+pub trait AsyncStreamingClient {
+    /// Connect to Nakadi and resolve to the id of the stream
+    /// and the lines received on that stream.
+    fn connect<'a>(
+        &'a self,
+        subscription_id: &'a SubscriptionId,
+        flow_id: FlowId,
+    ) -> BoxFuture<'a, Result<(StreamId, LineStream), ConnectError>>;
+}
+
+/// Connects to Nakadi via HTTP and creates a stream of
+/// lines from the data received from Nakadi over the network
+#[derive(Clone)]
+pub struct AsyncNakadiStreamingClient<M> {
+    http_client: HttpClient,
+    token_provider: Arc<dyn ProvidesAccessToken + Send + Sync + 'static>,
+    config: Config,
+    metrics_collector: M,
+}
+
+impl<M> AsyncNakadiStreamingClient<M>
+where
+    M: MetricsCollector,
+{
+    /// Create a new `AsyncNakadiStreamingClient<M>`.
+    pub fn new<T: ProvidesAccessToken + Send + Sync + 'static>(
+        config: Config,
+        token_provider: T,
+        metrics_collector: M,
+    ) -> Result<AsyncNakadiStreamingClient<M>, Error> {
+        AsyncNakadiStreamingClient::with_shared_access_token_provider(
+            config,
+            Arc::new(token_provider),
+            metrics_collector,
+        )
+    }
+
+    /// Create a new `AsyncNakadiStreamingClient<DevNullMetricsCollector>` that
+    /// does not collect any metrics.
+    pub fn without_metrics<T: ProvidesAccessToken + Send + Sync + 'static>(
+        config: Config,
+        token_provider: T,
+    ) -> Result<AsyncNakadiStreamingClient<DevNullMetricsCollector>, Error> {
+        AsyncNakadiStreamingClient::with_shared_access_token_provider(
+            config,
+            Arc::new(token_provider),
+            DevNullMetricsCollector,
+        )
+    }
+
+    /// Create a new `AsyncNakadiStreamingClient<M>` with a shared access token provider.
+    pub fn with_shared_access_token_provider(
+        config: Config,
+        token_provider: Arc<dyn ProvidesAccessToken + Send + Sync + 'static>,
+        metrics_collector: M,
+    ) -> Result<AsyncNakadiStreamingClient<M>, Error> {
+        let http_client = HttpClientBuilder::new()
+            .build()
+            .context("Could not create HTTP client")?;
+
+        Ok(AsyncNakadiStreamingClient {
+            http_client,
+            token_provider,
+            config,
+            metrics_collector,
+        })
+    }
+}
+
+impl<M> AsyncNakadiStreamingClient<M>
+where
+    M: MetricsCollector,
+{
+    async fn send_connect_request(
+        &self,
+        subscription_id: &SubscriptionId,
+        flow_id: &FlowId,
+    ) -> Result<Response, ConnectError> {
+        let mut headers = HeaderMap::new();
+
+        headers.insert("X-Flow-Id", flow_id.0.parse().unwrap());
+
+        let request_builder = if self.config.partitions.is_empty() {
+            let connect_url = create_connect_url(&self.config, subscription_id);
+            self.http_client.get(&connect_url).headers(headers)
+        } else {
+            let connect_url = create_events_url(&self.config, subscription_id);
+            self.http_client
+                .post(&connect_url)
+                .headers(headers)
+                .json(&ConnectRequestBody::from_config(&self.config))
+        };
+
+        let request_builder = if let Some(AccessToken(token)) = self.token_provider.get_token()? {
+            request_builder.bearer_auth(token)
+        } else {
+            request_builder
+        };
+
+        self.metrics_collector.streaming_connect_attempt();
+
+        Ok(request_builder.send().await?)
+    }
+}
+
+impl<M> AsyncStreamingClient for AsyncNakadiStreamingClient<M>
+where
+    M: MetricsCollector + Send + Sync,
+{
+    fn connect<'a>(
+        &'a self,
+        subscription_id: &'a SubscriptionId,
+        flow_id: FlowId,
+    ) -> BoxFuture<'a, Result<(StreamId, LineStream), ConnectError>> {
+        async move {
+            let mut response = self.send_connect_request(subscription_id, &flow_id).await?;
+
+            if response.status() == StatusCode::UNAUTHORIZED
+                && renew_rejected_token(&*self.token_provider, "connect")
+            {
+                self.metrics_collector.streaming_connect_attempt_failed();
+                response = self.send_connect_request(subscription_id, &flow_id).await?;
+            }
+
+            match response.status() {
+                StatusCode::OK => {
+                    let stream_id = stream_id_from_headers(response.headers(), flow_id)?;
+                    let bytes = response.bytes_stream().map_err(IoError::other);
+                    Ok((stream_id, NakadiLineStream::new(bytes).boxed()))
+                }
+                other_status => {
+                    self.metrics_collector.streaming_connect_attempt_failed();
+                    let body = response
+                        .text()
+                        .await
+                        .unwrap_or_else(|_| "Nakadion: Could not read body.".to_string());
+                    Err(connect_error(other_status, body, flow_id))
+                }
+            }
+        }
+        .boxed()
+    }
+}
+
+/// Splits the chunks of bytes received from Nakadi into lines.
+pub struct NakadiLineStream<S> {
+    bytes: S,
+    buffer: Vec<u8>,
+    // Where to continue searching for the next line break in `buffer`
+    search_from: usize,
+    finished: bool,
+}
+
+impl<S> NakadiLineStream<S>
+where
+    S: Stream<Item = Result<Bytes, IoError>> + Unpin,
+{
+    pub fn new(bytes: S) -> NakadiLineStream<S> {
+        NakadiLineStream {
+            bytes,
+            buffer: Vec::new(),
+            search_from: 0,
+            finished: false,
+        }
+    }
+
+    fn next_line(&mut self) -> Option<RawLine> {
+        let pos = self.buffer[self.search_from..]
+            .iter()
+            .position(|b| *b == LINE_SPLIT_BYTE)?;
+        let split_at = self.search_from + pos;
+        let rest = self.buffer.split_off(split_at + 1);
+        let mut bytes = ::std::mem::replace(&mut self.buffer, rest);
+        bytes.pop();
+        self.search_from = 0;
+        Some(RawLine {
+            bytes,
+            received_at: Instant::now(),
+        })
+    }
+}
+
+impl<S> Stream for NakadiLineStream<S>
+where
+    S: Stream<Item = Result<Bytes, IoError>> + Unpin,
+{
+    type Item = LineResult;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<LineResult>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(line) = this.next_line() {
+                return Poll::Ready(Some(Ok(line)));
+            }
+
+            if this.finished {
+                // A last line might not be terminated by a line break
+                if this.buffer.is_empty() {
+                    return Poll::Ready(None);
+                } else {
+                    let bytes = ::std::mem::take(&mut this.buffer);
+                    return Poll::Ready(Some(Ok(RawLine {
+                        bytes,
+                        received_at: Instant::now(),
+                    })));
+                }
+            }
+
+            match this.bytes.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(chunk))) => {
+                    this.search_from = this.buffer.len();
+                    this.buffer.extend_from_slice(&chunk);
+                }
+                Poll::Ready(Some(Err(err))) => {
+                    this.finished = true;
+                    this.buffer.clear();
+                    return Poll::Ready(Some(Err(err)));
+                }
+                Poll::Ready(None) => this.finished = true,
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+#[test]
+fn line_stream_splits_chunks_into_lines() {
+    let chunks: Vec<Result<Bytes, IoError>> = vec![
+        Ok(Bytes::from_static(b"first")),
+        Ok(Bytes::from_static(b" line\nsecond line\nthi")),
+        Ok(Bytes::from_static(b"rd line\n")),
+        Ok(Bytes::from_static(b"last line")),
+    ];
+
+    let lines: Vec<Vec<u8>> = futures::executor::block_on(
+        NakadiLineStream::new(futures::stream::iter(chunks))
+            .map(|line| line.unwrap().bytes)
+            .collect(),
+    );
+
+    assert_eq!(
+        lines,
+        vec![
+            b"first line".to_vec(),
+            b"second line".to_vec(),
+            b"third line".to_vec(),
+            b"last line".to_vec(),
+        ]
+    );
+}
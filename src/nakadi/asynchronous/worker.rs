@@ -0,0 +1,280 @@
+//! Processing a partition on a task
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use failure::*;
+use futures::future::{self, Either};
+use futures::pin_mut;
+use serde_json;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::delay_for;
+
+use crate::cancellation_token::{AutoCancellationToken, CancellationToken};
+use crate::nakadi::asynchronous::committer::Committer;
+use crate::nakadi::asynchronous::handler::AsyncBatchHandler;
+use crate::nakadi::batch::Batch;
+use crate::nakadi::handler::{HandlerStopReason, ProcessingStatus, SubscriptionCursor};
+use crate::nakadi::metrics::{MetricsCollector, MetricsContext};
+use crate::nakadi::model::PartitionId;
+use crate::nakadi::retry::{BatchRetries, NextAttempt};
+use crate::nakadi::HandlerRetryPolicy;
+
+/// A worker drives an `AsyncBatchHandler` for a given partition.
+///
+/// Batches are processed one after the other so that the
+/// handler never processes two batches at the same time.
+pub(crate) struct Worker {
+    sender: mpsc::UnboundedSender<Batch>,
+    handle: JoinHandle<()>,
+    partition: PartitionId,
+    /// Why the worker was told to stop
+    stop_reason: Arc<Mutex<HandlerStopReason>>,
+}
+
+impl Worker {
+    /// Spawn the worker task on the current runtime.
+    ///
+    /// It will run until the `Worker` is dropped, a stop is requested
+    /// via `lifecycle` or the handler fails.
+    pub fn start<H, M>(
+        handler: H,
+        committer: Committer,
+        partition: PartitionId,
+        lifecycle: AutoCancellationToken,
+        metrics_collector: M,
+        handler_retry_policy: HandlerRetryPolicy,
+    ) -> Worker
+    where
+        H: AsyncBatchHandler + 'static,
+        M: MetricsCollector + Send + Sync + 'static,
+    {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let stop_reason = Arc::new(Mutex::new(HandlerStopReason::Cancelled));
+
+        let handle = tokio::spawn(handler_loop(
+            receiver,
+            lifecycle,
+            partition.clone(),
+            handler,
+            committer,
+            metrics_collector,
+            handler_retry_policy,
+            stop_reason.clone(),
+        ));
+
+        Worker {
+            sender,
+            handle,
+            partition,
+            stop_reason,
+        }
+    }
+
+    /// Tell the handler that the stream ended once the worker stops.
+    ///
+    /// Otherwise the handler will be told that it was cancelled.
+    pub fn end_of_stream(&self) {
+        *self.stop_reason.lock().unwrap() = HandlerStopReason::StreamEnded;
+    }
+
+    /// Process the batch.
+    pub fn process(&self, batch: Batch) -> Result<(), Error> {
+        self.sender.send(batch).map_err(|_| {
+            format_err!(
+                "[Worker, partition={}] Could not send batch. Channel to worker task disconnected.",
+                self.partition
+            )
+        })
+    }
+
+    /// The partition this worker is processing
+    pub fn partition(&self) -> &PartitionId {
+        &self.partition
+    }
+
+    /// Stop accepting batches and wait for the task to finish.
+    pub async fn join(self) {
+        let Worker {
+            sender,
+            handle,
+            partition,
+            ..
+        } = self;
+        drop(sender);
+        if let Err(err) = handle.await {
+            error!(
+                "[Worker, partition={}] Worker task failed: {}",
+                partition, err
+            );
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn handler_loop<H, M>(
+    mut receiver: mpsc::UnboundedReceiver<Batch>,
+    lifecycle: AutoCancellationToken,
+    partition: PartitionId,
+    mut handler: H,
+    committer: Committer,
+    metrics_collector: M,
+    handler_retry_policy: HandlerRetryPolicy,
+    external_stop_reason: Arc<Mutex<HandlerStopReason>>,
+) where
+    H: AsyncBatchHandler,
+    M: MetricsCollector,
+{
+    let stream_id = committer.stream_id().clone();
+    let subscription_id = committer.subscription_id().clone();
+
+    info!(
+        "[Worker, stream={}, partition={}] Started.",
+        stream_id, partition
+    );
+    metrics_collector.worker_worker_started();
+
+    let stopped_externally = || external_stop_reason.lock().unwrap().clone();
+    let mut last_processed_cursor = None;
+
+    let stop_reason = loop {
+        let batch = if let Some(batch) = receiver.recv().await {
+            batch
+        } else {
+            info!(
+                "[Worker, stream={}, partition={}] Cannot receive more batches. \
+                 Channel closed. Stopping.",
+                stream_id, partition
+            );
+            break stopped_externally();
+        };
+
+        if lifecycle.cancellation_requested() {
+            info!(
+                "[Worker, stream={}, partition={}] Stop requested externally.",
+                stream_id, partition
+            );
+            break stopped_externally();
+        }
+
+        let ctx = MetricsContext::for_batch(&subscription_id, &batch.batch_line);
+        metrics_collector.worker_batch_received(&ctx, batch.received_at);
+
+        let cursor: SubscriptionCursor = match serde_json::from_slice(batch.batch_line.cursor()) {
+            Ok(cursor) => cursor,
+            Err(err) => {
+                error!(
+                    "[Worker, stream={}, partition={}] Could not parse cursor. Stopping: {}",
+                    stream_id, partition, err
+                );
+                break HandlerStopReason::Failed {
+                    reason: format!("Could not parse cursor: {}", err),
+                };
+            }
+        };
+
+        let events = if let Some(events) = batch.batch_line.events() {
+            events
+        } else {
+            warn!(
+                "[Worker, stream={}, partition={}] \
+                 Received batch without events.",
+                stream_id, partition
+            );
+            continue;
+        };
+
+        metrics_collector.worker_batch_size_bytes(&ctx, events.len());
+
+        let mut retries = BatchRetries::new(&handler_retry_policy, batch.received_at, false);
+        let handler_result = loop {
+            let start = Instant::now();
+            let handler_result = handler.handle(&cursor, events).await;
+            metrics_collector.worker_batch_processed(&ctx, start);
+
+            let after = match retries.next_attempt(handler_result) {
+                NextAttempt::Done(handler_result) => break Some(handler_result),
+                NextAttempt::RetryAfter(after) => after,
+            };
+
+            info!(
+                "[Worker, stream={}, partition={}] Handler requested a retry. \
+                 Retrying in {:?}(retry #{}).",
+                stream_id,
+                partition,
+                after,
+                retries.retries()
+            );
+
+            if !wait_for_retry(after, &lifecycle).await {
+                break None;
+            }
+        };
+
+        let num_events_hint = match handler_result {
+            Some(ProcessingStatus::Processed(num_events_hint)) => {
+                num_events_hint.iter().for_each(|n| {
+                    metrics_collector.worker_events_in_same_batch_processed(&ctx, *n)
+                });
+                num_events_hint
+            }
+            Some(ProcessingStatus::Failed { reason }) => {
+                warn!(
+                    "[Worker, stream={}, partition={}] Handler failed: {}",
+                    stream_id, partition, reason
+                );
+                break HandlerStopReason::Failed { reason };
+            }
+            Some(ProcessingStatus::Retry { .. }) => unreachable!("Retries are handled above"),
+            None => {
+                info!(
+                    "[Worker, stream={}, partition={}] Stop requested externally while \
+                     waiting for a retry.",
+                    stream_id, partition
+                );
+                break stopped_externally();
+            }
+        };
+
+        last_processed_cursor = Some(cursor);
+
+        if let Err(err) = committer.request_commit(batch, num_events_hint) {
+            warn!(
+                "[Worker, stream={}, partition={}] \
+                 Committer did not accept batch commit request. \
+                 Stopping: {}",
+                stream_id, partition, err
+            );
+            break HandlerStopReason::Failed {
+                reason: format!("Committer did not accept batch commit request: {}", err),
+            };
+        }
+    };
+
+    info!(
+        "[Worker, stream={}, partition={}] Stopping handler: {:?}",
+        stream_id, partition, stop_reason
+    );
+    handler
+        .on_stop(&stop_reason, last_processed_cursor.as_ref())
+        .await;
+
+    metrics_collector.worker_worker_stopped();
+
+    info!(
+        "[Worker, stream={}, partition={}] Stopped.",
+        stream_id, partition
+    );
+}
+
+/// Wait until a retry is due.
+///
+/// Returns `false` if a stop was requested while waiting.
+async fn wait_for_retry(after: Duration, lifecycle: &AutoCancellationToken) -> bool {
+    let cancellation_request = lifecycle.cancellation_request();
+    pin_mut!(cancellation_request);
+    match future::select(delay_for(after), cancellation_request).await {
+        Either::Left(_) => !lifecycle.cancellation_requested(),
+        Either::Right(_) => false,
+    }
+}
@@ -51,10 +51,9 @@ impl BatchLine {
         &self.bytes[a..=b]
     }
 
-    #[cfg(test)]
     pub fn event_type_str(&self) -> Result<&str, Error> {
         ::std::str::from_utf8(self.event_type())
-            .map_err(|err| format_err!("Partition is not UTF-8: {}", err))
+            .map_err(|err| format_err!("Event type is not UTF-8: {}", err))
     }
 
     pub fn events(&self) -> Option<&[u8]> {
@@ -70,40 +69,20 @@ impl BatchLine {
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Default)]
 pub struct LineItems {
     pub cursor: Cursor,
     pub events: Option<(usize, usize)>,
     pub info: Option<(usize, usize)>,
 }
 
-impl Default for LineItems {
-    fn default() -> LineItems {
-        LineItems {
-            cursor: Default::default(),
-            events: None,
-            info: None,
-        }
-    }
-}
-
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Default)]
 pub struct Cursor {
     pub line_position: (usize, usize),
     pub partition: (usize, usize),
     pub event_type: (usize, usize),
 }
 
-impl Default for Cursor {
-    fn default() -> Cursor {
-        Cursor {
-            line_position: (0, 0),
-            partition: (0, 0),
-            event_type: (0, 0),
-        }
-    }
-}
-
 #[test]
 fn parse_subscription_batch_line_with_info() {
     let line_sample = r#"{"cursor":{"partition":"6","offset":"543","#.to_owned()
@@ -136,7 +115,7 @@ fn parse_subscription_batch_line_with_info() {
     assert_eq!(line.event_type_str().unwrap(), "order.ORDER_RECEIVED");
     assert_eq!(line.events(), Some(events_sample.as_bytes()));
     assert_eq!(line.info(), Some(&info_sample[..]));
-    assert_eq!(line.is_keep_alive_line(), false);
+    assert!(!line.is_keep_alive_line());
 }
 
 #[test]
@@ -169,7 +148,7 @@ fn parse_subscription_batch_line_without_info() {
     assert_eq!(line.event_type_str().unwrap(), "order.ORDER_RECEIVED");
     assert_eq!(line.events(), Some(events_sample.as_bytes()));
     assert_eq!(line.info(), None);
-    assert_eq!(line.is_keep_alive_line(), false);
+    assert!(!line.is_keep_alive_line());
 }
 
 #[test]
@@ -191,7 +170,7 @@ fn parse_subscription_batch_line_keep_alive_with_info() {
     assert_eq!(line.partition_str().unwrap(), "6");
     assert_eq!(line.event_type_str().unwrap(), "order.ORDER_RECEIVED");
     assert_eq!(line.info(), Some(&info_sample[..]));
-    assert_eq!(line.is_keep_alive_line(), true);
+    assert!(line.is_keep_alive_line());
 }
 
 #[test]
@@ -211,7 +190,7 @@ fn parse_subscription_batch_line_keep_alive_without_info() {
     assert_eq!(line.partition_str().unwrap(), "6");
     assert_eq!(line.event_type_str().unwrap(), "order.ORDER_RECEIVED");
     assert_eq!(line.info(), None);
-    assert_eq!(line.is_keep_alive_line(), true);
+    assert!(line.is_keep_alive_line());
 }
 
 mod line_parsing {
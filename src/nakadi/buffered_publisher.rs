@@ -0,0 +1,446 @@
+//! Publish single events in batches
+//!
+//! The `BufferedPublisher` collects events of a single event type
+//! and publishes them in batches from a background thread.
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use failure::*;
+use serde::Serialize;
+use serde_json;
+
+use crate::nakadi::publisher::{
+    BatchItemResponse, NakadiPublisher, PublishError, PublishStatus, PublishingStatus,
+};
+
+/// What to do when the queue of a `BufferedPublisher` is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackpressurePolicy {
+    /// Block the caller until there is room in the queue
+    Block,
+    /// Drop the event. Its `PublishReceipt` will report `PublishOutcome::Dropped`
+    Drop,
+    /// Return `BufferedPublishError::QueueFull` to the caller
+    Error,
+}
+
+/// Configures when a `BufferedPublisher` flushes its events
+#[derive(Debug, Clone)]
+pub struct BufferedPublisherConfig {
+    /// Flush once this many events are buffered
+    pub max_events: usize,
+    /// Flush once the buffered events have this many bytes.
+    ///
+    /// Events are flushed before an event would exceed this limit
+    /// so only a single event larger than this makes a bigger batch.
+    pub max_bytes: usize,
+    /// Flush once the oldest buffered event is this old
+    pub max_age: Duration,
+    /// The number of events that can be queued for the
+    /// background thread before the `BackpressurePolicy` applies.
+    pub queue_size: usize,
+    pub backpressure_policy: BackpressurePolicy,
+    /// The budget for retrying to publish a batch.
+    /// See `NakadiPublisher::publish_raw`
+    pub publish_budget: Duration,
+}
+
+impl Default for BufferedPublisherConfig {
+    fn default() -> Self {
+        BufferedPublisherConfig {
+            max_events: 500,
+            max_bytes: 1_000_000,
+            max_age: Duration::from_secs(1),
+            queue_size: 10_000,
+            backpressure_policy: BackpressurePolicy::Block,
+            publish_budget: Duration::from_secs(10),
+        }
+    }
+}
+
+/// The outcome of publishing a single event
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PublishOutcome {
+    /// The event was published
+    Published,
+    /// Nakadi did not accept the event
+    Rejected(BatchItemResponse),
+    /// The batch containing the event could not be published
+    Failed(String),
+    /// The event was dropped due to backpressure
+    Dropped,
+}
+
+/// Notifies on the outcome of publishing a single event
+pub struct PublishReceipt {
+    receiver: mpsc::Receiver<PublishOutcome>,
+}
+
+impl PublishReceipt {
+    /// Block until the outcome is known
+    pub fn wait(self) -> PublishOutcome {
+        self.receiver
+            .recv()
+            .unwrap_or_else(|_| PublishOutcome::Failed("The publisher is gone".to_string()))
+    }
+
+    /// Block until the outcome is known or the timeout elapsed.
+    ///
+    /// Returns `None` if the outcome is not known after `timeout`.
+    pub fn wait_timeout(&self, timeout: Duration) -> Option<PublishOutcome> {
+        match self.receiver.recv_timeout(timeout) {
+            Ok(outcome) => Some(outcome),
+            Err(mpsc::RecvTimeoutError::Timeout) => None,
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                Some(PublishOutcome::Failed("The publisher is gone".to_string()))
+            }
+        }
+    }
+
+    /// Returns the outcome if it is already known
+    pub fn try_outcome(&self) -> Option<PublishOutcome> {
+        match self.receiver.try_recv() {
+            Ok(outcome) => Some(outcome),
+            Err(mpsc::TryRecvError::Empty) => None,
+            Err(mpsc::TryRecvError::Disconnected) => {
+                Some(PublishOutcome::Failed("The publisher is gone".to_string()))
+            }
+        }
+    }
+}
+
+/// An event waiting to be published
+struct BufferedEvent {
+    bytes: Vec<u8>,
+    notify: mpsc::Sender<PublishOutcome>,
+    buffered_at: Instant,
+}
+
+/// Publishes events of a single event type in batches.
+///
+/// Events are queued and published from a background thread
+/// once one of the limits of the `BufferedPublisherConfig` is reached.
+///
+/// Dropping the `BufferedPublisher` publishes all events still
+/// buffered and blocks until that is done.
+pub struct BufferedPublisher {
+    sender: Option<mpsc::SyncSender<BufferedEvent>>,
+    handle: Option<thread::JoinHandle<()>>,
+    backpressure_policy: BackpressurePolicy,
+}
+
+impl BufferedPublisher {
+    /// Create a new `BufferedPublisher` and start its background thread
+    ///
+    /// # Errors
+    ///
+    /// The background thread could not be started.
+    pub fn new<E: Into<String>>(
+        publisher: NakadiPublisher,
+        event_type: E,
+        config: BufferedPublisherConfig,
+    ) -> Result<BufferedPublisher, Error> {
+        let event_type = event_type.into();
+        let (sender, receiver) = mpsc::sync_channel(config.queue_size);
+
+        let backpressure_policy = config.backpressure_policy;
+        let publish_budget = config.publish_budget;
+        let builder = thread::Builder::new().name(format!("nakadion-publisher-{}", event_type));
+        let handle = builder
+            .spawn(move || {
+                flush_loop(receiver, config, |bytes| {
+                    publisher.publish_raw(&event_type, bytes, None, publish_budget)
+                })
+            })
+            .context("Could not start the publisher thread")?;
+
+        Ok(BufferedPublisher {
+            sender: Some(sender),
+            handle: Some(handle),
+            backpressure_policy,
+        })
+    }
+
+    /// Queue an event for publishing.
+    ///
+    /// # Errors
+    ///
+    /// The event could not be serialized or queued.
+    pub fn publish<T: Serialize>(&self, event: &T) -> Result<PublishReceipt, BufferedPublishError> {
+        let bytes = serde_json::to_vec(event)
+            .map_err(|err| BufferedPublishError::Serialization(err.to_string()))?;
+        self.publish_raw(bytes)
+    }
+
+    /// Queue an already serialized event for publishing.
+    ///
+    /// # Errors
+    ///
+    /// The event could not be queued.
+    pub fn publish_raw(&self, bytes: Vec<u8>) -> Result<PublishReceipt, BufferedPublishError> {
+        let sender = self.sender.as_ref().ok_or(BufferedPublishError::Stopped)?;
+
+        let (notify, receiver) = mpsc::channel();
+        let event = BufferedEvent {
+            bytes,
+            notify,
+            buffered_at: Instant::now(),
+        };
+
+        match self.backpressure_policy {
+            BackpressurePolicy::Block => sender
+                .send(event)
+                .map_err(|_| BufferedPublishError::Stopped)?,
+            BackpressurePolicy::Drop => match sender.try_send(event) {
+                Ok(()) => (),
+                Err(mpsc::TrySendError::Full(event)) => {
+                    let _ = event.notify.send(PublishOutcome::Dropped);
+                }
+                Err(mpsc::TrySendError::Disconnected(_)) => {
+                    return Err(BufferedPublishError::Stopped)
+                }
+            },
+            BackpressurePolicy::Error => match sender.try_send(event) {
+                Ok(()) => (),
+                Err(mpsc::TrySendError::Full(_)) => return Err(BufferedPublishError::QueueFull),
+                Err(mpsc::TrySendError::Disconnected(_)) => {
+                    return Err(BufferedPublishError::Stopped)
+                }
+            },
+        }
+
+        Ok(PublishReceipt { receiver })
+    }
+}
+
+impl Drop for BufferedPublisher {
+    fn drop(&mut self) {
+        // Closing the channel makes the background thread flush and stop
+        self.sender.take();
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                error!("[BufferedPublisher] The background thread panicked.");
+            }
+        }
+    }
+}
+
+fn flush_loop<F>(
+    receiver: mpsc::Receiver<BufferedEvent>,
+    config: BufferedPublisherConfig,
+    mut publish: F,
+) where
+    F: FnMut(Vec<u8>) -> Result<PublishStatus, PublishError>,
+{
+    let mut buffer: Vec<BufferedEvent> = Vec::new();
+    let mut buffered_bytes = 0;
+    loop {
+        let timeout = buffer
+            .first()
+            .map(|oldest| {
+                (oldest.buffered_at + config.max_age).saturating_duration_since(Instant::now())
+            })
+            .unwrap_or(config.max_age);
+
+        match receiver.recv_timeout(timeout) {
+            Ok(event) => {
+                // Flush first if the event would make the batch too large
+                if !buffer.is_empty() && buffered_bytes + event.bytes.len() > config.max_bytes {
+                    flush(&mut buffer, &mut publish);
+                    buffered_bytes = 0;
+                }
+                buffered_bytes += event.bytes.len();
+                buffer.push(event);
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => (),
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                if !buffer.is_empty() {
+                    flush(&mut buffer, &mut publish);
+                }
+                break;
+            }
+        }
+
+        let is_due = buffer.len() >= config.max_events
+            || buffered_bytes >= config.max_bytes
+            || buffer
+                .first()
+                .map(|oldest| oldest.buffered_at.elapsed() >= config.max_age)
+                .unwrap_or(false);
+
+        if is_due {
+            flush(&mut buffer, &mut publish);
+            buffered_bytes = 0;
+        }
+    }
+}
+
+/// Publish all buffered events and notify on the outcomes.
+fn flush<F>(buffer: &mut Vec<BufferedEvent>, publish: &mut F)
+where
+    F: FnMut(Vec<u8>) -> Result<PublishStatus, PublishError>,
+{
+    let events = ::std::mem::take(buffer);
+
+    let mut bytes = Vec::with_capacity(events.iter().map(|e| e.bytes.len() + 1).sum::<usize>() + 1);
+    bytes.push(b'[');
+    for (n, event) in events.iter().enumerate() {
+        if n > 0 {
+            bytes.push(b',');
+        }
+        bytes.extend_from_slice(&event.bytes);
+    }
+    bytes.push(b']');
+
+    match publish(bytes) {
+        Ok(PublishStatus::AllEventsPublished) => {
+            for event in events {
+                let _ = event.notify.send(PublishOutcome::Published);
+            }
+        }
+        Ok(PublishStatus::NotAllEventsPublished(items)) => {
+            for (event, item) in events.into_iter().zip(items) {
+                let outcome = if item.publishing_status == PublishingStatus::Submitted {
+                    PublishOutcome::Published
+                } else {
+                    PublishOutcome::Rejected(item)
+                };
+                let _ = event.notify.send(outcome);
+            }
+        }
+        Err(err) => {
+            warn!("[BufferedPublisher] Could not publish batch: {}", err);
+            let reason = err.to_string();
+            for event in events {
+                let _ = event.notify.send(PublishOutcome::Failed(reason.clone()));
+            }
+        }
+    }
+}
+
+/// Errors that can happen when queueing an event
+/// on a `BufferedPublisher`.
+#[derive(Fail, Debug)]
+pub enum BufferedPublishError {
+    #[fail(display = "Could not serialize event: {}", _0)]
+    Serialization(String),
+    #[fail(display = "The queue is full")]
+    QueueFull,
+    #[fail(display = "The background thread has stopped")]
+    Stopped,
+}
+
+#[test]
+fn flush_loop_flushes_on_max_events_and_when_closed() {
+    let (sender, receiver) = mpsc::sync_channel(10);
+    let config = BufferedPublisherConfig {
+        max_events: 2,
+        max_age: Duration::from_secs(60),
+        ..Default::default()
+    };
+
+    let mut receipts = Vec::new();
+    for n in 0..3 {
+        let (notify, receiver) = mpsc::channel();
+        sender
+            .send(BufferedEvent {
+                bytes: format!("{}", n).into_bytes(),
+                notify,
+                buffered_at: Instant::now(),
+            })
+            .unwrap();
+        receipts.push(PublishReceipt { receiver });
+    }
+    drop(sender);
+
+    let mut batches = Vec::new();
+    flush_loop(receiver, config, |bytes| {
+        batches.push(String::from_utf8(bytes).unwrap());
+        Ok(PublishStatus::AllEventsPublished)
+    });
+
+    assert_eq!(batches, vec!["[0,1]".to_string(), "[2]".to_string()]);
+    for receipt in receipts {
+        assert_eq!(receipt.wait(), PublishOutcome::Published);
+    }
+}
+
+#[test]
+fn flush_loop_does_not_exceed_max_bytes() {
+    let (sender, receiver) = mpsc::sync_channel(10);
+    let config = BufferedPublisherConfig {
+        max_bytes: 5,
+        max_age: Duration::from_secs(60),
+        ..Default::default()
+    };
+
+    for event in &["aa", "bb", "cc", "dddddd"] {
+        let (notify, _) = mpsc::channel();
+        sender
+            .send(BufferedEvent {
+                bytes: event.as_bytes().to_vec(),
+                notify,
+                buffered_at: Instant::now(),
+            })
+            .unwrap();
+    }
+    drop(sender);
+
+    let mut batches = Vec::new();
+    flush_loop(receiver, config, |bytes| {
+        batches.push(String::from_utf8(bytes).unwrap());
+        Ok(PublishStatus::AllEventsPublished)
+    });
+
+    assert_eq!(
+        batches,
+        vec![
+            "[aa,bb]".to_string(),
+            "[cc]".to_string(),
+            "[dddddd]".to_string()
+        ]
+    );
+}
+
+#[test]
+fn flush_notifies_rejected_events() {
+    let mut receivers = Vec::new();
+    let mut buffer = Vec::new();
+    for n in 0..2 {
+        let (notify, receiver) = mpsc::channel();
+        buffer.push(BufferedEvent {
+            bytes: format!("{}", n).into_bytes(),
+            notify,
+            buffered_at: Instant::now(),
+        });
+        receivers.push(PublishReceipt { receiver });
+    }
+
+    let rejected = BatchItemResponse {
+        eid: None,
+        publishing_status: PublishingStatus::Failed,
+        step: None,
+        detail: Some("boom".to_string()),
+    };
+    let items = vec![
+        BatchItemResponse {
+            eid: None,
+            publishing_status: PublishingStatus::Submitted,
+            step: None,
+            detail: None,
+        },
+        rejected.clone(),
+    ];
+
+    flush(&mut buffer, &mut |_| {
+        Ok(PublishStatus::NotAllEventsPublished(items.clone()))
+    });
+
+    assert!(buffer.is_empty());
+    assert_eq!(receivers[0].try_outcome(), Some(PublishOutcome::Published));
+    assert_eq!(
+        receivers[1].try_outcome(),
+        Some(PublishOutcome::Rejected(rejected))
+    );
+}
@@ -1,18 +1,29 @@
+//! Keeping track of cursors and committing them
 use std::collections::hash_map::Entry;
 use std::collections::HashMap;
+#[cfg(feature = "blocking")]
 use std::sync::mpsc;
+#[cfg(feature = "blocking")]
 use std::sync::Arc;
-use std::thread;
 use std::time::{Duration, Instant};
 
+#[cfg(feature = "blocking")]
 use crate::cancellation_token::*;
+#[cfg(feature = "blocking")]
 use failure::{Error, Fail};
 
+#[cfg(feature = "blocking")]
 use crate::nakadi::api::{ApiClient, CommitError, CommitStatus};
 use crate::nakadi::batch::Batch;
-use crate::nakadi::metrics::MetricsCollector;
-use crate::nakadi::model::{FlowId, StreamId, SubscriptionId};
+#[cfg(feature = "blocking")]
+use crate::nakadi::consumer::{ShutdownTracker, StopReason};
+use crate::nakadi::metrics::{MetricsCollector, MetricsContext};
+use crate::nakadi::model::SubscriptionId;
+#[cfg(feature = "blocking")]
+use crate::nakadi::model::{FlowId, StreamId};
 use crate::nakadi::CommitStrategy;
+#[cfg(feature = "blocking")]
+use crate::thread_handle::ThreadHandle;
 
 const CURSOR_COMMIT_OFFSET: u64 = 55;
 
@@ -25,6 +36,7 @@ const CURSOR_COMMIT_OFFSET: u64 = 55;
 ///
 /// The `Committer` creates a background thread and works
 /// asynchronously to the event processing.
+#[cfg(feature = "blocking")]
 #[derive(Clone)]
 pub struct Committer {
     sender: mpsc::Sender<CommitterMessage>,
@@ -32,12 +44,15 @@ pub struct Committer {
     lifecycle: Arc<CancellationTokenSource>,
     subscription_id: SubscriptionId,
     metrics_collector: Arc<dyn MetricsCollector + Send + Sync + 'static>,
+    thread: Arc<ThreadHandle<Option<StopReason>>>,
 }
 
+#[cfg(feature = "blocking")]
 enum CommitterMessage {
     Commit(Batch, Option<usize>),
 }
 
+#[cfg(feature = "blocking")]
 impl Committer {
     /// Start a new `Committer`. The committer uses
     /// an `ApiClient` to commit cursors.
@@ -47,6 +62,7 @@ impl Committer {
         subscription_id: SubscriptionId,
         stream_id: StreamId,
         metrics_collector: M,
+        shutdown: ShutdownTracker,
     ) -> Self
     where
         C: ApiClient + Send + 'static,
@@ -56,7 +72,7 @@ impl Committer {
 
         let lifecycle = Arc::new(CancellationTokenSource::new(metrics_collector.clone()));
 
-        start_commit_loop(
+        let thread = start_commit_loop(
             receiver,
             strategy,
             subscription_id.clone(),
@@ -64,6 +80,7 @@ impl Committer {
             client,
             lifecycle.auto_token(),
             metrics_collector.clone(),
+            shutdown,
         );
 
         Committer {
@@ -72,6 +89,7 @@ impl Committer {
             lifecycle,
             subscription_id,
             metrics_collector: Arc::new(metrics_collector),
+            thread: Arc::new(thread),
         }
     }
 
@@ -99,6 +117,10 @@ impl Committer {
         &self.stream_id
     }
 
+    pub fn subscription_id(&self) -> &SubscriptionId {
+        &self.subscription_id
+    }
+
     /// Is the committer still running?
     pub fn is_running(&self) -> bool {
         !(self.lifecycle.is_any_cancelled())
@@ -108,8 +130,31 @@ impl Committer {
     pub fn stop(&self) {
         self.lifecycle.request_cancellation()
     }
+
+    /// Order the committer to commit all cursors it already
+    /// received and then stop.
+    pub fn drain(&self) {
+        self.lifecycle.request_drain()
+    }
+
+    /// Block until the committer thread finished or `timeout` elapsed.
+    ///
+    /// Returns true if the committer thread finished.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        self.thread.wait_timeout(timeout)
+    }
+
+    /// Block until the committer thread finished.
+    ///
+    /// Returns a `StopReason` if the consumer should stop because
+    /// committing failed permanently.
+    pub fn join(&self) -> Option<StopReason> {
+        StopReason::from_joined(self.thread.join())
+    }
 }
 
+#[cfg(feature = "blocking")]
+#[allow(clippy::too_many_arguments)]
 fn start_commit_loop<C, M>(
     receiver: mpsc::Receiver<CommitterMessage>,
     strategy: CommitStrategy,
@@ -118,27 +163,30 @@ fn start_commit_loop<C, M>(
     connector: C,
     lifecycle: AutoCancellationToken,
     metrics_collector: M,
-) where
+    shutdown: ShutdownTracker,
+) -> ThreadHandle<Option<StopReason>>
+where
     C: ApiClient + Send + 'static,
     M: MetricsCollector + Send + 'static,
 {
-    let builder = thread::Builder::new().name("nakadion-committer".into());
-    builder
-        .spawn(move || {
-            run_commit_loop(
-                receiver,
-                strategy,
-                subscription_id,
-                stream_id,
-                connector,
-                lifecycle,
-                metrics_collector,
-            );
-        })
-        .unwrap();
+    ThreadHandle::spawn("nakadion-committer".into(), move || {
+        run_commit_loop(
+            receiver,
+            strategy,
+            subscription_id,
+            stream_id,
+            connector,
+            lifecycle,
+            metrics_collector,
+            shutdown,
+        )
+    })
 }
 
-struct CommitEntry {
+/// The cursors to be committed keyed by partition and event type
+pub(crate) type CommitEntries = HashMap<(Vec<u8>, Vec<u8>), CommitEntry>;
+
+pub(crate) struct CommitEntry {
     // timestamp when this entry was created
     created_at: Instant,
     // No matter how many batches/cursors are added, this is the deadline
@@ -214,8 +262,86 @@ impl CommitEntry {
     pub fn is_due_by_deadline(&self) -> bool {
         self.commit_deadline <= Instant::now()
     }
+
+    /// The cursor of the most recent batch of this entry
+    pub fn cursor(&self) -> &[u8] {
+        self.batch.batch_line.cursor()
+    }
+}
+
+/// Add the cursor of the given batch to the cursors to be committed.
+pub(crate) fn add_commit_entry(
+    all_cursors: &mut CommitEntries,
+    batch: Batch,
+    strategy: CommitStrategy,
+    num_events_hint: Option<usize>,
+) {
+    let key = (
+        batch.batch_line.partition().to_vec(),
+        batch.batch_line.event_type().to_vec(),
+    );
+
+    match all_cursors.entry(key) {
+        Entry::Vacant(entry) => {
+            entry.insert(CommitEntry::new(batch, strategy, num_events_hint));
+        }
+        Entry::Occupied(mut entry) => {
+            entry.get_mut().update(batch, num_events_hint);
+        }
+    }
+}
+
+/// Cursors that are due to be committed
+pub(crate) struct DueCursors {
+    pub cursors: Vec<Vec<u8>>,
+    pub num_batches: usize,
+    pub num_events: usize,
+}
+
+/// Returns the cursors to commit if a commit is due according
+/// to the `CommitStrategy` or the deadlines of the cursors.
+pub(crate) fn due_cursors<M>(
+    all_cursors: &CommitEntries,
+    subscription_id: &SubscriptionId,
+    strategy: CommitStrategy,
+    metrics_collector: &M,
+) -> Option<DueCursors>
+where
+    M: MetricsCollector,
+{
+    let num_batches: usize = all_cursors.iter().map(|entry| entry.1.num_batches).sum();
+    let num_events: usize = all_cursors.iter().map(|entry| entry.1.num_events).sum();
+
+    let commit_by_other_than_deadline = match strategy {
+        CommitStrategy::AllBatches => true,
+        CommitStrategy::Batches { after_batches, .. } => num_batches >= after_batches as usize,
+        CommitStrategy::Events { after_events, .. } => num_events >= after_events as usize,
+        _ => false,
+    };
+
+    let commit_by_deadline = all_cursors.values().any(|entry| entry.is_due_by_deadline());
+
+    if all_cursors.is_empty() || !(commit_by_deadline || commit_by_other_than_deadline) {
+        return None;
+    }
+
+    let mut due = DueCursors {
+        cursors: Vec::with_capacity(all_cursors.len()),
+        num_batches: 0,
+        num_events: 0,
+    };
+    for entry in all_cursors.values() {
+        due.num_batches += entry.num_batches;
+        due.num_events += entry.num_events;
+        update_cursor_metrics(metrics_collector, subscription_id, entry);
+        due.cursors.push(entry.cursor().to_vec());
+    }
+
+    Some(due)
 }
 
+#[cfg(feature = "blocking")]
+#[allow(clippy::too_many_arguments)]
 fn run_commit_loop<C, M>(
     receiver: mpsc::Receiver<CommitterMessage>,
     strategy: CommitStrategy,
@@ -224,46 +350,58 @@ fn run_commit_loop<C, M>(
     client: C,
     lifecycle: AutoCancellationToken,
     metrics_collector: M,
-) where
+    shutdown: ShutdownTracker,
+) -> Option<StopReason>
+where
     C: ApiClient,
     M: MetricsCollector,
 {
     let mut cursors = HashMap::new();
+    let mut stop_reason = None;
     loop {
         if lifecycle.cancellation_requested() {
             info!(
                 "[Committer, subscription={}, stream={}] Abort requested. Flushing cursors",
                 subscription_id, stream_id
             );
-            flush_all_cursors::<_>(cursors, &subscription_id, &stream_id, &client);
+            for CommitterMessage::Commit(next_batch, num_events_hint) in receiver.try_iter() {
+                add_commit_entry(&mut cursors, next_batch, strategy, num_events_hint);
+            }
+            let committed = flush_all_cursors::<_>(cursors, &subscription_id, &stream_id, &client);
+            shutdown.cursors_committed(committed);
             break;
         }
 
         match receiver.recv_timeout(Duration::from_millis(50)) {
             Ok(CommitterMessage::Commit(next_batch, num_events_hint)) => {
-                metrics_collector.committer_batch_received(next_batch.received_at);
-                let key = (
-                    next_batch.batch_line.partition().to_vec(),
-                    next_batch.batch_line.event_type().to_vec(),
+                metrics_collector.committer_batch_received(
+                    &MetricsContext::for_batch(&subscription_id, &next_batch.batch_line),
+                    next_batch.received_at,
                 );
-
-                match cursors.entry(key) {
-                    Entry::Vacant(entry) => {
-                        entry.insert(CommitEntry::new(next_batch, strategy, num_events_hint));
-                    }
-                    Entry::Occupied(mut entry) => {
-                        entry.get_mut().update(next_batch, num_events_hint);
-                    }
+                add_commit_entry(&mut cursors, next_batch, strategy, num_events_hint);
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                if lifecycle.drain_requested() {
+                    info!(
+                        "[Committer, subscription={}, stream={}] All cursors received. \
+                         Flushing cursors",
+                        subscription_id, stream_id
+                    );
+                    let committed =
+                        flush_all_cursors::<_>(cursors, &subscription_id, &stream_id, &client);
+                    shutdown.cursors_committed(committed);
+                    break;
                 }
             }
-            Err(mpsc::RecvTimeoutError::Timeout) => (),
             Err(mpsc::RecvTimeoutError::Disconnected) => {
                 warn!(
                     "[Committer, subscription={}, stream={}] Commit channel disconnected.\
                      Flushing all cursors before stopping.",
                     subscription_id, stream_id
                 );
-                flush_all_cursors::<_>(cursors, &subscription_id, &stream_id, &client);
+                let committed =
+                    flush_all_cursors::<_>(cursors, &subscription_id, &stream_id, &client);
+                shutdown.cursors_committed(committed);
                 break;
             }
         }
@@ -275,6 +413,7 @@ fn run_commit_loop<C, M>(
             &client,
             strategy,
             &metrics_collector,
+            &shutdown,
         ) {
             Ok(CommitStatus::NotAllOffsetsIncreased) => info!(
                 "[Committer, subscription={}, stream={}] Not all cursors were increased.",
@@ -285,6 +424,9 @@ fn run_commit_loop<C, M>(
                     "[Committer, subscription={}, stream={}] Aborting. Failed to commit cursors: {}",
                     subscription_id, stream_id, err
                 );
+                if err.is_permanent() {
+                    stop_reason = Some(StopReason::CommitFailed(err));
+                }
                 break;
             }
             _ => {}
@@ -295,14 +437,19 @@ fn run_commit_loop<C, M>(
         "[Committer, subscription={}, stream={}] Committer stopped.",
         subscription_id, stream_id
     );
+
+    stop_reason
 }
 
+/// Commits all cursors and returns the number of cursors committed.
+#[cfg(feature = "blocking")]
 fn flush_all_cursors<C>(
-    all_cursors: HashMap<(Vec<u8>, Vec<u8>), CommitEntry>,
+    all_cursors: CommitEntries,
     subscription_id: &SubscriptionId,
     stream_id: &StreamId,
     connector: &C,
-) where
+) -> usize
+where
     C: ApiClient,
 {
     // We are not interested in metrics here
@@ -311,127 +458,116 @@ fn flush_all_cursors<C>(
         info!(
             "[Committer, subscription={}, stream={}] No cursors to finally commit.",
             subscription_id, stream_id
-        )
-    } else {
-        let cursors_to_commit: Vec<_> = all_cursors
-            .values()
-            .map(|v| v.batch.batch_line.cursor())
-            .collect();
+        );
+        return 0;
+    }
 
-        let flow_id = FlowId::default();
+    let cursors_to_commit: Vec<_> = all_cursors.values().map(|v| v.cursor()).collect();
 
-        match connector.commit_cursors(
-            subscription_id,
-            stream_id,
-            &cursors_to_commit,
-            flow_id.clone(),
-        ) {
-            Ok(CommitStatus::AllOffsetsIncreased) => info!(
-                "[Committer, subscription={}, stream={}, flow id={}] All remaining offsets\
-                 increased.",
-                subscription_id, stream_id, flow_id
-            ),
-            Ok(CommitStatus::NotAllOffsetsIncreased) => info!(
-                "[Committer, subscription={}, stream={}, flow id={}] Not all remaining\
-                 offsets increased.",
-                subscription_id, stream_id, flow_id
-            ),
-            Ok(CommitStatus::NothingToCommit) => info!(
-                "[Committer, subscription={}, stream={}, flow id={}] There was nothing\
-                 to be finally committed.",
-                subscription_id, stream_id, flow_id
-            ),
-            Err(err) => error!(
+    let flow_id = FlowId::default();
+
+    match connector.commit_cursors(
+        subscription_id,
+        stream_id,
+        &cursors_to_commit,
+        flow_id.clone(),
+    ) {
+        Ok(CommitStatus::AllOffsetsIncreased) => info!(
+            "[Committer, subscription={}, stream={}, flow id={}] All remaining offsets\
+             increased.",
+            subscription_id, stream_id, flow_id
+        ),
+        Ok(CommitStatus::NotAllOffsetsIncreased) => info!(
+            "[Committer, subscription={}, stream={}, flow id={}] Not all remaining\
+             offsets increased.",
+            subscription_id, stream_id, flow_id
+        ),
+        Ok(CommitStatus::NothingToCommit) => info!(
+            "[Committer, subscription={}, stream={}, flow id={}] There was nothing\
+             to be finally committed.",
+            subscription_id, stream_id, flow_id
+        ),
+        Err(err) => {
+            error!(
                 "[Committer, subscription={}, stream={}, flow id={}] Failed to commit all\
                  remaining cursors: {}",
                 subscription_id, stream_id, flow_id, err
-            ),
+            );
+            return 0;
         }
     }
+
+    cursors_to_commit.len()
 }
 
+#[cfg(feature = "blocking")]
 fn flush_if_due<C, M>(
-    all_cursors: &mut HashMap<(Vec<u8>, Vec<u8>), CommitEntry>,
+    all_cursors: &mut CommitEntries,
     subscription_id: &SubscriptionId,
     stream_id: &StreamId,
     client: &C,
     strategy: CommitStrategy,
     metrics_collector: &M,
+    shutdown: &ShutdownTracker,
 ) -> Result<CommitStatus, CommitError>
 where
     C: ApiClient,
     M: MetricsCollector,
 {
-    let num_batches: usize = all_cursors.iter().map(|entry| entry.1.num_batches).sum();
-    let num_events: usize = all_cursors.iter().map(|entry| entry.1.num_events).sum();
-
-    let commit_by_other_than_deadline = match strategy {
-        CommitStrategy::AllBatches => true,
-        CommitStrategy::Batches { after_batches, .. } => num_batches >= after_batches as usize,
-        CommitStrategy::Events { after_events, .. } => num_events >= after_events as usize,
-        _ => false,
-    };
-
-    let commit_by_deadline = all_cursors.values().any(|entry| entry.is_due_by_deadline());
-
-    let mut cursors_to_commit: Vec<Vec<u8>> = Vec::new();
-    let mut num_batches_to_commit = 0;
-    let mut num_events_to_commit = 0;
-    if commit_by_deadline || commit_by_other_than_deadline {
-        for entry in all_cursors.values() {
-            num_batches_to_commit += entry.num_batches;
-            num_events_to_commit += entry.num_events;
-            update_cursor_metrics(metrics_collector, entry);
-            cursors_to_commit.push(entry.batch.batch_line.cursor().to_vec());
-        }
-    }
+    let due =
+        if let Some(due) = due_cursors(all_cursors, subscription_id, strategy, metrics_collector) {
+            due
+        } else {
+            return Ok(CommitStatus::NothingToCommit);
+        };
 
     let flow_id = FlowId::default();
-
-    let status = if !cursors_to_commit.is_empty() {
-        let start = Instant::now();
-        match client.commit_cursors_budgeted(
-            subscription_id,
-            stream_id,
-            &cursors_to_commit,
-            flow_id.clone(),
-            Duration::from_secs(5),
-        ) {
-            Ok(s) => {
-                metrics_collector.committer_cursor_commit_attempt(start);
-                metrics_collector.committer_cursor_committed(start);
-                metrics_collector.committer_batches_committed(num_batches_to_commit);
-                metrics_collector.committer_events_committed(num_events_to_commit);
-                all_cursors.clear();
-                s
-            }
-            Err(err) => {
-                metrics_collector.committer_cursor_commit_attempt(start);
-                metrics_collector.committer_cursor_commit_failed(start);
-                return Err(err);
-            }
+    let ctx = MetricsContext::for_subscription(subscription_id);
+
+    let start = Instant::now();
+    match client.commit_cursors_budgeted(
+        subscription_id,
+        stream_id,
+        &due.cursors,
+        flow_id,
+        Duration::from_secs(5),
+    ) {
+        Ok(status) => {
+            metrics_collector.committer_cursor_commit_attempt(&ctx, start);
+            metrics_collector.committer_cursor_committed(&ctx, start);
+            metrics_collector.committer_batches_committed(&ctx, due.num_batches);
+            metrics_collector.committer_events_committed(&ctx, due.num_events);
+            shutdown.cursors_committed(due.cursors.len());
+            all_cursors.clear();
+            Ok(status)
         }
-    } else {
-        CommitStatus::NothingToCommit
-    };
-
-    Ok(status)
+        Err(err) => {
+            metrics_collector.committer_cursor_commit_attempt(&ctx, start);
+            metrics_collector.committer_cursor_commit_failed(&ctx, start);
+            Err(err)
+        }
+    }
 }
 
-fn update_cursor_metrics<M>(metrics_collector: &M, entry: &CommitEntry)
-where
+fn update_cursor_metrics<M>(
+    metrics_collector: &M,
+    subscription_id: &SubscriptionId,
+    entry: &CommitEntry,
+) where
     M: MetricsCollector,
 {
+    let ctx = MetricsContext::for_batch(subscription_id, &entry.batch.batch_line);
+
     metrics_collector
-        .committer_first_cursor_age_on_commit(entry.first_cursor_received_at.elapsed());
+        .committer_first_cursor_age_on_commit(&ctx, entry.first_cursor_received_at.elapsed());
     metrics_collector
-        .committer_last_cursor_age_on_commit(entry.current_cursor_received_at.elapsed());
-    metrics_collector.committer_cursor_buffer_time(entry.created_at.elapsed());
+        .committer_last_cursor_age_on_commit(&ctx, entry.current_cursor_received_at.elapsed());
+    metrics_collector.committer_cursor_buffer_time(&ctx, entry.created_at.elapsed());
 
     let commit_deadline = entry.first_cursor_received_at + Duration::from_secs(60);
     let now = Instant::now();
     if commit_deadline >= now {
         metrics_collector
-            .committer_time_left_on_commit_until_invalid(commit_deadline.duration_since(now));
+            .committer_time_left_on_commit_until_invalid(&ctx, commit_deadline.duration_since(now));
     }
 }
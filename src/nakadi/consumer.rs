@@ -1,27 +1,31 @@
 //! The consumer iterates over batches of events.
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
 
 use failure::*;
 
-use crate::cancellation_token::{AutoCancellationToken, CancellationToken, CancellationTokenSource};
+use crate::cancellation_token::{
+    AutoCancellationToken, CancellationToken, CancellationTokenSource,
+};
 
-use crate::nakadi::api::ApiClient;
+use crate::nakadi::api::{ApiClient, CommitError};
 use crate::nakadi::batch::{Batch, BatchLine};
 use crate::nakadi::committer::Committer;
+use crate::nakadi::dead_letter::DeadLetterSink;
 use crate::nakadi::dispatcher::Dispatcher;
-use crate::nakadi::handler::HandlerFactory;
-use crate::nakadi::metrics::MetricsCollector;
+use crate::nakadi::handler::{CreateHandlerError, HandlerFactory};
+use crate::nakadi::lifecycle::LifecycleListener;
+use crate::nakadi::metrics::{MetricsCollector, MetricsContext};
 use crate::nakadi::model::*;
 use crate::nakadi::streaming_client::{ConnectError, LineResult, RawLine, StreamingClient};
-use crate::nakadi::CommitStrategy;
+use crate::nakadi::{CommitStrategy, ConsumerSettings, HandlerRetryPolicy, ReconnectPolicy};
+use crate::thread_handle::ThreadHandle;
 
-/// Sequence of backoffs after failed commit attempts
-const CONNECT_RETRY_BACKOFF_MS: &[u64] = &[
-    10, 50, 100, 500, 1000, 1000, 1000, 3000, 3000, 3000, 5000, 5000, 5000, 10_000, 10_000, 10_000,
-    15_000, 15_000, 15_000,
-];
+/// How long `Consumer::shutdown` waits for the consumer
+/// to stop after the shutdown timed out
+const STOP_AFTER_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
 
 /// The consumer connects to the stream using a `StreamingClient` and then
 /// iterates over the batches.
@@ -35,6 +39,9 @@ const CONNECT_RETRY_BACKOFF_MS: &[u64] = &[
 pub struct Consumer {
     lifecycle: CancellationTokenSource,
     _subscription_id: SubscriptionId,
+    shutdown: ShutdownTracker,
+    stopped: StopNotifier,
+    thread: ThreadHandle,
 }
 
 impl Consumer {
@@ -44,9 +51,8 @@ impl Consumer {
         api_client: A,
         subscription_id: SubscriptionId,
         handler_factory: HF,
-        commit_strategy: CommitStrategy,
         metrics_collector: M,
-        min_idle_worker_lifetime: Option<Duration>,
+        settings: ConsumerSettings,
     ) -> Consumer
     where
         C: StreamingClient + Clone + Send + 'static,
@@ -57,24 +63,33 @@ impl Consumer {
         let lifecycle = CancellationTokenSource::new(metrics_collector.clone());
 
         let cancellation_token = lifecycle.auto_token();
+        let shutdown = ShutdownTracker::default();
+        let stopped = StopNotifier::default();
 
-        let consumer = Consumer {
-            lifecycle,
-            _subscription_id: subscription_id.clone(),
-        };
-
-        start_consumer_loop(ConsumerLoopSettings {
+        let thread = start_consumer_loop(ConsumerLoopSettings {
             streaming_client,
             api_client,
             handler_factory,
-            commit_strategy,
-            subscription_id,
+            commit_strategy: settings.commit_strategy,
+            subscription_id: subscription_id.clone(),
             lifecycle: cancellation_token,
             metrics_collector,
-            min_idle_worker_lifetime,
+            min_idle_worker_lifetime: settings.min_idle_worker_lifetime,
+            handler_retry_policy: settings.handler_retry_policy,
+            reconnect_policy: settings.reconnect_policy,
+            dead_letter_sink: settings.dead_letter_sink,
+            lifecycle_listener: settings.lifecycle_listener,
+            shutdown: shutdown.clone(),
+            stopped: stopped.clone(),
         });
 
-        consumer
+        Consumer {
+            lifecycle,
+            _subscription_id: subscription_id,
+            shutdown,
+            stopped,
+            thread,
+        }
     }
 
     pub fn running(&self) -> bool {
@@ -84,6 +99,258 @@ impl Consumer {
     pub fn stop(&self) {
         self.lifecycle.request_cancellation()
     }
+
+    /// Stop reading from the stream, let the workers finish the
+    /// batches already received and commit their cursors.
+    ///
+    /// If this takes longer than `timeout` the consumer is stopped
+    /// like with `stop` and the batches not yet processed are dropped.
+    /// The report is built once the consumer stopped. If the consumer
+    /// does not stop within another 5 seconds, e.g. because a handler
+    /// is blocked, the counts of the report are lower bounds.
+    pub fn shutdown(&self, timeout: Duration) -> ShutdownReport {
+        self.shutdown.start();
+        self.lifecycle.request_drain();
+
+        if !self.thread.wait_timeout(timeout) {
+            warn!(
+                "[Consumer] Shutdown timed out after {:?}. Stopping.",
+                timeout
+            );
+            self.lifecycle.request_cancellation();
+            if !self.thread.wait_timeout(STOP_AFTER_SHUTDOWN_TIMEOUT) {
+                warn!(
+                    "[Consumer] Consumer did not stop within {:?}. \
+                     The shutdown report may be incomplete.",
+                    STOP_AFTER_SHUTDOWN_TIMEOUT
+                );
+            }
+            return self.shutdown.report(true);
+        }
+
+        self.shutdown.report(false)
+    }
+
+    /// Block until the consumer stopped.
+    pub fn join(&self) -> StopReason {
+        self.thread.join();
+        self.stopped
+            .reason()
+            .expect("The consumer thread finished without a stop reason")
+    }
+
+    /// Block until the consumer stopped or `timeout` elapsed.
+    ///
+    /// Returns `None` if the consumer is still running.
+    pub fn wait_timeout(&self, timeout: Duration) -> Option<StopReason> {
+        if self.thread.wait_timeout(timeout) {
+            self.stopped.reason()
+        } else {
+            None
+        }
+    }
+
+    /// Returns a channel receiving the `StopReason` once the consumer stopped.
+    ///
+    /// If the consumer already stopped the `StopReason` can be received immediately.
+    pub fn stop_notification(&self) -> mpsc::Receiver<StopReason> {
+        self.stopped.subscribe()
+    }
+
+    /// Call `callback` with the `StopReason` once the consumer stopped.
+    ///
+    /// The callback is called on the consumer thread or immediately
+    /// if the consumer already stopped.
+    pub fn on_stop<F>(&self, callback: F)
+    where
+        F: FnOnce(&StopReason) + Send + 'static,
+    {
+        self.stopped.on_stop(callback)
+    }
+
+    /// Returns why the consumer stopped or `None` if it is still running.
+    pub fn stop_reason(&self) -> Option<StopReason> {
+        self.stopped.reason()
+    }
+}
+
+/// The reason why a `Consumer` stopped
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StopReason {
+    /// A stop was requested via `stop` or `shutdown`
+    /// or because the consumer was dropped
+    Requested,
+    /// Nakadi does not know the subscription
+    SubscriptionNotFound(String),
+    /// Access to the subscription was denied
+    Forbidden(String),
+    /// The consumer gave up connecting to the stream for
+    /// another reason. Contains the last connect error.
+    ConnectFailed(String),
+    /// The `HandlerFactory` could not create a handler
+    HandlerFactory(CreateHandlerError),
+    /// Committing cursors failed in a way reconnecting
+    /// to the stream would not fix
+    CommitFailed(CommitError),
+    /// The thread with the given name panicked
+    Panicked { thread: String },
+}
+
+impl StopReason {
+    fn from_connect_error(err: ConnectError) -> StopReason {
+        match err {
+            ConnectError::SubscriptionNotFound(_, _) => {
+                StopReason::SubscriptionNotFound(err.to_string())
+            }
+            ConnectError::Forbidden(_, _) => StopReason::Forbidden(err.to_string()),
+            err => StopReason::ConnectFailed(err.to_string()),
+        }
+    }
+
+    /// The reason a component thread stopped the consumer for, if any.
+    pub(crate) fn from_joined(
+        joined: Option<Result<Option<StopReason>, String>>,
+    ) -> Option<StopReason> {
+        match joined {
+            Some(Ok(reason)) => reason,
+            Some(Err(thread)) => Some(StopReason::Panicked { thread }),
+            None => None,
+        }
+    }
+}
+
+type StopCallback = Box<dyn FnOnce(&StopReason) + Send>;
+
+/// Keeps the `StopReason` once the consumer stopped and
+/// passes it to everyone interested.
+#[derive(Clone, Default)]
+struct StopNotifier {
+    state: Arc<Mutex<StopState>>,
+}
+
+#[derive(Default)]
+struct StopState {
+    reason: Option<StopReason>,
+    callbacks: Vec<StopCallback>,
+}
+
+impl StopNotifier {
+    fn subscribe(&self) -> mpsc::Receiver<StopReason> {
+        let (sender, receiver) = mpsc::channel();
+        self.on_stop(move |reason| {
+            let _ = sender.send(reason.clone());
+        });
+        receiver
+    }
+
+    fn on_stop<F>(&self, callback: F)
+    where
+        F: FnOnce(&StopReason) + Send + 'static,
+    {
+        let reason = {
+            let mut state = self.state.lock().unwrap();
+            if state.reason.is_none() {
+                state.callbacks.push(Box::new(callback));
+                return;
+            }
+            state.reason.clone()
+        };
+        // Called without holding the lock so that the
+        // callback may use the `StopNotifier` itself.
+        if let Some(reason) = reason {
+            callback(&reason);
+        }
+    }
+
+    /// Only the first reason is kept
+    fn notify(&self, reason: StopReason) {
+        let callbacks = {
+            let mut state = self.state.lock().unwrap();
+            if state.reason.is_some() {
+                return;
+            }
+            state.reason = Some(reason.clone());
+            ::std::mem::take(&mut state.callbacks)
+        };
+        for callback in callbacks {
+            callback(&reason);
+        }
+    }
+
+    fn reason(&self) -> Option<StopReason> {
+        self.state.lock().unwrap().reason.clone()
+    }
+}
+
+/// Notifies with `StopReason::Panicked` when dropped while panicking
+struct NotifyOnPanic(StopNotifier);
+
+impl Drop for NotifyOnPanic {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            let thread = thread::current().name().unwrap_or("<unnamed>").to_string();
+            self.0.notify(StopReason::Panicked { thread });
+        }
+    }
+}
+
+/// The outcome of a graceful shutdown
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ShutdownReport {
+    /// Batches processed after the shutdown started
+    pub batches_drained: usize,
+    /// Batches already received which were not processed
+    /// because the timeout expired
+    pub batches_dropped: usize,
+    /// Cursors committed after the shutdown started
+    pub cursors_committed: usize,
+    /// True if the timeout expired before the consumer stopped
+    pub timed_out: bool,
+}
+
+/// Collects the numbers for a `ShutdownReport` from the
+/// components of the current stream.
+///
+/// Nothing is counted before the shutdown started.
+#[derive(Clone, Default)]
+pub(crate) struct ShutdownTracker {
+    started: Arc<AtomicBool>,
+    batches_drained: Arc<AtomicUsize>,
+    batches_dropped: Arc<AtomicUsize>,
+    cursors_committed: Arc<AtomicUsize>,
+}
+
+impl ShutdownTracker {
+    pub fn start(&self) {
+        self.started.store(true, Ordering::Relaxed);
+    }
+
+    pub fn batch_processed(&self) {
+        self.count(&self.batches_drained, 1);
+    }
+
+    pub fn batches_dropped(&self, n: usize) {
+        self.count(&self.batches_dropped, n);
+    }
+
+    pub fn cursors_committed(&self, n: usize) {
+        self.count(&self.cursors_committed, n);
+    }
+
+    pub fn report(&self, timed_out: bool) -> ShutdownReport {
+        ShutdownReport {
+            batches_drained: self.batches_drained.load(Ordering::Relaxed),
+            batches_dropped: self.batches_dropped.load(Ordering::Relaxed),
+            cursors_committed: self.cursors_committed.load(Ordering::Relaxed),
+            timed_out,
+        }
+    }
+
+    fn count(&self, counter: &AtomicUsize, n: usize) {
+        if self.started.load(Ordering::Relaxed) {
+            counter.fetch_add(n, Ordering::Relaxed);
+        }
+    }
 }
 
 struct ConsumerLoopSettings<C, A, HF, M> {
@@ -95,22 +362,34 @@ struct ConsumerLoopSettings<C, A, HF, M> {
     lifecycle: AutoCancellationToken,
     metrics_collector: M,
     min_idle_worker_lifetime: Option<Duration>,
+    handler_retry_policy: HandlerRetryPolicy,
+    reconnect_policy: ReconnectPolicy,
+    dead_letter_sink: Option<Arc<dyn DeadLetterSink + Send + Sync>>,
+    lifecycle_listener: Option<Arc<dyn LifecycleListener + Send + Sync>>,
+    shutdown: ShutdownTracker,
+    stopped: StopNotifier,
 }
 
-fn start_consumer_loop<C, A, HF, M>(consumer_loop_settings: ConsumerLoopSettings<C, A, HF, M>)
+fn start_consumer_loop<C, A, HF, M>(
+    consumer_loop_settings: ConsumerLoopSettings<C, A, HF, M>,
+) -> ThreadHandle
 where
     C: StreamingClient + Clone + Send + 'static,
     A: ApiClient + Clone + Send + 'static,
     HF: HandlerFactory + Send + Sync + 'static,
     M: MetricsCollector + Clone + Send + Sync + 'static,
 {
-    let builder = thread::Builder::new().name("nakadion-consumer".into());
-    builder
-        .spawn(move || consumer_loop(consumer_loop_settings))
-        .unwrap();
+    ThreadHandle::spawn("nakadion-consumer".into(), move || {
+        let stopped = consumer_loop_settings.stopped.clone();
+        let _notify_on_panic = NotifyOnPanic(stopped.clone());
+        let reason = consumer_loop(consumer_loop_settings);
+        stopped.notify(reason);
+    })
 }
 
-fn consumer_loop<C, A, HF, M>(consumer_loop_settings: ConsumerLoopSettings<C, A, HF, M>)
+fn consumer_loop<C, A, HF, M>(
+    consumer_loop_settings: ConsumerLoopSettings<C, A, HF, M>,
+) -> StopReason
 where
     C: StreamingClient + Clone + Send + 'static,
     A: ApiClient + Clone + Send + 'static,
@@ -126,17 +405,31 @@ where
         commit_strategy,
         metrics_collector,
         min_idle_worker_lifetime,
+        handler_retry_policy,
+        reconnect_policy,
+        dead_letter_sink,
+        lifecycle_listener,
+        shutdown,
+        ..
     } = consumer_loop_settings;
 
     let handler_factory = Arc::new(handler_factory);
 
-    loop {
+    let reason = loop {
         if lifecycle.cancellation_requested() {
             info!(
                 "[Consumer, subscription={}] Abort requested",
                 subscription_id
             );
-            break;
+            break StopReason::Requested;
+        }
+
+        if lifecycle.drain_requested() {
+            info!(
+                "[Consumer, subscription={}] Shutdown requested",
+                subscription_id
+            );
+            break StopReason::Requested;
         }
 
         info!(
@@ -147,7 +440,7 @@ where
         let (stream_id, line_iterator) = match connect(
             &streaming_client,
             &subscription_id,
-            Duration::from_secs(300),
+            &reconnect_policy,
             &lifecycle,
         ) {
             Ok(v) => {
@@ -155,19 +448,14 @@ where
                 v
             }
             Err(err) => {
-                if err.is_permanent() {
-                    error!(
-                        "[Consumer, subscription={}] Permanent connection error: {}",
-                        subscription_id, err
-                    );
-                    break;
-                } else {
-                    warn!(
-                        "[Consumer, subscription={}] Temporary connection error: {}",
-                        subscription_id, err
-                    );
-                    continue;
+                if lifecycle.cancellation_requested() || lifecycle.drain_requested() {
+                    break StopReason::Requested;
                 }
+                error!(
+                    "[Consumer, subscription={}] Giving up to connect: {}",
+                    subscription_id, err
+                );
+                break StopReason::from_connect_error(err);
             }
         };
 
@@ -177,12 +465,17 @@ where
         );
         let connected_since = Instant::now();
 
+        if let Some(ref lifecycle_listener) = lifecycle_listener {
+            lifecycle_listener.on_stream_connected(&stream_id);
+        }
+
         let committer = Committer::start(
             api_client.clone(),
             commit_strategy,
             subscription_id.clone(),
             stream_id.clone(),
             metrics_collector.clone(),
+            shutdown.clone(),
         );
 
         let dispatcher = Dispatcher::start(
@@ -190,9 +483,13 @@ where
             committer.clone(),
             metrics_collector.clone(),
             min_idle_worker_lifetime,
+            handler_retry_policy,
+            dead_letter_sink.clone(),
+            lifecycle_listener.clone(),
+            shutdown.clone(),
         );
 
-        consume(
+        let stop_reason = consume(
             line_iterator,
             subscription_id.clone(),
             stream_id.clone(),
@@ -200,6 +497,7 @@ where
             committer,
             &lifecycle,
             &metrics_collector,
+            lifecycle_listener.as_ref(),
         );
 
         info!(
@@ -207,15 +505,31 @@ where
             subscription_id, stream_id
         );
 
+        if let Some(ref lifecycle_listener) = lifecycle_listener {
+            lifecycle_listener.on_stream_closed(&stream_id);
+        }
+
         metrics_collector.consumer_connection_lifetime(connected_since);
-    }
+
+        if let Some(stop_reason) = stop_reason {
+            error!(
+                "[Consumer, subscription={}, stream={}] A component failed \
+                 permanently: {:?}",
+                subscription_id, stream_id, stop_reason
+            );
+            break stop_reason;
+        }
+    };
 
     info!(
-        "[Consumer, subscription={}] Nakadi consumer stopped.  Exiting",
-        subscription_id
+        "[Consumer, subscription={}] Nakadi consumer stopped({:?}).  Exiting",
+        subscription_id, reason
     );
+
+    reason
 }
 
+#[allow(clippy::too_many_arguments)]
 fn consume<I, M>(
     line_iterator: I,
     subscription_id: SubscriptionId,
@@ -224,16 +538,43 @@ fn consume<I, M>(
     committer: Committer,
     lifecycle: &AutoCancellationToken,
     metrics_collector: &M,
-) where
-    I: Iterator<Item = LineResult>,
+    lifecycle_listener: Option<&Arc<dyn LifecycleListener + Send + Sync>>,
+) -> Option<StopReason>
+where
+    I: Iterator<Item = LineResult> + Send + 'static,
     M: MetricsCollector,
 {
-    for line_result in line_iterator {
+    let lines = match read_lines(line_iterator, &stream_id) {
+        Ok(lines) => Some(lines),
+        Err(err) => {
+            error!(
+                "[Consumer, subscription={}, stream={}] {}",
+                subscription_id, stream_id, err
+            );
+            None
+        }
+    };
+
+    // Stays set if the stream ended or the connection broke
+    let mut stream_ended = lines.is_some();
+
+    while let Some(ref lines) = lines {
         if lifecycle.cancellation_requested() {
             info!(
                 "[Consumer, subscription={}, stream={}] Abort requested",
                 subscription_id, stream_id
             );
+            stream_ended = false;
+            break;
+        }
+
+        if lifecycle.drain_requested() {
+            info!(
+                "[Consumer, subscription={}, stream={}] Shutdown requested. \
+                 Not reading any more lines.",
+                subscription_id, stream_id
+            );
+            stream_ended = false;
             break;
         }
 
@@ -243,6 +584,7 @@ fn consume<I, M>(
                 subscription_id, stream_id
             );
             metrics_collector.other_dispatcher_gone();
+            stream_ended = false;
             break;
         }
 
@@ -252,9 +594,17 @@ fn consume<I, M>(
                 subscription_id, stream_id
             );
             metrics_collector.other_committer_gone();
+            stream_ended = false;
             break;
         }
 
+        let line_result = match lines.recv_timeout(Duration::from_millis(100)) {
+            Ok(line_result) => line_result,
+            Err(mpsc::RecvTimeoutError::Timeout) => continue,
+            // The stream ended
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        };
+
         match line_result {
             Ok(raw_line) => {
                 if let Err(err) = process_batch_line(
@@ -263,12 +613,14 @@ fn consume<I, M>(
                     &stream_id,
                     raw_line,
                     metrics_collector,
+                    lifecycle_listener,
                 ) {
                     error!(
                         "[Consumer, subscription={}, stream={}] Could not process batch line: \
                          {}",
                         subscription_id, stream_id, err
                     );
+                    stream_ended = false;
                     break;
                 }
             }
@@ -282,6 +634,8 @@ fn consume<I, M>(
         }
     }
 
+    let drain = lifecycle.drain_requested() && !lifecycle.cancellation_requested();
+
     if dispatcher.is_running() {
         info!(
             "[Consumer, subscription={}, stream={}] Stream consumption ended or aborted. \
@@ -289,17 +643,33 @@ fn consume<I, M>(
             subscription_id, stream_id
         );
 
-        info!(
-            "[Consumer, subscription={}, stream={}] Stopping dispatcher.",
-            subscription_id, stream_id
-        );
-
-        dispatcher.stop();
+        if drain {
+            info!(
+                "[Consumer, subscription={}, stream={}] Draining dispatcher.",
+                subscription_id, stream_id
+            );
+            dispatcher.drain();
+        } else if stream_ended {
+            info!(
+                "[Consumer, subscription={}, stream={}] Stream ended. Stopping dispatcher.",
+                subscription_id, stream_id
+            );
+            dispatcher.end_of_stream();
+        } else {
+            info!(
+                "[Consumer, subscription={}, stream={}] Stopping dispatcher.",
+                subscription_id, stream_id
+            );
+            dispatcher.stop();
+        }
 
-        while dispatcher.is_running() {
-            thread::sleep(Duration::from_millis(10));
+        while !dispatcher.wait_timeout(Duration::from_millis(100)) {
+            if lifecycle.cancellation_requested() {
+                dispatcher.stop();
+            }
         }
     }
+    let dispatcher_stop_reason = dispatcher.join();
 
     info!(
         "[Consumer, subscription={}, stream={}] Dispatcher stopped.",
@@ -307,22 +677,60 @@ fn consume<I, M>(
     );
 
     if committer.is_running() {
-        info!(
-            "[Consumer, subscription={}, stream={}] Stopping committer",
-            subscription_id, stream_id
-        );
-
-        committer.stop();
+        if drain {
+            info!(
+                "[Consumer, subscription={}, stream={}] Draining committer",
+                subscription_id, stream_id
+            );
+            committer.drain();
+        } else {
+            info!(
+                "[Consumer, subscription={}, stream={}] Stopping committer",
+                subscription_id, stream_id
+            );
+            committer.stop();
+        }
 
-        while committer.is_running() {
-            thread::sleep(Duration::from_millis(10));
+        while !committer.wait_timeout(Duration::from_millis(100)) {
+            if lifecycle.cancellation_requested() {
+                committer.stop();
+            }
         }
     }
+    let committer_stop_reason = committer.join();
 
     info!(
         "[Consumer, subscription={}, stream={}] Committer stopped.",
         subscription_id, stream_id
     );
+
+    dispatcher_stop_reason.or(committer_stop_reason)
+}
+
+/// Reads the lines of a stream on a separate thread.
+///
+/// This way the consumer notices a stop request without waiting for
+/// the next line. Once the receiver is dropped the thread stops after
+/// reading the next line and closes the connection.
+fn read_lines<I>(
+    line_iterator: I,
+    stream_id: &StreamId,
+) -> Result<mpsc::Receiver<LineResult>, Error>
+where
+    I: Iterator<Item = LineResult> + Send + 'static,
+{
+    let (sender, receiver) = mpsc::sync_channel(0);
+    thread::Builder::new()
+        .name(format!("nakadion-lines-{}", stream_id))
+        .spawn(move || {
+            for line_result in line_iterator {
+                if sender.send(line_result).is_err() {
+                    break;
+                }
+            }
+        })
+        .context("Could not start the thread reading lines")?;
+    Ok(receiver)
 }
 
 fn process_batch_line<M>(
@@ -331,6 +739,7 @@ fn process_batch_line<M>(
     stream_id: &StreamId,
     raw_line: RawLine,
     metrics_collector: &M,
+    lifecycle_listener: Option<&Arc<dyn LifecycleListener + Send + Sync>>,
 ) -> Result<(), Error>
 where
     M: MetricsCollector,
@@ -348,6 +757,9 @@ where
                     "[Consumer, subscription={}, stream={}] Received info: {}",
                     subscription_id, stream_id, info
                 );
+                if let Some(lifecycle_listener) = lifecycle_listener {
+                    lifecycle_listener.on_info_line(stream_id, info);
+                }
             }
             Err(err) => warn!(
                 "[Consumer, subscription={}, stream={}] Received info line \
@@ -362,7 +774,10 @@ where
         metrics_collector.consumer_keep_alive_line_received(num_bytes);
         Ok(())
     } else {
-        metrics_collector.consumer_batch_received(raw_line.received_at);
+        metrics_collector.consumer_batch_received(
+            &MetricsContext::for_batch(subscription_id, &batch_line),
+            raw_line.received_at,
+        );
         metrics_collector.consumer_batch_line_received(num_bytes);
         dispatcher
             .dispatch(Batch {
@@ -380,13 +795,15 @@ where
     }
 }
 
+/// Tries to connect until successful or until the `ReconnectPolicy`
+/// gives up.
 fn connect<C: StreamingClient>(
     client: &C,
     subscription_id: &SubscriptionId,
-    max_dur: Duration,
+    reconnect_policy: &ReconnectPolicy,
     lifecycle: &AutoCancellationToken,
 ) -> Result<(StreamId, C::LineIterator), ConnectError> {
-    let deadline = Instant::now() + max_dur;
+    let started = Instant::now();
     let mut attempt = 0;
     loop {
         attempt += 1;
@@ -396,13 +813,17 @@ fn connect<C: StreamingClient>(
                 return Ok(it);
             }
             Err(err) => {
-                let sleep_dur_ms = *CONNECT_RETRY_BACKOFF_MS.get(attempt).unwrap_or(&30_000);
-                if Instant::now() >= deadline {
+                if reconnect_policy.gives_up_on(&err) {
+                    return Err(err);
+                } else if reconnect_policy.deadline_exceeded(started) {
                     return Err(ConnectError::Other(
-                        format!("Failed to connect to Nakadi after {} attempts.", attempt),
+                        format!(
+                            "Failed to connect to Nakadi after {} attempts. Last error: {}",
+                            attempt, err
+                        ),
                         flow_id,
                     ));
-                } else if lifecycle.cancellation_requested() {
+                } else if lifecycle.cancellation_requested() || lifecycle.drain_requested() {
                     return Err(ConnectError::Other(
                         format!(
                             "Failed to connect to Nakadi after {} attempts. Abort requested",
@@ -411,13 +832,853 @@ fn connect<C: StreamingClient>(
                         flow_id,
                     ));
                 } else {
+                    let delay = reconnect_policy.delay_after(attempt, &err);
                     warn!(
                         "Failed to connect(attempt {}) to Nakadi(retry in {}ms): {}",
-                        attempt, sleep_dur_ms, err
+                        attempt,
+                        delay.as_millis(),
+                        err
                     );
-                    thread::sleep(Duration::from_millis(sleep_dur_ms));
+                    thread::sleep(delay);
                 }
             }
         }
     }
 }
+
+#[cfg(feature = "test-support")]
+#[test]
+fn shutdown_processes_and_commits_received_batches() {
+    use serde_json::json;
+
+    use crate::nakadi::handler::{
+        BatchHandler, CreateHandlerError, HandlerFactory, ProcessingStatus, SubscriptionCursor,
+    };
+    use crate::nakadi::metrics::DevNullMetricsCollector;
+    use crate::test_support::{RecordingApiClient, ScriptedStream, ScriptedStreamingClient};
+
+    struct SlowHandler(Arc<AtomicUsize>);
+
+    impl BatchHandler for SlowHandler {
+        fn handle(&mut self, _cursor: &SubscriptionCursor, _events: &[u8]) -> ProcessingStatus {
+            thread::sleep(Duration::from_millis(100));
+            self.0.fetch_add(1, Ordering::SeqCst);
+            ProcessingStatus::Processed(None)
+        }
+    }
+
+    struct Factory(Arc<AtomicUsize>);
+
+    impl HandlerFactory for Factory {
+        type Handler = SlowHandler;
+
+        fn create_handler(
+            &self,
+            _partition: &PartitionId,
+        ) -> Result<SlowHandler, CreateHandlerError> {
+            Ok(SlowHandler(self.0.clone()))
+        }
+    }
+
+    let cursor = |offset: usize| SubscriptionCursor {
+        partition: PartitionId::new("0"),
+        offset: offset.to_string(),
+        event_type: "test_event".to_string(),
+    };
+
+    let stream = (0..5).fold(ScriptedStream::new("stream-1"), |stream, offset| {
+        stream.batch(&cursor(offset), &[json!({ "offset": offset })])
+    });
+    let streaming_client = ScriptedStreamingClient::new();
+    streaming_client.push_stream(stream.wait(Duration::from_millis(300)));
+    let api_client = RecordingApiClient::new();
+    let handled = Arc::new(AtomicUsize::new(0));
+
+    let consumer = Consumer::start(
+        streaming_client,
+        api_client.clone(),
+        SubscriptionId::new("subscription"),
+        Factory(handled.clone()),
+        DevNullMetricsCollector,
+        ConsumerSettings {
+            commit_strategy: CommitStrategy::Latest,
+            ..ConsumerSettings::default()
+        },
+    );
+
+    while handled.load(Ordering::SeqCst) == 0 {
+        thread::sleep(Duration::from_millis(5));
+    }
+    let report = consumer.shutdown(Duration::from_secs(5));
+
+    assert!(!report.timed_out);
+    assert_eq!(report.batches_dropped, 0);
+    assert_eq!(report.cursors_committed, 1);
+    assert_eq!(handled.load(Ordering::SeqCst), 5);
+    assert!(report.batches_drained >= 3);
+    let last_committed = api_client
+        .commit_calls()
+        .iter()
+        .flat_map(|call| call.subscription_cursors())
+        .map(|c| c.offset)
+        .next_back();
+    assert_eq!(last_committed.as_deref(), Some("4"));
+}
+
+#[cfg(feature = "test-support")]
+#[test]
+fn shutdown_does_not_wait_for_the_next_line() {
+    use serde_json::json;
+
+    use crate::nakadi::handler::{
+        BatchHandler, CreateHandlerError, HandlerFactory, ProcessingStatus, SubscriptionCursor,
+    };
+    use crate::nakadi::metrics::DevNullMetricsCollector;
+    use crate::test_support::{RecordingApiClient, ScriptedStream, ScriptedStreamingClient};
+
+    struct Handler(Arc<AtomicUsize>);
+
+    impl BatchHandler for Handler {
+        fn handle(&mut self, _cursor: &SubscriptionCursor, _events: &[u8]) -> ProcessingStatus {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            ProcessingStatus::Processed(None)
+        }
+    }
+
+    struct Factory(Arc<AtomicUsize>);
+
+    impl HandlerFactory for Factory {
+        type Handler = Handler;
+
+        fn create_handler(&self, _partition: &PartitionId) -> Result<Handler, CreateHandlerError> {
+            Ok(Handler(self.0.clone()))
+        }
+    }
+
+    let cursor = SubscriptionCursor {
+        partition: PartitionId::new("0"),
+        offset: "0".to_string(),
+        event_type: "test_event".to_string(),
+    };
+    // Like a stream without events until the batch flush timeout
+    let stream = ScriptedStream::new("stream-1")
+        .batch(&cursor, &[json!({ "offset": 0 })])
+        .wait(Duration::from_secs(30));
+    let streaming_client = ScriptedStreamingClient::new();
+    streaming_client.push_stream(stream);
+    let api_client = RecordingApiClient::new();
+    let handled = Arc::new(AtomicUsize::new(0));
+
+    let consumer = Consumer::start(
+        streaming_client,
+        api_client.clone(),
+        SubscriptionId::new("subscription"),
+        Factory(handled.clone()),
+        DevNullMetricsCollector,
+        ConsumerSettings::default(),
+    );
+
+    while handled.load(Ordering::SeqCst) == 0 {
+        thread::sleep(Duration::from_millis(5));
+    }
+    let started = Instant::now();
+    let report = consumer.shutdown(Duration::from_secs(10));
+
+    assert!(!report.timed_out);
+    assert!(started.elapsed() < Duration::from_secs(5));
+    assert_eq!(report.batches_dropped, 0);
+    assert_eq!(api_client.commit_calls().len(), 1);
+}
+
+#[cfg(feature = "test-support")]
+#[test]
+fn a_timed_out_shutdown_reports_the_dropped_batches() {
+    use serde_json::json;
+
+    use crate::nakadi::handler::{
+        BatchHandler, CreateHandlerError, HandlerFactory, ProcessingStatus, SubscriptionCursor,
+    };
+    use crate::nakadi::metrics::DevNullMetricsCollector;
+    use crate::test_support::{RecordingApiClient, ScriptedStream, ScriptedStreamingClient};
+
+    struct SlowHandler(Arc<AtomicUsize>);
+
+    impl BatchHandler for SlowHandler {
+        fn handle(&mut self, _cursor: &SubscriptionCursor, _events: &[u8]) -> ProcessingStatus {
+            thread::sleep(Duration::from_millis(200));
+            self.0.fetch_add(1, Ordering::SeqCst);
+            ProcessingStatus::Processed(None)
+        }
+    }
+
+    struct Factory(Arc<AtomicUsize>);
+
+    impl HandlerFactory for Factory {
+        type Handler = SlowHandler;
+
+        fn create_handler(
+            &self,
+            _partition: &PartitionId,
+        ) -> Result<SlowHandler, CreateHandlerError> {
+            Ok(SlowHandler(self.0.clone()))
+        }
+    }
+
+    let cursor = |offset: usize| SubscriptionCursor {
+        partition: PartitionId::new("0"),
+        offset: offset.to_string(),
+        event_type: "test_event".to_string(),
+    };
+
+    let stream = (0..5).fold(ScriptedStream::new("stream-1"), |stream, offset| {
+        stream.batch(&cursor(offset), &[json!({ "offset": offset })])
+    });
+    // Keep alives let the consumer notice the shutdown
+    let stream = (0..40).fold(stream, |stream, _| {
+        stream
+            .wait(Duration::from_millis(50))
+            .keep_alive(&cursor(4))
+    });
+    let streaming_client = ScriptedStreamingClient::new();
+    streaming_client.push_stream(stream);
+    let handled = Arc::new(AtomicUsize::new(0));
+
+    let consumer = Consumer::start(
+        streaming_client,
+        RecordingApiClient::new(),
+        SubscriptionId::new("subscription"),
+        Factory(handled.clone()),
+        DevNullMetricsCollector,
+        ConsumerSettings {
+            commit_strategy: CommitStrategy::Latest,
+            ..ConsumerSettings::default()
+        },
+    );
+
+    while handled.load(Ordering::SeqCst) == 0 {
+        thread::sleep(Duration::from_millis(5));
+    }
+    let report = consumer.shutdown(Duration::from_millis(50));
+
+    assert!(report.timed_out);
+    assert!(consumer.wait_timeout(Duration::from_millis(0)).is_some());
+    let handled = handled.load(Ordering::SeqCst);
+    assert!(report.batches_dropped > 0);
+    assert_eq!(handled + report.batches_dropped, 5);
+    assert!(report.batches_drained < handled);
+}
+
+#[cfg(feature = "test-support")]
+#[test]
+fn notifies_why_the_consumer_stopped() {
+    use crate::nakadi::handler::{
+        BatchHandler, CreateHandlerError, HandlerFactory, ProcessingStatus, SubscriptionCursor,
+    };
+    use crate::nakadi::metrics::DevNullMetricsCollector;
+    use crate::test_support::{RecordingApiClient, ScriptedStreamingClient};
+
+    struct Handler;
+
+    impl BatchHandler for Handler {
+        fn handle(&mut self, _cursor: &SubscriptionCursor, _events: &[u8]) -> ProcessingStatus {
+            ProcessingStatus::Processed(None)
+        }
+    }
+
+    struct Factory;
+
+    impl HandlerFactory for Factory {
+        type Handler = Handler;
+
+        fn create_handler(&self, _partition: &PartitionId) -> Result<Handler, CreateHandlerError> {
+            Ok(Handler)
+        }
+    }
+
+    let streaming_client = ScriptedStreamingClient::new();
+    streaming_client.push_connect_error(ConnectError::SubscriptionNotFound(
+        "404".into(),
+        FlowId::default(),
+    ));
+
+    let consumer = Consumer::start(
+        streaming_client,
+        RecordingApiClient::new(),
+        SubscriptionId::new("subscription"),
+        Factory,
+        DevNullMetricsCollector,
+        ConsumerSettings::default(),
+    );
+    let notification = consumer.stop_notification();
+    let (callback_tx, callback_rx) = ::std::sync::mpsc::channel();
+    consumer.on_stop(move |reason| callback_tx.send(reason.clone()).unwrap());
+
+    let reason = consumer.join();
+
+    match reason {
+        StopReason::SubscriptionNotFound(ref message) => assert!(message.contains("404")),
+        ref other => panic!("unexpected stop reason {:?}", other),
+    }
+    assert!(!consumer.running());
+    assert_eq!(consumer.stop_reason(), Some(reason.clone()));
+    assert_eq!(callback_rx.recv().unwrap(), reason);
+    // A callback registered after stopping may query the stop reason
+    let (late_tx, late_rx) = ::std::sync::mpsc::channel();
+    let stopped = consumer.stopped.clone();
+    consumer.on_stop(move |_| late_tx.send(stopped.reason()).unwrap());
+    assert_eq!(late_rx.recv().unwrap(), Some(reason.clone()));
+    assert_eq!(notification.recv().unwrap(), reason);
+    assert_eq!(consumer.stop_notification().recv().unwrap(), reason);
+    assert_eq!(
+        consumer.wait_timeout(Duration::from_millis(1)),
+        Some(reason)
+    );
+}
+
+#[cfg(feature = "test-support")]
+#[test]
+fn stops_when_an_idle_worker_fails() {
+    use serde_json::json;
+
+    use crate::nakadi::handler::{
+        BatchHandler, CreateHandlerError, HandlerFactory, HandlerStopReason, ProcessingStatus,
+        SubscriptionCursor,
+    };
+    use crate::nakadi::metrics::DevNullMetricsCollector;
+    use crate::test_support::{RecordingApiClient, ScriptedStream, ScriptedStreamingClient};
+
+    struct Handler;
+
+    impl BatchHandler for Handler {
+        fn handle(&mut self, _cursor: &SubscriptionCursor, _events: &[u8]) -> ProcessingStatus {
+            ProcessingStatus::Processed(None)
+        }
+
+        fn on_stop(&mut self, reason: &HandlerStopReason, _cursor: Option<&SubscriptionCursor>) {
+            if *reason == HandlerStopReason::IdleTimeout {
+                panic!("Failed to stop an idle handler");
+            }
+        }
+    }
+
+    struct Factory;
+
+    impl HandlerFactory for Factory {
+        type Handler = Handler;
+
+        fn create_handler(&self, _partition: &PartitionId) -> Result<Handler, CreateHandlerError> {
+            Ok(Handler)
+        }
+    }
+
+    let cursor = SubscriptionCursor {
+        partition: PartitionId::new("0"),
+        offset: "0".to_string(),
+        event_type: "test_event".to_string(),
+    };
+    // Idle workers are checked every 5 seconds
+    let stream = ScriptedStream::new("stream-1").batch(&cursor, &[json!({ "offset": 0 })]);
+    let stream = (0..200).fold(stream, |stream, _| {
+        stream.wait(Duration::from_millis(50)).keep_alive(&cursor)
+    });
+
+    let streaming_client = ScriptedStreamingClient::new();
+    streaming_client.push_stream(stream);
+
+    let consumer = Consumer::start(
+        streaming_client,
+        RecordingApiClient::new(),
+        SubscriptionId::new("subscription"),
+        Factory,
+        DevNullMetricsCollector,
+        ConsumerSettings {
+            min_idle_worker_lifetime: Some(Duration::from_millis(500)),
+            ..ConsumerSettings::default()
+        },
+    );
+
+    match consumer.join() {
+        StopReason::Panicked { ref thread } => assert_eq!(thread, "nakadion-worker-0"),
+        other => panic!("unexpected stop reason {:?}", other),
+    }
+}
+
+#[cfg(feature = "test-support")]
+#[test]
+fn reconnects_when_a_commit_is_throttled() {
+    use serde_json::json;
+
+    use crate::nakadi::handler::{
+        BatchHandler, CreateHandlerError, HandlerFactory, ProcessingStatus, SubscriptionCursor,
+    };
+    use crate::nakadi::metrics::DevNullMetricsCollector;
+    use crate::test_support::{RecordingApiClient, ScriptedStream, ScriptedStreamingClient};
+
+    struct Handler;
+
+    impl BatchHandler for Handler {
+        fn handle(&mut self, _cursor: &SubscriptionCursor, _events: &[u8]) -> ProcessingStatus {
+            ProcessingStatus::Processed(None)
+        }
+    }
+
+    struct Factory;
+
+    impl HandlerFactory for Factory {
+        type Handler = Handler;
+
+        fn create_handler(&self, _partition: &PartitionId) -> Result<Handler, CreateHandlerError> {
+            Ok(Handler)
+        }
+    }
+
+    let cursor = |offset: usize| SubscriptionCursor {
+        partition: PartitionId::new("0"),
+        offset: offset.to_string(),
+        event_type: "test_event".to_string(),
+    };
+    let stream = |id: &str, offset: usize| {
+        let stream = ScriptedStream::new(id).batch(&cursor(offset), &[json!({ "offset": offset })]);
+        (0..20).fold(stream, |stream, _| {
+            stream
+                .wait(Duration::from_millis(50))
+                .keep_alive(&cursor(offset))
+        })
+    };
+
+    let streaming_client = ScriptedStreamingClient::new();
+    streaming_client.push_stream(stream("stream-1", 0));
+    streaming_client.push_stream(stream("stream-2", 1));
+    let api_client = RecordingApiClient::new();
+    api_client.push_commit_result(Err(CommitError::Client(
+        "429 Too Many Requests: slow down".to_string(),
+        FlowId::default(),
+    )));
+
+    let consumer = Consumer::start(
+        streaming_client.clone(),
+        api_client.clone(),
+        SubscriptionId::new("subscription"),
+        Factory,
+        DevNullMetricsCollector,
+        ConsumerSettings::default(),
+    );
+
+    let committed = || {
+        api_client
+            .commit_calls()
+            .iter()
+            .flat_map(|call| call.subscription_cursors())
+            .map(|c| c.offset)
+            .collect::<Vec<_>>()
+    };
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !committed().contains(&"1".to_string()) {
+        assert!(Instant::now() < deadline, "stream-2 was not consumed");
+        thread::sleep(Duration::from_millis(5));
+    }
+
+    assert!(consumer.running());
+    assert_eq!(consumer.stop_reason(), None);
+    assert_eq!(streaming_client.connect_attempts().len(), 2);
+
+    consumer.stop();
+    assert_eq!(consumer.join(), StopReason::Requested);
+}
+
+#[cfg(feature = "test-support")]
+#[test]
+fn stops_when_the_handler_factory_fails() {
+    use serde_json::json;
+
+    use crate::nakadi::handler::{
+        BatchHandler, CreateHandlerError, HandlerFactory, ProcessingStatus, SubscriptionCursor,
+    };
+    use crate::nakadi::metrics::DevNullMetricsCollector;
+    use crate::test_support::{RecordingApiClient, ScriptedStream, ScriptedStreamingClient};
+
+    struct Handler;
+
+    impl BatchHandler for Handler {
+        fn handle(&mut self, _cursor: &SubscriptionCursor, _events: &[u8]) -> ProcessingStatus {
+            ProcessingStatus::Processed(None)
+        }
+    }
+
+    struct Factory;
+
+    impl HandlerFactory for Factory {
+        type Handler = Handler;
+
+        fn create_handler(&self, _partition: &PartitionId) -> Result<Handler, CreateHandlerError> {
+            Err(CreateHandlerError {
+                message: "no handler".into(),
+            })
+        }
+    }
+
+    let cursor = SubscriptionCursor {
+        partition: PartitionId::new("0"),
+        offset: "0".to_string(),
+        event_type: "test_event".to_string(),
+    };
+    let streaming_client = ScriptedStreamingClient::new();
+    streaming_client.push_stream(
+        ScriptedStream::new("stream-1")
+            .batch(&cursor, &[json!({ "offset": 0 })])
+            .wait(Duration::from_millis(200))
+            .keep_alive(&cursor)
+            .wait(Duration::from_secs(5)),
+    );
+
+    let consumer = Consumer::start(
+        streaming_client,
+        RecordingApiClient::new(),
+        SubscriptionId::new("subscription"),
+        Factory,
+        DevNullMetricsCollector,
+        ConsumerSettings::default(),
+    );
+
+    let reason = consumer.wait_timeout(Duration::from_secs(3));
+
+    assert_eq!(
+        reason,
+        Some(StopReason::HandlerFactory(CreateHandlerError {
+            message: "no handler".into(),
+        }))
+    );
+}
+
+#[cfg(feature = "test-support")]
+#[test]
+fn notifies_the_lifecycle_listener() {
+    use serde_json::json;
+
+    use crate::nakadi::handler::{
+        BatchHandler, CreateHandlerError, HandlerFactory, ProcessingStatus, SubscriptionCursor,
+    };
+    use crate::nakadi::metrics::DevNullMetricsCollector;
+    use crate::test_support::{RecordingApiClient, ScriptedStream, ScriptedStreamingClient};
+
+    struct Handler;
+
+    impl BatchHandler for Handler {
+        fn handle(&mut self, _cursor: &SubscriptionCursor, _events: &[u8]) -> ProcessingStatus {
+            ProcessingStatus::Processed(None)
+        }
+    }
+
+    struct Factory;
+
+    impl HandlerFactory for Factory {
+        type Handler = Handler;
+
+        fn create_handler(&self, _partition: &PartitionId) -> Result<Handler, CreateHandlerError> {
+            Ok(Handler)
+        }
+    }
+
+    #[derive(Default)]
+    struct Listener(Mutex<Vec<String>>);
+
+    impl Listener {
+        fn record(&self, event: String) {
+            self.0.lock().unwrap().push(event);
+        }
+    }
+
+    impl LifecycleListener for Listener {
+        fn on_stream_connected(&self, stream_id: &StreamId) {
+            self.record(format!("connected {}", stream_id));
+        }
+
+        fn on_stream_closed(&self, stream_id: &StreamId) {
+            self.record(format!("closed {}", stream_id));
+        }
+
+        fn on_partition_assigned(&self, _stream_id: &StreamId, partition: &PartitionId) {
+            self.record(format!("assigned {}", partition));
+        }
+
+        fn on_partition_revoked(&self, _stream_id: &StreamId, partition: &PartitionId) {
+            self.record(format!("revoked {}", partition));
+        }
+
+        fn on_info_line(&self, _stream_id: &StreamId, info: &str) {
+            self.record(format!("info {}", info));
+        }
+    }
+
+    let cursor = |partition: &str| SubscriptionCursor {
+        partition: PartitionId::new(partition),
+        offset: "0".to_string(),
+        event_type: "test_event".to_string(),
+    };
+
+    let streaming_client = ScriptedStreamingClient::new();
+    streaming_client.push_stream(
+        ScriptedStream::new("stream-1")
+            .batch(&cursor("0"), &[json!({ "a": 1 })])
+            .info(&cursor("0"), "debug")
+            .batch(&cursor("1"), &[json!({ "a": 2 })])
+            .wait(Duration::from_millis(100)),
+    );
+    streaming_client.push_connect_error(ConnectError::SubscriptionNotFound(
+        "404".into(),
+        FlowId::default(),
+    ));
+    let listener = Arc::new(Listener::default());
+
+    let consumer = Consumer::start(
+        streaming_client,
+        RecordingApiClient::new(),
+        SubscriptionId::new("subscription"),
+        Factory,
+        DevNullMetricsCollector,
+        ConsumerSettings {
+            lifecycle_listener: Some(listener.clone()),
+            ..ConsumerSettings::default()
+        },
+    );
+    consumer.join();
+
+    let events = listener.0.lock().unwrap().clone();
+    let position = |event: &str| {
+        events
+            .iter()
+            .position(|e| e == event)
+            .unwrap_or_else(|| panic!("missing event '{}' in {:?}", event, events))
+    };
+
+    assert_eq!(events.len(), 7, "{:?}", events);
+    assert_eq!(position("connected stream-1"), 0);
+    assert_eq!(position("closed stream-1"), 6);
+    assert!(position("assigned 0") < position("revoked 0"));
+    assert!(position("assigned 1") < position("revoked 1"));
+    position(r#"info {"debug":"debug"}"#);
+}
+
+#[cfg(feature = "test-support")]
+#[test]
+fn tells_handlers_why_they_stopped() {
+    use serde_json::json;
+
+    use crate::nakadi::handler::{
+        BatchHandler, CreateHandlerError, HandlerFactory, HandlerStopReason, ProcessingStatus,
+        SubscriptionCursor,
+    };
+    use crate::nakadi::metrics::DevNullMetricsCollector;
+    use crate::test_support::{RecordingApiClient, ScriptedStream, ScriptedStreamingClient};
+
+    type Stops = Arc<Mutex<Vec<(PartitionId, HandlerStopReason, Option<String>)>>>;
+
+    struct Handler(PartitionId, Stops);
+
+    impl BatchHandler for Handler {
+        fn handle(&mut self, _cursor: &SubscriptionCursor, _events: &[u8]) -> ProcessingStatus {
+            if self.0 == PartitionId::new("1") {
+                ProcessingStatus::failed("boom")
+            } else {
+                ProcessingStatus::Processed(None)
+            }
+        }
+
+        fn on_stop(
+            &mut self,
+            reason: &HandlerStopReason,
+            last_processed_cursor: Option<&SubscriptionCursor>,
+        ) {
+            self.1.lock().unwrap().push((
+                self.0.clone(),
+                reason.clone(),
+                last_processed_cursor.map(|c| c.offset.clone()),
+            ));
+        }
+    }
+
+    struct Factory(Stops);
+
+    impl HandlerFactory for Factory {
+        type Handler = Handler;
+
+        fn create_handler(&self, partition: &PartitionId) -> Result<Handler, CreateHandlerError> {
+            Ok(Handler(partition.clone(), self.0.clone()))
+        }
+    }
+
+    let cursor = |partition: &str, offset: &str| SubscriptionCursor {
+        partition: PartitionId::new(partition),
+        offset: offset.to_string(),
+        event_type: "test_event".to_string(),
+    };
+
+    let streaming_client = ScriptedStreamingClient::new();
+    streaming_client.push_stream(
+        ScriptedStream::new("stream-1")
+            .batch(&cursor("0", "0"), &[json!({ "a": 1 })])
+            .batch(&cursor("1", "0"), &[json!({ "a": 2 })])
+            .batch(&cursor("0", "1"), &[json!({ "a": 3 })])
+            .wait(Duration::from_millis(100)),
+    );
+    // Keep alives let the consumer notice that it was stopped
+    let stream = ScriptedStream::new("stream-2").batch(&cursor("2", "0"), &[json!({ "a": 4 })]);
+    streaming_client.push_stream((0..40).fold(stream, |stream, _| {
+        stream
+            .wait(Duration::from_millis(50))
+            .keep_alive(&cursor("2", "0"))
+    }));
+    let api_client = RecordingApiClient::new();
+    let stops = Stops::default();
+
+    let consumer = Consumer::start(
+        streaming_client,
+        api_client.clone(),
+        SubscriptionId::new("subscription"),
+        Factory(stops.clone()),
+        DevNullMetricsCollector,
+        ConsumerSettings::default(),
+    );
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !api_client
+        .commit_calls()
+        .iter()
+        .flat_map(|call| call.subscription_cursors())
+        .any(|c| c.partition == PartitionId::new("2"))
+    {
+        assert!(Instant::now() < deadline, "stream-2 was not consumed");
+        thread::sleep(Duration::from_millis(5));
+    }
+    consumer.stop();
+    consumer.join();
+
+    let mut stops = stops.lock().unwrap().clone();
+    stops.sort_by_key(|stop| stop.0.to_string());
+    assert_eq!(
+        stops,
+        vec![
+            (
+                PartitionId::new("0"),
+                HandlerStopReason::StreamEnded,
+                Some("1".to_string())
+            ),
+            (
+                PartitionId::new("1"),
+                HandlerStopReason::Failed {
+                    reason: "boom".to_string()
+                },
+                None
+            ),
+            (
+                PartitionId::new("2"),
+                HandlerStopReason::Cancelled,
+                Some("0".to_string())
+            ),
+        ]
+    );
+}
+
+#[cfg(feature = "test-support")]
+#[test]
+fn puts_failed_batches_into_the_dead_letter_sink_once_retries_are_exhausted() {
+    use serde_json::json;
+
+    use crate::nakadi::dead_letter::DeadLetterError;
+    use crate::nakadi::handler::{
+        BatchHandler, CreateHandlerError, HandlerFactory, ProcessingStatus, SubscriptionCursor,
+    };
+    use crate::nakadi::metrics::DevNullMetricsCollector;
+    use crate::test_support::{RecordingApiClient, ScriptedStream, ScriptedStreamingClient};
+
+    struct FailingHandler(Arc<AtomicUsize>);
+
+    impl BatchHandler for FailingHandler {
+        fn handle(&mut self, _cursor: &SubscriptionCursor, _events: &[u8]) -> ProcessingStatus {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            ProcessingStatus::failed("boom")
+        }
+    }
+
+    struct Factory(Arc<AtomicUsize>);
+
+    impl HandlerFactory for Factory {
+        type Handler = FailingHandler;
+
+        fn create_handler(
+            &self,
+            _partition: &PartitionId,
+        ) -> Result<FailingHandler, CreateHandlerError> {
+            Ok(FailingHandler(self.0.clone()))
+        }
+    }
+
+    #[derive(Default)]
+    struct Sink(Mutex<Vec<(String, String)>>);
+
+    impl DeadLetterSink for Sink {
+        fn put_batch(
+            &self,
+            cursor: &SubscriptionCursor,
+            _events: &[u8],
+            reason: &str,
+        ) -> Result<(), DeadLetterError> {
+            self.0
+                .lock()
+                .unwrap()
+                .push((cursor.offset.clone(), reason.to_string()));
+            Ok(())
+        }
+    }
+
+    let cursor = SubscriptionCursor {
+        partition: PartitionId::new("0"),
+        offset: "0".to_string(),
+        event_type: "test_event".to_string(),
+    };
+    let streaming_client = ScriptedStreamingClient::new();
+    streaming_client.push_stream(
+        ScriptedStream::new("stream-1")
+            .batch(&cursor, &[json!({ "offset": 0 })])
+            .wait(Duration::from_millis(300)),
+    );
+    streaming_client.push_connect_error(ConnectError::SubscriptionNotFound(
+        "404".into(),
+        FlowId::default(),
+    ));
+    let api_client = RecordingApiClient::new();
+    let attempts = Arc::new(AtomicUsize::new(0));
+    let sink = Arc::new(Sink::default());
+    let policy = HandlerRetryPolicy {
+        max_retries: Some(2),
+        max_retry_seconds: 30,
+        failed_retry_millis: 10,
+    };
+
+    let consumer = Consumer::start(
+        streaming_client,
+        api_client.clone(),
+        SubscriptionId::new("subscription"),
+        Factory(attempts.clone()),
+        DevNullMetricsCollector,
+        ConsumerSettings {
+            handler_retry_policy: policy,
+            dead_letter_sink: Some(sink.clone()),
+            ..ConsumerSettings::default()
+        },
+    );
+    consumer.join();
+
+    assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    assert_eq!(
+        *sink.0.lock().unwrap(),
+        vec![("0".to_string(), "boom".to_string())]
+    );
+    let committed: Vec<_> = api_client
+        .commit_calls()
+        .iter()
+        .flat_map(|call| call.subscription_cursors())
+        .map(|c| c.offset)
+        .collect();
+    assert_eq!(committed, vec!["0".to_string()]);
+}
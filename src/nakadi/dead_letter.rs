@@ -0,0 +1,563 @@
+//! Dead letter handling for batches that could not be processed
+//!
+//! When a `BatchHandler` fails on a batch or the `HandlerRetryPolicy`
+//! is exhausted, the stream is usually aborted. Since Nakadi will
+//! send the same batch again after reconnecting, a batch that can never be
+//! processed blocks the subscription forever.
+//!
+//! If a `DeadLetterSink` is configured, the worker puts the failed
+//! batch into the sink instead and commits its cursor afterwards, so that
+//! consumption can continue.
+//!
+//! Two sinks are provided:
+//!
+//! * `FileDeadLetterSink`: Appends dead letters as JSON lines to a file
+//! * `NakadiDeadLetterSink`: Publishes dead letters to a Nakadi event type
+//!
+//! Events of a `TypedBatchHandler` that could not be deserialized can
+//! be put into a sink individually by wrapping the handler
+//! in a `DeadLetterMalformedEvents`.
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "blocking")]
+use std::time::Duration;
+
+use chrono::offset::Utc;
+use chrono::DateTime;
+use failure::*;
+use serde_json;
+#[cfg(feature = "blocking")]
+use uuid::Uuid;
+
+#[cfg(feature = "blocking")]
+use crate::nakadi::events::OutgoingMetadata;
+use crate::nakadi::handler::{
+    EventDeserializationResult, HandlerStopReason, SubscriptionCursor, TypedBatchHandler,
+    TypedProcessingStatus,
+};
+use crate::nakadi::metrics::{MetricsCollector, MetricsContext};
+use crate::nakadi::model::SubscriptionId;
+#[cfg(feature = "blocking")]
+use crate::nakadi::publisher::{NakadiPublisher, PublishStatus};
+
+/// Receives batches that could not be processed.
+///
+/// The sink is shared by all workers and therefore
+/// may be called concurrently.
+pub trait DeadLetterSink {
+    /// Put a batch that could not be processed into the sink.
+    ///
+    /// `events` contains the raw bytes of the batch as received from
+    /// Nakadi which is usually a JSON array of events.
+    ///
+    /// If an error is returned the cursor of the batch will not be committed
+    /// and the stream will be aborted.
+    fn put_batch(
+        &self,
+        cursor: &SubscriptionCursor,
+        events: &[u8],
+        reason: &str,
+    ) -> Result<(), DeadLetterError>;
+
+    /// Put a single event that could not be processed into the sink.
+    ///
+    /// The default implementation puts a batch containing only
+    /// the given event into the sink.
+    fn put_event(
+        &self,
+        cursor: &SubscriptionCursor,
+        event: &serde_json::Value,
+        reason: &str,
+    ) -> Result<(), DeadLetterError> {
+        let events = serde_json::to_vec(&[event])
+            .map_err(|err| DeadLetterError::Serialization(err.to_string()))?;
+        self.put_batch(cursor, &events, reason)
+    }
+}
+
+impl fmt::Debug for dyn DeadLetterSink + Send + Sync {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "DeadLetterSink")
+    }
+}
+
+/// A batch that could not be processed as written by the
+/// provided sinks.
+///
+/// # Serialization(JSON)
+///
+/// ```javascript
+/// {
+///     "cursor": {
+///         "partition": "6",
+///         "offset": "543",
+///         "event_type": "order.ORDER_RECEIVED"
+///     },
+///     "reason": "Database unavailable",
+///     "events": [{"id": 1}, {"id": 2}],
+///     "dead_lettered_at": "2019-10-10T10:10:10.123Z"
+/// }
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeadLetter {
+    /// The cursor of the failed batch
+    pub cursor: SubscriptionCursor,
+    /// Why the batch could not be processed
+    pub reason: String,
+    /// The events of the batch. If the batch was not valid JSON
+    /// this is the lossy UTF-8 representation of the batch as a string.
+    pub events: serde_json::Value,
+    /// When the batch was put into the sink
+    pub dead_lettered_at: DateTime<Utc>,
+}
+
+impl DeadLetter {
+    /// Create a new `DeadLetter` from the raw bytes of a batch
+    pub fn new<T: Into<String>>(cursor: &SubscriptionCursor, events: &[u8], reason: T) -> Self {
+        let events = serde_json::from_slice(events).unwrap_or_else(|_| {
+            serde_json::Value::String(String::from_utf8_lossy(events).into_owned())
+        });
+
+        DeadLetter {
+            cursor: cursor.clone(),
+            reason: reason.into(),
+            events,
+            dead_lettered_at: Utc::now(),
+        }
+    }
+}
+
+/// Appends dead letters to a file.
+///
+/// Each `DeadLetter` is written as JSON on a single line.
+pub struct FileDeadLetterSink {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl FileDeadLetterSink {
+    /// Create a new `FileDeadLetterSink`.
+    ///
+    /// The file is created if it does not exist yet.
+    ///
+    /// # Errors
+    ///
+    /// The file could not be opened for appending.
+    pub fn new<P: Into<PathBuf>>(path: P) -> Result<FileDeadLetterSink, DeadLetterError> {
+        let path = path.into();
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|err| {
+                DeadLetterError::Io(format!("Could not open '{}': {}", path.display(), err))
+            })?;
+
+        Ok(FileDeadLetterSink {
+            path,
+            file: Mutex::new(file),
+        })
+    }
+}
+
+impl DeadLetterSink for FileDeadLetterSink {
+    fn put_batch(
+        &self,
+        cursor: &SubscriptionCursor,
+        events: &[u8],
+        reason: &str,
+    ) -> Result<(), DeadLetterError> {
+        let mut line = serde_json::to_vec(&DeadLetter::new(cursor, events, reason))
+            .map_err(|err| DeadLetterError::Serialization(err.to_string()))?;
+        line.push(b'\n');
+
+        let mut file = match self.file.lock() {
+            Ok(file) => file,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        file.write_all(&line)
+            .and_then(|_| file.flush())
+            .map_err(|err| {
+                DeadLetterError::Io(format!(
+                    "Could not write to '{}': {}",
+                    self.path.display(),
+                    err
+                ))
+            })
+    }
+}
+
+/// Publishes dead letters to an event type on Nakadi
+/// using a `NakadiPublisher`.
+///
+/// Each `DeadLetter` is published as a single event
+/// with an additional `metadata` field so that it can be
+/// published to an event type of category `business`.
+#[cfg(feature = "blocking")]
+pub struct NakadiDeadLetterSink {
+    publisher: NakadiPublisher,
+    event_type: String,
+    publish_budget: Duration,
+}
+
+#[cfg(feature = "blocking")]
+impl NakadiDeadLetterSink {
+    /// Create a new `NakadiDeadLetterSink` that publishes to `event_type`.
+    ///
+    /// Publishing is retried for at most 10 seconds.
+    pub fn new<T: Into<String>>(publisher: NakadiPublisher, event_type: T) -> NakadiDeadLetterSink {
+        NakadiDeadLetterSink {
+            publisher,
+            event_type: event_type.into(),
+            publish_budget: Duration::from_secs(10),
+        }
+    }
+
+    /// Sets the maximum duration publishing a dead letter is retried.
+    pub fn publish_budget(mut self, publish_budget: Duration) -> NakadiDeadLetterSink {
+        self.publish_budget = publish_budget;
+        self
+    }
+}
+
+#[cfg(feature = "blocking")]
+#[derive(Serialize)]
+struct DeadLetterEvent<'a> {
+    metadata: OutgoingMetadata,
+    #[serde(flatten)]
+    dead_letter: &'a DeadLetter,
+}
+
+#[cfg(feature = "blocking")]
+impl DeadLetterSink for NakadiDeadLetterSink {
+    fn put_batch(
+        &self,
+        cursor: &SubscriptionCursor,
+        events: &[u8],
+        reason: &str,
+    ) -> Result<(), DeadLetterError> {
+        let dead_letter = DeadLetter::new(cursor, events, reason);
+        let event = DeadLetterEvent {
+            metadata: OutgoingMetadata {
+                eid: Uuid::new_v4(),
+                event_type: None,
+                occurred_at: dead_letter.dead_lettered_at,
+                parent_eids: Vec::new(),
+                partition: None,
+            },
+            dead_letter: &dead_letter,
+        };
+
+        match self
+            .publisher
+            .publish_events(&self.event_type, &[event], None, self.publish_budget)
+        {
+            Ok(PublishStatus::AllEventsPublished) => Ok(()),
+            Ok(PublishStatus::NotAllEventsPublished(_)) => Err(DeadLetterError::Publish(format!(
+                "Dead letter was not accepted by event type '{}'",
+                self.event_type
+            ))),
+            Err(err) => Err(DeadLetterError::Publish(err.to_string())),
+        }
+    }
+}
+
+/// Wraps a `TypedBatchHandler` so that events which could not be
+/// deserialized are put into a `DeadLetterSink` instead of failing
+/// the whole batch.
+///
+/// All successfully deserialized events of the batch are passed to the
+/// wrapped handler. The number of skipped events is reported to
+/// the `MetricsCollector` if one was given. It is reported for the given
+/// subscription and the event type and partition of the batch.
+///
+/// If the wrapped handler requests a retry of such a batch, the malformed
+/// events are not put into the sink again.
+pub struct DeadLetterMalformedEvents<H> {
+    handler: H,
+    dead_letter_sink: Arc<dyn DeadLetterSink + Send + Sync>,
+    metrics_collector: Option<(SubscriptionId, Box<dyn MetricsCollector + Send>)>,
+    /// The cursor of the last batch whose malformed events
+    /// were put into the sink
+    dead_lettered_cursor: Option<SubscriptionCursor>,
+}
+
+impl<H> DeadLetterMalformedEvents<H>
+where
+    H: TypedBatchHandler,
+{
+    /// Wrap the given handler
+    pub fn new(
+        handler: H,
+        dead_letter_sink: Arc<dyn DeadLetterSink + Send + Sync>,
+    ) -> DeadLetterMalformedEvents<H> {
+        DeadLetterMalformedEvents {
+            handler,
+            dead_letter_sink,
+            metrics_collector: None,
+            dead_lettered_cursor: None,
+        }
+    }
+
+    /// Report the number of skipped events to the given `MetricsCollector`
+    /// for the subscription the handler consumes
+    pub fn metrics_collector<M>(
+        mut self,
+        metrics_collector: M,
+        subscription_id: SubscriptionId,
+    ) -> DeadLetterMalformedEvents<H>
+    where
+        M: MetricsCollector + Send + 'static,
+    {
+        self.metrics_collector = Some((subscription_id, Box::new(metrics_collector)));
+        self
+    }
+}
+
+impl<H> TypedBatchHandler for DeadLetterMalformedEvents<H>
+where
+    H: TypedBatchHandler,
+{
+    type Event = H::Event;
+
+    fn handle(
+        &mut self,
+        cursor: &SubscriptionCursor,
+        events: Vec<Self::Event>,
+    ) -> TypedProcessingStatus {
+        self.handler.handle(cursor, events)
+    }
+
+    fn handle_deserialization_errors(
+        &mut self,
+        cursor: &SubscriptionCursor,
+        results: Vec<EventDeserializationResult<Self::Event>>,
+    ) -> TypedProcessingStatus {
+        let mut events = Vec::with_capacity(results.len());
+        let mut num_skipped = 0;
+        // A retried batch has the same cursor
+        let is_retry = self.dead_lettered_cursor.as_ref() == Some(cursor);
+
+        for result in results {
+            match result {
+                Ok(event) => events.push(event),
+                Err(_) if is_retry => (),
+                Err((raw_event, err)) => {
+                    let reason = format!("Could not deserialize event: {}", err);
+                    if let Err(err) = self.dead_letter_sink.put_event(cursor, &raw_event, &reason) {
+                        return TypedProcessingStatus::Failed {
+                            reason: format!(
+                                "Could not put malformed event into dead letter sink: {}",
+                                err
+                            ),
+                        };
+                    }
+                    num_skipped += 1;
+                }
+            }
+        }
+
+        if num_skipped > 0 {
+            self.dead_lettered_cursor = Some(cursor.clone());
+        }
+
+        if let Some((ref subscription_id, ref metrics_collector)) = self.metrics_collector {
+            let ctx = MetricsContext::for_partition(
+                subscription_id,
+                &cursor.event_type,
+                &cursor.partition.0,
+            );
+            metrics_collector.worker_events_skipped(&ctx, num_skipped);
+        }
+
+        if num_skipped > 0 {
+            warn!(
+                "[Partition {}] Put {} malformed events into the dead letter sink",
+                cursor.partition, num_skipped
+            );
+        }
+
+        if events.is_empty() {
+            TypedProcessingStatus::Processed
+        } else {
+            self.handler.handle(cursor, events)
+        }
+    }
+
+    fn on_stop(
+        &mut self,
+        reason: &HandlerStopReason,
+        last_processed_cursor: Option<&SubscriptionCursor>,
+    ) {
+        self.handler.on_stop(reason, last_processed_cursor)
+    }
+}
+
+/// Errors that can happen when putting a batch into a `DeadLetterSink`
+#[derive(Fail, Debug)]
+pub enum DeadLetterError {
+    #[fail(display = "IO Error: {}", _0)]
+    Io(String),
+    #[fail(display = "Serialization Error: {}", _0)]
+    Serialization(String),
+    #[fail(display = "Publish Error: {}", _0)]
+    Publish(String),
+    #[fail(display = "Other Error: {}", _0)]
+    Other(String),
+}
+
+#[test]
+fn dead_letter_keeps_valid_json_events() {
+    use crate::nakadi::model::PartitionId;
+
+    let cursor = SubscriptionCursor {
+        partition: PartitionId::new("6"),
+        offset: "543".to_string(),
+        event_type: "order.ORDER_RECEIVED".to_string(),
+    };
+
+    let dead_letter = DeadLetter::new(&cursor, b"[{\"id\":1}]", "failed");
+
+    assert_eq!(dead_letter.events, serde_json::json!([{"id": 1}]));
+}
+
+#[test]
+fn dead_letter_keeps_invalid_json_events_as_string() {
+    use crate::nakadi::model::PartitionId;
+
+    let cursor = SubscriptionCursor {
+        partition: PartitionId::new("6"),
+        offset: "543".to_string(),
+        event_type: "order.ORDER_RECEIVED".to_string(),
+    };
+
+    let dead_letter = DeadLetter::new(&cursor, b"[{\"id\":", "failed");
+
+    assert_eq!(
+        dead_letter.events,
+        serde_json::Value::String("[{\"id\":".to_string())
+    );
+}
+
+#[test]
+fn dead_letter_malformed_events_passes_valid_events_to_handler() {
+    use crate::nakadi::handler::BatchHandler;
+    use crate::nakadi::model::PartitionId;
+
+    struct CollectingSink(Mutex<Vec<serde_json::Value>>);
+
+    impl DeadLetterSink for CollectingSink {
+        fn put_batch(
+            &self,
+            _cursor: &SubscriptionCursor,
+            events: &[u8],
+            _reason: &str,
+        ) -> Result<(), DeadLetterError> {
+            let mut events: Vec<serde_json::Value> = serde_json::from_slice(events).unwrap();
+            self.0.lock().unwrap().append(&mut events);
+            Ok(())
+        }
+    }
+
+    struct SummingHandler(u64);
+
+    impl TypedBatchHandler for SummingHandler {
+        type Event = u64;
+
+        fn handle(
+            &mut self,
+            _cursor: &SubscriptionCursor,
+            events: Vec<u64>,
+        ) -> TypedProcessingStatus {
+            self.0 += events.iter().sum::<u64>();
+            TypedProcessingStatus::Processed
+        }
+    }
+
+    let cursor = SubscriptionCursor {
+        partition: PartitionId::new("6"),
+        offset: "543".to_string(),
+        event_type: "numbers".to_string(),
+    };
+
+    let sink = Arc::new(CollectingSink(Mutex::new(Vec::new())));
+    let mut handler = DeadLetterMalformedEvents::new(SummingHandler(0), sink.clone());
+
+    let status = BatchHandler::handle(&mut handler, &cursor, b"[1,\"two\",3]");
+
+    assert_eq!(
+        status,
+        crate::nakadi::handler::ProcessingStatus::Processed(Some(3))
+    );
+    assert_eq!(handler.handler.0, 4);
+    assert_eq!(*sink.0.lock().unwrap(), vec![serde_json::json!("two")]);
+}
+
+#[test]
+fn dead_letter_malformed_events_puts_events_into_the_sink_once_per_batch() {
+    use std::time::Duration;
+
+    use crate::nakadi::handler::{BatchHandler, ProcessingStatus};
+    use crate::nakadi::model::PartitionId;
+
+    struct CountingSink(Mutex<usize>);
+
+    impl DeadLetterSink for CountingSink {
+        fn put_batch(
+            &self,
+            _cursor: &SubscriptionCursor,
+            _events: &[u8],
+            _reason: &str,
+        ) -> Result<(), DeadLetterError> {
+            *self.0.lock().unwrap() += 1;
+            Ok(())
+        }
+    }
+
+    struct RetryOnceHandler(Vec<Vec<u64>>);
+
+    impl TypedBatchHandler for RetryOnceHandler {
+        type Event = u64;
+
+        fn handle(
+            &mut self,
+            _cursor: &SubscriptionCursor,
+            events: Vec<u64>,
+        ) -> TypedProcessingStatus {
+            self.0.push(events);
+            if self.0.len() == 1 {
+                TypedProcessingStatus::Retry {
+                    after: Duration::from_millis(10),
+                }
+            } else {
+                TypedProcessingStatus::Processed
+            }
+        }
+    }
+
+    let cursor = |offset: &str| SubscriptionCursor {
+        partition: PartitionId::new("6"),
+        offset: offset.to_string(),
+        event_type: "numbers".to_string(),
+    };
+
+    let sink = Arc::new(CountingSink(Mutex::new(0)));
+    let mut handler = DeadLetterMalformedEvents::new(RetryOnceHandler(Vec::new()), sink.clone());
+
+    let events = b"[1,\"two\",3]";
+    match BatchHandler::handle(&mut handler, &cursor("543"), events) {
+        ProcessingStatus::Retry { .. } => {}
+        other => panic!("Expected a retry but got {:?}", other),
+    }
+    assert_eq!(*sink.0.lock().unwrap(), 1);
+    BatchHandler::handle(&mut handler, &cursor("543"), events);
+    assert_eq!(*sink.0.lock().unwrap(), 1);
+    BatchHandler::handle(&mut handler, &cursor("544"), events);
+    assert_eq!(*sink.0.lock().unwrap(), 2);
+
+    assert_eq!(handler.handler.0, vec![vec![1, 3], vec![1, 3], vec![1, 3]]);
+}
@@ -1,19 +1,26 @@
 //! The processor orchestrates the workers
 
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc;
 use std::sync::Arc;
-use std::thread;
 use std::time::{Duration, Instant};
 
-use crate::cancellation_token::{AutoCancellationToken, CancellationToken, CancellationTokenSource};
+use crate::cancellation_token::{
+    AutoCancellationToken, CancellationToken, CancellationTokenSource,
+};
 use failure::{Error, Fail};
 
 use crate::nakadi::batch::Batch;
 use crate::nakadi::committer::Committer;
+use crate::nakadi::consumer::{ShutdownTracker, StopReason};
+use crate::nakadi::dead_letter::DeadLetterSink;
 use crate::nakadi::handler::HandlerFactory;
-use crate::nakadi::metrics::MetricsCollector;
+use crate::nakadi::lifecycle::LifecycleListener;
+use crate::nakadi::metrics::{MetricsCollector, MetricsContext};
 use crate::nakadi::model::{PartitionId, StreamId};
 use crate::nakadi::worker::Worker;
+use crate::nakadi::HandlerRetryPolicy;
+use crate::thread_handle::ThreadHandle;
 
 /// The dispatcher takes batch lines and sends them to the workers.
 ///
@@ -24,15 +31,23 @@ pub struct Dispatcher {
     /// Send batches with this sender
     sender: mpsc::Sender<Batch>,
     lifecycle: CancellationTokenSource,
+    /// Set if the dispatcher was stopped because the stream ended
+    stream_ended: Arc<AtomicBool>,
     metrics_collector: Box<dyn MetricsCollector>,
+    thread: ThreadHandle<Option<StopReason>>,
 }
 
 impl Dispatcher {
+    #[allow(clippy::too_many_arguments)]
     pub fn start<HF, M>(
         handler_factory: Arc<HF>,
         committer: Committer,
         metrics_collector: M,
         min_idle_worker_lifetime: Option<Duration>,
+        handler_retry_policy: HandlerRetryPolicy,
+        dead_letter_sink: Option<Arc<dyn DeadLetterSink + Send + Sync>>,
+        lifecycle_listener: Option<Arc<dyn LifecycleListener + Send + Sync>>,
+        shutdown: ShutdownTracker,
     ) -> Dispatcher
     where
         HF: HandlerFactory + Send + Sync + 'static,
@@ -43,33 +58,72 @@ impl Dispatcher {
         let lifecycle = CancellationTokenSource::new(metrics_collector.clone());
 
         let cancellation_token = lifecycle.auto_token();
+        let stream_ended = Arc::new(AtomicBool::new(false));
 
-        let handle = Dispatcher {
-            lifecycle,
-            sender,
-            metrics_collector: Box::new(metrics_collector.clone()),
-        };
-
-        start_dispatcher_loop(
+        let thread = start_dispatcher_loop(
             receiver,
             cancellation_token,
+            stream_ended.clone(),
             handler_factory,
             committer,
-            metrics_collector,
+            metrics_collector.clone(),
             min_idle_worker_lifetime,
+            handler_retry_policy,
+            dead_letter_sink,
+            lifecycle_listener,
+            shutdown,
         );
 
-        handle
+        Dispatcher {
+            lifecycle,
+            stream_ended,
+            sender,
+            metrics_collector: Box::new(metrics_collector),
+            thread,
+        }
     }
 
     pub fn is_running(&self) -> bool {
         !self.lifecycle.is_any_cancelled()
     }
 
+    /// Stop the dispatcher and its workers.
+    ///
+    /// The handlers will be told that they were cancelled.
     pub fn stop(&self) {
         self.lifecycle.request_cancellation()
     }
 
+    /// Stop the dispatcher and its workers because the stream ended.
+    ///
+    /// The handlers will be told that the stream ended.
+    pub fn end_of_stream(&self) {
+        self.stream_ended.store(true, Ordering::SeqCst);
+        self.stop()
+    }
+
+    /// Dispatch the batches already sent to the dispatcher,
+    /// let the workers process them and then stop.
+    pub fn drain(&self) {
+        self.lifecycle.request_drain()
+    }
+
+    /// Block until the dispatcher thread finished or `timeout` elapsed.
+    ///
+    /// Returns true if the dispatcher thread finished.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        self.thread.wait_timeout(timeout)
+    }
+
+    /// Block until the dispatcher thread finished.
+    ///
+    /// The dispatcher thread finishes after all of its workers.
+    /// Returns a `StopReason` if the consumer should stop because
+    /// the dispatcher or a worker failed permanently.
+    pub fn join(self) -> Option<StopReason> {
+        StopReason::from_joined(self.thread.join())
+    }
+
     pub fn dispatch(&self, batch: Batch) -> Result<(), Error> {
         self.sender.send(batch).map_err(|err| {
             self.metrics_collector.other_dispatcher_gone();
@@ -79,40 +133,56 @@ impl Dispatcher {
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn start_dispatcher_loop<HF, M>(
     receiver: mpsc::Receiver<Batch>,
     lifecycle: AutoCancellationToken,
+    stream_ended: Arc<AtomicBool>,
     handler_factory: Arc<HF>,
     committer: Committer,
     metrics_collector: M,
     min_idle_worker_lifetime: Option<Duration>,
-) where
+    handler_retry_policy: HandlerRetryPolicy,
+    dead_letter_sink: Option<Arc<dyn DeadLetterSink + Send + Sync>>,
+    lifecycle_listener: Option<Arc<dyn LifecycleListener + Send + Sync>>,
+    shutdown: ShutdownTracker,
+) -> ThreadHandle<Option<StopReason>>
+where
     HF: HandlerFactory + Send + Sync + 'static,
     M: MetricsCollector + Clone + Send + Sync + 'static,
 {
-    let builder = thread::Builder::new().name("nakadion-dispatcher".into());
-    builder
-        .spawn(move || {
-            dispatcher_loop(
-                receiver,
-                lifecycle,
-                handler_factory,
-                committer,
-                metrics_collector,
-                min_idle_worker_lifetime,
-            )
-        })
-        .unwrap();
+    ThreadHandle::spawn("nakadion-dispatcher".into(), move || {
+        dispatcher_loop(
+            receiver,
+            lifecycle,
+            &stream_ended,
+            handler_factory,
+            committer,
+            metrics_collector,
+            min_idle_worker_lifetime,
+            handler_retry_policy,
+            dead_letter_sink,
+            lifecycle_listener,
+            shutdown,
+        )
+    })
 }
 
+#[allow(clippy::too_many_arguments)]
 fn dispatcher_loop<HF, M>(
     receiver: mpsc::Receiver<Batch>,
     lifecycle: AutoCancellationToken,
+    stream_ended: &AtomicBool,
     handler_factory: Arc<HF>,
     committer: Committer,
     metrics_collector: M,
     min_idle_worker_lifetime: Option<Duration>,
-) where
+    handler_retry_policy: HandlerRetryPolicy,
+    dead_letter_sink: Option<Arc<dyn DeadLetterSink + Send + Sync>>,
+    lifecycle_listener: Option<Arc<dyn LifecycleListener + Send + Sync>>,
+    shutdown: ShutdownTracker,
+) -> Option<StopReason>
+where
     HF: HandlerFactory,
     M: MetricsCollector + Clone + Sync + Send + 'static,
 {
@@ -121,6 +191,7 @@ fn dispatcher_loop<HF, M>(
     let stream_id = committer.stream_id().clone();
     let mut workers: Vec<(Worker, Instant)> = Vec::with_capacity(32);
     let mut idle_workers_last_checked = Instant::now();
+    let mut stop_reason = None;
 
     info!("[Dispatcher, stream={}] Started.", committer.stream_id(),);
     loop {
@@ -144,19 +215,38 @@ fn dispatcher_loop<HF, M>(
 
         if idle_workers_last_checked.elapsed() >= Duration::from_secs(5) {
             if let Some(min_idle_worker_lifetime) = min_idle_worker_lifetime {
-                workers = kill_idle_workers(
+                let (survivors, idle_workers_stop_reason) = kill_idle_workers(
                     workers,
                     &metrics_collector,
                     min_idle_worker_lifetime,
                     &stream_id,
+                    lifecycle_listener.as_ref(),
                 );
-                idle_workers_last_checked = Instant::now()
+                workers = survivors;
+                idle_workers_last_checked = Instant::now();
+                if idle_workers_stop_reason.is_some() {
+                    error!(
+                        "[Dispatcher, stream={}] An idle worker failed. Stopping.",
+                        stream_id
+                    );
+                    stop_reason = idle_workers_stop_reason;
+                    break;
+                }
             }
         }
 
         let batch = match receiver.recv_timeout(Duration::from_millis(5)) {
             Ok(batch) => batch,
-            Err(mpsc::RecvTimeoutError::Timeout) => continue,
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                if lifecycle.drain_requested() {
+                    info!(
+                        "[Dispatcher, stream={}] All batches dispatched. Draining workers.",
+                        stream_id
+                    );
+                    break;
+                }
+                continue;
+            }
             Err(mpsc::RecvTimeoutError::Disconnected) => {
                 info!(
                     "[Dispatcher, stream={}] Channel disconnected. Stopping.",
@@ -167,7 +257,10 @@ fn dispatcher_loop<HF, M>(
             }
         };
 
-        metrics_collector.dispatcher_batch_received(batch.received_at);
+        metrics_collector.dispatcher_batch_received(
+            &MetricsContext::for_batch(committer.subscription_id(), &batch.batch_line),
+            batch.received_at,
+        );
 
         if batch.batch_line.events().is_none() {
             error!(
@@ -209,6 +302,7 @@ fn dispatcher_loop<HF, M>(
                         "[Dispatcher, stream={}, partition={}] Handler factory failed: {}",
                         stream_id, partition, err
                     );
+                    stop_reason = Some(StopReason::HandlerFactory(err));
                     break;
                 }
             };
@@ -223,9 +317,15 @@ fn dispatcher_loop<HF, M>(
                 committer.clone(),
                 partition.clone(),
                 metrics_collector.clone(),
+                handler_retry_policy,
+                dead_letter_sink.clone(),
+                shutdown.clone(),
             );
             workers.push((worker, Instant::now()));
             metrics_collector.dispatcher_current_workers(workers.len());
+            if let Some(ref lifecycle_listener) = lifecycle_listener {
+                lifecycle_listener.on_partition_assigned(&stream_id, &partition);
+            }
             &workers[workers.len() - 1].0
         };
 
@@ -238,13 +338,32 @@ fn dispatcher_loop<HF, M>(
         }
     }
 
+    shutdown.batches_dropped(receiver.try_iter().count());
+
+    let drain = lifecycle.drain_requested() && !lifecycle.cancellation_requested();
     workers.iter().for_each(|w| {
-        info!(
-            "[Dispatcher, stream={}, partition={}] Requesting worker to stop.",
-            stream_id,
-            w.0.partition()
-        );
-        w.0.stop()
+        if drain {
+            info!(
+                "[Dispatcher, stream={}, partition={}] Requesting worker to drain.",
+                stream_id,
+                w.0.partition()
+            );
+            w.0.drain()
+        } else if stream_ended.load(Ordering::SeqCst) {
+            info!(
+                "[Dispatcher, stream={}, partition={}] Stream ended. Requesting worker to stop.",
+                stream_id,
+                w.0.partition()
+            );
+            w.0.end_of_stream()
+        } else {
+            info!(
+                "[Dispatcher, stream={}, partition={}] Requesting worker to stop.",
+                stream_id,
+                w.0.partition()
+            );
+            w.0.stop()
+        }
     });
 
     info!(
@@ -253,8 +372,18 @@ fn dispatcher_loop<HF, M>(
         workers.len()
     );
 
-    while workers.iter().any(|w| w.0.running()) {
-        thread::sleep(Duration::from_millis(10));
+    for (worker, _) in workers {
+        while !worker.wait_timeout(Duration::from_millis(100)) {
+            if lifecycle.cancellation_requested() {
+                worker.stop();
+            }
+        }
+        let partition = worker.partition().clone();
+        let worker_stop_reason = worker.join();
+        stop_reason = stop_reason.or(worker_stop_reason);
+        if let Some(ref lifecycle_listener) = lifecycle_listener {
+            lifecycle_listener.on_partition_revoked(&stream_id, &partition);
+        }
     }
 
     metrics_collector.dispatcher_current_workers(0);
@@ -262,16 +391,24 @@ fn dispatcher_loop<HF, M>(
     info!("[Dispatcher, stream={}] All workers stopped.", stream_id);
 
     info!("[Dispatcher, stream={}] Stopped.", stream_id);
+
+    stop_reason
 }
 
+/// Stops the workers which were idle for at least `min_idle_worker_lifetime`.
+///
+/// Returns the remaining workers and a `StopReason` if
+/// a stopped worker failed.
 fn kill_idle_workers(
     workers: Vec<(Worker, Instant)>,
     metrics_collector: &dyn MetricsCollector,
     min_idle_worker_lifetime: Duration,
     stream: &StreamId,
-) -> Vec<(Worker, Instant)> {
+    lifecycle_listener: Option<&Arc<dyn LifecycleListener + Send + Sync>>,
+) -> (Vec<(Worker, Instant)>, Option<StopReason>) {
     let mut survivors = Vec::new();
     let mut stopped = Vec::new();
+    let mut stop_reason = None;
 
     for (worker, last_used) in workers {
         if last_used.elapsed() >= min_idle_worker_lifetime {
@@ -280,20 +417,29 @@ fn kill_idle_workers(
                 stream,
                 worker.partition()
             );
-            worker.stop();
+            worker.stop_idle();
             stopped.push(worker)
         } else {
             survivors.push((worker, last_used));
         }
     }
 
-    while stopped.iter().any(Worker::running) {
-        thread::sleep(Duration::from_millis(5));
-    }
-
     if !stopped.is_empty() {
+        for worker in stopped {
+            let partition = worker.partition().clone();
+            if let Some(worker_stop_reason) = worker.join() {
+                error!(
+                    "[Dispatcher, stream={}, partition={}] Idle worker failed: {:?}",
+                    stream, partition, worker_stop_reason
+                );
+                stop_reason = stop_reason.or(Some(worker_stop_reason));
+            }
+            if let Some(lifecycle_listener) = lifecycle_listener {
+                lifecycle_listener.on_partition_revoked(stream, &partition);
+            }
+        }
         metrics_collector.dispatcher_current_workers(survivors.len());
     }
 
-    survivors
+    (survivors, stop_reason)
 }
@@ -1,4 +1,6 @@
 //! Handler for handling events and implementing event processing logic
+use std::time::Duration;
+
 use serde::de::DeserializeOwned;
 use serde_json;
 
@@ -25,11 +27,20 @@ pub enum ProcessingStatus {
     /// batch.
     Processed(Option<usize>),
     /// Processing failed. Do not commit the cursor. This
-    /// always ends in the streaming being aborted for the current
-    /// stream.
+    /// ends in the streaming being aborted for the current
+    /// stream unless a `DeadLetterSink` is configured. In that case
+    /// the batch is put into the sink and the cursor gets committed.
     ///
     /// A reason must be given which will be logged.
     Failed { reason: String },
+    /// Processing failed but might succeed later. Do not commit the cursor.
+    ///
+    /// The worker will invoke the handler again with the same batch
+    /// after the given duration has elapsed while keeping the stream alive.
+    /// How often and for how long a batch is retried is limited by the
+    /// `HandlerRetryPolicy`. Once the policy is exhausted this is treated
+    /// like `Failed`.
+    Retry { after: Duration },
 }
 
 impl ProcessingStatus {
@@ -51,6 +62,28 @@ impl ProcessingStatus {
             reason: reason.into(),
         }
     }
+
+    /// Processing should be retried with the same batch after
+    /// the given duration.
+    pub fn retry(after: Duration) -> ProcessingStatus {
+        ProcessingStatus::Retry { after }
+    }
+}
+
+/// The reason why the worker of a `BatchHandler` stopped
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HandlerStopReason {
+    /// The worker did not receive any batches for longer than
+    /// the `min_idle_worker_lifetime`.
+    IdleTimeout,
+    /// The stream ended.
+    StreamEnded,
+    /// The consumer was stopped or another component
+    /// of the consumer failed.
+    Cancelled,
+    /// The worker could not continue, e.g. because the handler
+    /// failed on a batch and there was no `DeadLetterSink` to put it into.
+    Failed { reason: String },
 }
 
 /// A handler that contains batch processing logic.
@@ -102,11 +135,26 @@ pub trait BatchHandler {
     ///
     /// Calling this method may never panic!
     fn handle(&mut self, cursor: &SubscriptionCursor, events: &[u8]) -> ProcessingStatus;
+
+    /// Called once before the worker stops and the handler gets dropped.
+    ///
+    /// Use it to flush buffered writes and to release resources.
+    /// `last_processed_cursor` is the cursor of the last batch the handler
+    /// processed or `None` if it did not process any batch.
+    ///
+    /// The default implementation does nothing.
+    fn on_stop(
+        &mut self,
+        _reason: &HandlerStopReason,
+        _last_processed_cursor: Option<&SubscriptionCursor>,
+    ) {
+    }
 }
 
 /// An error that can happen when the `HandlerFactory` was not able to create
-/// a new handler. This will abort the consumption of the current stream.
-#[derive(Debug, Fail)]
+/// a new handler. This will stop the consumer with
+/// `StopReason::HandlerFactory`.
+#[derive(Debug, Clone, PartialEq, Eq, Fail)]
 #[fail(display = "{}", message)]
 pub struct CreateHandlerError {
     pub message: String,
@@ -119,11 +167,11 @@ pub struct CreateHandlerError {
 /// A `HandlerFactory` can be used in two ways:
 ///
 /// * It does not contain any state it shares with the created `BatchHandler`s.
-/// This is useful when incoming data is partitioned in a way that all
-/// `BatchHandler`s act only on data that never appears on another partition.
+///   This is useful when incoming data is partitioned in a way that all
+///   `BatchHandler`s act only on data that never appears on another partition.
 ///
 /// * It contains state that is shared with the `BatchHandler`s. E.g. a cache
-/// that conatins data that can appear on other partitions.
+///   that conatins data that can appear on other partitions.
 /// # Example
 ///
 /// ```rust
@@ -206,6 +254,9 @@ pub enum TypedProcessingStatus {
     Processed,
     /// Processing events failed and the stream should be aborted.
     Failed { reason: String },
+    /// Processing events failed but should be retried with the same
+    /// events after the given duration.
+    Retry { after: Duration },
 }
 
 /// Basically the same a `BatchHandler` with the difference that
@@ -276,6 +327,16 @@ pub trait TypedBatchHandler {
             ),
         }
     }
+
+    /// Called once before the worker stops and the handler gets dropped.
+    ///
+    /// See `BatchHandler::on_stop`.
+    fn on_stop(
+        &mut self,
+        _reason: &HandlerStopReason,
+        _last_processed_cursor: Option<&SubscriptionCursor>,
+    ) {
+    }
 }
 
 pub type EventDeserializationResult<T> = Result<T, (serde_json::Value, serde_json::Error)>;
@@ -293,6 +354,7 @@ where
                 match TypedBatchHandler::handle(self, cursor, events) {
                     TypedProcessingStatus::Processed => ProcessingStatus::processed(n),
                     TypedProcessingStatus::Failed { reason } => ProcessingStatus::Failed { reason },
+                    TypedProcessingStatus::Retry { after } => ProcessingStatus::Retry { after },
                 }
             }
             Err(_) => match try_deserialize_individually::<E>(events) {
@@ -303,6 +365,7 @@ where
                         TypedProcessingStatus::Failed { reason } => {
                             ProcessingStatus::Failed { reason }
                         }
+                        TypedProcessingStatus::Retry { after } => ProcessingStatus::Retry { after },
                     }
                 }
                 Err(err) => ProcessingStatus::Failed {
@@ -311,6 +374,14 @@ where
             },
         }
     }
+
+    fn on_stop(
+        &mut self,
+        reason: &HandlerStopReason,
+        last_processed_cursor: Option<&SubscriptionCursor>,
+    ) {
+        TypedBatchHandler::on_stop(self, reason, last_processed_cursor)
+    }
 }
 
 // This function clones the ast before deserializing... but we are in an
@@ -350,3 +421,42 @@ fn parse_cursor() {
 
     assert_eq!(parsed, expected);
 }
+
+#[test]
+fn typed_batch_handler_receives_on_stop() {
+    struct MyHandler(Option<(HandlerStopReason, Option<SubscriptionCursor>)>);
+
+    impl TypedBatchHandler for MyHandler {
+        type Event = i32;
+
+        fn handle(
+            &mut self,
+            _cursor: &SubscriptionCursor,
+            _events: Vec<i32>,
+        ) -> TypedProcessingStatus {
+            TypedProcessingStatus::Processed
+        }
+
+        fn on_stop(
+            &mut self,
+            reason: &HandlerStopReason,
+            last_processed_cursor: Option<&SubscriptionCursor>,
+        ) {
+            self.0 = Some((reason.clone(), last_processed_cursor.cloned()));
+        }
+    }
+
+    let cursor = SubscriptionCursor {
+        partition: PartitionId::new("1"),
+        offset: "53".to_string(),
+        event_type: "test_event".to_string(),
+    };
+    let mut handler = MyHandler(None);
+
+    BatchHandler::on_stop(&mut handler, &HandlerStopReason::IdleTimeout, Some(&cursor));
+
+    assert_eq!(
+        handler.0,
+        Some((HandlerStopReason::IdleTimeout, Some(cursor)))
+    );
+}
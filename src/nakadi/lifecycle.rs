@@ -0,0 +1,58 @@
+//! Notifications on the lifecycle of streams and partitions
+//!
+//! A `LifecycleListener` is told when the consumer connects to a
+//! stream, when it starts and stops consuming a partition and
+//! when the stream closes. This allows to flush state
+//! kept per partition once the partition is gone.
+//!
+//! Nakadi does not announce partition assignments. A partition
+//! counts as assigned once the first batch of that partition has been
+//! received on the current stream. It counts as revoked once its worker
+//! has been stopped. This happens when the worker was idle for longer
+//! than the configured `min_idle_worker_lifetime` (e.g. after the
+//! partition has been rebalanced to another consumer) or when the
+//! stream was closed.
+use std::fmt;
+
+use crate::nakadi::model::{PartitionId, StreamId};
+
+/// Receives notifications on the lifecycle of streams and partitions.
+///
+/// The listener is shared by the consumer and all workers and
+/// therefore may be called concurrently. All methods do nothing by
+/// default.
+///
+/// The methods are called from the consumer's background threads and
+/// should return quickly since consumption is blocked meanwhile.
+pub trait LifecycleListener {
+    /// A new stream has been connected.
+    fn on_stream_connected(&self, _stream_id: &StreamId) {}
+
+    /// The stream has been closed.
+    ///
+    /// This is called after all partitions of the stream
+    /// have been revoked.
+    fn on_stream_closed(&self, _stream_id: &StreamId) {}
+
+    /// The first batch for `partition` was received on the stream and
+    /// a handler for the partition has been created.
+    fn on_partition_assigned(&self, _stream_id: &StreamId, _partition: &PartitionId) {}
+
+    /// The worker of `partition` has been stopped and
+    /// its handler has been dropped.
+    ///
+    /// No more batches of the partition will be processed unless it
+    /// is assigned again.
+    fn on_partition_revoked(&self, _stream_id: &StreamId, _partition: &PartitionId) {}
+
+    /// Nakadi sent debug information along with a batch.
+    ///
+    /// `info` is the raw JSON object of the `info` field of the batch line.
+    fn on_info_line(&self, _stream_id: &StreamId, _info: &str) {}
+}
+
+impl fmt::Debug for dyn LifecycleListener + Send + Sync {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "LifecycleListener")
+    }
+}
@@ -0,0 +1,179 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use super::{MetricsCollector, MetricsContext};
+
+/// A `MetricsCollector` forwarding every callback
+/// to all of the collectors added to it.
+///
+/// This allows to report to several metrics systems at once
+/// e.g. while migrating from one to another.
+///
+/// Clones share the same collectors.
+#[derive(Clone, Default)]
+pub struct FanOutMetricsCollector {
+    collectors: Vec<Arc<dyn MetricsCollector + Send + Sync + 'static>>,
+}
+
+impl FanOutMetricsCollector {
+    /// Create a `FanOutMetricsCollector` without any collectors
+    pub fn new() -> FanOutMetricsCollector {
+        FanOutMetricsCollector::default()
+    }
+
+    /// Also forward all callbacks to the given collector
+    pub fn with_collector<M>(mut self, metrics_collector: M) -> FanOutMetricsCollector
+    where
+        M: MetricsCollector + Send + Sync + 'static,
+    {
+        self.collectors.push(Arc::new(metrics_collector));
+        self
+    }
+
+    fn each<F>(&self, f: F)
+    where
+        F: Fn(&dyn MetricsCollector),
+    {
+        self.collectors
+            .iter()
+            .for_each(|collector| f(collector.as_ref()));
+    }
+}
+
+impl MetricsCollector for FanOutMetricsCollector {
+    fn streaming_connect_attempt(&self) {
+        self.each(|c| c.streaming_connect_attempt());
+    }
+    fn streaming_connect_attempt_failed(&self) {
+        self.each(|c| c.streaming_connect_attempt_failed());
+    }
+
+    fn consumer_connected(&self, attempt_started: Instant) {
+        self.each(|c| c.consumer_connected(attempt_started));
+    }
+    fn consumer_connection_lifetime(&self, connected_since: Instant) {
+        self.each(|c| c.consumer_connection_lifetime(connected_since));
+    }
+    fn consumer_line_received(&self, bytes: usize) {
+        self.each(|c| c.consumer_line_received(bytes));
+    }
+    fn consumer_info_line_received(&self, bytes: usize) {
+        self.each(|c| c.consumer_info_line_received(bytes));
+    }
+    fn consumer_keep_alive_line_received(&self, bytes: usize) {
+        self.each(|c| c.consumer_keep_alive_line_received(bytes));
+    }
+    fn consumer_batch_line_received(&self, bytes: usize) {
+        self.each(|c| c.consumer_batch_line_received(bytes));
+    }
+    fn consumer_batch_received(&self, ctx: &MetricsContext, batch_received_at_timestamp: Instant) {
+        self.each(|c| c.consumer_batch_received(ctx, batch_received_at_timestamp));
+    }
+
+    fn dispatcher_batch_received(
+        &self,
+        ctx: &MetricsContext,
+        batch_received_at_timestamp: Instant,
+    ) {
+        self.each(|c| c.dispatcher_batch_received(ctx, batch_received_at_timestamp));
+    }
+    fn dispatcher_current_workers(&self, num_workers: usize) {
+        self.each(|c| c.dispatcher_current_workers(num_workers));
+    }
+
+    fn worker_worker_started(&self) {
+        self.each(|c| c.worker_worker_started());
+    }
+    fn worker_worker_stopped(&self) {
+        self.each(|c| c.worker_worker_stopped());
+    }
+    fn worker_batch_received(&self, ctx: &MetricsContext, batch_received_at_timestamp: Instant) {
+        self.each(|c| c.worker_batch_received(ctx, batch_received_at_timestamp));
+    }
+    fn worker_batch_size_bytes(&self, ctx: &MetricsContext, bytes: usize) {
+        self.each(|c| c.worker_batch_size_bytes(ctx, bytes));
+    }
+    fn worker_batch_processed(&self, ctx: &MetricsContext, started: Instant) {
+        self.each(|c| c.worker_batch_processed(ctx, started));
+    }
+    fn worker_events_in_same_batch_processed(&self, ctx: &MetricsContext, n: usize) {
+        self.each(|c| c.worker_events_in_same_batch_processed(ctx, n));
+    }
+    fn worker_events_skipped(&self, ctx: &MetricsContext, n: usize) {
+        self.each(|c| c.worker_events_skipped(ctx, n));
+    }
+
+    fn committer_batch_received(&self, ctx: &MetricsContext, batch_received_at_timestamp: Instant) {
+        self.each(|c| c.committer_batch_received(ctx, batch_received_at_timestamp));
+    }
+    fn committer_cursor_commit_attempt(
+        &self,
+        ctx: &MetricsContext,
+        commit_attempt_started: Instant,
+    ) {
+        self.each(|c| c.committer_cursor_commit_attempt(ctx, commit_attempt_started));
+    }
+    fn committer_cursor_committed(&self, ctx: &MetricsContext, commit_attempt_started: Instant) {
+        self.each(|c| c.committer_cursor_committed(ctx, commit_attempt_started));
+    }
+    fn committer_cursor_commit_failed(
+        &self,
+        ctx: &MetricsContext,
+        commit_attempt_started: Instant,
+    ) {
+        self.each(|c| c.committer_cursor_commit_failed(ctx, commit_attempt_started));
+    }
+    fn committer_batches_committed(&self, ctx: &MetricsContext, n: usize) {
+        self.each(|c| c.committer_batches_committed(ctx, n));
+    }
+    fn committer_events_committed(&self, ctx: &MetricsContext, n: usize) {
+        self.each(|c| c.committer_events_committed(ctx, n));
+    }
+    fn committer_first_cursor_age_on_commit(&self, ctx: &MetricsContext, age: Duration) {
+        self.each(|c| c.committer_first_cursor_age_on_commit(ctx, age));
+    }
+    fn committer_last_cursor_age_on_commit(&self, ctx: &MetricsContext, age: Duration) {
+        self.each(|c| c.committer_last_cursor_age_on_commit(ctx, age));
+    }
+    fn committer_cursor_buffer_time(&self, ctx: &MetricsContext, time_buffered: Duration) {
+        self.each(|c| c.committer_cursor_buffer_time(ctx, time_buffered));
+    }
+    fn committer_time_left_on_commit_until_invalid(
+        &self,
+        ctx: &MetricsContext,
+        time_left: Duration,
+    ) {
+        self.each(|c| c.committer_time_left_on_commit_until_invalid(ctx, time_left));
+    }
+
+    fn other_panicked(&self) {
+        self.each(|c| c.other_panicked());
+    }
+    fn other_dispatcher_gone(&self) {
+        self.each(|c| c.other_dispatcher_gone());
+    }
+    fn other_worker_gone(&self) {
+        self.each(|c| c.other_worker_gone());
+    }
+    fn other_committer_gone(&self) {
+        self.each(|c| c.other_committer_gone());
+    }
+}
+
+#[test]
+fn forwards_callbacks_to_all_collectors() {
+    use super::LoggingMetricsCollector;
+
+    let first = LoggingMetricsCollector::new(Duration::from_secs(3600)).unwrap();
+    let second = LoggingMetricsCollector::new(Duration::from_secs(3600)).unwrap();
+    let collector = FanOutMetricsCollector::new()
+        .with_collector(first.clone())
+        .with_collector(second.clone());
+
+    collector.streaming_connect_attempt();
+    collector.streaming_connect_attempt_failed();
+
+    for summary in &[first.summary(), second.summary()] {
+        assert!(summary.starts_with("connect_attempts=1 connect_failures=1"));
+    }
+}
@@ -0,0 +1,352 @@
+use std::collections::hash_map::RandomState;
+use std::fmt::Write;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use failure::*;
+
+use super::{MetricsCollector, MetricsContext};
+
+/// At most this many latencies are sampled per interval
+/// to calculate quantiles from.
+const MAX_SAMPLES: usize = 10_000;
+
+/// A `MetricsCollector` which aggregates the metrics and
+/// periodically logs a summary line at level `INFO`.
+///
+/// Counters are summed up and quantiles of the latencies are
+/// calculated for each interval. All values are reset after
+/// a summary has been logged.
+///
+/// Summaries are logged from a background thread which
+/// stops once the `LoggingMetricsCollector` and all of
+/// its clones have been dropped.
+#[derive(Clone)]
+pub struct LoggingMetricsCollector {
+    aggregate: Arc<Mutex<Aggregate>>,
+}
+
+impl LoggingMetricsCollector {
+    /// Create a new `LoggingMetricsCollector` logging
+    /// a summary every `interval`.
+    ///
+    /// # Errors
+    ///
+    /// The background thread could not be started.
+    pub fn new(interval: Duration) -> Result<LoggingMetricsCollector, Error> {
+        let aggregate = Arc::new(Mutex::new(Aggregate::default()));
+
+        let weak_aggregate = Arc::downgrade(&aggregate);
+        thread::Builder::new()
+            .name("nakadion-metrics-log".to_string())
+            .spawn(move || log_loop(weak_aggregate, interval))
+            .context("Could not start metrics logging thread")?;
+
+        Ok(LoggingMetricsCollector { aggregate })
+    }
+
+    /// The summary of everything collected since
+    /// the last summary was logged.
+    pub fn summary(&self) -> String {
+        self.aggregate.lock().unwrap().summary()
+    }
+
+    fn update<F>(&self, f: F)
+    where
+        F: FnOnce(&mut Aggregate),
+    {
+        f(&mut self.aggregate.lock().unwrap())
+    }
+}
+
+fn log_loop(aggregate: Weak<Mutex<Aggregate>>, interval: Duration) {
+    let poll_interval = ::std::cmp::min(interval, Duration::from_millis(100));
+    let mut last_logged = Instant::now();
+    loop {
+        thread::sleep(poll_interval);
+
+        let aggregate = if let Some(aggregate) = aggregate.upgrade() {
+            aggregate
+        } else {
+            break;
+        };
+
+        if last_logged.elapsed() >= interval {
+            let mut aggregate = aggregate.lock().unwrap();
+            info!("[Metrics] {}", aggregate.summary());
+            *aggregate = Aggregate {
+                current_workers: aggregate.current_workers,
+                ..Aggregate::default()
+            };
+            last_logged = Instant::now();
+        }
+    }
+    debug!("Metrics logging thread stopped");
+}
+
+/// The latencies observed within an interval
+///
+/// A uniform sample of at most `MAX_SAMPLES` latencies is kept
+/// using reservoir sampling. The maximum is tracked exactly.
+struct Latencies {
+    samples: Vec<Duration>,
+    observed: u64,
+    max: Duration,
+    /// State of the xorshift generator choosing the samples to replace
+    random: u64,
+}
+
+impl Default for Latencies {
+    fn default() -> Latencies {
+        Latencies {
+            samples: Vec::new(),
+            observed: 0,
+            max: Duration::default(),
+            // The state must not be 0
+            random: RandomState::new().build_hasher().finish() | 1,
+        }
+    }
+}
+
+impl Latencies {
+    fn observe(&mut self, latency: Duration) {
+        self.observed += 1;
+        self.max = ::std::cmp::max(self.max, latency);
+
+        if self.samples.len() < MAX_SAMPLES {
+            self.samples.push(latency);
+        } else {
+            let idx = self.next_random() % self.observed;
+            if idx < MAX_SAMPLES as u64 {
+                self.samples[idx as usize] = latency;
+            }
+        }
+    }
+
+    /// xorshift64*
+    fn next_random(&mut self) -> u64 {
+        self.random ^= self.random >> 12;
+        self.random ^= self.random << 25;
+        self.random ^= self.random >> 27;
+        self.random.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    fn since(&mut self, started: Instant) {
+        self.observe(started.elapsed())
+    }
+
+    /// Formats the 50th and 99th percentile and the maximum in milliseconds
+    fn quantiles(&self) -> String {
+        if self.samples.is_empty() {
+            return "-".to_string();
+        }
+
+        let mut sorted = self.samples.clone();
+        sorted.sort();
+        let at = |q: f64| {
+            let idx = ((sorted.len() - 1) as f64 * q).round() as usize;
+            sorted[idx].as_secs_f64() * 1000.0
+        };
+
+        format!(
+            "{:.1}/{:.1}/{:.1}",
+            at(0.5),
+            at(0.99),
+            self.max.as_secs_f64() * 1000.0
+        )
+    }
+}
+
+#[derive(Default)]
+struct Aggregate {
+    connect_attempts: usize,
+    connect_attempts_failed: usize,
+    lines_received: usize,
+    bytes_received: usize,
+    batches_received: usize,
+    current_workers: usize,
+    events_processed: usize,
+    events_skipped: usize,
+    batch_processing: Latencies,
+    batch_lag: Latencies,
+    commit_attempts: usize,
+    commit_attempts_failed: usize,
+    commit_latency: Latencies,
+    events_committed: usize,
+    cursor_buffer_time: Latencies,
+    incidents: usize,
+}
+
+impl Aggregate {
+    fn summary(&self) -> String {
+        let mut summary = String::new();
+        let _ = write!(
+            summary,
+            "connect_attempts={} connect_failures={} lines={} bytes={} batches={} \
+             workers={} events_processed={} events_skipped={} \
+             batch_processing_ms(p50/p99/max)={} batch_lag_ms(p50/p99/max)={} \
+             commits={} commit_failures={} commit_ms(p50/p99/max)={} \
+             events_committed={} cursor_buffer_ms(p50/p99/max)={} incidents={}",
+            self.connect_attempts,
+            self.connect_attempts_failed,
+            self.lines_received,
+            self.bytes_received,
+            self.batches_received,
+            self.current_workers,
+            self.events_processed,
+            self.events_skipped,
+            self.batch_processing.quantiles(),
+            self.batch_lag.quantiles(),
+            self.commit_attempts,
+            self.commit_attempts_failed,
+            self.commit_latency.quantiles(),
+            self.events_committed,
+            self.cursor_buffer_time.quantiles(),
+            self.incidents,
+        );
+        summary
+    }
+}
+
+impl MetricsCollector for LoggingMetricsCollector {
+    fn streaming_connect_attempt(&self) {
+        self.update(|a| a.connect_attempts += 1);
+    }
+    fn streaming_connect_attempt_failed(&self) {
+        self.update(|a| a.connect_attempts_failed += 1);
+    }
+
+    fn consumer_connected(&self, _attempt_started: Instant) {}
+    fn consumer_connection_lifetime(&self, _connected_since: Instant) {}
+    fn consumer_line_received(&self, bytes: usize) {
+        self.update(|a| {
+            a.lines_received += 1;
+            a.bytes_received += bytes;
+        });
+    }
+    fn consumer_info_line_received(&self, _bytes: usize) {}
+    fn consumer_keep_alive_line_received(&self, _bytes: usize) {}
+    fn consumer_batch_line_received(&self, _bytes: usize) {}
+    fn consumer_batch_received(
+        &self,
+        _ctx: &MetricsContext,
+        _batch_received_at_timestamp: Instant,
+    ) {
+        self.update(|a| a.batches_received += 1);
+    }
+
+    fn dispatcher_batch_received(
+        &self,
+        _ctx: &MetricsContext,
+        _batch_received_at_timestamp: Instant,
+    ) {
+    }
+    fn dispatcher_current_workers(&self, num_workers: usize) {
+        self.update(|a| a.current_workers = num_workers);
+    }
+
+    fn worker_worker_started(&self) {}
+    fn worker_worker_stopped(&self) {}
+    fn worker_batch_received(&self, _ctx: &MetricsContext, batch_received_at_timestamp: Instant) {
+        self.update(|a| a.batch_lag.since(batch_received_at_timestamp));
+    }
+    fn worker_batch_size_bytes(&self, _ctx: &MetricsContext, _bytes: usize) {}
+    fn worker_batch_processed(&self, _ctx: &MetricsContext, started: Instant) {
+        self.update(|a| a.batch_processing.since(started));
+    }
+    fn worker_events_in_same_batch_processed(&self, _ctx: &MetricsContext, n: usize) {
+        self.update(|a| a.events_processed += n);
+    }
+    fn worker_events_skipped(&self, _ctx: &MetricsContext, n: usize) {
+        self.update(|a| a.events_skipped += n);
+    }
+
+    fn committer_batch_received(
+        &self,
+        _ctx: &MetricsContext,
+        _batch_received_at_timestamp: Instant,
+    ) {
+    }
+    fn committer_cursor_commit_attempt(
+        &self,
+        _ctx: &MetricsContext,
+        _commit_attempt_started: Instant,
+    ) {
+        self.update(|a| a.commit_attempts += 1);
+    }
+    fn committer_cursor_committed(&self, _ctx: &MetricsContext, commit_attempt_started: Instant) {
+        self.update(|a| a.commit_latency.since(commit_attempt_started));
+    }
+    fn committer_cursor_commit_failed(
+        &self,
+        _ctx: &MetricsContext,
+        _commit_attempt_started: Instant,
+    ) {
+        self.update(|a| a.commit_attempts_failed += 1);
+    }
+    fn committer_batches_committed(&self, _ctx: &MetricsContext, _n: usize) {}
+    fn committer_events_committed(&self, _ctx: &MetricsContext, n: usize) {
+        self.update(|a| a.events_committed += n);
+    }
+    fn committer_first_cursor_age_on_commit(&self, _ctx: &MetricsContext, _age: Duration) {}
+    fn committer_last_cursor_age_on_commit(&self, _ctx: &MetricsContext, _age: Duration) {}
+    fn committer_cursor_buffer_time(&self, _ctx: &MetricsContext, time_buffered: Duration) {
+        self.update(|a| a.cursor_buffer_time.observe(time_buffered));
+    }
+    fn committer_time_left_on_commit_until_invalid(
+        &self,
+        _ctx: &MetricsContext,
+        _time_left: Duration,
+    ) {
+    }
+
+    fn other_panicked(&self) {
+        self.update(|a| a.incidents += 1);
+    }
+    fn other_dispatcher_gone(&self) {
+        self.update(|a| a.incidents += 1);
+    }
+    fn other_worker_gone(&self) {
+        self.update(|a| a.incidents += 1);
+    }
+    fn other_committer_gone(&self) {
+        self.update(|a| a.incidents += 1);
+    }
+}
+
+#[test]
+fn summarizes_counters_and_latency_quantiles() {
+    let mut latencies = Latencies::default();
+    for ms in 1..=100 {
+        latencies.observe(Duration::from_millis(ms));
+    }
+    assert_eq!(latencies.quantiles(), "51.0/99.0/100.0");
+    assert_eq!(Latencies::default().quantiles(), "-");
+
+    let collector = LoggingMetricsCollector::new(Duration::from_secs(3600)).unwrap();
+    collector.streaming_connect_attempt();
+    collector.consumer_line_received(10);
+    collector.consumer_line_received(20);
+    collector.dispatcher_current_workers(2);
+
+    let summary = collector.summary();
+    assert!(summary.starts_with("connect_attempts=1 connect_failures=0 lines=2 bytes=30"));
+    assert!(summary.contains("workers=2"));
+    assert!(summary.contains("commit_ms(p50/p99/max)=-"));
+}
+
+#[test]
+fn samples_latencies_of_the_whole_interval() {
+    let mut latencies = Latencies::default();
+    for _ in 0..MAX_SAMPLES {
+        latencies.observe(Duration::from_millis(1));
+    }
+    for _ in 0..3 * MAX_SAMPLES {
+        latencies.observe(Duration::from_millis(100));
+    }
+
+    assert_eq!(latencies.samples.len(), MAX_SAMPLES);
+    assert_eq!(latencies.quantiles(), "100.0/100.0/100.0");
+}
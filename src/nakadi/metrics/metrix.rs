@@ -1,3 +1,5 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
 use metrix::cockpit::*;
@@ -7,6 +9,8 @@ use metrix::TelemetryTransmitterSync;
 use metrix::TimeUnit;
 use metrix::TransmitsTelemetryData;
 
+use super::MetricsContext;
+
 #[derive(Clone, PartialEq, Eq)]
 enum OtherMetrics {
     Panicked,
@@ -46,6 +50,7 @@ enum WorkerMetrics {
     BatchSizeInBytes,
     BatchProcessed,
     EventsProcessed,
+    EventsSkipped,
 }
 
 #[derive(Clone, PartialEq, Eq)]
@@ -62,8 +67,57 @@ enum CommitterMetrics {
     TimeLeftUntilInvalid,
 }
 
+#[derive(Clone, PartialEq, Eq)]
+enum PartitionMetric {
+    BatchReceived,
+    BatchSizeInBytes,
+    BatchProcessed,
+    EventsProcessed,
+    EventsSkipped,
+    FirstCursorAgeOnCommit,
+    LastCursorAgeOnCommit,
+    CursorBufferTime,
+    TimeLeftUntilInvalid,
+}
+
+/// A metric of a single partition.
+///
+/// The panels of a partition only match the labels of their partition.
+#[derive(Clone, PartialEq, Eq)]
+struct PartitionMetrics {
+    partition: String,
+    metric: PartitionMetric,
+}
+
+/// The default maximum number of partitions for which panels are created
+const DEFAULT_PARTITION_LIMIT: usize = 64;
+
+#[derive(Clone, PartialEq, Eq)]
+enum PublisherMetrics {
+    Attempt,
+    BytesSent,
+    Retry,
+    Responded,
+    EventsPublished,
+    PartialSuccess,
+    EventsNotPublished,
+    FailedWithoutResponse,
+    FailedUnauthorized,
+    FailedForbidden,
+    FailedUnprocessableEntity,
+    FailedServerError,
+    FailedOther,
+}
+
 /// A `MetricsCollector` that works with the [`metrix`](https://crates.io/crates/metrix)
 ///  library
+///
+/// Metrics concerning a single partition are also broken down by partition.
+/// The panels of a partition are in the cockpit `<event_type>-<partition>`
+/// of the processor `partitions` and are created once the first metric
+/// of the partition is collected. To bound the number of panels this is
+/// done for at most 64 partitions unless configured otherwise with
+/// `partition_limit`.
 #[derive(Clone)]
 pub struct MetrixCollector {
     connector: TelemetryTransmitterSync<ConnectorMetrics>,
@@ -71,7 +125,10 @@ pub struct MetrixCollector {
     dispatcher: TelemetryTransmitterSync<DispatcherMetrics>,
     worker: TelemetryTransmitterSync<WorkerMetrics>,
     committer: TelemetryTransmitterSync<CommitterMetrics>,
+    partitions: TelemetryTransmitterSync<PartitionMetrics>,
     other: TelemetryTransmitterSync<OtherMetrics>,
+    known_partitions: Arc<Mutex<HashSet<String>>>,
+    partition_limit: usize,
 }
 
 impl MetrixCollector {
@@ -86,6 +143,7 @@ impl MetrixCollector {
         let (dispatcher_tx, dispatcher_rx) = create_dispatcher_metrics();
         let (worker_tx, worker_rx) = create_worker_metrics();
         let (committer_tx, committer_rx) = create_committer_metrics();
+        let (partitions_tx, partitions_rx) = TelemetryProcessor::new_pair("partitions");
         let (other_tx, other_rx) = create_other_metrics();
 
         add_metrics_to.add_processor(connector_rx);
@@ -93,6 +151,7 @@ impl MetrixCollector {
         add_metrics_to.add_processor(dispatcher_rx);
         add_metrics_to.add_processor(worker_rx);
         add_metrics_to.add_processor(committer_rx);
+        add_metrics_to.add_processor(partitions_rx);
         add_metrics_to.add_processor(other_rx);
 
         MetrixCollector {
@@ -101,9 +160,49 @@ impl MetrixCollector {
             dispatcher: dispatcher_tx,
             worker: worker_tx,
             committer: committer_tx,
+            partitions: partitions_tx.synced(),
             other: other_tx,
+            known_partitions: Arc::new(Mutex::new(HashSet::new())),
+            partition_limit: DEFAULT_PARTITION_LIMIT,
         }
     }
+
+    /// Create panels for at most `partition_limit` partitions.
+    ///
+    /// Metrics of further partitions are only collected in the
+    /// panels of all partitions. The default is 64.
+    pub fn partition_limit(mut self, partition_limit: usize) -> MetrixCollector {
+        self.partition_limit = partition_limit;
+        self
+    }
+
+    /// The label of `metric` for the partition of `ctx`.
+    ///
+    /// Creates the cockpit of the partition if the partition is new.
+    /// Returns `None` if `ctx` does not concern a single partition or
+    /// the partition limit has been reached.
+    fn partition_label(
+        &self,
+        ctx: &MetricsContext,
+        metric: PartitionMetric,
+    ) -> Option<PartitionMetrics> {
+        let partition = match (ctx.event_type, ctx.partition) {
+            (Some(event_type), Some(partition)) => format!("{}-{}", event_type, partition),
+            _ => return None,
+        };
+
+        let mut known_partitions = self.known_partitions.lock().unwrap();
+        if !known_partitions.contains(&partition) {
+            if known_partitions.len() >= self.partition_limit {
+                return None;
+            }
+            self.partitions
+                .add_cockpit(create_partition_cockpit(&partition));
+            known_partitions.insert(partition.clone());
+        }
+
+        Some(PartitionMetrics { partition, metric })
+    }
 }
 
 impl super::MetricsCollector for MetrixCollector {
@@ -140,12 +239,16 @@ impl super::MetricsCollector for MetrixCollector {
         self.consumer
             .observed_one_value_now(ConsumerMetrics::BatchLineReceived, bytes as u64);
     }
-    fn consumer_batch_received(&self, batch_received_at_timestamp: Instant) {
+    fn consumer_batch_received(&self, _ctx: &MetricsContext, batch_received_at_timestamp: Instant) {
         self.consumer
             .measure_time(ConsumerMetrics::BatchReceived, batch_received_at_timestamp);
     }
 
-    fn dispatcher_batch_received(&self, batch_received_at_timestamp: Instant) {
+    fn dispatcher_batch_received(
+        &self,
+        _ctx: &MetricsContext,
+        batch_received_at_timestamp: Instant,
+    ) {
         self.dispatcher.measure_time(
             DispatcherMetrics::BatchReceived,
             batch_received_at_timestamp,
@@ -156,9 +259,13 @@ impl super::MetricsCollector for MetrixCollector {
             .observed_one_value_now(DispatcherMetrics::NumWorkers, num_workers as u64);
     }
 
-    fn worker_batch_received(&self, batch_received_at_timestamp: Instant) {
+    fn worker_batch_received(&self, ctx: &MetricsContext, batch_received_at_timestamp: Instant) {
         self.worker
             .measure_time(WorkerMetrics::BatchReceived, batch_received_at_timestamp);
+        if let Some(label) = self.partition_label(ctx, PartitionMetric::BatchReceived) {
+            self.partitions
+                .measure_time(label, batch_received_at_timestamp);
+        }
     }
     fn worker_worker_started(&self) {
         self.worker.observed_one_now(WorkerMetrics::WorkerStarted);
@@ -166,66 +273,113 @@ impl super::MetricsCollector for MetrixCollector {
     fn worker_worker_stopped(&self) {
         self.worker.observed_one_now(WorkerMetrics::WorkerStopped);
     }
-    fn worker_batch_size_bytes(&self, bytes: usize) {
+    fn worker_batch_size_bytes(&self, ctx: &MetricsContext, bytes: usize) {
         self.worker
             .observed_one_value_now(WorkerMetrics::BatchSizeInBytes, bytes as u64);
+        if let Some(label) = self.partition_label(ctx, PartitionMetric::BatchSizeInBytes) {
+            self.partitions.observed_one_value_now(label, bytes as u64);
+        }
     }
-    fn worker_batch_processed(&self, started: Instant) {
+    fn worker_batch_processed(&self, ctx: &MetricsContext, started: Instant) {
         self.worker
             .measure_time(WorkerMetrics::BatchProcessed, started);
+        if let Some(label) = self.partition_label(ctx, PartitionMetric::BatchProcessed) {
+            self.partitions.measure_time(label, started);
+        }
     }
-    fn worker_events_in_same_batch_processed(&self, n: usize) {
+    fn worker_events_in_same_batch_processed(&self, ctx: &MetricsContext, n: usize) {
         self.worker
             .observed_one_value_now(WorkerMetrics::EventsProcessed, n as u64);
+        if let Some(label) = self.partition_label(ctx, PartitionMetric::EventsProcessed) {
+            self.partitions.observed_one_value_now(label, n as u64);
+        }
+    }
+    fn worker_events_skipped(&self, ctx: &MetricsContext, n: usize) {
+        if n > 0 {
+            self.worker
+                .observed_one_value_now(WorkerMetrics::EventsSkipped, n as u64);
+            if let Some(label) = self.partition_label(ctx, PartitionMetric::EventsSkipped) {
+                self.partitions.observed_one_value_now(label, n as u64);
+            }
+        }
     }
 
-    fn committer_batch_received(&self, batch_received_at_timestamp: Instant) {
+    fn committer_batch_received(
+        &self,
+        _ctx: &MetricsContext,
+        batch_received_at_timestamp: Instant,
+    ) {
         self.committer
             .measure_time(CommitterMetrics::BatchReceived, batch_received_at_timestamp);
     }
-    fn committer_cursor_committed(&self, commit_attempt_started: Instant) {
+    fn committer_cursor_committed(&self, _ctx: &MetricsContext, commit_attempt_started: Instant) {
         self.committer
             .measure_time(CommitterMetrics::CursorCommitted, commit_attempt_started);
     }
-    fn committer_batches_committed(&self, n: usize) {
+    fn committer_batches_committed(&self, _ctx: &MetricsContext, n: usize) {
         if n > 0 {
             self.committer
                 .observed_now(CommitterMetrics::BatchesCommitted, n as u64);
         }
     }
-    fn committer_events_committed(&self, n: usize) {
+    fn committer_events_committed(&self, _ctx: &MetricsContext, n: usize) {
         if n > 0 {
             self.committer
                 .observed_now(CommitterMetrics::EventsCommitted, n as u64);
         }
     }
-    fn committer_cursor_commit_attempt(&self, commit_attempt_started: Instant) {
+    fn committer_cursor_commit_attempt(
+        &self,
+        _ctx: &MetricsContext,
+        commit_attempt_started: Instant,
+    ) {
         self.committer.measure_time(
             CommitterMetrics::CursorCommitAttempt,
             commit_attempt_started,
         );
     }
-    fn committer_cursor_commit_failed(&self, commit_attempt_started: Instant) {
+    fn committer_cursor_commit_failed(
+        &self,
+        _ctx: &MetricsContext,
+        commit_attempt_started: Instant,
+    ) {
         self.committer.measure_time(
             CommitterMetrics::CursorCommitAttemptFailed,
             commit_attempt_started,
         );
     }
-    fn committer_first_cursor_age_on_commit(&self, age: Duration) {
+    fn committer_first_cursor_age_on_commit(&self, ctx: &MetricsContext, age: Duration) {
         self.committer
             .observed_one_duration_now(CommitterMetrics::FirstCursorAgeOnCommit, age);
+        if let Some(label) = self.partition_label(ctx, PartitionMetric::FirstCursorAgeOnCommit) {
+            self.partitions.observed_one_duration_now(label, age);
+        }
     }
-    fn committer_last_cursor_age_on_commit(&self, age: Duration) {
+    fn committer_last_cursor_age_on_commit(&self, ctx: &MetricsContext, age: Duration) {
         self.committer
             .observed_one_duration_now(CommitterMetrics::LastCursorAgeOnCommit, age);
+        if let Some(label) = self.partition_label(ctx, PartitionMetric::LastCursorAgeOnCommit) {
+            self.partitions.observed_one_duration_now(label, age);
+        }
     }
-    fn committer_cursor_buffer_time(&self, time_buffered: Duration) {
+    fn committer_cursor_buffer_time(&self, ctx: &MetricsContext, time_buffered: Duration) {
         self.committer
             .observed_one_duration_now(CommitterMetrics::CursorBufferTime, time_buffered);
+        if let Some(label) = self.partition_label(ctx, PartitionMetric::CursorBufferTime) {
+            self.partitions
+                .observed_one_duration_now(label, time_buffered);
+        }
     }
-    fn committer_time_left_on_commit_until_invalid(&self, time_left: Duration) {
+    fn committer_time_left_on_commit_until_invalid(
+        &self,
+        ctx: &MetricsContext,
+        time_left: Duration,
+    ) {
         self.committer
             .observed_one_duration_now(CommitterMetrics::TimeLeftUntilInvalid, time_left);
+        if let Some(label) = self.partition_label(ctx, PartitionMetric::TimeLeftUntilInvalid) {
+            self.partitions.observed_one_duration_now(label, time_left);
+        }
     }
 
     fn other_panicked(&self) {
@@ -242,6 +396,126 @@ impl super::MetricsCollector for MetrixCollector {
     }
 }
 
+/// A `PublisherMetricsCollector` that works with the
+/// [`metrix`](https://crates.io/crates/metrix) library
+#[derive(Clone)]
+pub struct MetrixPublisherCollector {
+    publisher: TelemetryTransmitterSync<PublisherMetrics>,
+}
+
+impl MetrixPublisherCollector {
+    /// Creates a new collector that
+    /// is attached to `add_metrics_to`.
+    pub fn new<T>(add_metrics_to: &mut T) -> MetrixPublisherCollector
+    where
+        T: AggregatesProcessors,
+    {
+        let (publisher_tx, publisher_rx) = create_publisher_metrics();
+
+        add_metrics_to.add_processor(publisher_rx);
+
+        MetrixPublisherCollector {
+            publisher: publisher_tx,
+        }
+    }
+}
+
+impl super::PublisherMetricsCollector for MetrixPublisherCollector {
+    fn publisher_attempt(&self, _event_type: &str, num_events: usize, bytes: usize) {
+        self.publisher
+            .observed_one_value_now(PublisherMetrics::Attempt, num_events as u64);
+        self.publisher
+            .observed_one_value_now(PublisherMetrics::BytesSent, bytes as u64);
+    }
+    fn publisher_retry(&self, _event_type: &str) {
+        self.publisher.observed_one_now(PublisherMetrics::Retry);
+    }
+    fn publisher_responded(&self, _event_type: &str, attempt_started: Instant) {
+        self.publisher
+            .measure_time(PublisherMetrics::Responded, attempt_started);
+    }
+    fn publisher_events_published(&self, _event_type: &str, n: usize) {
+        self.publisher
+            .observed_one_value_now(PublisherMetrics::EventsPublished, n as u64);
+    }
+    fn publisher_partial_success(&self, _event_type: &str, published: usize, not_published: usize) {
+        self.publisher
+            .observed_one_value_now(PublisherMetrics::PartialSuccess, published as u64);
+        self.publisher
+            .observed_one_value_now(PublisherMetrics::EventsNotPublished, not_published as u64);
+    }
+    fn publisher_failed(&self, _event_type: &str, status: Option<u16>) {
+        let label = match status {
+            None => PublisherMetrics::FailedWithoutResponse,
+            Some(401) => PublisherMetrics::FailedUnauthorized,
+            Some(403) => PublisherMetrics::FailedForbidden,
+            Some(422) => PublisherMetrics::FailedUnprocessableEntity,
+            Some(status) if status >= 500 => PublisherMetrics::FailedServerError,
+            Some(_) => PublisherMetrics::FailedOther,
+        };
+        self.publisher.observed_one_now(label);
+    }
+}
+
+fn create_publisher_metrics() -> (
+    TelemetryTransmitterSync<PublisherMetrics>,
+    TelemetryProcessor<PublisherMetrics>,
+) {
+    let mut cockpit: Cockpit<PublisherMetrics> = Cockpit::without_name();
+
+    let attempts_panel = Panel::named(PublisherMetrics::Attempt, "attempts");
+    add_counting_and_distribution_instruments_to_cockpit(attempts_panel, &mut cockpit);
+
+    let mut bytes_sent_panel = Panel::named(PublisherMetrics::BytesSent, "bytes_sent");
+    bytes_sent_panel.add_instrument(ValueMeter::new_with_defaults("bytes_per_second"));
+    bytes_sent_panel.add_instrument(Histogram::new_with_defaults("bytes_distribution"));
+    cockpit.add_panel(bytes_sent_panel);
+
+    let retries_panel = Panel::named(PublisherMetrics::Retry, "retries");
+    add_counting_instruments_to_cockpit(retries_panel, &mut cockpit);
+
+    let responded_panel = Panel::named(PublisherMetrics::Responded, "responses");
+    add_counting_and_time_ms_instruments_to_cockpit(responded_panel, &mut cockpit);
+
+    let mut events_published_panel =
+        Panel::named(PublisherMetrics::EventsPublished, "events_published");
+    events_published_panel.add_instrument(ValueMeter::new_with_defaults("per_second"));
+    events_published_panel.add_instrument(Histogram::new_with_defaults("batch_size"));
+    cockpit.add_panel(events_published_panel);
+
+    let partial_successes_panel =
+        Panel::named(PublisherMetrics::PartialSuccess, "partial_successes");
+    add_counting_and_distribution_instruments_to_cockpit(partial_successes_panel, &mut cockpit);
+
+    let mut events_not_published_panel =
+        Panel::named(PublisherMetrics::EventsNotPublished, "events_not_published");
+    events_not_published_panel.add_instrument(ValueMeter::new_with_defaults("per_second"));
+    cockpit.add_panel(events_not_published_panel);
+
+    for (label, name) in &[
+        (
+            PublisherMetrics::FailedWithoutResponse,
+            "failed_without_response",
+        ),
+        (PublisherMetrics::FailedUnauthorized, "failed_unauthorized"),
+        (PublisherMetrics::FailedForbidden, "failed_forbidden"),
+        (
+            PublisherMetrics::FailedUnprocessableEntity,
+            "failed_unprocessable_entity",
+        ),
+        (PublisherMetrics::FailedServerError, "failed_server_error"),
+        (PublisherMetrics::FailedOther, "failed_other"),
+    ] {
+        add_counting_instruments_to_cockpit(Panel::named(label.clone(), *name), &mut cockpit);
+    }
+
+    let (tx, rx) = TelemetryProcessor::new_pair("publisher");
+
+    tx.add_cockpit(cockpit);
+
+    (tx.synced(), rx)
+}
+
 fn create_other_metrics() -> (
     TelemetryTransmitterSync<OtherMetrics>,
     TelemetryProcessor<OtherMetrics>,
@@ -424,6 +698,10 @@ fn create_worker_metrics() -> (
     events_processed_panel.set_histogram(Histogram::new_with_defaults("batch_size"));
     cockpit.add_panel(events_processed_panel);
 
+    let mut events_skipped_panel = Panel::named(WorkerMetrics::EventsSkipped, "events_skipped");
+    events_skipped_panel.add_instrument(ValueMeter::new_with_defaults("per_second"));
+    cockpit.add_panel(events_skipped_panel);
+
     let mut worker_started_panel = Panel::new(WorkerMetrics::WorkerStarted);
     let mut tracker = LastOccurrenceTracker::new_with_defaults("worker_started");
     tracker.set_title("Worker started");
@@ -531,6 +809,65 @@ fn create_committer_metrics() -> (
     (tx.synced(), rx)
 }
 
+fn create_partition_cockpit(partition: &str) -> Cockpit<PartitionMetrics> {
+    let label = |metric| PartitionMetrics {
+        partition: partition.to_string(),
+        metric,
+    };
+    let mut cockpit = Cockpit::new(partition.to_string());
+
+    let mut batches_received_panel =
+        Panel::named(label(PartitionMetric::BatchReceived), "batches_received");
+    batches_received_panel.set_counter(Counter::new_with_defaults("count"));
+    batches_received_panel.set_meter(Meter::new_with_defaults("per_second"));
+    batches_received_panel.set_histogram(
+        Histogram::new_with_defaults("elapsed_us").display_time_unit(TimeUnit::Microseconds),
+    );
+    cockpit.add_panel(batches_received_panel);
+
+    let mut event_bytes_panel =
+        Panel::named(label(PartitionMetric::BatchSizeInBytes), "incoming_batches");
+    event_bytes_panel.add_instrument(ValueMeter::new_with_defaults("bytes_per_second"));
+    event_bytes_panel.set_histogram(Histogram::new_with_defaults("bytes_distribution"));
+    cockpit.add_panel(event_bytes_panel);
+
+    let batches_processed_panel =
+        Panel::named(label(PartitionMetric::BatchProcessed), "batches_processed");
+    add_counting_and_time_us_instruments_to_cockpit(batches_processed_panel, &mut cockpit);
+
+    let mut events_processed_panel =
+        Panel::named(label(PartitionMetric::EventsProcessed), "events_processed");
+    events_processed_panel.add_instrument(ValueMeter::new_with_defaults("per_second"));
+    events_processed_panel.set_histogram(Histogram::new_with_defaults("batch_size"));
+    cockpit.add_panel(events_processed_panel);
+
+    let mut events_skipped_panel =
+        Panel::named(label(PartitionMetric::EventsSkipped), "events_skipped");
+    events_skipped_panel.add_instrument(ValueMeter::new_with_defaults("per_second"));
+    cockpit.add_panel(events_skipped_panel);
+
+    for (metric, name) in &[
+        (
+            PartitionMetric::FirstCursorAgeOnCommit,
+            "first_cursor_age_on_commit",
+        ),
+        (
+            PartitionMetric::LastCursorAgeOnCommit,
+            "last_cursor_age_on_commit",
+        ),
+        (PartitionMetric::CursorBufferTime, "cursor_buffer_time"),
+        (
+            PartitionMetric::TimeLeftUntilInvalid,
+            "cursor_time_left_until_invalid",
+        ),
+    ] {
+        let panel = Panel::named(label(metric.clone()), *name);
+        add_us_histogram_instruments_to_cockpit(panel, &mut cockpit);
+    }
+
+    cockpit
+}
+
 fn add_line_instruments_to_cockpit<L>(mut panel: Panel<L>, cockpit: &mut Cockpit<L>)
 where
     L: Clone + Eq + Send + 'static,
@@ -608,3 +945,40 @@ where
     );
     cockpit.add_panel(panel);
 }
+
+#[test]
+fn creates_panels_for_a_limited_number_of_partitions() {
+    use std::thread;
+
+    use metrix::driver::TelemetryDriver;
+
+    use super::MetricsCollector;
+    use crate::nakadi::model::SubscriptionId;
+
+    let mut driver = TelemetryDriver::default();
+    let collector = MetrixCollector::new(&mut driver).partition_limit(2);
+    let subscription_id = SubscriptionId::new("subscription");
+
+    for partition in &["0", "1", "2"] {
+        let ctx = MetricsContext::for_partition(&subscription_id, "test_event", partition);
+        collector.worker_batch_processed(&ctx, Instant::now());
+    }
+    collector.worker_batch_processed(
+        &MetricsContext::for_subscription(&subscription_id),
+        Instant::now(),
+    );
+
+    let has_count = |path: &str| {
+        let snapshot = driver.snapshot(false).unwrap();
+        let found = snapshot.find(&format!("{}/count", path)).opt().is_some();
+        found
+    };
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !has_count("workers/batches_processed") && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    assert!(has_count("partitions/test_event-0/batches_processed"));
+    assert!(has_count("partitions/test_event-1/batches_processed"));
+    assert!(!has_count("partitions/test_event-2/batches_processed"));
+}
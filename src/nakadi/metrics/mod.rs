@@ -13,16 +13,97 @@
 //! for `MetricsCollector` using [metrix](https://crates.io/crates/metrix)
 //! is provided and as are constructor
 //! functions for Nakadion.
+//!
+//! `FanOutMetricsCollector` forwards all metrics to several
+//! collectors and `LoggingMetricsCollector` periodically logs
+//! a summary of the metrics.
+//!
+//! Publishers report to a `PublisherMetricsCollector`. With the
+//! feature `metrix` enabled `MetrixPublisherCollector` implements it.
+//!
+//! When the feature `prometheus` is enabled a `PrometheusCollector`
+//! registering its metrics with a
+//! [prometheus](https://crates.io/crates/prometheus) `Registry`
+//! is provided.
 use std::time::{Duration, Instant};
 
+use crate::nakadi::batch::BatchLine;
+use crate::nakadi::model::SubscriptionId;
+
+pub use self::fan_out::FanOutMetricsCollector;
+pub use self::logging::LoggingMetricsCollector;
 #[cfg(feature = "metrix")]
-pub use self::metrix::MetrixCollector;
+pub use self::metrix::{MetrixCollector, MetrixPublisherCollector};
 
+#[cfg(feature = "prometheus")]
+pub use self::prometheus::{render as render_prometheus, PrometheusCollector};
+
+mod fan_out;
+mod logging;
 #[cfg(feature = "metrix")]
 mod metrix;
+#[cfg(feature = "prometheus")]
+mod prometheus;
+
+/// What a metric passed to a `MetricsCollector` was collected for.
+///
+/// Metrics concerning a single batch or cursor carry the event type
+/// and the partition so that they can be broken down by partition.
+/// Metrics concerning a whole commit only carry the subscription.
+#[derive(Debug, Clone, Copy)]
+pub struct MetricsContext<'a> {
+    /// The subscription the metric was collected for
+    pub subscription_id: &'a SubscriptionId,
+    /// The event type if the metric concerns a single event type
+    pub event_type: Option<&'a str>,
+    /// The partition if the metric concerns a single partition
+    pub partition: Option<&'a str>,
+}
+
+impl<'a> MetricsContext<'a> {
+    /// A context for metrics concerning the whole subscription
+    pub fn for_subscription(subscription_id: &'a SubscriptionId) -> MetricsContext<'a> {
+        MetricsContext {
+            subscription_id,
+            event_type: None,
+            partition: None,
+        }
+    }
+
+    /// A context for metrics concerning a partition of an event type
+    pub fn for_partition(
+        subscription_id: &'a SubscriptionId,
+        event_type: &'a str,
+        partition: &'a str,
+    ) -> MetricsContext<'a> {
+        MetricsContext {
+            subscription_id,
+            event_type: Some(event_type),
+            partition: Some(partition),
+        }
+    }
+
+    /// A context for metrics concerning the given batch.
+    ///
+    /// The event type or the partition are omitted if they are not valid UTF-8.
+    pub fn for_batch(
+        subscription_id: &'a SubscriptionId,
+        batch_line: &'a BatchLine,
+    ) -> MetricsContext<'a> {
+        MetricsContext {
+            subscription_id,
+            event_type: batch_line.event_type_str().ok(),
+            partition: batch_line.partition_str().ok(),
+        }
+    }
+}
 
 /// An interface for a `Nakadion` that `Nakadion` can use to notify
 /// on changing values and states.
+///
+/// Callbacks concerning batches and commits receive a `MetricsContext`
+/// identifying the subscription and, where the metric concerns a
+/// single batch or cursor, the event type and the partition.
 pub trait MetricsCollector {
     /// A connect attempt for streaming has been made.
     fn streaming_connect_attempt(&self);
@@ -35,7 +116,7 @@ pub trait MetricsCollector {
     /// # Parameters
     ///
     /// * attempt_started: The timestampt when the attempt
-    /// to establish a connection was started
+    ///   to establish a connection was started
     fn consumer_connected(&self, attempt_started: Instant);
 
     /// The instant of when the connection that just shut
@@ -52,10 +133,10 @@ pub trait MetricsCollector {
     /// A line of events with the given number of bytes was reveived.
     fn consumer_batch_line_received(&self, bytes: usize);
     /// Time elapsed from receiving the batch from `Nakadi`.
-    fn consumer_batch_received(&self, batch_received_at_timestamp: Instant);
+    fn consumer_batch_received(&self, ctx: &MetricsContext, batch_received_at_timestamp: Instant);
 
     /// Time elapsed from receiving the batch from `Nakadi`.
-    fn dispatcher_batch_received(&self, batch_received_at_timestamp: Instant);
+    fn dispatcher_batch_received(&self, ctx: &MetricsContext, batch_received_at_timestamp: Instant);
     /// The number of workers currently processing partitions.
     fn dispatcher_current_workers(&self, num_workers: usize);
 
@@ -64,43 +145,56 @@ pub trait MetricsCollector {
     /// A worker was stopped
     fn worker_worker_stopped(&self);
     /// Time elapsed from receiving the batch from `Nakadi`.
-    fn worker_batch_received(&self, batch_received_at_timestamp: Instant);
+    fn worker_batch_received(&self, ctx: &MetricsContext, batch_received_at_timestamp: Instant);
     /// Events with a comined legth of `bytes` bytes have been
     /// received.
-    fn worker_batch_size_bytes(&self, bytes: usize);
+    fn worker_batch_size_bytes(&self, ctx: &MetricsContext, bytes: usize);
     /// A batch has been processed where processing was started at 'started`.
-    fn worker_batch_processed(&self, started: Instant);
+    fn worker_batch_processed(&self, ctx: &MetricsContext, started: Instant);
     /// The worker processed `n` events of the same batch.
-    fn worker_events_in_same_batch_processed(&self, n: usize);
+    fn worker_events_in_same_batch_processed(&self, ctx: &MetricsContext, n: usize);
+    /// The worker skipped `n` events of the same batch
+    /// because they could not be deserialized.
+    ///
+    /// The default implementation does nothing.
+    fn worker_events_skipped(&self, _ctx: &MetricsContext, _n: usize) {}
 
     /// Time elapsed from receiving the batch from `Nakadi`.
-    fn committer_batch_received(&self, batch_received_at_timestamp: Instant);
+    fn committer_batch_received(&self, ctx: &MetricsContext, batch_received_at_timestamp: Instant);
     /// A commit attempt has been made. It was started at `commit_attempt_started`.
     /// No difference is made between success and failure.
-    fn committer_cursor_commit_attempt(&self, commit_attempt_started: Instant);
+    fn committer_cursor_commit_attempt(
+        &self,
+        ctx: &MetricsContext,
+        commit_attempt_started: Instant,
+    );
     /// A cursor has been committed and the instant when the commit attempt was started
     /// is given.
-    fn committer_cursor_committed(&self, commit_attempt_started: Instant);
+    fn committer_cursor_committed(&self, ctx: &MetricsContext, commit_attempt_started: Instant);
     /// A cursor has not been committed and the instant when the commit attempt was started
     /// is given.
-    fn committer_cursor_commit_failed(&self, commit_attempt_started: Instant);
+    fn committer_cursor_commit_failed(&self, ctx: &MetricsContext, commit_attempt_started: Instant);
     /// The number of batches that have been committed with the last cursor.
-    fn committer_batches_committed(&self, n: usize);
+    fn committer_batches_committed(&self, ctx: &MetricsContext, n: usize);
     /// The number of events that have been committed with the last cursor.
-    fn committer_events_committed(&self, n: usize);
+    fn committer_events_committed(&self, ctx: &MetricsContext, n: usize);
     /// How old is this cursor first(oldest) that is committed with the current cursor?
     /// `received_at` is the timestamp when `Nakadion` received the batch
-    fn committer_first_cursor_age_on_commit(&self, age: Duration);
+    fn committer_first_cursor_age_on_commit(&self, ctx: &MetricsContext, age: Duration);
     /// How old is this cursor that is currently committed?
     /// This is the cursor that also commits the previously buffered cursors.
     /// `received_at` is the timestamp when `Nakadion` received the batch
-    fn committer_last_cursor_age_on_commit(&self, age: Duration);
+    fn committer_last_cursor_age_on_commit(&self, ctx: &MetricsContext, age: Duration);
     /// How much time has elapsed from receiving intial cursor to be committed
     /// until that cursor finally got committed?
-    fn committer_cursor_buffer_time(&self, time_buffered: Duration);
+    fn committer_cursor_buffer_time(&self, ctx: &MetricsContext, time_buffered: Duration);
     /// The time left when committing the cursor until the stream would have become
     /// invalid.
-    fn committer_time_left_on_commit_until_invalid(&self, time_left: Duration);
+    fn committer_time_left_on_commit_until_invalid(
+        &self,
+        ctx: &MetricsContext,
+        time_left: Duration,
+    );
     /// A panic occured somewhere.
     fn other_panicked(&self);
     fn other_dispatcher_gone(&self);
@@ -122,31 +216,123 @@ impl MetricsCollector for DevNullMetricsCollector {
     fn consumer_info_line_received(&self, _bytes: usize) {}
     fn consumer_keep_alive_line_received(&self, _bytes: usize) {}
     fn consumer_batch_line_received(&self, _bytes: usize) {}
-    fn consumer_batch_received(&self, _batch_received_at_timestamp: Instant) {}
+    fn consumer_batch_received(
+        &self,
+        _ctx: &MetricsContext,
+        _batch_received_at_timestamp: Instant,
+    ) {
+    }
 
-    fn dispatcher_batch_received(&self, _batch_received_at_timestamp: Instant) {}
+    fn dispatcher_batch_received(
+        &self,
+        _ctx: &MetricsContext,
+        _batch_received_at_timestamp: Instant,
+    ) {
+    }
     fn dispatcher_current_workers(&self, _num_workers: usize) {}
 
-    fn worker_batch_received(&self, _batch_received_at_timestamp: Instant) {}
+    fn worker_batch_received(&self, _ctx: &MetricsContext, _batch_received_at_timestamp: Instant) {}
     fn worker_worker_started(&self) {}
     fn worker_worker_stopped(&self) {}
-    fn worker_batch_size_bytes(&self, _bytes: usize) {}
-    fn worker_batch_processed(&self, _started: Instant) {}
-    fn worker_events_in_same_batch_processed(&self, _n: usize) {}
-
-    fn committer_batch_received(&self, _batch_received_at_timestamp: Instant) {}
-    fn committer_cursor_committed(&self, _commit_attempt_started: Instant) {}
-    fn committer_batches_committed(&self, _n: usize) {}
-    fn committer_events_committed(&self, _n: usize) {}
-    fn committer_cursor_commit_attempt(&self, _commit_attempt_started: Instant) {}
-    fn committer_cursor_commit_failed(&self, _commit_attempt_started: Instant) {}
-    fn committer_first_cursor_age_on_commit(&self, _age: Duration) {}
-    fn committer_last_cursor_age_on_commit(&self, _age: Duration) {}
-    fn committer_cursor_buffer_time(&self, _time_buffered: Duration) {}
-    fn committer_time_left_on_commit_until_invalid(&self, _time_left: Duration) {}
+    fn worker_batch_size_bytes(&self, _ctx: &MetricsContext, _bytes: usize) {}
+    fn worker_batch_processed(&self, _ctx: &MetricsContext, _started: Instant) {}
+    fn worker_events_in_same_batch_processed(&self, _ctx: &MetricsContext, _n: usize) {}
+    fn worker_events_skipped(&self, _ctx: &MetricsContext, _n: usize) {}
+
+    fn committer_batch_received(
+        &self,
+        _ctx: &MetricsContext,
+        _batch_received_at_timestamp: Instant,
+    ) {
+    }
+    fn committer_cursor_committed(&self, _ctx: &MetricsContext, _commit_attempt_started: Instant) {}
+    fn committer_batches_committed(&self, _ctx: &MetricsContext, _n: usize) {}
+    fn committer_events_committed(&self, _ctx: &MetricsContext, _n: usize) {}
+    fn committer_cursor_commit_attempt(
+        &self,
+        _ctx: &MetricsContext,
+        _commit_attempt_started: Instant,
+    ) {
+    }
+    fn committer_cursor_commit_failed(
+        &self,
+        _ctx: &MetricsContext,
+        _commit_attempt_started: Instant,
+    ) {
+    }
+    fn committer_first_cursor_age_on_commit(&self, _ctx: &MetricsContext, _age: Duration) {}
+    fn committer_last_cursor_age_on_commit(&self, _ctx: &MetricsContext, _age: Duration) {}
+    fn committer_cursor_buffer_time(&self, _ctx: &MetricsContext, _time_buffered: Duration) {}
+    fn committer_time_left_on_commit_until_invalid(
+        &self,
+        _ctx: &MetricsContext,
+        _time_left: Duration,
+    ) {
+    }
 
     fn other_panicked(&self) {}
     fn other_dispatcher_gone(&self) {}
     fn other_worker_gone(&self) {}
     fn other_committer_gone(&self) {}
 }
+
+/// An interface publishers use to notify on publishing events.
+///
+/// Retries of the publishers each cause a new attempt so that
+/// the callbacks for a single attempt may be invoked multiple
+/// times when publishing a single batch of events.
+pub trait PublisherMetricsCollector {
+    /// A request publishing `num_events` events with a
+    /// body of `bytes` bytes is about to be sent.
+    ///
+    /// `num_events` is 0 for events published via `publish_raw` if
+    /// partial successes are not retried since the events are not parsed then.
+    fn publisher_attempt(&self, event_type: &str, num_events: usize, bytes: usize);
+    /// Publishing is retried because the last attempt failed
+    /// or not all events were published.
+    fn publisher_retry(&self, event_type: &str);
+    /// `Nakadi` responded to a request which was sent at `attempt_started`.
+    fn publisher_responded(&self, event_type: &str, attempt_started: Instant);
+    /// All `n` events of a request were published.
+    fn publisher_events_published(&self, event_type: &str, n: usize);
+    /// Only some events of a request were published.
+    ///
+    /// `not_published` events failed or publishing them was aborted.
+    fn publisher_partial_success(&self, event_type: &str, published: usize, not_published: usize);
+    /// An attempt failed.
+    ///
+    /// `status` is the HTTP status `Nakadi` responded with or `None`
+    /// if no response was received.
+    fn publisher_failed(&self, event_type: &str, status: Option<u16>);
+}
+
+impl PublisherMetricsCollector for DevNullMetricsCollector {
+    fn publisher_attempt(&self, _event_type: &str, _num_events: usize, _bytes: usize) {}
+    fn publisher_retry(&self, _event_type: &str) {}
+    fn publisher_responded(&self, _event_type: &str, _attempt_started: Instant) {}
+    fn publisher_events_published(&self, _event_type: &str, _n: usize) {}
+    fn publisher_partial_success(
+        &self,
+        _event_type: &str,
+        _published: usize,
+        _not_published: usize,
+    ) {
+    }
+    fn publisher_failed(&self, _event_type: &str, _status: Option<u16>) {}
+}
+
+#[test]
+fn metrics_context_for_batch_has_event_type_and_partition() {
+    let batch_line = BatchLine::new(
+        br#"{"cursor":{"partition":"5","offset":"543","event_type":"order.ORDER_RECEIVED","cursor_token":"b75c3102-98a4-4385-a5fd-b96f1d7872f2"},"events":[{}]}"#
+            .to_vec(),
+    )
+    .unwrap();
+    let subscription_id = SubscriptionId::new("subscription");
+
+    let ctx = MetricsContext::for_batch(&subscription_id, &batch_line);
+
+    assert_eq!(ctx.subscription_id.0, "subscription");
+    assert_eq!(ctx.event_type, Some("order.ORDER_RECEIVED"));
+    assert_eq!(ctx.partition, Some("5"));
+}
@@ -0,0 +1,527 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use ::prometheus::{
+    exponential_buckets, Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter,
+    IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+};
+use failure::*;
+
+use crate::nakadi::model::SubscriptionId;
+
+use super::MetricsContext;
+
+const NAMESPACE: &str = "nakadion";
+
+/// Labels of metrics broken down by partition
+const PARTITION_LABELS: &[&str] = &["event_type", "partition"];
+
+/// Buckets in seconds for durations of requests and processing
+const LATENCY_BUCKETS: &[f64] = &[
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Buckets in seconds for the age of cursors and batches
+const AGE_BUCKETS: &[f64] = &[
+    0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0,
+];
+
+struct Metrics {
+    connect_attempts: IntCounter,
+    connect_attempts_failed: IntCounter,
+
+    connect_duration: Histogram,
+    connection_lifetime: Histogram,
+    lines_received: IntCounter,
+    line_bytes_received: IntCounter,
+    lines_received_by_kind: IntCounterVec,
+    line_bytes_received_by_kind: IntCounterVec,
+    consumer_batch_age: Histogram,
+
+    dispatcher_batch_age: Histogram,
+    dispatcher_workers: IntGauge,
+
+    workers_started: IntCounter,
+    workers_stopped: IntCounter,
+    worker_batch_age: HistogramVec,
+    worker_batch_size_bytes: HistogramVec,
+    worker_batch_processing_time: HistogramVec,
+    worker_events_processed: IntCounterVec,
+    worker_events_skipped: IntCounterVec,
+
+    committer_batch_age: Histogram,
+    commit_attempts: IntCounter,
+    commit_attempts_failed: IntCounter,
+    commit_duration: Histogram,
+    batches_committed: IntCounter,
+    events_committed: IntCounter,
+    first_cursor_age_on_commit: HistogramVec,
+    last_cursor_age_on_commit: HistogramVec,
+    cursor_buffer_time: HistogramVec,
+    time_left_until_invalid: HistogramVec,
+
+    other: IntCounterVec,
+}
+
+/// A `MetricsCollector` that registers its metrics with
+/// a [`prometheus`](https://crates.io/crates/prometheus) `Registry`
+///
+/// All metrics are prefixed with `nakadion_` and labeled
+/// with the subscription they were collected for.
+///
+/// This is only available with the `prometheus` feature enabled.
+#[derive(Clone)]
+pub struct PrometheusCollector {
+    registry: Registry,
+    metrics: Arc<Metrics>,
+}
+
+impl PrometheusCollector {
+    /// Creates a new collector registering its metrics with `registry`.
+    ///
+    /// # Errors
+    ///
+    /// The metrics could not be registered. This happens
+    /// if metrics were already registered for the same subscription.
+    pub fn new(
+        registry: &Registry,
+        subscription_id: &SubscriptionId,
+    ) -> Result<PrometheusCollector, Error> {
+        let m = MetricsFactory {
+            registry,
+            subscription: &subscription_id.0,
+        };
+
+        let bytes_buckets = exponential_buckets(256.0, 4.0, 10)?;
+
+        let metrics = Metrics {
+            connect_attempts: m.counter(
+                "streaming_connect_attempts_total",
+                "Attempts to connect to a stream",
+            )?,
+            connect_attempts_failed: m.counter(
+                "streaming_connect_attempts_failed_total",
+                "Failed attempts to connect to a stream",
+            )?,
+
+            connect_duration: m.histogram(
+                "consumer_connect_duration_seconds",
+                "Time from starting to connect until connected",
+                AGE_BUCKETS,
+            )?,
+            connection_lifetime: m.histogram(
+                "consumer_connection_lifetime_seconds",
+                "Time a stream was connected",
+                AGE_BUCKETS,
+            )?,
+            lines_received: m.counter(
+                "consumer_lines_received_total",
+                "Lines received from Nakadi",
+            )?,
+            line_bytes_received: m.counter(
+                "consumer_line_bytes_received_total",
+                "Bytes of lines received from Nakadi",
+            )?,
+            lines_received_by_kind: m.counter_vec(
+                "consumer_lines_received_by_kind_total",
+                "Lines received from Nakadi by kind",
+                "kind",
+            )?,
+            line_bytes_received_by_kind: m.counter_vec(
+                "consumer_line_bytes_received_by_kind_total",
+                "Bytes of lines received from Nakadi by kind",
+                "kind",
+            )?,
+            consumer_batch_age: m.histogram(
+                "consumer_batch_age_seconds",
+                "Age of a batch when it was passed on by the consumer",
+                LATENCY_BUCKETS,
+            )?,
+
+            dispatcher_batch_age: m.histogram(
+                "dispatcher_batch_age_seconds",
+                "Age of a batch when it reached the dispatcher",
+                LATENCY_BUCKETS,
+            )?,
+            dispatcher_workers: m
+                .gauge("dispatcher_workers", "Number of workers of the dispatcher")?,
+
+            workers_started: m.counter("worker_started_total", "Workers started")?,
+            workers_stopped: m.counter("worker_stopped_total", "Workers stopped")?,
+            worker_batch_age: m.partitioned_histogram(
+                "worker_batch_age_seconds",
+                "Age of a batch when it reached a worker",
+                LATENCY_BUCKETS,
+            )?,
+            worker_batch_size_bytes: m.partitioned_histogram(
+                "worker_batch_size_bytes",
+                "Size of the events of a batch",
+                &bytes_buckets,
+            )?,
+            worker_batch_processing_time: m.partitioned_histogram(
+                "worker_batch_processing_seconds",
+                "Time a handler took to process a batch",
+                LATENCY_BUCKETS,
+            )?,
+            worker_events_processed: m.partitioned_counter(
+                "worker_events_processed_total",
+                "Events processed by handlers",
+            )?,
+            worker_events_skipped: m.partitioned_counter(
+                "worker_events_skipped_total",
+                "Events skipped because they could not be deserialized",
+            )?,
+
+            committer_batch_age: m.histogram(
+                "committer_batch_age_seconds",
+                "Age of a batch when its cursor reached the committer",
+                LATENCY_BUCKETS,
+            )?,
+            commit_attempts: m.counter(
+                "committer_commit_attempts_total",
+                "Attempts to commit cursors",
+            )?,
+            commit_attempts_failed: m.counter(
+                "committer_commit_attempts_failed_total",
+                "Failed attempts to commit cursors",
+            )?,
+            commit_duration: m.histogram(
+                "committer_commit_duration_seconds",
+                "Time it took to commit cursors",
+                LATENCY_BUCKETS,
+            )?,
+            batches_committed: m.counter(
+                "committer_batches_committed_total",
+                "Batches whose cursors were committed",
+            )?,
+            events_committed: m.counter(
+                "committer_events_committed_total",
+                "Events whose cursors were committed",
+            )?,
+            first_cursor_age_on_commit: m.partitioned_histogram(
+                "committer_first_cursor_age_on_commit_seconds",
+                "Age of the oldest cursor when committed",
+                AGE_BUCKETS,
+            )?,
+            last_cursor_age_on_commit: m.partitioned_histogram(
+                "committer_last_cursor_age_on_commit_seconds",
+                "Age of the newest cursor when committed",
+                AGE_BUCKETS,
+            )?,
+            cursor_buffer_time: m.partitioned_histogram(
+                "committer_cursor_buffer_seconds",
+                "Time a cursor was buffered before being committed",
+                AGE_BUCKETS,
+            )?,
+            time_left_until_invalid: m.partitioned_histogram(
+                "committer_time_left_until_invalid_seconds",
+                "Time left on commit until a cursor would have been invalid",
+                AGE_BUCKETS,
+            )?,
+
+            other: m.counter_vec(
+                "other_incidents_total",
+                "Panics and components gone unexpectedly",
+                "incident",
+            )?,
+        };
+
+        Ok(PrometheusCollector {
+            registry: registry.clone(),
+            metrics: Arc::new(metrics),
+        })
+    }
+
+    /// Renders all metrics of the `Registry` this collector
+    /// was registered with in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        render(&self.registry)
+    }
+
+    fn line_of_kind_received(&self, kind: &str, bytes: usize) {
+        self.metrics
+            .lines_received_by_kind
+            .with_label_values(&[kind])
+            .inc();
+        self.metrics
+            .line_bytes_received_by_kind
+            .with_label_values(&[kind])
+            .inc_by(bytes as i64);
+    }
+}
+
+/// Renders all metrics of `registry` in the Prometheus text exposition format.
+pub fn render(registry: &Registry) -> String {
+    let mut buffer = Vec::new();
+    if let Err(err) = TextEncoder::new().encode(&registry.gather(), &mut buffer) {
+        warn!("Could not render metrics: {}", err);
+    }
+    String::from_utf8_lossy(&buffer).into_owned()
+}
+
+struct MetricsFactory<'a> {
+    registry: &'a Registry,
+    subscription: &'a str,
+}
+
+impl<'a> MetricsFactory<'a> {
+    fn opts(&self, name: &str, help: &str) -> Opts {
+        Opts::new(name, help)
+            .namespace(NAMESPACE)
+            .const_label("subscription", self.subscription)
+    }
+
+    fn counter(&self, name: &str, help: &str) -> Result<IntCounter, Error> {
+        let counter = IntCounter::with_opts(self.opts(name, help))?;
+        self.registry.register(Box::new(counter.clone()))?;
+        Ok(counter)
+    }
+
+    fn counter_vec(&self, name: &str, help: &str, label: &str) -> Result<IntCounterVec, Error> {
+        let counter = IntCounterVec::new(self.opts(name, help), &[label])?;
+        self.registry.register(Box::new(counter.clone()))?;
+        Ok(counter)
+    }
+
+    fn partitioned_counter(&self, name: &str, help: &str) -> Result<IntCounterVec, Error> {
+        let counter = IntCounterVec::new(self.opts(name, help), PARTITION_LABELS)?;
+        self.registry.register(Box::new(counter.clone()))?;
+        Ok(counter)
+    }
+
+    fn gauge(&self, name: &str, help: &str) -> Result<IntGauge, Error> {
+        let gauge = IntGauge::with_opts(self.opts(name, help))?;
+        self.registry.register(Box::new(gauge.clone()))?;
+        Ok(gauge)
+    }
+
+    fn histogram(&self, name: &str, help: &str, buckets: &[f64]) -> Result<Histogram, Error> {
+        let opts = HistogramOpts::from(self.opts(name, help)).buckets(buckets.to_vec());
+        let histogram = Histogram::with_opts(opts)?;
+        self.registry.register(Box::new(histogram.clone()))?;
+        Ok(histogram)
+    }
+
+    fn partitioned_histogram(
+        &self,
+        name: &str,
+        help: &str,
+        buckets: &[f64],
+    ) -> Result<HistogramVec, Error> {
+        let opts = HistogramOpts::from(self.opts(name, help)).buckets(buckets.to_vec());
+        let histogram = HistogramVec::new(opts, PARTITION_LABELS)?;
+        self.registry.register(Box::new(histogram.clone()))?;
+        Ok(histogram)
+    }
+}
+
+/// The values for `PARTITION_LABELS`. Unknown values are left empty.
+fn partition_labels<'a>(ctx: &MetricsContext<'a>) -> [&'a str; 2] {
+    [ctx.event_type.unwrap_or(""), ctx.partition.unwrap_or("")]
+}
+
+fn seconds_since(instant: Instant) -> f64 {
+    instant.elapsed().as_secs_f64()
+}
+
+impl super::MetricsCollector for PrometheusCollector {
+    fn streaming_connect_attempt(&self) {
+        self.metrics.connect_attempts.inc();
+    }
+    fn streaming_connect_attempt_failed(&self) {
+        self.metrics.connect_attempts_failed.inc();
+    }
+
+    fn consumer_connected(&self, attempt_started: Instant) {
+        self.metrics
+            .connect_duration
+            .observe(seconds_since(attempt_started));
+    }
+    fn consumer_connection_lifetime(&self, connected_since: Instant) {
+        self.metrics
+            .connection_lifetime
+            .observe(seconds_since(connected_since));
+    }
+    fn consumer_line_received(&self, bytes: usize) {
+        self.metrics.lines_received.inc();
+        self.metrics.line_bytes_received.inc_by(bytes as i64);
+    }
+    fn consumer_info_line_received(&self, bytes: usize) {
+        self.line_of_kind_received("info", bytes);
+    }
+    fn consumer_keep_alive_line_received(&self, bytes: usize) {
+        self.line_of_kind_received("keep_alive", bytes);
+    }
+    fn consumer_batch_line_received(&self, bytes: usize) {
+        self.line_of_kind_received("batch", bytes);
+    }
+    fn consumer_batch_received(&self, _ctx: &MetricsContext, batch_received_at_timestamp: Instant) {
+        self.metrics
+            .consumer_batch_age
+            .observe(seconds_since(batch_received_at_timestamp));
+    }
+
+    fn dispatcher_batch_received(
+        &self,
+        _ctx: &MetricsContext,
+        batch_received_at_timestamp: Instant,
+    ) {
+        self.metrics
+            .dispatcher_batch_age
+            .observe(seconds_since(batch_received_at_timestamp));
+    }
+    fn dispatcher_current_workers(&self, num_workers: usize) {
+        self.metrics.dispatcher_workers.set(num_workers as i64);
+    }
+
+    fn worker_batch_received(&self, ctx: &MetricsContext, batch_received_at_timestamp: Instant) {
+        self.metrics
+            .worker_batch_age
+            .with_label_values(&partition_labels(ctx))
+            .observe(seconds_since(batch_received_at_timestamp));
+    }
+    fn worker_worker_started(&self) {
+        self.metrics.workers_started.inc();
+    }
+    fn worker_worker_stopped(&self) {
+        self.metrics.workers_stopped.inc();
+    }
+    fn worker_batch_size_bytes(&self, ctx: &MetricsContext, bytes: usize) {
+        self.metrics
+            .worker_batch_size_bytes
+            .with_label_values(&partition_labels(ctx))
+            .observe(bytes as f64);
+    }
+    fn worker_batch_processed(&self, ctx: &MetricsContext, started: Instant) {
+        self.metrics
+            .worker_batch_processing_time
+            .with_label_values(&partition_labels(ctx))
+            .observe(seconds_since(started));
+    }
+    fn worker_events_in_same_batch_processed(&self, ctx: &MetricsContext, n: usize) {
+        self.metrics
+            .worker_events_processed
+            .with_label_values(&partition_labels(ctx))
+            .inc_by(n as i64);
+    }
+    fn worker_events_skipped(&self, ctx: &MetricsContext, n: usize) {
+        self.metrics
+            .worker_events_skipped
+            .with_label_values(&partition_labels(ctx))
+            .inc_by(n as i64);
+    }
+
+    fn committer_batch_received(
+        &self,
+        _ctx: &MetricsContext,
+        batch_received_at_timestamp: Instant,
+    ) {
+        self.metrics
+            .committer_batch_age
+            .observe(seconds_since(batch_received_at_timestamp));
+    }
+    fn committer_cursor_commit_attempt(
+        &self,
+        _ctx: &MetricsContext,
+        _commit_attempt_started: Instant,
+    ) {
+        self.metrics.commit_attempts.inc();
+    }
+    fn committer_cursor_committed(&self, _ctx: &MetricsContext, commit_attempt_started: Instant) {
+        self.metrics
+            .commit_duration
+            .observe(seconds_since(commit_attempt_started));
+    }
+    fn committer_cursor_commit_failed(
+        &self,
+        _ctx: &MetricsContext,
+        _commit_attempt_started: Instant,
+    ) {
+        self.metrics.commit_attempts_failed.inc();
+    }
+    fn committer_batches_committed(&self, _ctx: &MetricsContext, n: usize) {
+        self.metrics.batches_committed.inc_by(n as i64);
+    }
+    fn committer_events_committed(&self, _ctx: &MetricsContext, n: usize) {
+        self.metrics.events_committed.inc_by(n as i64);
+    }
+    fn committer_first_cursor_age_on_commit(&self, ctx: &MetricsContext, age: Duration) {
+        self.metrics
+            .first_cursor_age_on_commit
+            .with_label_values(&partition_labels(ctx))
+            .observe(age.as_secs_f64());
+    }
+    fn committer_last_cursor_age_on_commit(&self, ctx: &MetricsContext, age: Duration) {
+        self.metrics
+            .last_cursor_age_on_commit
+            .with_label_values(&partition_labels(ctx))
+            .observe(age.as_secs_f64());
+    }
+    fn committer_cursor_buffer_time(&self, ctx: &MetricsContext, time_buffered: Duration) {
+        self.metrics
+            .cursor_buffer_time
+            .with_label_values(&partition_labels(ctx))
+            .observe(time_buffered.as_secs_f64());
+    }
+    fn committer_time_left_on_commit_until_invalid(
+        &self,
+        ctx: &MetricsContext,
+        time_left: Duration,
+    ) {
+        self.metrics
+            .time_left_until_invalid
+            .with_label_values(&partition_labels(ctx))
+            .observe(time_left.as_secs_f64());
+    }
+
+    fn other_panicked(&self) {
+        self.metrics.other.with_label_values(&["panicked"]).inc();
+    }
+    fn other_dispatcher_gone(&self) {
+        self.metrics
+            .other
+            .with_label_values(&["dispatcher_gone"])
+            .inc();
+    }
+    fn other_worker_gone(&self) {
+        self.metrics.other.with_label_values(&["worker_gone"]).inc();
+    }
+    fn other_committer_gone(&self) {
+        self.metrics
+            .other
+            .with_label_values(&["committer_gone"])
+            .inc();
+    }
+}
+
+#[test]
+fn renders_metrics_labeled_by_subscription() {
+    use super::MetricsCollector;
+
+    let registry = Registry::new();
+    let collector = PrometheusCollector::new(&registry, &SubscriptionId::new("sub-1")).unwrap();
+
+    collector.streaming_connect_attempt();
+    collector.consumer_batch_line_received(100);
+    collector.dispatcher_current_workers(3);
+    collector.worker_events_in_same_batch_processed(
+        &MetricsContext::for_partition(&SubscriptionId::new("sub-1"), "order.created", "7"),
+        5,
+    );
+
+    let rendered = collector.render();
+    assert!(
+        rendered.contains("nakadion_streaming_connect_attempts_total{subscription=\"sub-1\"} 1")
+    );
+    assert!(rendered.contains(
+        "nakadion_consumer_line_bytes_received_by_kind_total{kind=\"batch\",subscription=\"sub-1\"} 100"
+    ));
+    assert!(rendered.contains("nakadion_dispatcher_workers{subscription=\"sub-1\"} 3"));
+    assert!(rendered.contains(
+        "nakadion_worker_events_processed_total\
+         {event_type=\"order.created\",partition=\"7\",subscription=\"sub-1\"} 5"
+    ));
+
+    assert!(PrometheusCollector::new(&registry, &SubscriptionId::new("sub-1")).is_err());
+    assert!(PrometheusCollector::new(&registry, &SubscriptionId::new("sub-2")).is_ok());
+}
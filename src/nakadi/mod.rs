@@ -1,33 +1,49 @@
+use std::collections::hash_map::RandomState;
 use std::env;
 use std::fmt;
+use std::hash::{BuildHasher, Hasher};
 use std::sync::Arc;
-use std::thread;
 use std::time::Duration;
 
 use failure::*;
-use serde_json;
 
 pub mod api;
+#[cfg(feature = "async")]
+pub mod asynchronous;
 pub mod batch;
+#[cfg(feature = "blocking")]
+pub mod buffered_publisher;
 pub mod committer;
+#[cfg(feature = "blocking")]
 pub mod consumer;
+pub mod dead_letter;
+#[cfg(feature = "blocking")]
 pub mod dispatcher;
 pub mod events;
 pub mod handler;
+pub mod lifecycle;
 pub mod metrics;
 pub mod model;
 pub mod publisher;
+pub(crate) mod retry;
 pub mod streaming_client;
+#[cfg(feature = "blocking")]
 pub mod worker;
 
-use crate::auth::ProvidesAccessToken;
-use metrics::{DevNullMetricsCollector, MetricsCollector};
-use crate::nakadi::api::{ApiClient, NakadiApiClient};
-use crate::nakadi::handler::HandlerFactory;
+use crate::nakadi::dead_letter::DeadLetterSink;
+use crate::nakadi::lifecycle::LifecycleListener;
 use crate::nakadi::model::SubscriptionId;
-use crate::nakadi::streaming_client::StreamingClient;
-
-#[cfg(feature = "metrix")]
+use crate::nakadi::streaming_client::{ConnectError, EventTypePartition};
+#[cfg(feature = "blocking")]
+use crate::{
+    auth::ProvidesAccessToken,
+    nakadi::api::{ApiClient, NakadiApiClient},
+    nakadi::handler::HandlerFactory,
+    nakadi::metrics::{DevNullMetricsCollector, MetricsCollector},
+    nakadi::streaming_client::StreamingClient,
+};
+
+#[cfg(all(feature = "blocking", feature = "metrix"))]
 use metrix::processor::AggregatesProcessors;
 
 /// Strategy for committing cursors
@@ -161,6 +177,408 @@ fn commit_strategy_serialize_events() {
     );
 }
 
+/// Limits how a worker retries a batch when its `BatchHandler`
+/// returned `ProcessingStatus::Retry`.
+///
+/// If a `DeadLetterSink` is configured a batch for which the handler
+/// returned `ProcessingStatus::Failed` is retried within the same limits
+/// after `failed_retry_millis`. Once the retries are exhausted the batch
+/// is put into the sink and its cursor is committed.
+///
+/// The cursor of a batch must be committed within the commit timeout
+/// of the stream, which is 60 seconds by default. Otherwise Nakadi closes
+/// the stream. `max_retry_seconds` may therefore be at most 50 seconds
+/// so that there is time left to commit the cursor. Nakadion refuses to
+/// start with a policy exceeding this.
+///
+/// # Serialization(JSON)
+///
+/// ```javascript
+/// {
+///     "max_retries": 5,
+///     "max_retry_seconds": 30,
+///     "failed_retry_millis": 1000
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HandlerRetryPolicy {
+    /// The maximum number of retries for a single batch.
+    /// If `None` the number of retries is only limited by time.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_retries: Option<u32>,
+    /// The maximum number of seconds after receiving a batch
+    /// within which the batch may be retried.
+    pub max_retry_seconds: u16,
+    /// The delay in milliseconds before a failed batch is retried
+    /// when a `DeadLetterSink` is configured.
+    pub failed_retry_millis: u64,
+}
+
+/// The maximum for `HandlerRetryPolicy::max_retry_seconds`.
+///
+/// Cursors are committed at the latest 55 seconds after a batch was received
+/// since Nakadi closes the stream after 60 seconds. This leaves 5 seconds to
+/// commit a batch retried as long as possible.
+const MAX_RETRY_SECONDS: u16 = 50;
+
+impl HandlerRetryPolicy {
+    /// Fails if a batch could be retried for so long that
+    /// its cursor can not be committed within the commit timeout.
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.max_retry_seconds > MAX_RETRY_SECONDS {
+            bail!(
+                "max_retry_seconds of the handler retry policy must not exceed {} \
+                 seconds to commit within the commit timeout but is {}",
+                MAX_RETRY_SECONDS,
+                self.max_retry_seconds
+            );
+        }
+        Ok(())
+    }
+
+    /// The time after receiving a batch within which the batch may be retried.
+    pub fn max_retry_duration(&self) -> Duration {
+        Duration::from_secs(u64::from(self.max_retry_seconds))
+    }
+
+    /// The delay before a failed batch is retried.
+    pub fn failed_retry_delay(&self) -> Duration {
+        Duration::from_millis(self.failed_retry_millis)
+    }
+}
+
+impl Default for HandlerRetryPolicy {
+    fn default() -> HandlerRetryPolicy {
+        HandlerRetryPolicy {
+            max_retries: None,
+            max_retry_seconds: 30,
+            failed_retry_millis: 1000,
+        }
+    }
+}
+
+#[test]
+fn handler_retry_policy_serialize() {
+    let policy = HandlerRetryPolicy {
+        max_retries: Some(5),
+        max_retry_seconds: 30,
+        failed_retry_millis: 500,
+    };
+
+    let json_str = serde_json::to_string(&policy).unwrap();
+
+    assert_eq!(
+        &json_str,
+        "{\"max_retries\":5,\"max_retry_seconds\":30,\"failed_retry_millis\":500}"
+    );
+}
+
+#[test]
+fn handler_retry_policy_must_leave_time_to_commit() {
+    let policy = |max_retry_seconds| HandlerRetryPolicy {
+        max_retry_seconds,
+        ..HandlerRetryPolicy::default()
+    };
+
+    assert!(HandlerRetryPolicy::default().validate().is_ok());
+    assert!(policy(50).validate().is_ok());
+    assert!(policy(51).validate().is_err());
+
+    let result = NakadionBuilder::default()
+        .nakadi_host("http://localhost")
+        .subscription_discovery(SubscriptionDiscovery::ExistingId(SubscriptionId::new(
+            "subscription",
+        )))
+        .handler_retry_policy(policy(60))
+        .build_config();
+    assert!(result.is_err());
+}
+
+#[test]
+fn handler_retry_policy_deserialize_without_max_retries() {
+    let policy: HandlerRetryPolicy = serde_json::from_str("{\"max_retry_seconds\":20}").unwrap();
+
+    assert_eq!(
+        policy,
+        HandlerRetryPolicy {
+            max_retries: None,
+            max_retry_seconds: 20,
+            failed_retry_millis: 1000,
+        }
+    );
+}
+
+/// The delays between failed attempts to connect to a stream
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ReconnectBackoff {
+    /// Wait the given milliseconds after the n-th failed attempt.
+    /// The last value is used once the sequence is exhausted.
+    /// An empty sequence falls back to the default delays.
+    ///
+    /// # Serialization(JSON)
+    ///
+    /// ```javascript
+    /// {
+    ///     "Sequence": {
+    ///         "millis": [100, 1000, 5000]
+    ///     }
+    /// }
+    /// ```
+    Sequence { millis: Vec<u64> },
+    /// Start with `initial_millis` and multiply the delay by `multiplier`
+    /// after each failed attempt until `max_millis` is reached.
+    ///
+    /// Each delay is randomly varied by up to `jitter` times its value,
+    /// e.g. a `jitter` of 0.2 gives delays between 80% and 120% of the
+    /// calculated delay.
+    ///
+    /// # Serialization(JSON)
+    ///
+    /// ```javascript
+    /// {
+    ///     "Exponential": {
+    ///         "initial_millis": 50,
+    ///         "multiplier": 2.0,
+    ///         "max_millis": 30000,
+    ///         "jitter": 0.2
+    ///     }
+    /// }
+    /// ```
+    Exponential {
+        initial_millis: u64,
+        multiplier: f64,
+        max_millis: u64,
+        jitter: f64,
+    },
+}
+
+impl ReconnectBackoff {
+    /// The delay after the given number of failed attempts(starting with 1)
+    pub fn delay(&self, attempt: usize) -> Duration {
+        let attempt = attempt.max(1);
+        match *self {
+            ReconnectBackoff::Sequence { ref millis } if millis.is_empty() => {
+                ReconnectBackoff::default().delay(attempt)
+            }
+            ReconnectBackoff::Sequence { ref millis } => {
+                let idx = ::std::cmp::min(attempt, millis.len()) - 1;
+                Duration::from_millis(millis[idx])
+            }
+            ReconnectBackoff::Exponential {
+                initial_millis,
+                multiplier,
+                max_millis,
+                jitter,
+            } => {
+                let exp = ::std::cmp::min(attempt - 1, i32::MAX as usize) as i32;
+                let millis = (initial_millis as f64 * multiplier.powi(exp)).min(max_millis as f64);
+                let jitter = jitter.clamp(0.0, 1.0);
+                let factor = 1.0 - jitter + 2.0 * jitter * random_fraction();
+                Duration::from_millis((millis * factor).max(0.0) as u64)
+            }
+        }
+    }
+}
+
+impl Default for ReconnectBackoff {
+    fn default() -> ReconnectBackoff {
+        ReconnectBackoff::Sequence {
+            millis: vec![
+                50, 100, 500, 1000, 1000, 1000, 3000, 3000, 3000, 5000, 5000, 5000, 10_000, 10_000,
+                10_000, 15_000, 15_000, 15_000, 30_000,
+            ],
+        }
+    }
+}
+
+/// A number in `[0, 1]` which is good enough for spreading reconnects.
+fn random_fraction() -> f64 {
+    let random = RandomState::new().build_hasher().finish();
+    random as f64 / u64::MAX as f64
+}
+
+/// What to do when Nakadi responds with `409 Conflict` on connect
+/// which means that there are no free slots on the subscription.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ConflictBehaviour {
+    /// Retry with the delays of the `ReconnectBackoff`
+    ///
+    /// # Serialization(JSON)
+    ///
+    /// ```javascript
+    /// "Retry"
+    /// ```
+    Retry,
+    /// Retry after a fixed number of seconds. Slots usually only become
+    /// free when other consumers leave so there is no need to hurry.
+    ///
+    /// # Serialization(JSON)
+    ///
+    /// ```javascript
+    /// {
+    ///     "RetryAfter": {
+    ///         "seconds": 60
+    ///     }
+    /// }
+    /// ```
+    RetryAfter { seconds: u64 },
+    /// Stop consuming
+    ///
+    /// # Serialization(JSON)
+    ///
+    /// ```javascript
+    /// "GiveUp"
+    /// ```
+    GiveUp,
+}
+
+/// Controls how the consumer (re)connects to a stream.
+///
+/// The consumer stops if it gives up connecting.
+///
+/// # Serialization(JSON)
+///
+/// All fields are optional and fall back to their defaults.
+///
+/// ```javascript
+/// {
+///     "backoff": { "Sequence": { "millis": [100, 1000, 5000] } },
+///     "max_reconnect_seconds": 600,
+///     "give_up_on_subscription_not_found": true,
+///     "give_up_on_forbidden": false,
+///     "on_conflict": "Retry"
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ReconnectPolicy {
+    /// The delays between failed connect attempts
+    pub backoff: ReconnectBackoff,
+    /// Give up if no connection could be established within this
+    /// many seconds. If `None` connecting is retried indefinitely.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_reconnect_seconds: Option<u64>,
+    /// Give up if Nakadi responds with `404 Not Found`
+    pub give_up_on_subscription_not_found: bool,
+    /// Give up if Nakadi responds with `403 Forbidden`
+    pub give_up_on_forbidden: bool,
+    /// What to do when there are no free slots on the subscription
+    pub on_conflict: ConflictBehaviour,
+}
+
+impl ReconnectPolicy {
+    /// Returns true if no further attempts should be made after the
+    /// given error.
+    ///
+    /// Invalid connect requests(`400 Bad Request`) are never retried.
+    pub fn gives_up_on(&self, err: &ConnectError) -> bool {
+        match err {
+            ConnectError::BadRequest(_, _) => true,
+            ConnectError::SubscriptionNotFound(_, _) => self.give_up_on_subscription_not_found,
+            ConnectError::Forbidden(_, _) => self.give_up_on_forbidden,
+            ConnectError::Conflict(_, _) => self.on_conflict == ConflictBehaviour::GiveUp,
+            _ => false,
+        }
+    }
+
+    /// The delay before the next attempt after `attempt`(starting with 1)
+    /// attempts failed with the given error.
+    pub fn delay_after(&self, attempt: usize, err: &ConnectError) -> Duration {
+        match (err, self.on_conflict) {
+            (ConnectError::Conflict(_, _), ConflictBehaviour::RetryAfter { seconds }) => {
+                Duration::from_secs(seconds)
+            }
+            _ => self.backoff.delay(attempt),
+        }
+    }
+
+    /// Returns true if connecting started at `started` should not
+    /// be retried anymore.
+    pub fn deadline_exceeded(&self, started: ::std::time::Instant) -> bool {
+        self.max_reconnect_seconds
+            .map(|secs| started.elapsed() >= Duration::from_secs(secs))
+            .unwrap_or(false)
+    }
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> ReconnectPolicy {
+        ReconnectPolicy {
+            backoff: ReconnectBackoff::default(),
+            max_reconnect_seconds: None,
+            give_up_on_subscription_not_found: true,
+            give_up_on_forbidden: true,
+            on_conflict: ConflictBehaviour::Retry,
+        }
+    }
+}
+
+#[test]
+fn reconnect_backoff_sequence_repeats_last_delay() {
+    let backoff = ReconnectBackoff::Sequence {
+        millis: vec![10, 20, 30],
+    };
+
+    let delays: Vec<_> = (1..=5).map(|attempt| backoff.delay(attempt)).collect();
+
+    assert_eq!(
+        delays,
+        [10, 20, 30, 30, 30]
+            .iter()
+            .map(|&ms| Duration::from_millis(ms))
+            .collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn reconnect_backoff_empty_sequence_uses_default_delays() {
+    let backoff = ReconnectBackoff::Sequence { millis: Vec::new() };
+
+    for attempt in 1..=30 {
+        assert_eq!(
+            backoff.delay(attempt),
+            ReconnectBackoff::default().delay(attempt)
+        );
+    }
+    assert!(backoff.delay(1) > Duration::from_millis(0));
+}
+
+#[test]
+fn reconnect_backoff_exponential_stays_within_jitter_and_max() {
+    let backoff = ReconnectBackoff::Exponential {
+        initial_millis: 100,
+        multiplier: 2.0,
+        max_millis: 1000,
+        jitter: 0.1,
+    };
+
+    for _ in 0..100 {
+        let third = backoff.delay(3);
+        assert!(third >= Duration::from_millis(360) && third <= Duration::from_millis(440));
+        let tenth = backoff.delay(10);
+        assert!(tenth >= Duration::from_millis(900) && tenth <= Duration::from_millis(1100));
+    }
+}
+
+#[test]
+fn reconnect_policy_deserialize_partial() {
+    let policy: ReconnectPolicy = serde_json::from_str(
+        "{\"give_up_on_forbidden\":false,\"on_conflict\":{\"RetryAfter\":{\"seconds\":60}}}",
+    )
+    .unwrap();
+
+    let forbidden = ConnectError::Forbidden("403".into(), Default::default());
+    let conflict = ConnectError::Conflict("409".into(), Default::default());
+    let not_found = ConnectError::SubscriptionNotFound("404".into(), Default::default());
+
+    assert!(!policy.gives_up_on(&forbidden));
+    assert!(!policy.gives_up_on(&conflict));
+    assert!(policy.gives_up_on(&not_found));
+    assert_eq!(policy.delay_after(1, &conflict), Duration::from_secs(60));
+    assert_eq!(policy.delay_after(1, &forbidden), Duration::from_millis(50));
+}
+
 /// Describes how `Nakadion` should resolve the `SubscriptionId` to
 /// connect to a subscription.
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -252,15 +670,15 @@ pub struct NakadionConfig {
     /// Maximum time in seconds to wait for the flushing of each chunk (per partition).
     ///
     ///  * If the amount of buffered Events reaches `batch_limit`
-    /// before this `batch_flush_timeout` is reached, the messages are immediately
-    /// flushed to the client and batch flush timer is reset.
+    ///    before this `batch_flush_timeout` is reached, the messages are immediately
+    ///    flushed to the client and batch flush timer is reset.
     ///  * If 0 or undefined, will assume 30 seconds.
     pub batch_flush_timeout: Duration,
     /// Maximum number of `Event`s in each chunk (and therefore per partition) of the
     /// stream.
     ///
     ///  * If 0 or unspecified will buffer Events indefinitely and flush on reaching of
-    ///  `batch_flush_timeout`.
+    ///    `batch_flush_timeout`.
     pub batch_limit: usize,
     /// The amount of uncommitted events Nakadi will stream before pausing the stream.
     /// When in paused state and commit comes - the stream will resume. Minimal value
@@ -277,9 +695,70 @@ pub struct NakadionConfig {
     /// The time after which a worker that received no events
     /// will be shut down.
     pub min_idle_worker_lifetime: Option<Duration>,
+    /// The partitions to consume. If empty, Nakadi will assign
+    /// the partitions automatically.
+    pub partitions: Vec<EventTypePartition>,
+    /// Limits retries of batches for which the handler requested a retry
+    pub handler_retry_policy: HandlerRetryPolicy,
+    /// Controls how the consumer (re)connects to the stream
+    pub reconnect_policy: ReconnectPolicy,
+    /// Receives batches that could not be processed. If `None`
+    /// the stream will be aborted on such batches.
+    pub dead_letter_sink: Option<Arc<dyn DeadLetterSink + Send + Sync>>,
+    /// Is notified when streams and partitions come and go
+    pub lifecycle_listener: Option<Arc<dyn LifecycleListener + Send + Sync>>,
+}
+
+impl NakadionConfig {
+    /// The settings of the consumer contained in this configuration
+    pub fn consumer_settings(&self) -> ConsumerSettings {
+        ConsumerSettings {
+            commit_strategy: self.commit_strategy,
+            min_idle_worker_lifetime: self.min_idle_worker_lifetime,
+            handler_retry_policy: self.handler_retry_policy,
+            reconnect_policy: self.reconnect_policy.clone(),
+            dead_letter_sink: self.dead_letter_sink.clone(),
+            lifecycle_listener: self.lifecycle_listener.clone(),
+        }
+    }
+}
+
+/// Settings of the consumer when starting it with manually
+/// created components via `start_with`
+#[derive(Debug, Clone)]
+pub struct ConsumerSettings {
+    /// The `CommitStrategy` to use. The default is `CommitStrategy::AllBatches`.
+    pub commit_strategy: CommitStrategy,
+    /// The time after which a worker that received no events
+    /// will be shut down. The default is to never shut down workers.
+    pub min_idle_worker_lifetime: Option<Duration>,
+    /// Limits retries of batches for which the handler requested a retry
+    pub handler_retry_policy: HandlerRetryPolicy,
+    /// Controls how the consumer (re)connects to the stream
+    pub reconnect_policy: ReconnectPolicy,
+    /// Receives batches that could not be processed. The default
+    /// is to abort the stream on such batches.
+    pub dead_letter_sink: Option<Arc<dyn DeadLetterSink + Send + Sync>>,
+    /// Is notified when streams and partitions come and go.
+    /// The default is to notify no one.
+    pub lifecycle_listener: Option<Arc<dyn LifecycleListener + Send + Sync>>,
+}
+
+impl Default for ConsumerSettings {
+    fn default() -> ConsumerSettings {
+        ConsumerSettings {
+            commit_strategy: CommitStrategy::AllBatches,
+            min_idle_worker_lifetime: None,
+            handler_retry_policy: HandlerRetryPolicy::default(),
+            reconnect_policy: ReconnectPolicy::default(),
+            dead_letter_sink: None,
+            lifecycle_listener: None,
+        }
+    }
 }
 
 /// Build a `NakadionConfig` or directly build and start `Nakadion`
+#[derive(Default)]
 pub struct NakadionBuilder {
     /// The configuration of the streaming client used to connect to the stream.
     ///
@@ -294,18 +773,18 @@ pub struct NakadionBuilder {
     /// The time after which a worker that received no events
     /// will be shut down. The default is to never shut down workers.
     pub min_idle_worker_lifetime: Option<Duration>,
-}
-
-impl Default for NakadionBuilder {
-    fn default() -> NakadionBuilder {
-        NakadionBuilder {
-            streaming_client_builder: Default::default(),
-            request_timeout: None,
-            commit_strategy: None,
-            subscription_discovery: None,
-            min_idle_worker_lifetime: None,
-        }
-    }
+    /// Limits retries of batches for which the handler requested a retry.
+    /// The default is `HandlerRetryPolicy::default()`.
+    pub handler_retry_policy: Option<HandlerRetryPolicy>,
+    /// Controls how the consumer (re)connects to the stream.
+    /// The default is `ReconnectPolicy::default()`.
+    pub reconnect_policy: Option<ReconnectPolicy>,
+    /// Receives batches that could not be processed. The default
+    /// is to abort the stream on such batches.
+    pub dead_letter_sink: Option<Arc<dyn DeadLetterSink + Send + Sync>>,
+    /// Is notified when streams and partitions come and go.
+    /// The default is to notify no one.
+    pub lifecycle_listener: Option<Arc<dyn LifecycleListener + Send + Sync>>,
 }
 
 impl NakadionBuilder {
@@ -341,8 +820,8 @@ impl NakadionBuilder {
     /// Maximum time in seconds to wait for the flushing of each chunk (per partition).
     ///
     ///  * If the amount of buffered Events reaches `batch_limit`
-    /// before this `batch_flush_timeout` is reached, the messages are immediately
-    /// flushed to the client and batch flush timer is reset.
+    ///    before this `batch_flush_timeout` is reached, the messages are immediately
+    ///    flushed to the client and batch flush timer is reset.
     ///  * If 0 or undefined, will assume 30 seconds.
     pub fn batch_flush_timeout(mut self, batch_flush_timeout: Duration) -> NakadionBuilder {
         self.streaming_client_builder.batch_flush_timeout = Some(batch_flush_timeout);
@@ -352,7 +831,7 @@ impl NakadionBuilder {
     /// stream.
     ///
     ///  * If 0 or unspecified will buffer Events indefinitely and flush on reaching of
-    ///  `batch_flush_timeout`.
+    ///    `batch_flush_timeout`.
     pub fn batch_limit(mut self, batch_limit: usize) -> NakadionBuilder {
         self.streaming_client_builder.batch_limit = Some(batch_limit);
         self
@@ -372,6 +851,15 @@ impl NakadionBuilder {
         self.streaming_client_builder.nakadi_host = Some(nakadi_host.into());
         self
     }
+    /// The partitions to consume.
+    ///
+    /// If set, the given partitions are requested from Nakadi instead of letting
+    /// Nakadi assign the partitions automatically. This allows pinning a consumer
+    /// to specific partitions.
+    pub fn partitions(mut self, partitions: Vec<EventTypePartition>) -> NakadionBuilder {
+        self.streaming_client_builder.partitions = Some(partitions);
+        self
+    }
 
     /// The request timeout used when committing events
     pub fn request_timeout(mut self, request_timeout: Duration) -> NakadionBuilder {
@@ -410,27 +898,77 @@ impl NakadionBuilder {
         self
     }
 
+    /// Limits how often and for how long a batch is retried
+    /// when the handler requested a retry.
+    ///
+    /// Building the config fails if the policy would retry
+    /// longer than the commit timeout allows.
+    pub fn handler_retry_policy(
+        mut self,
+        handler_retry_policy: HandlerRetryPolicy,
+    ) -> NakadionBuilder {
+        self.handler_retry_policy = Some(handler_retry_policy);
+        self
+    }
+
+    /// Controls the delays between connect attempts and
+    /// on which errors the consumer gives up connecting.
+    pub fn reconnect_policy(mut self, reconnect_policy: ReconnectPolicy) -> NakadionBuilder {
+        self.reconnect_policy = Some(reconnect_policy);
+        self
+    }
+
+    /// Batches that could not be processed are put into the given
+    /// sink and their cursors get committed afterwards.
+    ///
+    /// Without a sink the stream is aborted on such batches.
+    pub fn dead_letter_sink<S>(mut self, dead_letter_sink: S) -> NakadionBuilder
+    where
+        S: DeadLetterSink + Send + Sync + 'static,
+    {
+        self.dead_letter_sink = Some(Arc::new(dead_letter_sink));
+        self
+    }
+
+    /// The given listener is notified when streams get connected or closed
+    /// and when partitions get assigned or revoked.
+    ///
+    /// Use it to flush state kept per partition.
+    pub fn lifecycle_listener<L>(mut self, lifecycle_listener: L) -> NakadionBuilder
+    where
+        L: LifecycleListener + Send + Sync + 'static,
+    {
+        self.lifecycle_listener = Some(Arc::new(lifecycle_listener));
+        self
+    }
+
     /// Create a new builder from environment variables.
     ///
     /// # Environment Variables:
     ///
     /// * `NAKADION_NAKADI_HOST`: See `NakadionBuilder::nakadi_host`
     /// * `NAKADION_MAX_UNCOMMITTED_EVENTS`: See
-    /// `NakadionBuilder::max_uncommitted_events`
+    ///   `NakadionBuilder::max_uncommitted_events`
     /// * `NAKADION_BATCH_LIMIT`: See `NakadionBuilder::batch_limit`
     /// * `NAKADION_BATCH_FLUSH_TIMEOUT_SECS`: See
-    /// `NakadionBuilder::batch_flush_timeout`
+    ///   `NakadionBuilder::batch_flush_timeout`
     /// * `NAKADION_STREAM_TIMEOUT_SECS`: See `NakadionBuilder::stream_timeout`
     /// * `NAKADION_STREAM_LIMIT`: See `NakadionBuilder::stream_limit`
     /// * `NAKADION_STREAM_KEEP_ALIVE_LIMIT´: See
-    /// `NakadionBuilder::stream_keep_alive_limit`
+    ///   `NakadionBuilder::stream_keep_alive_limit`
+    /// * `NAKADION_PARTITIONS`: See `NakadionBuilder::partitions`.
+    ///   Value must be the JSON representation of a list of `EventTypePartition`s.
     /// * `NAKADION_REQUEST_TIMEOUT_MS`: See `NakadionBuilder::request_timeout`
     /// * `NAKADION_COMMIT_STRATEGY`: See `NakadionBuilder::commit_strategy`.
-    /// Value must be the JSON representation of a `CommitStrategy`.
+    ///   Value must be the JSON representation of a `CommitStrategy`.
     /// * `NAKADION_SUBSCRIPTION_DISCOVERY`: See `NakadionBuilder::subscription_discovery`.
-    /// Value must be the JSON representation of a `SubscriptionDiscovery`.
+    ///   Value must be the JSON representation of a `SubscriptionDiscovery`.
     /// * `NAKADION_MIN_IDLE_WORKER_LIFETIME_SECS`:
-    /// See `NakadionBuilder::min_idle_worker_lifetime`
+    ///   See `NakadionBuilder::min_idle_worker_lifetime`
+    /// * `NAKADION_HANDLER_RETRY_POLICY`: See `NakadionBuilder::handler_retry_policy`.
+    ///   Value must be the JSON representation of a `HandlerRetryPolicy`.
+    /// * `NAKADION_RECONNECT_POLICY`: See `NakadionBuilder::reconnect_policy`.
+    ///   Value must be the JSON representation of a `ReconnectPolicy`.
     ///
     /// # Errors
     ///
@@ -438,8 +976,10 @@ impl NakadionBuilder {
     pub fn from_env() -> Result<NakadionBuilder, Error> {
         let streaming_client_builder = streaming_client::ConfigBuilder::from_env()?;
 
-        let mut builder = NakadionBuilder::default();
-        builder.streaming_client_builder = streaming_client_builder;
+        let builder = NakadionBuilder {
+            streaming_client_builder,
+            ..NakadionBuilder::default()
+        };
 
         let builder = if let Ok(env_val) = env::var("NAKADION_REQUEST_TIMEOUT_MS") {
             builder.request_timeout(Duration::from_millis(
@@ -493,6 +1033,30 @@ impl NakadionBuilder {
             builder
         };
 
+        let builder = if let Ok(env_val) = env::var("NAKADION_HANDLER_RETRY_POLICY") {
+            let handler_retry_policy = serde_json::from_str(&env_val)
+                .context("Could not parse 'NAKADION_HANDLER_RETRY_POLICY'")?;
+            builder.handler_retry_policy(handler_retry_policy)
+        } else {
+            warn!(
+                "Environment variable 'NAKADION_HANDLER_RETRY_POLICY' not found. It will be set \
+                 to the default."
+            );
+            builder
+        };
+
+        let builder = if let Ok(env_val) = env::var("NAKADION_RECONNECT_POLICY") {
+            let reconnect_policy = serde_json::from_str(&env_val)
+                .context("Could not parse 'NAKADION_RECONNECT_POLICY'")?;
+            builder.reconnect_policy(reconnect_policy)
+        } else {
+            warn!(
+                "Environment variable 'NAKADION_RECONNECT_POLICY' not found. It will be set \
+                 to the default."
+            );
+            builder
+        };
+
         Ok(builder)
     }
 
@@ -523,6 +1087,9 @@ impl NakadionBuilder {
                 return Err(format_err!("Subscription discovery is missing"));
             };
 
+        let handler_retry_policy = self.handler_retry_policy.unwrap_or_default();
+        handler_retry_policy.validate()?;
+
         Ok(NakadionConfig {
             stream_keep_alive_limit: streaming_client_config.stream_keep_alive_limit,
             stream_limit: streaming_client_config.stream_limit,
@@ -535,6 +1102,11 @@ impl NakadionBuilder {
             subscription_discovery,
             nakadi_host: streaming_client_config.nakadi_host,
             min_idle_worker_lifetime: self.min_idle_worker_lifetime,
+            partitions: streaming_client_config.partitions,
+            handler_retry_policy,
+            reconnect_policy: self.reconnect_policy.unwrap_or_default(),
+            dead_letter_sink: self.dead_letter_sink,
+            lifecycle_listener: self.lifecycle_listener,
         })
     }
 
@@ -546,6 +1118,7 @@ impl NakadionBuilder {
     ///
     /// Not all mandatory values have been set or
     /// creating `Nakadion` failed.
+    #[cfg(feature = "blocking")]
     pub fn build_and_start<HF, P>(
         self,
         handler_factory: HF,
@@ -570,6 +1143,7 @@ impl NakadionBuilder {
     ///
     /// Not all mandatory values have been set or
     /// creating `Nakadion` failed.
+    #[cfg(feature = "blocking")]
     pub fn build_and_start_with_metrics<HF, P, M>(
         self,
         handler_factory: HF,
@@ -600,7 +1174,7 @@ impl NakadionBuilder {
     ///
     /// Not all mandatory values have been set or
     /// creating `Nakadion` failed.
-    #[cfg(feature = "metrix")]
+    #[cfg(all(feature = "blocking", feature = "metrix"))]
     pub fn build_and_start_with_metrix<HF, P, T>(
         self,
         handler_factory: HF,
@@ -612,7 +1186,7 @@ impl NakadionBuilder {
         P: ProvidesAccessToken + Send + Sync + 'static,
         T: AggregatesProcessors,
     {
-        let metrix_collector = ::nakadi::metrics::MetrixCollector::new(put_metrics_here);
+        let metrix_collector = crate::nakadi::metrics::MetrixCollector::new(put_metrics_here);
         let config = self.build_config()?;
 
         Nakadion::start(
@@ -629,10 +1203,12 @@ impl NakadionBuilder {
 /// Once instantiated it can only be used
 /// query its running state or to stop
 /// consuming events.
+#[cfg(feature = "blocking")]
 pub struct Nakadion {
     guard: Arc<DropGuard>,
 }
 
+#[cfg(feature = "blocking")]
 impl Nakadion {
     /// Start with manually created components and parameter
     ///
@@ -640,15 +1216,15 @@ impl Nakadion {
     ///
     /// # Errors
     ///
-    /// Nakadion could not be started.
+    /// Nakadion could not be started. This is also the case if the
+    /// `HandlerRetryPolicy` of the settings is not valid.
     pub fn start_with<HF, C, A, M>(
         subscription_id: SubscriptionId,
         streaming_client: C,
         api_client: A,
         handler_factory: HF,
-        commit_strategy: CommitStrategy,
         metrics_collector: M,
-        min_idle_worker_lifetime: Option<Duration>,
+        consumer_settings: ConsumerSettings,
     ) -> Result<Nakadion, Error>
     where
         C: StreamingClient + Clone + Sync + Send + 'static,
@@ -656,14 +1232,15 @@ impl Nakadion {
         HF: HandlerFactory + Sync + Send + 'static,
         M: MetricsCollector + Clone + Send + Sync + 'static,
     {
+        consumer_settings.handler_retry_policy.validate()?;
+
         let consumer = consumer::Consumer::start(
             streaming_client,
             api_client,
             subscription_id,
             handler_factory,
-            commit_strategy,
             metrics_collector,
-            min_idle_worker_lifetime,
+            consumer_settings,
         );
 
         let guard = Arc::new(DropGuard { consumer });
@@ -689,6 +1266,7 @@ impl Nakadion {
         M: MetricsCollector + Clone + Send + Sync + 'static,
     {
         let access_token_provider = Arc::new(access_token_provider);
+        let consumer_settings = config.consumer_settings();
 
         let api_client = NakadiApiClient::with_shared_access_token_provider(
             api::Config {
@@ -727,6 +1305,7 @@ impl Nakadion {
             batch_limit: config.batch_limit,
             max_uncommitted_events: config.max_uncommitted_events,
             nakadi_host: config.nakadi_host,
+            partitions: config.partitions,
         };
 
         let streaming_client =
@@ -737,15 +1316,16 @@ impl Nakadion {
             )?;
 
         info!("Commit strategy is {:?}", config.commit_strategy);
+        info!("Handler retry policy is {:?}", config.handler_retry_policy);
+        info!("Reconnect policy is {:?}", config.reconnect_policy);
 
         Nakadion::start_with(
             subscription_id,
             streaming_client,
             api_client,
             handler_factory,
-            config.commit_strategy,
             metrics_collector,
-            config.min_idle_worker_lifetime,
+            consumer_settings,
         )
     }
 
@@ -759,31 +1339,79 @@ impl Nakadion {
         self.guard.consumer.stop()
     }
 
+    /// Stops Nakadion gracefully.
+    ///
+    /// No more lines are read from the stream, the batches already received
+    /// are processed and all resulting cursors are committed. If this does not
+    /// complete within `timeout` Nakadion is stopped like with `stop` and the
+    /// remaining batches are dropped.
+    ///
+    /// Returns once Nakadion stopped or the timeout expired.
+    pub fn shutdown(&self, timeout: Duration) -> consumer::ShutdownReport {
+        self.guard.consumer.shutdown(timeout)
+    }
+
     /// Block the current thread until Nakadion has stopped
     pub fn block_until_stopped(&self) {
-        self.block_until_stopped_with_interval(Duration::from_secs(1))
+        self.join();
     }
 
     /// Block the current thread until Nakadion has stopped.
+    #[deprecated(since = "0.16.0", note = "Nakadion is not polled anymore. Use `join`.")]
+    pub fn block_until_stopped_with_interval(&self, _poll_interval: Duration) {
+        self.join();
+    }
+
+    /// Block the current thread until Nakadion has stopped
+    /// and return why it stopped.
+    pub fn join(&self) -> consumer::StopReason {
+        self.guard.consumer.join()
+    }
+
+    /// Block the current thread until Nakadion has stopped or
+    /// `timeout` elapsed.
     ///
-    /// The state is polled at the given `poll_interval`.
-    pub fn block_until_stopped_with_interval(&self, poll_interval: Duration) {
-        while self.running() {
-            thread::sleep(poll_interval);
-        }
+    /// Returns `None` if Nakadion is still running.
+    pub fn wait_timeout(&self, timeout: Duration) -> Option<consumer::StopReason> {
+        self.guard.consumer.wait_timeout(timeout)
+    }
+
+    /// Returns a channel receiving the `StopReason` as soon as Nakadion stopped.
+    ///
+    /// If Nakadion already stopped the `StopReason` can be received immediately.
+    pub fn stop_notification(&self) -> ::std::sync::mpsc::Receiver<consumer::StopReason> {
+        self.guard.consumer.stop_notification()
+    }
+
+    /// Call `callback` with the `StopReason` once Nakadion stopped.
+    ///
+    /// If Nakadion already stopped the callback is called immediately.
+    pub fn on_stop<F>(&self, callback: F)
+    where
+        F: FnOnce(&consumer::StopReason) + Send + 'static,
+    {
+        self.guard.consumer.on_stop(callback)
+    }
+
+    /// Returns why Nakadion stopped or `None` if it is still running.
+    pub fn stop_reason(&self) -> Option<consumer::StopReason> {
+        self.guard.consumer.stop_reason()
     }
 }
 
+#[cfg(feature = "blocking")]
 struct DropGuard {
     consumer: consumer::Consumer,
 }
 
+#[cfg(feature = "blocking")]
 impl DropGuard {
     fn running(&self) -> bool {
         self.consumer.running()
     }
 }
 
+#[cfg(feature = "blocking")]
 impl Drop for DropGuard {
     fn drop(&mut self) {
         self.consumer.stop()
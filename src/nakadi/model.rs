@@ -54,7 +54,7 @@ impl fmt::Display for PartitionId {
 ///
 /// For more information on event types and subscriptions
 /// see [subscriptions](http://nakadi.io/manual.html#using_consuming-events-hila)
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct StreamId(pub String);
 
 impl StreamId {
@@ -94,14 +94,6 @@ impl Default for FlowId {
     }
 }
 
-/// Information on a current batch. This might be
-/// useful for a `Handler` that wants to do checkpointing on its own.
-#[derive(Clone, Debug)]
-pub struct BatchCommitData<'a> {
-    pub stream_id: StreamId,
-    pub cursor: &'a [u8],
-}
-
 /// The [`Nakadi Event Type`](https://github.com/zalando/nakadi#creating-event-types).
 /// Similiar to a topic.
 #[derive(Clone, Debug)]
@@ -110,7 +102,7 @@ pub struct EventType<'a>(pub &'a str);
 impl<'a> EventType<'a> {
     /// Creates a new instance of an
     /// [`EventType`](https://github.com/zalando/nakadi#creating-event-types).
-    pub fn new(value: &'a str) -> EventType {
+    pub fn new(value: &'a str) -> EventType<'a> {
         EventType(value)
     }
 }
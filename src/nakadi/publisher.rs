@@ -1,16 +1,34 @@
 //! Publish events to Nakadi
+#[cfg(feature = "blocking")]
 use std::io::Read;
+#[cfg(feature = "blocking")]
 use std::sync::Arc;
+#[cfg(any(feature = "blocking", feature = "async"))]
 use std::time::Duration;
+#[cfg(feature = "blocking")]
+use std::time::Instant;
 
-use backoff::{Error as BackoffError, ExponentialBackoff, Operation};
-use reqwest::header::{HeaderMap, CONTENT_TYPE};
+#[cfg(any(feature = "blocking", feature = "async"))]
+use backoff::backoff::Backoff;
+#[cfg(any(feature = "blocking", feature = "async"))]
+use backoff::ExponentialBackoff;
+#[cfg(feature = "blocking")]
+use backoff::{Error as BackoffError, Operation};
 use reqwest::StatusCode;
-use reqwest::blocking::{Client as HttpClient, Response};
+#[cfg(feature = "blocking")]
+use reqwest::{
+    blocking::{Client as HttpClient, Response},
+    header::{HeaderMap, CONTENT_TYPE},
+};
+#[cfg(feature = "blocking")]
 use serde::Serialize;
-use serde_json;
+use serde_json::{self, value::RawValue};
 
-use crate::auth::{AccessToken, ProvidesAccessToken};
+#[cfg(feature = "blocking")]
+use crate::auth::{renew_rejected_token, AccessToken, ProvidesAccessToken};
+#[cfg(feature = "blocking")]
+use crate::nakadi::metrics::DevNullMetricsCollector;
+use crate::nakadi::metrics::PublisherMetricsCollector;
 use crate::nakadi::model::FlowId;
 
 /// Publishes events to `Nakadi`
@@ -23,14 +41,20 @@ use crate::nakadi::model::FlowId;
 /// Constructors take a parameter `retry_on_partial_success`.
 /// If set to `true` partial successes on publishing events will be retried.
 /// Otherwise not.
+///
+/// No metrics are collected unless a `PublisherMetricsCollector`
+/// is set with `metrics_collector`.
+#[cfg(feature = "blocking")]
 #[derive(Clone)]
 pub struct NakadiPublisher {
     nakadi_base_url: Arc<String>,
     http_client: HttpClient,
     token_provider: Arc<dyn ProvidesAccessToken + Sync + Send + 'static>,
     retry_on_partial_success: bool,
+    metrics_collector: Arc<dyn PublisherMetricsCollector + Sync + Send + 'static>,
 }
 
+#[cfg(feature = "blocking")]
 impl NakadiPublisher {
     /// Create a new `NakadiPublisher`
     pub fn new<U: Into<String>, T: ProvidesAccessToken + Sync + Send + 'static>(
@@ -43,6 +67,7 @@ impl NakadiPublisher {
             http_client: HttpClient::new(),
             token_provider: Arc::new(token_provider),
             retry_on_partial_success,
+            metrics_collector: Arc::new(DevNullMetricsCollector),
         }
     }
 
@@ -57,15 +82,28 @@ impl NakadiPublisher {
             http_client: HttpClient::new(),
             token_provider,
             retry_on_partial_success,
+            metrics_collector: Arc::new(DevNullMetricsCollector),
         }
     }
 
+    /// Report publishing to the given `PublisherMetricsCollector`
+    pub fn metrics_collector<M>(mut self, metrics_collector: M) -> NakadiPublisher
+    where
+        M: PublisherMetricsCollector + Sync + Send + 'static,
+    {
+        self.metrics_collector = Arc::new(metrics_collector);
+        self
+    }
+
     /// Publish events packed into a vector of bytes.
     ///
     /// The events must be encoded in a way that `Nakadi`
     /// can understand and pass it`s validation mechanism
     /// especially regarding schemas.
     ///
+    /// The bytes are only parsed as a JSON array of events if
+    /// partial successes are retried. Otherwise they are sent unchanged.
+    ///
     /// The `budget` is the maximum `Duration` publishing events
     /// is retried. This can lead to events being published multiple
     /// times.
@@ -75,42 +113,66 @@ impl NakadiPublisher {
         bytes: Vec<u8>,
         flow_id: Option<FlowId>,
         budget: Duration,
+    ) -> Result<PublishStatus, PublishError> {
+        self.publish_bytes(event_type, &bytes, None, flow_id, budget)
+    }
+
+    /// Publish events packed into a vector of bytes
+    /// reporting `num_events` to the metrics if known.
+    fn publish_bytes(
+        &self,
+        event_type: &str,
+        bytes: &[u8],
+        num_events: Option<usize>,
+        flow_id: Option<FlowId>,
+        budget: Duration,
     ) -> Result<PublishStatus, PublishError> {
         let url = format!("{}/event-types/{}/events", self.nakadi_base_url, event_type);
 
-        let flow_id = flow_id.unwrap_or_else(FlowId::default);
+        let flow_id = flow_id.unwrap_or_default();
 
-        let mut op = || match publish_events(
-            &self.http_client,
-            &url,
-            &*self.token_provider,
-            bytes.clone(),
-            &flow_id,
-            self.retry_on_partial_success,
-        ) {
-            Ok(publish_status) => Ok(publish_status),
-            Err(err) => {
-                if err.is_retry_suggested() {
-                    Err(BackoffError::Transient(err))
-                } else {
-                    Err(BackoffError::Permanent(err))
+        let mut pending = PendingEvents::new(bytes, self.retry_on_partial_success, num_events)?;
+
+        let mut op = || {
+            let result = publish_events(
+                &self.http_client,
+                &url,
+                &*self.token_provider,
+                &*self.metrics_collector,
+                event_type,
+                &pending,
+                &flow_id,
+            )
+            .and_then(|publish_status| pending.update(publish_status, &flow_id));
+            match result {
+                Ok(PublishStatus::NotAllEventsPublished(_)) if self.retry_on_partial_success => {
+                    Err(BackoffError::Transient(PublishError::Other(
+                        "Partial success. Retry enabled.".to_string(),
+                        flow_id.clone(),
+                    )))
+                }
+                Ok(publish_status) => Ok(publish_status),
+                Err(err) => {
+                    if err.is_retry_suggested() {
+                        Err(BackoffError::Transient(err))
+                    } else {
+                        Err(BackoffError::Permanent(err))
+                    }
                 }
             }
         };
 
         let notify = |err, dur| {
             warn!("Publish error happened {:?}: {}", dur, err);
+            self.metrics_collector.publisher_retry(event_type);
         };
 
-        let mut backoff = ExponentialBackoff::default();
-        backoff.max_elapsed_time = Some(budget);
-        backoff.initial_interval = Duration::from_millis(50);
-        backoff.multiplier = 1.5;
+        let mut backoff = publish_backoff(budget);
 
         match op.retry_notify(&mut backoff, notify) {
             Ok(publish_status) => Ok(publish_status),
-            Err(BackoffError::Transient(err)) => Err(err),
-            Err(BackoffError::Permanent(err)) => Err(err),
+            Err(BackoffError::Transient(err)) => pending.give_up(err),
+            Err(BackoffError::Permanent(err)) => pending.give_up(err),
         }
     }
 
@@ -130,65 +192,287 @@ impl NakadiPublisher {
             Ok(bytes) => bytes,
             Err(err) => return Err(PublishError::Serialization(err.to_string())),
         };
-        self.publish_raw(event_type, bytes, flow_id, budget)
+        self.publish_bytes(event_type, &bytes, Some(events.len()), flow_id, budget)
     }
 }
 
+#[cfg(feature = "blocking")]
 fn publish_events(
     client: &HttpClient,
     url: &str,
     token_provider: &dyn ProvidesAccessToken,
-    bytes: Vec<u8>,
+    metrics_collector: &dyn PublisherMetricsCollector,
+    event_type: &str,
+    pending: &PendingEvents,
     flow_id: &FlowId,
-    retry_on_partial_success: bool,
 ) -> Result<PublishStatus, PublishError> {
+    let bytes = pending.body();
+    let num_events = pending.num_pending();
+    metrics_collector.publisher_attempt(event_type, num_events, bytes.len());
+
     let mut headers = HeaderMap::new();
 
     headers.insert("X-Flow-Id", flow_id.0.parse().unwrap());
     headers.insert(CONTENT_TYPE, "application/json".parse().unwrap());
 
-    let request_builder = client.post(url).headers(headers);
+    let send = || -> Result<Response, PublishError> {
+        let request_builder = client.post(url).headers(headers.clone());
+
+        let request_builder = match token_provider.get_token() {
+            Ok(Some(AccessToken(token))) => request_builder.bearer_auth(token),
+            Ok(None) => request_builder,
+            Err(err) => return Err(PublishError::Token(err.to_string())),
+        };
 
-    let request_builder = match token_provider.get_token() {
-        Ok(Some(AccessToken(token))) => request_builder.bearer_auth(token),
-        Ok(None) => request_builder,
-        Err(err) => return Err(PublishError::Token(err.to_string())),
+        request_builder
+            .body(bytes.clone())
+            .send()
+            .map_err(|err| PublishError::Other(format!("{}", err), flow_id.clone()))
     };
 
-    match request_builder.body(bytes).send() {
-        Ok(ref mut response) => match response.status() {
-            StatusCode::OK => Ok(PublishStatus::AllEventsPublished),
-            StatusCode::MULTI_STATUS => {
-                if !retry_on_partial_success {
-                    Ok(PublishStatus::NotAllEventsPublished)
-                } else {
-                    Err(PublishError::Other(
-                        "Partial success. Retry enabled.".to_string(),
+    let start = Instant::now();
+    let sent = send().and_then(|response| {
+        if response.status() == StatusCode::UNAUTHORIZED
+            && renew_rejected_token(token_provider, "publish")
+        {
+            send()
+        } else {
+            Ok(response)
+        }
+    });
+    let mut response = match sent {
+        Ok(response) => response,
+        Err(err) => {
+            metrics_collector.publisher_failed(event_type, None);
+            return Err(err);
+        }
+    };
+    metrics_collector.publisher_responded(event_type, start);
+
+    let status = response.status();
+    let outcome = publish_outcome(status, read_response_body(&mut response), flow_id);
+    collect_outcome_metrics(metrics_collector, event_type, num_events, status, &outcome);
+    outcome
+}
+
+/// The backoff between attempts to publish events which
+/// are retried for at most `budget`.
+#[cfg(any(feature = "blocking", feature = "async"))]
+pub(crate) fn publish_backoff(budget: Duration) -> ExponentialBackoff {
+    let mut backoff = ExponentialBackoff {
+        max_elapsed_time: Some(budget),
+        initial_interval: Duration::from_millis(50),
+        multiplier: 1.5,
+        ..ExponentialBackoff::default()
+    };
+    backoff.reset();
+    backoff
+}
+
+/// Maps the status and body of a response to a publish
+/// request to the outcome of publishing.
+pub(crate) fn publish_outcome(
+    status: StatusCode,
+    body: String,
+    flow_id: &FlowId,
+) -> Result<PublishStatus, PublishError> {
+    match status {
+        StatusCode::OK => Ok(PublishStatus::AllEventsPublished),
+        StatusCode::MULTI_STATUS => match serde_json::from_str(&body) {
+            Ok(items) => Ok(PublishStatus::NotAllEventsPublished(items)),
+            Err(err) => Err(PublishError::Other(
+                format!("Could not parse the items of a partial success: {}", err),
+                flow_id.clone(),
+            )),
+        },
+        StatusCode::UNAUTHORIZED => Err(PublishError::Unauthorized(body, flow_id.clone())),
+        StatusCode::FORBIDDEN => Err(PublishError::Forbidden(body, flow_id.clone())),
+        StatusCode::UNPROCESSABLE_ENTITY => {
+            Err(PublishError::UnprocessableEntity(body, flow_id.clone()))
+        }
+        _ => Err(PublishError::Other(body, flow_id.clone())),
+    }
+}
+
+/// Reports the outcome of a publish request to which `Nakadi` responded.
+pub(crate) fn collect_outcome_metrics(
+    metrics_collector: &dyn PublisherMetricsCollector,
+    event_type: &str,
+    num_events: usize,
+    status: StatusCode,
+    outcome: &Result<PublishStatus, PublishError>,
+) {
+    match outcome {
+        Ok(PublishStatus::AllEventsPublished) => {
+            metrics_collector.publisher_events_published(event_type, num_events)
+        }
+        Ok(PublishStatus::NotAllEventsPublished(items)) => {
+            let published = items
+                .iter()
+                .filter(|item| item.publishing_status == PublishingStatus::Submitted)
+                .count();
+            metrics_collector.publisher_partial_success(
+                event_type,
+                published,
+                items.len() - published,
+            )
+        }
+        Err(_) => metrics_collector.publisher_failed(event_type, Some(status.as_u16())),
+    }
+}
+
+/// Keeps track of the events of a single publish request
+/// across retries after partial successes.
+///
+/// Only the events which have not been submitted yet
+/// are sent again. This requires the events to be parsed.
+/// Otherwise all events are sent unchanged with each attempt.
+pub(crate) struct PendingEvents<'a> {
+    bytes: &'a [u8],
+    /// The number of events if not parsed. Only used for metrics.
+    num_events: usize,
+    /// The parsed events. `None` if the events are always sent unchanged.
+    events: Option<Vec<&'a RawValue>>,
+    /// The latest response for each event. `None` if nothing is known yet.
+    responses: Vec<Option<BatchItemResponse>>,
+    /// Indexes into `events` of the events still to be sent
+    pending: Vec<usize>,
+}
+
+impl<'a> PendingEvents<'a> {
+    /// Create from a JSON array of events
+    ///
+    /// The events are only parsed if `resend_failed_only` is `true`.
+    /// Otherwise `num_events` is reported to the metrics if known.
+    pub fn new(
+        bytes: &'a [u8],
+        resend_failed_only: bool,
+        num_events: Option<usize>,
+    ) -> Result<PendingEvents<'a>, PublishError> {
+        if !resend_failed_only {
+            return Ok(PendingEvents {
+                bytes,
+                num_events: num_events.unwrap_or(0),
+                events: None,
+                responses: Vec::new(),
+                pending: Vec::new(),
+            });
+        }
+
+        let events: Vec<&RawValue> = serde_json::from_slice(bytes)
+            .map_err(|err| PublishError::Serialization(err.to_string()))?;
+        Ok(PendingEvents {
+            bytes,
+            num_events: events.len(),
+            responses: vec![None; events.len()],
+            pending: (0..events.len()).collect(),
+            events: Some(events),
+        })
+    }
+
+    /// The number of events still to be sent
+    pub fn num_pending(&self) -> usize {
+        if self.events.is_some() {
+            self.pending.len()
+        } else {
+            self.num_events
+        }
+    }
+
+    /// A JSON array of the events still to be sent
+    pub fn body(&self) -> Vec<u8> {
+        let events = if let Some(ref events) = self.events {
+            events
+        } else {
+            return self.bytes.to_vec();
+        };
+
+        let mut body = Vec::new();
+        body.push(b'[');
+        for (n, idx) in self.pending.iter().enumerate() {
+            if n > 0 {
+                body.push(b',');
+            }
+            body.extend_from_slice(events[*idx].get().as_bytes());
+        }
+        body.push(b']');
+        body
+    }
+
+    /// Update the pending events with the outcome of sending `self.body()`.
+    ///
+    /// Returns the status of all events of the original request.
+    pub fn update(
+        &mut self,
+        publish_status: PublishStatus,
+        flow_id: &FlowId,
+    ) -> Result<PublishStatus, PublishError> {
+        if self.events.is_none() {
+            return Ok(publish_status);
+        }
+
+        match publish_status {
+            PublishStatus::AllEventsPublished => self.pending.clear(),
+            PublishStatus::NotAllEventsPublished(items) => {
+                if items.len() != self.pending.len() {
+                    return Err(PublishError::Other(
+                        format!(
+                            "Nakadi responded with {} items for {} events",
+                            items.len(),
+                            self.pending.len()
+                        ),
                         flow_id.clone(),
-                    ))
+                    ));
                 }
+                let mut still_pending = Vec::new();
+                for (idx, item) in self.pending.iter().zip(items) {
+                    if item.publishing_status != PublishingStatus::Submitted {
+                        still_pending.push(*idx);
+                    }
+                    self.responses[*idx] = Some(item);
+                }
+                self.pending = still_pending;
             }
-            StatusCode::UNAUTHORIZED => {
-                let msg = read_response_body(response);
-                Err(PublishError::Unauthorized(msg, flow_id.clone()))
-            }
-            StatusCode::FORBIDDEN => {
-                let msg = read_response_body(response);
-                Err(PublishError::Forbidden(msg, flow_id.clone()))
-            }
-            StatusCode::UNPROCESSABLE_ENTITY => {
-                let msg = read_response_body(response);
-                Err(PublishError::UnprocessableEntity(msg, flow_id.clone()))
-            }
-            _ => {
-                let msg = read_response_body(response);
-                Err(PublishError::Other(msg, flow_id.clone()))
-            }
-        },
-        Err(err) => Err(PublishError::Other(format!("{}", err), flow_id.clone())),
+        }
+        Ok(self.status())
+    }
+
+    /// The status of all events of the original request.
+    pub fn status(&self) -> PublishStatus {
+        if self.pending.is_empty() {
+            PublishStatus::AllEventsPublished
+        } else {
+            PublishStatus::NotAllEventsPublished(
+                self.responses
+                    .iter()
+                    // Every event got a response with the first partial success
+                    .map(|response| {
+                        response.clone().unwrap_or(BatchItemResponse {
+                            eid: None,
+                            publishing_status: PublishingStatus::Submitted,
+                            step: None,
+                            detail: None,
+                        })
+                    })
+                    .collect(),
+            )
+        }
+    }
+
+    /// Called once no more attempts will be made.
+    ///
+    /// If some events have been submitted the outcome is a partial success
+    /// and the error is dropped.
+    pub fn give_up(&self, err: PublishError) -> Result<PublishStatus, PublishError> {
+        if self.events.is_some() && self.pending.len() < self.num_events {
+            Ok(self.status())
+        } else {
+            Err(err)
+        }
     }
 }
 
+#[cfg(feature = "blocking")]
 fn read_response_body(response: &mut Response) -> String {
     let mut buf = String::new();
     response
@@ -203,7 +487,57 @@ pub enum PublishStatus {
     /// All events were written and accepted by `Nakadi`
     AllEventsPublished,
     /// Not all events were accepted by `Nakadi`
-    NotAllEventsPublished,
+    ///
+    /// Contains a response for each event in the order
+    /// the events were given.
+    NotAllEventsPublished(Vec<BatchItemResponse>),
+}
+
+/// The outcome of publishing a single event
+/// as reported by Nakadi on a partial success.
+///
+/// # Serialization(JSON)
+///
+/// ```javascript
+/// {
+///     "eid": "9cbc3a2b-5e6c-4e4b-a2a7-0de4c7c4a5a6",
+///     "publishing_status": "failed",
+///     "step": "publishing",
+///     "detail": "Could not publish event"
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BatchItemResponse {
+    /// The id of the event if it could be read
+    pub eid: Option<String>,
+    pub publishing_status: PublishingStatus,
+    /// The step in which publishing the event failed
+    pub step: Option<PublishingStep>,
+    /// Human readable information about the failure
+    pub detail: Option<String>,
+}
+
+/// Whether an event was published
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PublishingStatus {
+    /// The event was published
+    Submitted,
+    /// Publishing the event failed
+    Failed,
+    /// Publishing was not attempted because another event of the batch failed
+    Aborted,
+}
+
+/// The step publishing an event reached
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PublishingStep {
+    None,
+    Validating,
+    Partitioning,
+    Enriching,
+    Publishing,
 }
 
 /// Errors that can happen when publishing to `Nakadi`.
@@ -236,3 +570,288 @@ impl PublishError {
         }
     }
 }
+
+#[test]
+fn batch_item_response_deserialize() {
+    let json_str = r#"[{"eid":"1","publishing_status":"submitted","step":"none"},"#.to_owned()
+        + r#"{"publishing_status":"failed","step":"publishing","detail":"boom"}]"#;
+
+    let items: Vec<BatchItemResponse> = serde_json::from_str(&json_str).unwrap();
+
+    assert_eq!(
+        items,
+        vec![
+            BatchItemResponse {
+                eid: Some("1".to_string()),
+                publishing_status: PublishingStatus::Submitted,
+                step: Some(PublishingStep::None),
+                detail: None,
+            },
+            BatchItemResponse {
+                eid: None,
+                publishing_status: PublishingStatus::Failed,
+                step: Some(PublishingStep::Publishing),
+                detail: Some("boom".to_string()),
+            },
+        ]
+    );
+}
+
+#[test]
+fn pending_events_only_resend_failed_events() {
+    fn item(eid: &str, publishing_status: PublishingStatus) -> BatchItemResponse {
+        BatchItemResponse {
+            eid: Some(eid.to_string()),
+            publishing_status,
+            step: None,
+            detail: None,
+        }
+    }
+
+    let bytes = br#"[{"eid":"a"}, {"eid":"b"}, {"eid":"c"}]"#;
+    let flow_id = FlowId::default();
+
+    let mut pending = PendingEvents::new(bytes, true, None).unwrap();
+    assert_eq!(
+        pending.body(),
+        br#"[{"eid":"a"},{"eid":"b"},{"eid":"c"}]"#.to_vec()
+    );
+
+    let status = pending
+        .update(
+            PublishStatus::NotAllEventsPublished(vec![
+                item("a", PublishingStatus::Failed),
+                item("b", PublishingStatus::Submitted),
+                item("c", PublishingStatus::Aborted),
+            ]),
+            &flow_id,
+        )
+        .unwrap();
+    assert_eq!(pending.body(), br#"[{"eid":"a"},{"eid":"c"}]"#.to_vec());
+
+    match status {
+        PublishStatus::NotAllEventsPublished(items) => assert_eq!(
+            items,
+            vec![
+                item("a", PublishingStatus::Failed),
+                item("b", PublishingStatus::Submitted),
+                item("c", PublishingStatus::Aborted),
+            ]
+        ),
+        _ => panic!("expected a partial success"),
+    }
+
+    let status = pending
+        .update(
+            PublishStatus::NotAllEventsPublished(vec![
+                item("a", PublishingStatus::Submitted),
+                item("c", PublishingStatus::Failed),
+            ]),
+            &flow_id,
+        )
+        .unwrap();
+    assert_eq!(pending.body(), br#"[{"eid":"c"}]"#.to_vec());
+
+    match status {
+        PublishStatus::NotAllEventsPublished(items) => assert_eq!(
+            items,
+            vec![
+                item("a", PublishingStatus::Submitted),
+                item("b", PublishingStatus::Submitted),
+                item("c", PublishingStatus::Failed),
+            ]
+        ),
+        _ => panic!("expected a partial success"),
+    }
+
+    match pending.update(PublishStatus::AllEventsPublished, &flow_id) {
+        Ok(PublishStatus::AllEventsPublished) => (),
+        _ => panic!("expected all events to be published"),
+    }
+}
+
+#[test]
+fn pending_events_are_sent_unchanged_unless_failed_events_are_resent() {
+    let bytes = b"not parsed";
+    let flow_id = FlowId::default();
+
+    let mut pending = PendingEvents::new(bytes, false, Some(2)).unwrap();
+    assert_eq!(pending.body(), bytes.to_vec());
+    assert_eq!(pending.num_pending(), 2);
+
+    let items = vec![BatchItemResponse {
+        eid: None,
+        publishing_status: PublishingStatus::Failed,
+        step: None,
+        detail: None,
+    }];
+    match pending.update(
+        PublishStatus::NotAllEventsPublished(items.clone()),
+        &flow_id,
+    ) {
+        Ok(PublishStatus::NotAllEventsPublished(status_items)) => assert_eq!(status_items, items),
+        _ => panic!("expected the partial success to be passed through"),
+    }
+    assert_eq!(pending.body(), bytes.to_vec());
+    assert!(pending
+        .give_up(PublishError::Other("boom".to_string(), flow_id))
+        .is_err());
+}
+
+#[cfg(all(feature = "blocking", feature = "test-support"))]
+#[test]
+fn retries_once_with_a_fresh_token_when_unauthorized() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::auth::TokenError;
+    use crate::test_support::{Endpoint, FakeNakadi, Fault};
+
+    #[derive(Clone, Default)]
+    struct CountingTokenProvider(Arc<AtomicUsize>);
+
+    impl ProvidesAccessToken for CountingTokenProvider {
+        fn get_token(&self) -> Result<Option<AccessToken>, TokenError> {
+            Ok(Some(AccessToken::new("token")))
+        }
+
+        fn invalidate_token(&self) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    let nakadi = FakeNakadi::start().unwrap();
+    nakadi.create_event_type("test_event", 1);
+    nakadi.inject_fault(
+        Endpoint::Publish,
+        Fault::Status(401, "Token revoked".to_string()),
+    );
+
+    let token_provider = CountingTokenProvider::default();
+    let publisher = NakadiPublisher::new(nakadi.nakadi_host(), false, token_provider.clone());
+
+    let status = publisher
+        .publish_events("test_event", &[1, 2], None, Duration::from_millis(0))
+        .unwrap();
+
+    match status {
+        PublishStatus::AllEventsPublished => {}
+        other => panic!("Expected all events to be published but got {:?}", other),
+    }
+    assert_eq!(token_provider.0.load(Ordering::SeqCst), 1);
+    assert_eq!(nakadi.published_events("test_event").len(), 2);
+}
+
+#[cfg(all(feature = "blocking", feature = "test-support"))]
+#[test]
+fn does_not_retry_right_away_if_no_fresh_token_is_available() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use crate::auth::TokenError;
+    use crate::test_support::{Endpoint, FakeNakadi, Fault};
+
+    /// Like a provider which refreshes its tokens in the background
+    #[derive(Clone, Default)]
+    struct RefreshingTokenProvider(Arc<AtomicBool>);
+
+    impl ProvidesAccessToken for RefreshingTokenProvider {
+        fn get_token(&self) -> Result<Option<AccessToken>, TokenError> {
+            if self.0.load(Ordering::SeqCst) {
+                Err(TokenError::Other {
+                    message: "Refreshing".to_string(),
+                })
+            } else {
+                Ok(Some(AccessToken::new("token")))
+            }
+        }
+
+        fn invalidate_token(&self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    let nakadi = FakeNakadi::start().unwrap();
+    nakadi.create_event_type("test_event", 1);
+    nakadi.inject_fault(
+        Endpoint::Publish,
+        Fault::Status(401, "Token revoked".to_string()),
+    );
+
+    let token_provider = RefreshingTokenProvider::default();
+    let publisher = NakadiPublisher::new(nakadi.nakadi_host(), false, token_provider.clone());
+
+    match publisher.publish_events("test_event", &[1, 2], None, Duration::from_millis(0)) {
+        Err(PublishError::Unauthorized(_, _)) => {}
+        other => panic!("Expected the rejection but got {:?}", other),
+    }
+
+    // The token got refreshed
+    token_provider.0.store(false, Ordering::SeqCst);
+    let status = publisher
+        .publish_events("test_event", &[1, 2], None, Duration::from_millis(0))
+        .unwrap();
+
+    match status {
+        PublishStatus::AllEventsPublished => {}
+        other => panic!("Expected all events to be published but got {:?}", other),
+    }
+    assert_eq!(nakadi.published_events("test_event").len(), 2);
+}
+
+#[cfg(all(feature = "blocking", feature = "test-support"))]
+#[test]
+fn reports_attempts_failures_and_published_events() {
+    use std::sync::Mutex;
+    use std::time::Instant;
+
+    use crate::auth::NoAuthAccessTokenProvider;
+    use crate::test_support::{Endpoint, FakeNakadi, Fault};
+
+    #[derive(Clone, Default)]
+    struct RecordingCollector(Arc<Mutex<Vec<String>>>);
+
+    impl PublisherMetricsCollector for RecordingCollector {
+        fn publisher_attempt(&self, event_type: &str, num_events: usize, _bytes: usize) {
+            let record = format!("attempt {} {}", event_type, num_events);
+            self.0.lock().unwrap().push(record);
+        }
+        fn publisher_retry(&self, event_type: &str) {
+            self.0.lock().unwrap().push(format!("retry {}", event_type));
+        }
+        fn publisher_responded(&self, _event_type: &str, _attempt_started: Instant) {}
+        fn publisher_events_published(&self, event_type: &str, n: usize) {
+            let record = format!("published {} {}", event_type, n);
+            self.0.lock().unwrap().push(record);
+        }
+        fn publisher_partial_success(&self, _event_type: &str, _published: usize, _failed: usize) {}
+        fn publisher_failed(&self, event_type: &str, status: Option<u16>) {
+            let record = format!("failed {} {:?}", event_type, status);
+            self.0.lock().unwrap().push(record);
+        }
+    }
+
+    let nakadi = FakeNakadi::start().unwrap();
+    nakadi.create_event_type("test_event", 1);
+    nakadi.inject_fault(
+        Endpoint::Publish,
+        Fault::Status(503, "Unavailable".to_string()),
+    );
+
+    let collector = RecordingCollector::default();
+    let publisher = NakadiPublisher::new(nakadi.nakadi_host(), false, NoAuthAccessTokenProvider)
+        .metrics_collector(collector.clone());
+
+    publisher
+        .publish_events("test_event", &[1, 2], None, Duration::from_secs(5))
+        .unwrap();
+
+    assert_eq!(
+        *collector.0.lock().unwrap(),
+        vec![
+            "attempt test_event 2",
+            "failed test_event Some(503)",
+            "retry test_event",
+            "attempt test_event 2",
+            "published test_event 2",
+        ]
+    );
+}
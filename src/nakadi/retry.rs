@@ -0,0 +1,167 @@
+//! Retrying a batch within the limits of a `HandlerRetryPolicy`
+//!
+//! This is shared by the thread based and the async workers. Only
+//! waiting for a retry differs between them.
+use std::time::{Duration, Instant};
+
+use crate::nakadi::handler::ProcessingStatus;
+use crate::nakadi::HandlerRetryPolicy;
+
+/// What a worker has to do after its handler returned
+/// a `ProcessingStatus` for a batch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum NextAttempt {
+    /// Invoke the handler with the same batch again after the given delay
+    RetryAfter(Duration),
+    /// Processing the batch is done with the given status
+    /// which is never `ProcessingStatus::Retry`.
+    Done(ProcessingStatus),
+}
+
+/// Keeps track of the retries of a single batch
+pub(crate) struct BatchRetries {
+    max_retries: Option<u32>,
+    deadline: Instant,
+    failed_retry_delay: Option<Duration>,
+    retries: u32,
+}
+
+impl BatchRetries {
+    /// Start tracking the retries of a batch received at `received_at`
+    ///
+    /// If `retry_failed` is `true` a `ProcessingStatus::Failed` is retried
+    /// like a requested retry until the policy is exhausted.
+    pub fn new(
+        policy: &HandlerRetryPolicy,
+        received_at: Instant,
+        retry_failed: bool,
+    ) -> BatchRetries {
+        BatchRetries {
+            max_retries: policy.max_retries,
+            deadline: received_at + policy.max_retry_duration(),
+            failed_retry_delay: if retry_failed {
+                Some(policy.failed_retry_delay())
+            } else {
+                None
+            },
+            retries: 0,
+        }
+    }
+
+    /// The number of retries granted so far
+    pub fn retries(&self) -> u32 {
+        self.retries
+    }
+
+    /// Decide how to continue after the handler returned `status`.
+    ///
+    /// A retry requested by the handler turns into `ProcessingStatus::Failed`
+    /// once the policy is exhausted. A retried failure keeps the reason
+    /// given by the handler.
+    pub fn next_attempt(&mut self, status: ProcessingStatus) -> NextAttempt {
+        let (after, failure) = match (status, self.failed_retry_delay) {
+            (ProcessingStatus::Retry { after }, _) => (after, None),
+            (ProcessingStatus::Failed { reason }, Some(delay)) => (delay, Some(reason)),
+            (status, _) => return NextAttempt::Done(status),
+        };
+
+        if let Some(exhausted) = self.exhausted(after) {
+            let reason =
+                failure.unwrap_or_else(|| format!("Handler requested a retry but {}", exhausted));
+            return NextAttempt::Done(ProcessingStatus::Failed { reason });
+        }
+
+        self.retries += 1;
+        NextAttempt::RetryAfter(after)
+    }
+
+    fn exhausted(&self, after: Duration) -> Option<String> {
+        if let Some(max_retries) = self.max_retries {
+            if self.retries >= max_retries {
+                return Some(format!(
+                    "the maximum of {} retries has been reached.",
+                    max_retries
+                ));
+            }
+        }
+
+        if Instant::now() + after > self.deadline {
+            return Some(format!(
+                "retrying after {:?} would exceed the retry deadline. \
+                 {} retries have been made.",
+                after, self.retries
+            ));
+        }
+
+        None
+    }
+}
+
+#[test]
+fn retries_until_max_retries_are_reached() {
+    let policy = HandlerRetryPolicy {
+        max_retries: Some(2),
+        max_retry_seconds: 30,
+        failed_retry_millis: 10,
+    };
+    let mut retries = BatchRetries::new(&policy, Instant::now(), false);
+    let retry = || ProcessingStatus::retry(Duration::from_millis(10));
+
+    assert_eq!(
+        retries.next_attempt(retry()),
+        NextAttempt::RetryAfter(Duration::from_millis(10))
+    );
+    assert_eq!(
+        retries.next_attempt(retry()),
+        NextAttempt::RetryAfter(Duration::from_millis(10))
+    );
+    match retries.next_attempt(retry()) {
+        NextAttempt::Done(ProcessingStatus::Failed { .. }) => (),
+        other => panic!("unexpected attempt {:?}", other),
+    }
+    assert_eq!(retries.retries(), 2);
+}
+
+#[test]
+fn does_not_retry_beyond_the_deadline() {
+    let policy = HandlerRetryPolicy {
+        max_retries: None,
+        max_retry_seconds: 1,
+        failed_retry_millis: 10,
+    };
+    let mut retries = BatchRetries::new(&policy, Instant::now(), false);
+
+    match retries.next_attempt(ProcessingStatus::retry(Duration::from_secs(2))) {
+        NextAttempt::Done(ProcessingStatus::Failed { .. }) => (),
+        other => panic!("unexpected attempt {:?}", other),
+    }
+    assert_eq!(
+        retries.next_attempt(ProcessingStatus::processed(1)),
+        NextAttempt::Done(ProcessingStatus::processed(1))
+    );
+}
+
+#[test]
+fn retries_failures_only_if_enabled() {
+    let policy = HandlerRetryPolicy {
+        max_retries: Some(1),
+        max_retry_seconds: 30,
+        failed_retry_millis: 10,
+    };
+
+    let mut retries = BatchRetries::new(&policy, Instant::now(), false);
+    assert_eq!(
+        retries.next_attempt(ProcessingStatus::failed("boom")),
+        NextAttempt::Done(ProcessingStatus::failed("boom"))
+    );
+
+    let mut retries = BatchRetries::new(&policy, Instant::now(), true);
+    assert_eq!(
+        retries.next_attempt(ProcessingStatus::failed("boom")),
+        NextAttempt::RetryAfter(Duration::from_millis(10))
+    );
+    assert_eq!(
+        retries.next_attempt(ProcessingStatus::failed("boom")),
+        NextAttempt::Done(ProcessingStatus::failed("boom"))
+    );
+}
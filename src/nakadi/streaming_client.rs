@@ -1,20 +1,29 @@
 //! Connect to a stream and consume lines
 use std::env;
-use std::io::{BufRead, BufReader, Error as IoError, Read, Split};
+use std::io::Error as IoError;
+#[cfg(feature = "blocking")]
+use std::io::{BufRead, BufReader, Read, Split};
 /// Stream lines from a Nakadi subscription
+#[cfg(feature = "blocking")]
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use failure::*;
-use reqwest::header::HeaderMap;
 use reqwest::StatusCode;
-use reqwest::blocking::{Client as HttpClient, ClientBuilder as HttpClientBuilder, Response};
-
-use crate::auth::{AccessToken, ProvidesAccessToken, TokenError};
+#[cfg(feature = "blocking")]
+use reqwest::{
+    blocking::{Client as HttpClient, ClientBuilder as HttpClientBuilder, Response},
+    header::HeaderMap,
+};
+
+use crate::auth::TokenError;
+#[cfg(feature = "blocking")]
+use crate::auth::{renew_rejected_token, AccessToken, ProvidesAccessToken};
+#[cfg(feature = "blocking")]
 use crate::nakadi::metrics::{DevNullMetricsCollector, MetricsCollector};
-use crate::nakadi::model::{FlowId, StreamId, SubscriptionId};
+use crate::nakadi::model::{FlowId, PartitionId, StreamId, SubscriptionId};
 
-const LINE_SPLIT_BYTE: u8 = b'\n';
+pub(crate) const LINE_SPLIT_BYTE: u8 = b'\n';
 
 /// A line as received from Nakadi plus a timestamp.
 pub struct RawLine {
@@ -27,11 +36,13 @@ pub struct RawLine {
 pub type LineResult = ::std::result::Result<RawLine, IoError>;
 
 /// An iterator over lines received from Nakadi.
+#[cfg(feature = "blocking")]
 pub struct NakadiLineIterator {
     lines: Split<BufReader<Response>>,
 }
 
 /// An iterator over lines `Nakadion` understands.
+#[cfg(feature = "blocking")]
 impl NakadiLineIterator {
     pub fn new(response: Response) -> Self {
         let reader = BufReader::with_capacity(1024 * 1024, response);
@@ -41,6 +52,7 @@ impl NakadiLineIterator {
     }
 }
 
+#[cfg(feature = "blocking")]
 impl Iterator for NakadiLineIterator {
     type Item = LineResult;
 
@@ -54,9 +66,40 @@ impl Iterator for NakadiLineIterator {
     }
 }
 
+/// A partition of an event type.
+///
+/// Used to request specific partitions from Nakadi instead of
+/// letting Nakadi assign the partitions automatically.
+///
+/// # Serialization(JSON)
+///
+/// ```javascript
+/// {
+///     "event_type": "my_event_type",
+///     "partition": "0"
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EventTypePartition {
+    pub event_type: String,
+    pub partition: PartitionId,
+}
+
+impl EventTypePartition {
+    pub fn new<T: Into<String>>(event_type: T, partition: PartitionId) -> EventTypePartition {
+        EventTypePartition {
+            event_type: event_type.into(),
+            partition,
+        }
+    }
+}
+
 /// A client for connecting to a subscription on the Nakadi Event Broker
 pub trait StreamingClient {
-    type LineIterator: Iterator<Item = LineResult>;
+    /// The lines of a stream.
+    ///
+    /// The consumer reads them on a separate thread.
+    type LineIterator: Iterator<Item = LineResult> + Send + 'static;
     /// Establish a connection for stream consumption.
     fn connect(
         &self,
@@ -94,15 +137,15 @@ pub struct Config {
     /// Maximum time in seconds to wait for the flushing of each chunk (per partition).
     ///
     ///  * If the amount of buffered Events reaches `batch_limit`
-    /// before this `batch_flush_timeout` is reached, the messages are immediately
-    /// flushed to the client and batch flush timer is reset.
+    ///    before this `batch_flush_timeout` is reached, the messages are immediately
+    ///    flushed to the client and batch flush timer is reset.
     ///  * If 0 or undefined, will assume 30 seconds.
     pub batch_flush_timeout: Duration,
     /// Maximum number of `Event`s in each chunk (and therefore per partition) of the
     /// stream.
     ///
     ///  * If 0 or unspecified will buffer Events indefinitely and flush on reaching of
-    ///  `batch_flush_timeout`.
+    ///    `batch_flush_timeout`.
     pub batch_limit: usize,
     /// The amount of uncommitted events Nakadi will stream before pausing the stream.
     /// When in paused state and commit comes - the stream will resume. Minimal value
@@ -110,12 +153,18 @@ pub struct Config {
     pub max_uncommitted_events: usize,
     /// The URI prefix for the Nakadi Host, e.g. "https://my.nakadi.com"
     pub nakadi_host: String,
+    /// The partitions to consume.
+    ///
+    /// If empty, Nakadi will assign the partitions automatically.
+    /// Otherwise the given partitions are requested from Nakadi.
+    pub partitions: Vec<EventTypePartition>,
 }
 
 /// A builder for a `Config`.
 ///
 /// Please also read about
 /// [consuming events](http://nakadi.io/manual.html#/subscriptions/subscription_id/events_get)
+#[derive(Default)]
 pub struct ConfigBuilder {
     /// Maximum number of empty keep alive batches to get in a row before closing the
     /// connection. If 0 or undefined will send keep alive messages indefinitely.
@@ -140,8 +189,8 @@ pub struct ConfigBuilder {
     /// Maximum time in seconds to wait for the flushing of each chunk (per partition).
     ///
     ///  * If the amount of buffered Events reaches `batch_limit`
-    /// before this `batch_flush_timeout` is reached, the messages are immediately
-    /// flushed to the client and batch flush timer is reset.
+    ///    before this `batch_flush_timeout` is reached, the messages are immediately
+    ///    flushed to the client and batch flush timer is reset.
     ///  * If 0 or undefined, will assume 30 seconds.
     pub batch_flush_timeout: Option<Duration>,
     /// The amount of uncommitted events Nakadi will stream before pausing the stream.
@@ -154,20 +203,10 @@ pub struct ConfigBuilder {
     pub max_uncommitted_events: Option<usize>,
     /// The URI prefix for the Nakadi Host, e.g. "https://my.nakadi.com"
     pub nakadi_host: Option<String>,
-}
-
-impl Default for ConfigBuilder {
-    fn default() -> ConfigBuilder {
-        ConfigBuilder {
-            stream_keep_alive_limit: None,
-            stream_limit: None,
-            stream_timeout: None,
-            batch_flush_timeout: None,
-            batch_limit: None,
-            max_uncommitted_events: None,
-            nakadi_host: None,
-        }
-    }
+    /// The partitions to consume.
+    ///
+    /// If not set, Nakadi will assign the partitions automatically.
+    pub partitions: Option<Vec<EventTypePartition>>,
 }
 
 impl ConfigBuilder {
@@ -203,8 +242,8 @@ impl ConfigBuilder {
     /// Maximum time in seconds to wait for the flushing of each chunk (per partition).
     ///
     ///  * If the amount of buffered Events reaches `batch_limit`
-    /// before this `batch_flush_timeout` is reached, the messages are immediately
-    /// flushed to the client and batch flush timer is reset.
+    ///    before this `batch_flush_timeout` is reached, the messages are immediately
+    ///    flushed to the client and batch flush timer is reset.
     ///  * If 0 or undefined, will assume 30 seconds.
     pub fn batch_flush_timeout(mut self, batch_flush_timeout: Duration) -> ConfigBuilder {
         self.batch_flush_timeout = Some(batch_flush_timeout);
@@ -214,7 +253,7 @@ impl ConfigBuilder {
     /// stream.
     ///
     ///  * If 0 or unspecified will buffer Events indefinitely and flush on reaching of
-    ///  `batch_flush_timeout`.
+    ///    `batch_flush_timeout`.
     pub fn batch_limit(mut self, batch_limit: usize) -> ConfigBuilder {
         self.batch_limit = Some(batch_limit);
         self
@@ -231,6 +270,15 @@ impl ConfigBuilder {
         self.nakadi_host = Some(nakadi_host.into());
         self
     }
+    /// The partitions to consume.
+    ///
+    /// If set, the given partitions are requested from Nakadi instead of letting
+    /// Nakadi assign the partitions automatically. This allows pinning a consumer
+    /// to specific partitions.
+    pub fn partitions(mut self, partitions: Vec<EventTypePartition>) -> ConfigBuilder {
+        self.partitions = Some(partitions);
+        self
+    }
 
     /// Create a builder from environment variables.
     ///
@@ -242,14 +290,16 @@ impl ConfigBuilder {
     ///
     /// * NAKADION_NAKADI_HOST: See `ConfigBuilder::nakadi_host`
     /// * NAKADION_MAX_UNCOMMITTED_EVENTS: See
-    /// `ConfigBuilder::max_uncommitted_events`
+    ///   `ConfigBuilder::max_uncommitted_events`
     /// * NAKADION_BATCH_LIMIT: See `ConfigBuilder::batch_limit`
     /// * NAKADION_BATCH_FLUSH_TIMEOUT_SECS: See
-    /// `ConfigBuilder::batch_flush_timeout`
+    ///   `ConfigBuilder::batch_flush_timeout`
     /// * NAKADION_STREAM_TIMEOUT_SECS: See `ConfigBuilder::stream_timeout`
     /// * NAKADION_STREAM_LIMIT: See `ConfigBuilder::stream_limit`
     /// * NAKADION_STREAM_KEEP_ALIVE_LIMIT: See
-    /// `ConfigBuilder::stream_keep_alive_limit`
+    ///   `ConfigBuilder::stream_keep_alive_limit`
+    /// * NAKADION_PARTITIONS: See `ConfigBuilder::partitions`.
+    ///   Value must be the JSON representation of a list of `EventTypePartition`s.
     pub fn from_env() -> Result<ConfigBuilder, Error> {
         let builder = ConfigBuilder::default();
         let builder = if let Ok(env_val) = env::var("NAKADION_STREAM_KEEP_ALIVE_LIMIT") {
@@ -330,6 +380,17 @@ impl ConfigBuilder {
             );
             builder
         };
+        let builder = if let Ok(env_val) = env::var("NAKADION_PARTITIONS") {
+            let partitions =
+                serde_json::from_str(&env_val).context("Could not parse 'NAKADION_PARTITIONS'")?;
+            builder.partitions(partitions)
+        } else {
+            info!(
+                "Environment variable 'NAKADION_PARTITIONS' not found. Partitions will be \
+                 assigned by Nakadi."
+            );
+            builder
+        };
         Ok(builder)
     }
 
@@ -352,6 +413,7 @@ impl ConfigBuilder {
             batch_limit: self.batch_limit.unwrap_or(0),
             max_uncommitted_events: self.max_uncommitted_events.unwrap_or(0),
             nakadi_host,
+            partitions: self.partitions.unwrap_or_default(),
         })
     }
 
@@ -361,6 +423,7 @@ impl ConfigBuilder {
     /// # Errors
     ///
     /// The configuration was invalid.
+    #[cfg(feature = "blocking")]
     pub fn build_client<T, M>(
         self,
         token_provider: T,
@@ -382,6 +445,7 @@ impl ConfigBuilder {
     /// # Errors
     ///
     /// The configuration was invalid.
+    #[cfg(feature = "blocking")]
     pub fn build_client_with_shared_access_token_provider<M>(
         self,
         token_provider: Arc<dyn ProvidesAccessToken + Send + Sync + 'static>,
@@ -402,6 +466,7 @@ impl ConfigBuilder {
 
 /// Connects to Nakadi via HTTP and creates an iterator of
 /// lines from the data received from Nakadi over the network
+#[cfg(feature = "blocking")]
 #[derive(Clone)]
 pub struct NakadiStreamingClient<M> {
     http_client: HttpClient,
@@ -410,6 +475,7 @@ pub struct NakadiStreamingClient<M> {
     metrics_collector: M,
 }
 
+#[cfg(feature = "blocking")]
 impl<M> NakadiStreamingClient<M>
 where
     M: MetricsCollector,
@@ -460,15 +526,20 @@ where
     }
 }
 
-fn create_connect_url(config: &Config, subscription_id: &SubscriptionId) -> String {
-    let mut connect_url = String::new();
-    connect_url.push_str(&config.nakadi_host);
-    if !connect_url.ends_with('/') {
-        connect_url.push('/');
+pub(crate) fn create_events_url(config: &Config, subscription_id: &SubscriptionId) -> String {
+    let mut events_url = String::new();
+    events_url.push_str(&config.nakadi_host);
+    if !events_url.ends_with('/') {
+        events_url.push('/');
     }
-    connect_url.push_str("subscriptions/");
-    connect_url.push_str(&subscription_id.0);
-    connect_url.push_str("/events");
+    events_url.push_str("subscriptions/");
+    events_url.push_str(&subscription_id.0);
+    events_url.push_str("/events");
+    events_url
+}
+
+pub(crate) fn create_connect_url(config: &Config, subscription_id: &SubscriptionId) -> String {
+    let mut connect_url = create_events_url(config, subscription_id);
 
     let mut connect_params = Vec::new();
     if config.stream_keep_alive_limit != 0 {
@@ -510,23 +581,98 @@ fn create_connect_url(config: &Config, subscription_id: &SubscriptionId) -> Stri
     connect_url
 }
 
-impl<M> StreamingClient for NakadiStreamingClient<M>
+/// The body for requesting specific partitions via
+/// `POST /subscriptions/{id}/events`
+#[derive(Serialize)]
+pub(crate) struct ConnectRequestBody<'a> {
+    partitions: &'a [EventTypePartition],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream_keep_alive_limit: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream_limit: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream_timeout: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    batch_flush_timeout: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    batch_limit: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_uncommitted_events: Option<usize>,
+}
+
+impl<'a> ConnectRequestBody<'a> {
+    pub fn from_config(config: &'a Config) -> ConnectRequestBody<'a> {
+        let non_zero = |v: usize| if v != 0 { Some(v) } else { None };
+        let non_zero_secs = |d: Duration| {
+            if d != Duration::from_secs(0) {
+                Some(d.as_secs())
+            } else {
+                None
+            }
+        };
+
+        ConnectRequestBody {
+            partitions: &config.partitions,
+            stream_keep_alive_limit: non_zero(config.stream_keep_alive_limit),
+            stream_limit: non_zero(config.stream_limit),
+            stream_timeout: non_zero_secs(config.stream_timeout),
+            batch_flush_timeout: non_zero_secs(config.batch_flush_timeout),
+            batch_limit: non_zero(config.batch_limit),
+            max_uncommitted_events: non_zero(config.max_uncommitted_events),
+        }
+    }
+}
+
+#[test]
+fn connect_request_body_serialize() {
+    let config = Config {
+        stream_keep_alive_limit: 0,
+        stream_limit: 0,
+        stream_timeout: Duration::from_secs(0),
+        batch_flush_timeout: Duration::from_secs(5),
+        batch_limit: 10,
+        max_uncommitted_events: 100,
+        nakadi_host: "http://localhost".into(),
+        partitions: vec![
+            EventTypePartition::new("event_type_1", PartitionId::new("0")),
+            EventTypePartition::new("event_type_1", PartitionId::new("3")),
+        ],
+    };
+
+    let json_str = serde_json::to_string(&ConnectRequestBody::from_config(&config)).unwrap();
+
+    assert_eq!(
+        &json_str,
+        "{\"partitions\":[{\"event_type\":\"event_type_1\",\"partition\":\"0\"},\
+         {\"event_type\":\"event_type_1\",\"partition\":\"3\"}],\
+         \"batch_flush_timeout\":5,\"batch_limit\":10,\"max_uncommitted_events\":100}"
+    );
+}
+
+#[cfg(feature = "blocking")]
+impl<M> NakadiStreamingClient<M>
 where
     M: MetricsCollector,
 {
-    type LineIterator = NakadiLineIterator;
-    fn connect(
+    fn send_connect_request(
         &self,
         subscription_id: &SubscriptionId,
-        flow_id: FlowId,
-    ) -> ::std::result::Result<(StreamId, NakadiLineIterator), ConnectError> {
-        let connect_url = create_connect_url(&self.config, &subscription_id);
-
+        flow_id: &FlowId,
+    ) -> ::std::result::Result<Response, ConnectError> {
         let mut headers = HeaderMap::new();
 
         headers.insert("X-Flow-Id", flow_id.0.parse().unwrap());
 
-        let request_builder = self.http_client.get(&connect_url).headers(headers);
+        let request_builder = if self.config.partitions.is_empty() {
+            let connect_url = create_connect_url(&self.config, subscription_id);
+            self.http_client.get(&connect_url).headers(headers)
+        } else {
+            let connect_url = create_events_url(&self.config, subscription_id);
+            self.http_client
+                .post(&connect_url)
+                .headers(headers)
+                .json(&ConnectRequestBody::from_config(&self.config))
+        };
 
         let request_builder = if let Some(AccessToken(token)) = self.token_provider.get_token()? {
             request_builder.bearer_auth(token)
@@ -536,85 +682,40 @@ where
 
         self.metrics_collector.streaming_connect_attempt();
 
-        let mut response = request_builder.send()?;
+        Ok(request_builder.send()?)
+    }
+}
+
+#[cfg(feature = "blocking")]
+impl<M> StreamingClient for NakadiStreamingClient<M>
+where
+    M: MetricsCollector,
+{
+    type LineIterator = NakadiLineIterator;
+    fn connect(
+        &self,
+        subscription_id: &SubscriptionId,
+        flow_id: FlowId,
+    ) -> ::std::result::Result<(StreamId, NakadiLineIterator), ConnectError> {
+        let mut response = self.send_connect_request(subscription_id, &flow_id)?;
+
+        if response.status() == StatusCode::UNAUTHORIZED
+            && renew_rejected_token(&*self.token_provider, "connect")
+        {
+            self.metrics_collector.streaming_connect_attempt_failed();
+            response = self.send_connect_request(subscription_id, &flow_id)?;
+        }
 
         match response.status() {
             StatusCode::OK => {
-                let stream_id = if let Some(stream_id) = response
-                    .headers()
-                    .get("X-Nakadi-StreamId")
-                    .map(|stream_id| StreamId(stream_id.to_str().unwrap().into()))
-                {
-                    stream_id.clone()
-                } else {
-                    return Err(ConnectError::Other(
-                        "The response lacked the \
-                         'X-Nakadi-StreamId' header."
-                            .into(),
-                        flow_id.clone(),
-                    ));
-                };
+                let stream_id = stream_id_from_headers(response.headers(), flow_id)?;
                 Ok((stream_id, NakadiLineIterator::new(response)))
             }
-            StatusCode::FORBIDDEN => {
-                self.metrics_collector.streaming_connect_attempt_failed();
-                Err(ConnectError::Forbidden(
-                    format!(
-                        "{}: {}",
-                        StatusCode::FORBIDDEN,
-                        "Nakadion: Nakadi said forbidden."
-                    ),
-                    flow_id,
-                ))
-            }
-            StatusCode::UNAUTHORIZED => {
-                self.metrics_collector.streaming_connect_attempt_failed();
-                Err(ConnectError::Unauthorized(
-                    format!(
-                        "{}: {}",
-                        StatusCode::UNAUTHORIZED,
-                        read_response_body(&mut response)
-                    ),
-                    flow_id,
-                ))
-            }
-            StatusCode::NOT_FOUND => {
-                self.metrics_collector.streaming_connect_attempt_failed();
-                Err(ConnectError::SubscriptionNotFound(
-                    format!(
-                        "{}: {}",
-                        StatusCode::NOT_FOUND,
-                        read_response_body(&mut response)
-                    ),
-                    flow_id,
-                ))
-            }
-            StatusCode::BAD_REQUEST => {
-                self.metrics_collector.streaming_connect_attempt_failed();
-                Err(ConnectError::BadRequest(
-                    format!(
-                        "{}: {}",
-                        StatusCode::BAD_REQUEST,
-                        read_response_body(&mut response)
-                    ),
-                    flow_id,
-                ))
-            }
-            StatusCode::CONFLICT => {
-                self.metrics_collector.streaming_connect_attempt_failed();
-                Err(ConnectError::Conflict(
-                    format!(
-                        "{}: {}",
-                        StatusCode::CONFLICT,
-                        read_response_body(&mut response)
-                    ),
-                    flow_id,
-                ))
-            }
             other_status => {
                 self.metrics_collector.streaming_connect_attempt_failed();
-                Err(ConnectError::Other(
-                    format!("{}: {}", other_status, read_response_body(&mut response)),
+                Err(connect_error(
+                    other_status,
+                    read_response_body(&mut response),
                     flow_id,
                 ))
             }
@@ -622,6 +723,56 @@ where
     }
 }
 
+/// Extracts the `StreamId` from the headers of a successful connect attempt.
+pub(crate) fn stream_id_from_headers(
+    headers: &::reqwest::header::HeaderMap,
+    flow_id: FlowId,
+) -> Result<StreamId, ConnectError> {
+    if let Some(stream_id) = headers
+        .get("X-Nakadi-StreamId")
+        .and_then(|stream_id| stream_id.to_str().ok())
+    {
+        Ok(StreamId(stream_id.into()))
+    } else {
+        Err(ConnectError::Other(
+            "The response lacked the \
+             'X-Nakadi-StreamId' header."
+                .into(),
+            flow_id,
+        ))
+    }
+}
+
+/// Maps the status and body of a response to a failed
+/// connect attempt to a `ConnectError`.
+pub(crate) fn connect_error(status: StatusCode, body: String, flow_id: FlowId) -> ConnectError {
+    match status {
+        StatusCode::FORBIDDEN => ConnectError::Forbidden(
+            format!(
+                "{}: {}",
+                StatusCode::FORBIDDEN,
+                "Nakadion: Nakadi said forbidden."
+            ),
+            flow_id,
+        ),
+        StatusCode::UNAUTHORIZED => {
+            ConnectError::Unauthorized(format!("{}: {}", StatusCode::UNAUTHORIZED, body), flow_id)
+        }
+        StatusCode::NOT_FOUND => ConnectError::SubscriptionNotFound(
+            format!("{}: {}", StatusCode::NOT_FOUND, body),
+            flow_id,
+        ),
+        StatusCode::BAD_REQUEST => {
+            ConnectError::BadRequest(format!("{}: {}", StatusCode::BAD_REQUEST, body), flow_id)
+        }
+        StatusCode::CONFLICT => {
+            ConnectError::Conflict(format!("{}: {}", StatusCode::CONFLICT, body), flow_id)
+        }
+        other_status => ConnectError::Other(format!("{}: {}", other_status, body), flow_id),
+    }
+}
+
+#[cfg(feature = "blocking")]
 fn read_response_body(response: &mut Response) -> String {
     let mut buf = String::new();
     response
@@ -656,12 +807,12 @@ impl ConnectError {
     /// Returns true if this error can most possibly not
     /// be mitigated by performing a retry.
     pub fn is_permanent(&self) -> bool {
-        match *self {
-            ConnectError::Forbidden(_, _) => true,
-            ConnectError::BadRequest(_, _) => true,
-            ConnectError::SubscriptionNotFound(_, _) => true,
-            _ => false,
-        }
+        matches!(
+            *self,
+            ConnectError::Forbidden(_, _)
+                | ConnectError::BadRequest(_, _)
+                | ConnectError::SubscriptionNotFound(_, _)
+        )
     }
 }
 
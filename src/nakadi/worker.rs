@@ -1,6 +1,6 @@
 //! Processing a partition
 use std::sync::mpsc;
-use std::thread;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
 use crate::cancellation_token::*;
@@ -9,9 +9,16 @@ use serde_json;
 
 use crate::nakadi::batch::Batch;
 use crate::nakadi::committer::Committer;
-use crate::nakadi::handler::{BatchHandler, ProcessingStatus};
-use crate::nakadi::metrics::MetricsCollector;
+use crate::nakadi::consumer::{ShutdownTracker, StopReason};
+use crate::nakadi::dead_letter::DeadLetterSink;
+use crate::nakadi::handler::{
+    BatchHandler, HandlerStopReason, ProcessingStatus, SubscriptionCursor,
+};
+use crate::nakadi::metrics::{MetricsCollector, MetricsContext};
 use crate::nakadi::model::PartitionId;
+use crate::nakadi::retry::{BatchRetries, NextAttempt};
+use crate::nakadi::HandlerRetryPolicy;
+use crate::thread_handle::ThreadHandle;
 
 /// A worker is responsible for executing a handler on a given
 /// partition. A worker guarantees that its `BatchHandler`
@@ -23,17 +30,24 @@ pub struct Worker {
     /// The partition this worker is responsible for.
     partition: PartitionId,
     metrics_collector: Box<dyn MetricsCollector>,
+    /// Why the worker was told to stop
+    stop_reason: Arc<Mutex<HandlerStopReason>>,
+    thread: ThreadHandle,
 }
 
 impl Worker {
     /// Start the worker.
     ///
-    /// It will run until stop is called or the `BatchHandler` fails.
+    /// It will run until stop is called or the `BatchHandler` fails
+    /// and no `DeadLetterSink` is configured.
     pub fn start<H, M>(
         handler: H,
         committer: Committer,
         partition: PartitionId,
         metrics_collector: M,
+        handler_retry_policy: HandlerRetryPolicy,
+        dead_letter_sink: Option<Arc<dyn DeadLetterSink + Send + Sync>>,
+        shutdown: ShutdownTracker,
     ) -> Worker
     where
         H: BatchHandler + Send + 'static,
@@ -44,40 +58,70 @@ impl Worker {
         let lifecycle = CancellationTokenSource::new(metrics_collector.clone());
 
         let cancellation_token = lifecycle.auto_token();
+        let stop_reason = Arc::new(Mutex::new(HandlerStopReason::Cancelled));
 
-        let handle = Worker {
-            lifecycle,
-            sender,
-            partition: partition.clone(),
-            metrics_collector: Box::new(metrics_collector.clone()),
-        };
-
-        start_handler_loop(
+        let thread = start_handler_loop(
             receiver,
             cancellation_token,
-            partition,
+            partition.clone(),
             handler,
             committer,
-            metrics_collector,
+            metrics_collector.clone(),
+            handler_retry_policy,
+            dead_letter_sink,
+            shutdown,
+            stop_reason.clone(),
         );
 
-        handle
-    }
-
-    /// Returns true if the `Worker` is still running
-    pub fn running(&self) -> bool {
-        !self.lifecycle.is_any_cancelled()
+        Worker {
+            lifecycle,
+            sender,
+            partition,
+            metrics_collector: Box::new(metrics_collector),
+            stop_reason,
+            thread,
+        }
     }
 
     /// Request the worker to stop.
     ///
     /// This does not necessarily cause the worker to stop
-    /// immediately. Poll `self::running()` until the worker has
-    /// stopped if you depend on the fact that the worker reales stopped working.
+    /// immediately. Use `join` or `wait_timeout` if you depend
+    /// on the fact that the worker really stopped working.
+    ///
+    /// The handler will be told that it was cancelled.
     pub fn stop(&self) {
         self.lifecycle.request_cancellation()
     }
 
+    /// Request the worker to stop because it did not receive
+    /// any batches for too long.
+    ///
+    /// The handler will be told that it stopped due to an idle timeout.
+    pub fn stop_idle(&self) {
+        self.stop_with(HandlerStopReason::IdleTimeout)
+    }
+
+    /// Request the worker to stop because the stream ended.
+    ///
+    /// The handler will be told that the stream ended.
+    pub fn end_of_stream(&self) {
+        self.stop_with(HandlerStopReason::StreamEnded)
+    }
+
+    fn stop_with(&self, reason: HandlerStopReason) {
+        *self.stop_reason.lock().unwrap() = reason;
+        self.stop()
+    }
+
+    /// Request the worker to process the batches already sent
+    /// to it and then stop.
+    ///
+    /// No more batches may be sent to the worker afterwards.
+    pub fn drain(&self) {
+        self.lifecycle.request_drain()
+    }
+
     /// Process the batch.
     pub fn process(&self, batch: Batch) -> Result<(), Error> {
         self.sender.send(batch).map_err(|err| {
@@ -94,8 +138,23 @@ impl Worker {
     pub fn partition(&self) -> &PartitionId {
         &self.partition
     }
+
+    /// Block until the worker thread finished or `timeout` elapsed.
+    ///
+    /// Returns true if the worker thread finished.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        self.thread.wait_timeout(timeout)
+    }
+
+    /// Block until the worker thread finished.
+    ///
+    /// Returns a `StopReason` if the worker thread panicked.
+    pub fn join(self) -> Option<StopReason> {
+        StopReason::from_joined(self.thread.join().map(|joined| joined.map(|()| None)))
+    }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn start_handler_loop<H, M>(
     receiver: mpsc::Receiver<Batch>,
     lifecycle: AutoCancellationToken,
@@ -103,25 +162,32 @@ fn start_handler_loop<H, M>(
     handler: H,
     committer: Committer,
     metrics_collector: M,
-) where
+    handler_retry_policy: HandlerRetryPolicy,
+    dead_letter_sink: Option<Arc<dyn DeadLetterSink + Send + Sync>>,
+    shutdown: ShutdownTracker,
+    external_stop_reason: Arc<Mutex<HandlerStopReason>>,
+) -> ThreadHandle
+where
     H: BatchHandler + Send + 'static,
     M: MetricsCollector + Send + Sync + 'static,
 {
-    let builder = thread::Builder::new().name(format!("nakadion-worker-{}", partition));
-    builder
-        .spawn(move || {
-            handler_loop(
-                receiver,
-                lifecycle,
-                partition,
-                handler,
-                committer,
-                metrics_collector,
-            )
-        })
-        .unwrap();
+    ThreadHandle::spawn(format!("nakadion-worker-{}", partition), move || {
+        handler_loop(
+            receiver,
+            lifecycle,
+            partition,
+            handler,
+            committer,
+            metrics_collector,
+            handler_retry_policy,
+            dead_letter_sink,
+            shutdown,
+            &external_stop_reason,
+        )
+    })
 }
 
+#[allow(clippy::too_many_arguments)]
 fn handler_loop<H, M>(
     receiver: mpsc::Receiver<Batch>,
     lifecycle: AutoCancellationToken,
@@ -129,11 +195,16 @@ fn handler_loop<H, M>(
     handler: H,
     committer: Committer,
     metrics_collector: M,
+    handler_retry_policy: HandlerRetryPolicy,
+    dead_letter_sink: Option<Arc<dyn DeadLetterSink + Send + Sync>>,
+    shutdown: ShutdownTracker,
+    external_stop_reason: &Mutex<HandlerStopReason>,
 ) where
     H: BatchHandler,
     M: MetricsCollector,
 {
     let stream_id = committer.stream_id().clone();
+    let subscription_id = committer.subscription_id().clone();
     let mut handler = handler;
 
     info!(
@@ -142,39 +213,55 @@ fn handler_loop<H, M>(
     );
     metrics_collector.worker_worker_started();
 
-    loop {
+    let stopped_externally = || external_stop_reason.lock().unwrap().clone();
+    let mut last_processed_cursor = None;
+
+    let stop_reason = loop {
         if lifecycle.cancellation_requested() {
             info!(
                 "[Worker, stream={}, partition={}] Stop requested externally.",
                 stream_id, partition
             );
-            break;
+            break stopped_externally();
         }
 
         let batch = match receiver.recv_timeout(Duration::from_millis(20)) {
             Ok(batch) => batch,
-            Err(mpsc::RecvTimeoutError::Timeout) => continue,
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                if lifecycle.drain_requested() {
+                    info!(
+                        "[Worker, stream={}, partition={}] All batches processed. Stopping.",
+                        stream_id, partition
+                    );
+                    break HandlerStopReason::StreamEnded;
+                }
+                continue;
+            }
             Err(mpsc::RecvTimeoutError::Disconnected) => {
                 info!(
                     "[Worker, stream={}, partition={}] Cannot receive more batches. \
                      Channel disconnected. Stopping.",
                     stream_id, partition
                 );
-                break;
+                break HandlerStopReason::StreamEnded;
             }
         };
 
-        metrics_collector.worker_batch_received(batch.received_at);
+        let ctx = MetricsContext::for_batch(&subscription_id, &batch.batch_line);
+        metrics_collector.worker_batch_received(&ctx, batch.received_at);
 
-        let handler_result = {
-            let cursor = match serde_json::from_slice(batch.batch_line.cursor()) {
+        let (cursor, handler_result) = {
+            let cursor: SubscriptionCursor = match serde_json::from_slice(batch.batch_line.cursor())
+            {
                 Ok(cursor) => cursor,
                 Err(err) => {
                     error!(
                         "[Worker, stream={}, partition={}] Could not parse cursor. Stopping: {}",
                         stream_id, partition, err
                     );
-                    break;
+                    break HandlerStopReason::Failed {
+                        reason: format!("Could not parse cursor: {}", err),
+                    };
                 }
             };
 
@@ -189,40 +276,116 @@ fn handler_loop<H, M>(
                 continue;
             };
 
-            metrics_collector.worker_batch_size_bytes(events.len());
-            let start = Instant::now();
-            let handler_result = handler.handle(&cursor, events);
-            metrics_collector.worker_batch_processed(start);
+            metrics_collector.worker_batch_size_bytes(&ctx, events.len());
+
+            let mut retries = BatchRetries::new(
+                &handler_retry_policy,
+                batch.received_at,
+                dead_letter_sink.is_some(),
+            );
+            let handler_result = loop {
+                let start = Instant::now();
+                let handler_result = handler.handle(&cursor, events);
+                metrics_collector.worker_batch_processed(&ctx, start);
+
+                let after = match retries.next_attempt(handler_result) {
+                    NextAttempt::Done(handler_result) => break Some(handler_result),
+                    NextAttempt::RetryAfter(after) => after,
+                };
+
+                info!(
+                    "[Worker, stream={}, partition={}] Handler requested a retry or failed. \
+                     Retrying in {:?}(retry #{}).",
+                    stream_id,
+                    partition,
+                    after,
+                    retries.retries()
+                );
+
+                if !wait_for_retry(after, &lifecycle) {
+                    break None;
+                }
+            };
+
+            (cursor, handler_result)
+        };
+
+        let handler_result = if let Some(handler_result) = handler_result {
             handler_result
+        } else {
+            info!(
+                "[Worker, stream={}, partition={}] Stop requested externally while \
+                 waiting for a retry.",
+                stream_id, partition
+            );
+            break stopped_externally();
         };
 
-        match handler_result {
+        let num_events_hint = match handler_result {
             ProcessingStatus::Processed(num_events_hint) => {
+                num_events_hint.iter().for_each(|n| {
+                    metrics_collector.worker_events_in_same_batch_processed(&ctx, *n)
+                });
                 num_events_hint
-                    .iter()
-                    .for_each(|n| metrics_collector.worker_events_in_same_batch_processed(*n));
-                match committer.request_commit(batch, num_events_hint) {
-                    Ok(()) => continue,
-                    Err(err) => {
-                        warn!(
-                            "[Worker, stream={}, partition={}] \
-                             Committer did not accept batch commit request. \
-                             Stopping: {}",
-                            stream_id, partition, err
-                        );
-                        break;
-                    }
-                }
             }
             ProcessingStatus::Failed { reason } => {
+                let dead_letter_sink = if let Some(ref dead_letter_sink) = dead_letter_sink {
+                    dead_letter_sink
+                } else {
+                    warn!(
+                        "[Worker, stream={}, partition={}] Handler failed: {}",
+                        stream_id, partition, reason
+                    );
+                    break HandlerStopReason::Failed { reason };
+                };
+
                 warn!(
-                    "[Worker, stream={}, partition={}] Handler failed: {}",
+                    "[Worker, stream={}, partition={}] Handler failed and all retries \
+                     are exhausted. Putting batch into dead letter sink: {}",
                     stream_id, partition, reason
                 );
-                break;
+
+                let events = batch.batch_line.events().unwrap_or(&[]);
+                if let Err(err) = dead_letter_sink.put_batch(&cursor, events, &reason) {
+                    error!(
+                        "[Worker, stream={}, partition={}] \
+                         Could not put batch into dead letter sink. Stopping: {}",
+                        stream_id, partition, err
+                    );
+                    break HandlerStopReason::Failed {
+                        reason: format!("Could not put batch into dead letter sink: {}", err),
+                    };
+                }
+
+                None
             }
+            ProcessingStatus::Retry { .. } => unreachable!("Retries are handled above"),
+        };
+
+        last_processed_cursor = Some(cursor);
+
+        if let Err(err) = committer.request_commit(batch, num_events_hint) {
+            warn!(
+                "[Worker, stream={}, partition={}] \
+                 Committer did not accept batch commit request. \
+                 Stopping: {}",
+                stream_id, partition, err
+            );
+            break HandlerStopReason::Failed {
+                reason: format!("Committer did not accept batch commit request: {}", err),
+            };
         }
-    }
+
+        shutdown.batch_processed();
+    };
+
+    shutdown.batches_dropped(receiver.try_iter().count());
+
+    info!(
+        "[Worker, stream={}, partition={}] Stopping handler: {:?}",
+        stream_id, partition, stop_reason
+    );
+    handler.on_stop(&stop_reason, last_processed_cursor.as_ref());
 
     metrics_collector.worker_worker_stopped();
 
@@ -231,3 +394,10 @@ fn handler_loop<H, M>(
         stream_id, partition
     );
 }
+
+/// Wait until a retry is due.
+///
+/// Returns `false` if a stop was requested while waiting.
+fn wait_for_retry(after: Duration, lifecycle: &AutoCancellationToken) -> bool {
+    !lifecycle.wait_for_cancellation_request(after)
+}
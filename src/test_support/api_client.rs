@@ -0,0 +1,294 @@
+//! An `ApiClient` recording the calls made to it
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use serde_json;
+use uuid::Uuid;
+
+use crate::nakadi::api::stats::SubscriptionStats;
+use crate::nakadi::api::{
+    ApiClient, CommitError, CommitStatus, CreateEventTypeError, CreateSubscriptionError,
+    CreateSubscriptionStatus, DeleteEventTypeError, DeleteSubscriptionError, EventTypeDefinition,
+    GetCursorsError, ResetCursorsError, StatsError, Subscription, SubscriptionRequest,
+};
+use crate::nakadi::handler::SubscriptionCursor;
+use crate::nakadi::model::{FlowId, StreamId, SubscriptionId};
+
+/// A call to `ApiClient::commit_cursors_budgeted`
+#[derive(Debug, Clone)]
+pub struct CommitCall {
+    pub subscription_id: SubscriptionId,
+    pub stream_id: StreamId,
+    /// The cursors as passed to the `ApiClient`
+    pub cursors: Vec<Vec<u8>>,
+    pub flow_id: FlowId,
+    pub budget: Duration,
+}
+
+impl CommitCall {
+    /// The cursors of this call deserialized
+    ///
+    /// # Panics
+    ///
+    /// A cursor is not a valid `SubscriptionCursor`.
+    pub fn subscription_cursors(&self) -> Vec<SubscriptionCursor> {
+        self.cursors
+            .iter()
+            .map(|c| serde_json::from_slice(c).expect("Not a valid cursor"))
+            .collect()
+    }
+}
+
+/// An `ApiClient` keeping all state in memory
+///
+/// All calls to `commit_cursors_budgeted` are recorded.
+/// Commits succeed with `CommitStatus::AllOffsetsIncreased`
+/// unless other results were scripted with `push_commit_result`.
+/// Successfully committed cursors can be retrieved via
+/// `get_committed_cursors`.
+///
+/// Clones share the same state.
+#[derive(Clone, Default)]
+pub struct RecordingApiClient {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    commit_calls: Vec<CommitCall>,
+    commit_results: VecDeque<Result<CommitStatus, CommitError>>,
+    committed: Vec<SubscriptionCursor>,
+    event_types: Vec<String>,
+    subscriptions: Vec<Subscription>,
+    stats: SubscriptionStats,
+}
+
+impl RecordingApiClient {
+    pub fn new() -> RecordingApiClient {
+        RecordingApiClient::default()
+    }
+
+    /// Let a following commit end with the given result.
+    ///
+    /// Results are used in the order they were pushed.
+    pub fn push_commit_result(&self, result: Result<CommitStatus, CommitError>) {
+        self.lock().commit_results.push_back(result);
+    }
+
+    /// All calls to `commit_cursors_budgeted` in the order they were made
+    pub fn commit_calls(&self) -> Vec<CommitCall> {
+        self.lock().commit_calls.clone()
+    }
+
+    /// The names of all event types created and not deleted
+    pub fn event_types(&self) -> Vec<String> {
+        self.lock().event_types.clone()
+    }
+
+    /// Set the statistics returned by `subscription_stats`
+    pub fn set_stats(&self, stats: SubscriptionStats) {
+        self.lock().stats = stats;
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+}
+
+impl State {
+    fn update_committed(&mut self, cursors: Vec<SubscriptionCursor>) {
+        for cursor in cursors {
+            self.committed
+                .retain(|c| c.event_type != cursor.event_type || c.partition != cursor.partition);
+            self.committed.push(cursor);
+        }
+    }
+}
+
+impl ApiClient for RecordingApiClient {
+    fn commit_cursors_budgeted<T: AsRef<[u8]>>(
+        &self,
+        subscription_id: &SubscriptionId,
+        stream_id: &StreamId,
+        cursors: &[T],
+        flow_id: FlowId,
+        budget: Duration,
+    ) -> Result<CommitStatus, CommitError> {
+        let call = CommitCall {
+            subscription_id: subscription_id.clone(),
+            stream_id: stream_id.clone(),
+            cursors: cursors.iter().map(|c| c.as_ref().to_vec()).collect(),
+            flow_id,
+            budget,
+        };
+
+        let mut state = self.lock();
+        let result = state
+            .commit_results
+            .pop_front()
+            .unwrap_or(Ok(CommitStatus::AllOffsetsIncreased));
+
+        if result.is_ok() {
+            let committed = call
+                .cursors
+                .iter()
+                .filter_map(|c| serde_json::from_slice(c).ok())
+                .collect();
+            state.update_committed(committed);
+        }
+
+        state.commit_calls.push(call);
+        result
+    }
+
+    fn delete_event_type(&self, event_type_name: &str) -> Result<(), DeleteEventTypeError> {
+        self.lock().event_types.retain(|et| et != event_type_name);
+        Ok(())
+    }
+
+    fn create_event_type(
+        &self,
+        event_type: &EventTypeDefinition,
+    ) -> Result<(), CreateEventTypeError> {
+        let mut state = self.lock();
+        if state.event_types.contains(&event_type.name) {
+            return Err(CreateEventTypeError::Conflict(event_type.name.clone()));
+        }
+        state.event_types.push(event_type.name.clone());
+        Ok(())
+    }
+
+    fn create_subscription(
+        &self,
+        request: &SubscriptionRequest,
+    ) -> Result<CreateSubscriptionStatus, CreateSubscriptionError> {
+        let mut state = self.lock();
+        if let Some(existing) = state.subscriptions.iter().find(|s| {
+            s.owning_application == request.owning_application
+                && s.event_types == request.event_types
+        }) {
+            return Ok(CreateSubscriptionStatus::AlreadyExists(existing.clone()));
+        }
+
+        let subscription = Subscription {
+            id: SubscriptionId::new(Uuid::new_v4().to_string()),
+            owning_application: request.owning_application.clone(),
+            event_types: request.event_types.clone(),
+        };
+        state.subscriptions.push(subscription.clone());
+        Ok(CreateSubscriptionStatus::Created(subscription))
+    }
+
+    fn delete_subscription(&self, id: &SubscriptionId) -> Result<(), DeleteSubscriptionError> {
+        self.lock().subscriptions.retain(|s| s.id.0 != id.0);
+        Ok(())
+    }
+
+    fn subscription_stats(
+        &self,
+        _id: &SubscriptionId,
+        _show_time_lag: bool,
+    ) -> Result<SubscriptionStats, StatsError> {
+        Ok(self.lock().stats.clone())
+    }
+
+    fn get_committed_cursors(
+        &self,
+        _subscription_id: &SubscriptionId,
+        _flow_id: FlowId,
+    ) -> Result<Vec<SubscriptionCursor>, GetCursorsError> {
+        Ok(self.lock().committed.clone())
+    }
+
+    fn reset_cursors(
+        &self,
+        _subscription_id: &SubscriptionId,
+        cursors: &[SubscriptionCursor],
+        _flow_id: FlowId,
+    ) -> Result<(), ResetCursorsError> {
+        self.lock().update_committed(cursors.to_vec());
+        Ok(())
+    }
+}
+
+#[cfg(feature = "blocking")]
+#[test]
+fn records_the_commits_of_a_consumer() {
+    use std::thread;
+    use std::time::Instant;
+
+    use serde_json::json;
+
+    use crate::nakadi::handler::{
+        BatchHandler, CreateHandlerError, HandlerFactory, ProcessingStatus,
+    };
+    use crate::nakadi::metrics::DevNullMetricsCollector;
+    use crate::nakadi::model::PartitionId;
+    use crate::nakadi::{ConsumerSettings, Nakadion};
+    use crate::test_support::{ScriptedStream, ScriptedStreamingClient};
+
+    struct Handler;
+
+    impl BatchHandler for Handler {
+        fn handle(&mut self, _cursor: &SubscriptionCursor, _events: &[u8]) -> ProcessingStatus {
+            ProcessingStatus::Processed(None)
+        }
+    }
+
+    struct Factory;
+
+    impl HandlerFactory for Factory {
+        type Handler = Handler;
+
+        fn create_handler(&self, _partition: &PartitionId) -> Result<Handler, CreateHandlerError> {
+            Ok(Handler)
+        }
+    }
+
+    let cursor = |offset: &str| SubscriptionCursor {
+        partition: PartitionId::new("0"),
+        offset: offset.to_string(),
+        event_type: "test_event".to_string(),
+    };
+
+    let streaming_client = ScriptedStreamingClient::new();
+    streaming_client.push_stream(
+        ScriptedStream::new("stream-1")
+            .batch(&cursor("0"), &[json!({"a": 1})])
+            .keep_alive(&cursor("0"))
+            .batch(&cursor("1"), &[json!({"a": 2})])
+            .wait(Duration::from_millis(500)),
+    );
+    let api_client = RecordingApiClient::new();
+
+    let nakadion = Nakadion::start_with(
+        SubscriptionId::new("subscription"),
+        streaming_client,
+        api_client.clone(),
+        Factory,
+        DevNullMetricsCollector,
+        ConsumerSettings::default(),
+    )
+    .unwrap();
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while api_client
+        .get_committed_cursors(&SubscriptionId::new("subscription"), FlowId::default())
+        .unwrap()
+        != vec![cursor("1")]
+    {
+        assert!(Instant::now() < deadline, "Cursors were not committed");
+        thread::sleep(Duration::from_millis(10));
+    }
+    nakadion.stop();
+
+    let calls = api_client.commit_calls();
+    assert!(calls.iter().all(|call| call.stream_id.0 == "stream-1"));
+    let offsets: Vec<String> = calls
+        .iter()
+        .flat_map(|call| call.subscription_cursors())
+        .map(|c| c.offset)
+        .collect();
+    assert_eq!(offsets.last().map(String::as_str), Some("1"));
+}
@@ -0,0 +1,1320 @@
+//! A fake Nakadi serving HTTP from within the test process
+//!
+//! `FakeNakadi` listens on a local port and serves the parts of the
+//! Nakadi API used by `Nakadion`. Set the `nakadi_host` of the clients
+//! to `FakeNakadi::nakadi_host` to test consumers and publishers
+//! without a real Nakadi.
+//!
+//! The following endpoints are served:
+//!
+//! * `GET /subscriptions/{id}/events` and `POST /subscriptions/{id}/events`
+//! * `POST /subscriptions/{id}/cursors` to commit cursors
+//! * `GET /subscriptions/{id}/cursors` and `PATCH /subscriptions/{id}/cursors`
+//! * `GET /subscriptions/{id}/stats`
+//! * `POST /subscriptions` and `DELETE /subscriptions/{id}`
+//! * `POST /event-types` and `DELETE /event-types/{name}`
+//! * `POST /event-types/{name}/events`
+//!
+//! Some simplifications apply:
+//!
+//! * Only one stream can be connected to a subscription at a time.
+//!   Further connection attempts are rejected with `409 Conflict`.
+//! * Of the streaming parameters only `batch_limit`, `max_uncommitted_events`
+//!   and `partitions` are respected.
+//! * Partitions are named `"0"`, `"1"`, ... and offsets are the
+//!   zero padded index of an event within its partition.
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::{BufRead, BufReader, Error as IoError, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use failure::*;
+use reqwest::StatusCode;
+use serde::Serialize;
+use serde_json::{self, json, Value};
+use url::Url;
+use uuid::Uuid;
+
+use crate::nakadi::api::{EventTypeStatistics, ReadFrom, SubscriptionRequest};
+use crate::nakadi::handler::SubscriptionCursor;
+use crate::nakadi::model::{PartitionId, StreamId, SubscriptionId};
+use crate::nakadi::streaming_client::EventTypePartition;
+
+/// Configures the behaviour of a `FakeNakadi`
+#[derive(Debug, Clone)]
+pub struct FakeNakadiConfig {
+    /// A stream is closed if its batches were not
+    /// committed within this time after being sent.
+    pub commit_timeout: Duration,
+    /// A keep alive line is sent for each partition
+    /// of a stream if no batch was sent for this long.
+    pub keep_alive_interval: Duration,
+    /// The number of partitions of an event type created
+    /// via HTTP without a `default_statistic`
+    pub default_partitions: usize,
+}
+
+impl Default for FakeNakadiConfig {
+    fn default() -> Self {
+        FakeNakadiConfig {
+            commit_timeout: Duration::from_secs(60),
+            keep_alive_interval: Duration::from_millis(500),
+            default_partitions: 1,
+        }
+    }
+}
+
+/// An endpoint of a `FakeNakadi` faults can be injected into
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Endpoint {
+    /// Connecting to a stream
+    Stream,
+    /// Committing cursors
+    Commit,
+    /// Getting the committed cursors
+    GetCursors,
+    /// Resetting the committed cursors
+    ResetCursors,
+    /// Getting the statistics of a subscription
+    Stats,
+    /// Publishing events
+    Publish,
+    CreateEventType,
+    DeleteEventType,
+    CreateSubscription,
+    DeleteSubscription,
+}
+
+/// A fault to happen on the next request to an `Endpoint`
+#[derive(Debug, Clone)]
+pub enum Fault {
+    /// Respond with the given status code and body
+    Status(u16, String),
+    /// Close the connection without responding
+    CloseConnection,
+    /// Wait for the given time before handling the request
+    Delay(Duration),
+}
+
+/// A commit received by a `FakeNakadi`
+#[derive(Debug, Clone)]
+pub struct CommitRecord {
+    pub subscription_id: SubscriptionId,
+    pub stream_id: StreamId,
+    pub cursors: Vec<SubscriptionCursor>,
+}
+
+/// A local stand-in for Nakadi.
+///
+/// The server runs on background threads until
+/// the `FakeNakadi` is dropped.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use nakadion::test_support::FakeNakadi;
+/// use serde_json::json;
+///
+/// let nakadi = FakeNakadi::start().unwrap();
+///
+/// nakadi.create_event_type("my_event_type", 2);
+/// let subscription_id = nakadi.create_subscription("my_app", &["my_event_type"]);
+/// nakadi.publish("my_event_type", &[json!({"id": 1}), json!({"id": 2})]);
+///
+/// // Connect consumers with `nakadi.nakadi_host()` as the `nakadi_host`
+/// // and inspect what they committed afterwards
+/// let cursors = nakadi.committed_cursors(&subscription_id);
+/// ```
+pub struct FakeNakadi {
+    address: SocketAddr,
+    state: Arc<Mutex<State>>,
+    stop: Arc<AtomicBool>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+impl FakeNakadi {
+    /// Start a `FakeNakadi` with the default configuration
+    pub fn start() -> Result<FakeNakadi, Error> {
+        FakeNakadi::start_with(FakeNakadiConfig::default())
+    }
+
+    /// Start a `FakeNakadi` listening on a free local port
+    pub fn start_with(config: FakeNakadiConfig) -> Result<FakeNakadi, Error> {
+        let listener = TcpListener::bind("127.0.0.1:0").context("Could not bind fake Nakadi")?;
+        let address = listener.local_addr()?;
+
+        let state = Arc::new(Mutex::new(State::new(config)));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let handle = {
+            let state = state.clone();
+            let stop = stop.clone();
+            thread::Builder::new()
+                .name("fake-nakadi".to_string())
+                .spawn(move || accept_loop(listener, state, stop))
+                .context("Could not start fake Nakadi")?
+        };
+
+        Ok(FakeNakadi {
+            address,
+            state,
+            stop,
+            handle: Some(handle),
+        })
+    }
+
+    /// The URI to use as the `nakadi_host` of the clients
+    pub fn nakadi_host(&self) -> String {
+        format!("http://{}", self.address)
+    }
+
+    /// Create an event type with the given number of partitions.
+    ///
+    /// An already existing event type is replaced.
+    pub fn create_event_type<T: Into<String>>(&self, name: T, partitions: usize) {
+        self.lock()
+            .event_types
+            .insert(name.into(), EventTypeState::new(partitions));
+    }
+
+    /// Create a subscription reading the given event types from the beginning
+    pub fn create_subscription<T: Into<String>>(
+        &self,
+        owning_application: T,
+        event_types: &[&str],
+    ) -> SubscriptionId {
+        let id = Uuid::new_v4().to_string();
+        self.lock().subscriptions.insert(
+            id.clone(),
+            SubscriptionState::new(
+                owning_application.into(),
+                event_types.iter().map(|et| et.to_string()).collect(),
+            ),
+        );
+        SubscriptionId(id)
+    }
+
+    /// Publish events distributing them over all partitions
+    ///
+    /// # Panics
+    ///
+    /// The event type does not exist or an event could not be serialized.
+    pub fn publish<T: Serialize>(&self, event_type: &str, events: &[T]) {
+        let events = to_values(events);
+        self.lock()
+            .event_type_mut(event_type)
+            .append_round_robin(events);
+    }
+
+    /// Publish events to a single partition
+    ///
+    /// # Panics
+    ///
+    /// The event type or partition does not exist
+    /// or an event could not be serialized.
+    pub fn publish_to_partition<T: Serialize>(
+        &self,
+        event_type: &str,
+        partition: usize,
+        events: &[T],
+    ) {
+        let events = to_values(events);
+        let mut state = self.lock();
+        let partitions = &mut state.event_type_mut(event_type).partitions;
+        assert!(
+            partition < partitions.len(),
+            "Event type {} has no partition {}",
+            event_type,
+            partition
+        );
+        partitions[partition].extend(events);
+    }
+
+    /// All events of an event type ordered by partition
+    pub fn published_events(&self, event_type: &str) -> Vec<Value> {
+        self.lock()
+            .event_types
+            .get(event_type)
+            .map(|et| et.partitions.iter().flatten().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// The cursors currently committed for a subscription
+    pub fn committed_cursors(&self, subscription_id: &SubscriptionId) -> Vec<SubscriptionCursor> {
+        let state = self.lock();
+        state
+            .subscriptions
+            .get(&subscription_id.0)
+            .map(|subscription| {
+                state
+                    .subscription_partitions(subscription)
+                    .into_iter()
+                    .map(|key| SubscriptionCursor {
+                        partition: PartitionId(key.1.to_string()),
+                        offset: offset(subscription.committed(&key)),
+                        event_type: key.0,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// All commits received so far in the order they were received
+    pub fn commits(&self) -> Vec<CommitRecord> {
+        self.lock().commits.clone()
+    }
+
+    /// Use the given stream ids for the next streams connected.
+    ///
+    /// Once these are used up stream ids are generated.
+    pub fn set_next_stream_ids<I, T>(&self, stream_ids: I)
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<String>,
+    {
+        self.lock()
+            .next_stream_ids
+            .extend(stream_ids.into_iter().map(Into::into));
+    }
+
+    /// The id of the stream currently connected to the subscription
+    pub fn active_stream(&self, subscription_id: &SubscriptionId) -> Option<StreamId> {
+        self.lock()
+            .subscriptions
+            .get(&subscription_id.0)
+            .and_then(|subscription| subscription.stream.as_ref())
+            .map(|stream| StreamId(stream.id.clone()))
+    }
+
+    /// The number of streams opened so far
+    pub fn streams_opened(&self) -> usize {
+        self.lock().streams_opened
+    }
+
+    /// Close the connections of all connected streams
+    pub fn close_streams(&self) {
+        for subscription in self.lock().subscriptions.values_mut() {
+            subscription.stream = None;
+        }
+    }
+
+    /// Let the next request to `endpoint` fail with `fault`.
+    ///
+    /// Multiple faults for the same endpoint happen on
+    /// consecutive requests in the order they were injected.
+    pub fn inject_fault(&self, endpoint: Endpoint, fault: Fault) {
+        self.lock()
+            .faults
+            .entry(endpoint)
+            .or_default()
+            .push_back(fault);
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+}
+
+impl Drop for FakeNakadi {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        // Wake up the listener so that it sees the stop flag
+        let _ = TcpStream::connect(self.address);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+type PartitionKey = (String, usize);
+
+struct EventTypeState {
+    partitions: Vec<Vec<Value>>,
+    next_partition: usize,
+}
+
+impl EventTypeState {
+    fn new(partitions: usize) -> EventTypeState {
+        EventTypeState {
+            partitions: vec![Vec::new(); partitions.max(1)],
+            next_partition: 0,
+        }
+    }
+
+    fn append_round_robin(&mut self, events: Vec<Value>) {
+        for event in events {
+            self.partitions[self.next_partition].push(event);
+            self.next_partition = (self.next_partition + 1) % self.partitions.len();
+        }
+    }
+}
+
+struct SubscriptionState {
+    owning_application: String,
+    event_types: Vec<String>,
+    // The index of the next event to consume for each partition
+    committed: BTreeMap<PartitionKey, usize>,
+    stream: Option<ActiveStream>,
+}
+
+impl SubscriptionState {
+    fn new(owning_application: String, event_types: Vec<String>) -> SubscriptionState {
+        SubscriptionState {
+            owning_application,
+            event_types,
+            committed: BTreeMap::new(),
+            stream: None,
+        }
+    }
+
+    fn committed(&self, key: &PartitionKey) -> usize {
+        self.committed.get(key).cloned().unwrap_or(0)
+    }
+
+    fn to_json(&self, id: &str) -> String {
+        json!({
+            "id": id,
+            "owning_application": self.owning_application,
+            "event_types": self.event_types,
+        })
+        .to_string()
+    }
+}
+
+struct ActiveStream {
+    id: String,
+    partitions: Vec<PartitionKey>,
+    // The index of the next event to send for each partition
+    sent: BTreeMap<PartitionKey, usize>,
+    batch_limit: usize,
+    max_uncommitted_events: usize,
+    uncommitted_since: Option<Instant>,
+}
+
+/// The parameters of a stream either from the query or the body
+#[derive(Deserialize, Default)]
+struct StreamParameters {
+    #[serde(default)]
+    partitions: Vec<EventTypePartition>,
+    batch_limit: Option<usize>,
+    max_uncommitted_events: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct EventTypeBody {
+    name: String,
+    default_statistic: Option<EventTypeStatistics>,
+}
+
+#[derive(Deserialize)]
+struct CursorsBody {
+    items: Vec<SubscriptionCursor>,
+}
+
+struct State {
+    config: FakeNakadiConfig,
+    event_types: BTreeMap<String, EventTypeState>,
+    subscriptions: BTreeMap<String, SubscriptionState>,
+    commits: Vec<CommitRecord>,
+    faults: HashMap<Endpoint, VecDeque<Fault>>,
+    next_stream_ids: VecDeque<String>,
+    streams_opened: usize,
+}
+
+type Response = (u16, String);
+
+impl State {
+    fn new(config: FakeNakadiConfig) -> State {
+        State {
+            config,
+            event_types: BTreeMap::new(),
+            subscriptions: BTreeMap::new(),
+            commits: Vec::new(),
+            faults: HashMap::new(),
+            next_stream_ids: VecDeque::new(),
+            streams_opened: 0,
+        }
+    }
+
+    fn event_type_mut(&mut self, event_type: &str) -> &mut EventTypeState {
+        self.event_types
+            .get_mut(event_type)
+            .unwrap_or_else(|| panic!("Event type {} does not exist", event_type))
+    }
+
+    fn num_events(&self, key: &PartitionKey) -> usize {
+        self.event_types
+            .get(&key.0)
+            .and_then(|et| et.partitions.get(key.1))
+            .map(Vec::len)
+            .unwrap_or(0)
+    }
+
+    fn subscription_partitions(&self, subscription: &SubscriptionState) -> Vec<PartitionKey> {
+        subscription
+            .event_types
+            .iter()
+            .flat_map(|name| {
+                let num_partitions = self
+                    .event_types
+                    .get(name)
+                    .map(|et| et.partitions.len())
+                    .unwrap_or(0);
+                (0..num_partitions).map(move |p| (name.clone(), p))
+            })
+            .collect()
+    }
+
+    fn create_event_type(&mut self, body: &[u8]) -> Response {
+        let definition: EventTypeBody = match serde_json::from_slice(body) {
+            Ok(definition) => definition,
+            Err(err) => return (400, problem(err)),
+        };
+
+        if self.event_types.contains_key(&definition.name) {
+            return (409, problem("Event type already exists"));
+        }
+
+        let partitions = definition
+            .default_statistic
+            .map(|s| s.read_parallelism.max(s.write_parallelism) as usize)
+            .filter(|p| *p > 0)
+            .unwrap_or(self.config.default_partitions);
+
+        self.event_types
+            .insert(definition.name, EventTypeState::new(partitions));
+        (201, String::new())
+    }
+
+    fn delete_event_type(&mut self, name: &str) -> Response {
+        match self.event_types.remove(name) {
+            Some(_) => (200, String::new()),
+            None => (404, problem("Event type not found")),
+        }
+    }
+
+    fn publish(&mut self, name: &str, body: &[u8]) -> Response {
+        let events: Vec<Value> = match serde_json::from_slice(body) {
+            Ok(events) => events,
+            Err(err) => return (400, problem(err)),
+        };
+
+        match self.event_types.get_mut(name) {
+            Some(event_type) => {
+                event_type.append_round_robin(events);
+                (200, String::new())
+            }
+            None => (404, problem("Event type not found")),
+        }
+    }
+
+    fn create_subscription(&mut self, body: &[u8]) -> Response {
+        let request: SubscriptionRequest = match serde_json::from_slice(body) {
+            Ok(request) => request,
+            Err(err) => return (400, problem(err)),
+        };
+
+        if let Some(missing) = request
+            .event_types
+            .iter()
+            .find(|et| !self.event_types.contains_key(*et))
+        {
+            return (
+                422,
+                problem(format!("Event type {} does not exist", missing)),
+            );
+        }
+
+        if let Some((id, subscription)) = self.subscriptions.iter().find(|(_, s)| {
+            s.owning_application == request.owning_application
+                && s.event_types == request.event_types
+        }) {
+            return (200, subscription.to_json(id));
+        }
+
+        let id = Uuid::new_v4().to_string();
+        let mut subscription =
+            SubscriptionState::new(request.owning_application, request.event_types);
+        if let Some(ReadFrom::End) = request.read_from {
+            for key in self.subscription_partitions(&subscription) {
+                let end = self.num_events(&key);
+                subscription.committed.insert(key, end);
+            }
+        }
+        let json = subscription.to_json(&id);
+        self.subscriptions.insert(id, subscription);
+        (201, json)
+    }
+
+    fn delete_subscription(&mut self, id: &str) -> Response {
+        match self.subscriptions.remove(id) {
+            Some(_) => (204, String::new()),
+            None => (404, problem("Subscription not found")),
+        }
+    }
+
+    fn get_cursors(&self, id: &str) -> Response {
+        let subscription = match self.subscriptions.get(id) {
+            Some(subscription) => subscription,
+            None => return (404, problem("Subscription not found")),
+        };
+
+        let items: Vec<Value> = self
+            .subscription_partitions(subscription)
+            .into_iter()
+            .map(|key| {
+                let next = subscription.committed(&key);
+                cursor_json(&key, next)
+            })
+            .collect();
+        (200, json!({ "items": items }).to_string())
+    }
+
+    fn reset_cursors(&mut self, id: &str, body: &[u8]) -> Response {
+        let cursors: CursorsBody = match serde_json::from_slice(body) {
+            Ok(cursors) => cursors,
+            Err(err) => return (422, problem(err)),
+        };
+
+        let mut reset = Vec::new();
+        for cursor in cursors.items {
+            match self.parse_cursor(&cursor) {
+                Ok(parsed) => reset.push(parsed),
+                Err(response) => return response,
+            }
+        }
+
+        match self.subscriptions.get_mut(id) {
+            Some(subscription) => {
+                subscription.committed.extend(reset);
+                // Nakadi closes all streams when cursors are reset
+                subscription.stream = None;
+                (204, String::new())
+            }
+            None => (404, problem("Subscription not found")),
+        }
+    }
+
+    fn commit(&mut self, id: &str, stream_id: Option<&str>, body: &[u8]) -> Response {
+        let cursors: CursorsBody = match serde_json::from_slice(body) {
+            Ok(cursors) => cursors,
+            Err(err) => return (422, problem(err)),
+        };
+
+        let mut parsed = Vec::new();
+        for cursor in &cursors.items {
+            match self.parse_cursor(cursor) {
+                Ok(p) => parsed.push(p),
+                Err(response) => return response,
+            }
+        }
+
+        let subscription = match self.subscriptions.get_mut(id) {
+            Some(subscription) => subscription,
+            None => return (404, problem("Subscription not found")),
+        };
+
+        let stream = match (subscription.stream.as_mut(), stream_id) {
+            (Some(stream), Some(stream_id)) if stream.id == stream_id => stream,
+            _ => {
+                return (
+                    422,
+                    problem(format!(
+                        "Session with stream id {} not found",
+                        stream_id.unwrap_or("<none>")
+                    )),
+                )
+            }
+        };
+
+        if let Some((key, _)) = parsed.iter().find(|(k, _)| !stream.partitions.contains(k)) {
+            return (
+                422,
+                problem(format!(
+                    "Partition {} of {} is not assigned to the stream",
+                    key.1, key.0
+                )),
+            );
+        }
+
+        let mut results = Vec::new();
+        for (key, next) in parsed {
+            let committed = subscription.committed.entry(key).or_insert(0);
+            if next > *committed {
+                *committed = next;
+                results.push("committed");
+            } else {
+                results.push("outdated");
+            }
+        }
+
+        let committed = &subscription.committed;
+        let fully_committed = stream
+            .partitions
+            .iter()
+            .all(|k| stream.sent[k] <= committed.get(k).cloned().unwrap_or(0));
+        stream.uncommitted_since = if fully_committed {
+            None
+        } else {
+            Some(Instant::now())
+        };
+
+        self.commits.push(CommitRecord {
+            subscription_id: SubscriptionId(id.to_string()),
+            stream_id: StreamId(stream.id.clone()),
+            cursors: cursors.items.clone(),
+        });
+
+        if results.iter().all(|r| *r == "committed") {
+            (204, String::new())
+        } else {
+            let items: Vec<Value> = cursors
+                .items
+                .iter()
+                .zip(results)
+                .map(|(cursor, result)| json!({ "cursor": cursor, "result": result }))
+                .collect();
+            (200, json!({ "items": items }).to_string())
+        }
+    }
+
+    fn stats(&self, id: &str) -> Response {
+        let subscription = match self.subscriptions.get(id) {
+            Some(subscription) => subscription,
+            None => return (404, problem("Subscription not found")),
+        };
+
+        let items: Vec<Value> = subscription
+            .event_types
+            .iter()
+            .map(|name| {
+                let partitions: Vec<Value> = self
+                    .subscription_partitions(subscription)
+                    .into_iter()
+                    .filter(|key| key.0 == *name)
+                    .map(|key| {
+                        let unconsumed = self
+                            .num_events(&key)
+                            .saturating_sub(subscription.committed(&key));
+                        match subscription.stream {
+                            Some(ref stream) if stream.partitions.contains(&key) => json!({
+                                "partition": key.1.to_string(),
+                                "state": "assigned",
+                                "stream_id": stream.id,
+                                "unconsumed_events": unconsumed,
+                            }),
+                            _ => json!({
+                                "partition": key.1.to_string(),
+                                "state": "unassigned",
+                                "unconsumed_events": unconsumed,
+                            }),
+                        }
+                    })
+                    .collect();
+                json!({ "event_type": name, "partitions": partitions })
+            })
+            .collect();
+        (200, json!({ "items": items }).to_string())
+    }
+
+    fn open_stream(&mut self, id: &str, request: &Request) -> Result<String, Response> {
+        let parameters = if request.body.is_empty() {
+            StreamParameters {
+                batch_limit: request
+                    .query
+                    .get("batch_limit")
+                    .and_then(|v| v.parse().ok()),
+                max_uncommitted_events: request
+                    .query
+                    .get("max_uncommitted_events")
+                    .and_then(|v| v.parse().ok()),
+                ..StreamParameters::default()
+            }
+        } else {
+            serde_json::from_slice(&request.body).map_err(|err| (400, problem(err)))?
+        };
+
+        let subscription = self
+            .subscriptions
+            .get(id)
+            .ok_or_else(|| (404, problem("Subscription not found")))?;
+
+        if subscription.stream.is_some() {
+            return Err((409, problem("No free slots for streaming")));
+        }
+
+        let available = self.subscription_partitions(subscription);
+        let partitions = if parameters.partitions.is_empty() {
+            available
+        } else {
+            let mut requested = Vec::new();
+            for p in parameters.partitions {
+                let key = p
+                    .partition
+                    .0
+                    .parse()
+                    .ok()
+                    .map(|partition| (p.event_type.clone(), partition))
+                    .filter(|key| available.contains(key))
+                    .ok_or_else(|| {
+                        (
+                            422,
+                            problem(format!(
+                                "Partition {} of {} does not exist",
+                                p.partition, p.event_type
+                            )),
+                        )
+                    })?;
+                requested.push(key);
+            }
+            requested
+        };
+
+        let stream_id = self
+            .next_stream_ids
+            .pop_front()
+            .unwrap_or_else(|| Uuid::new_v4().to_string());
+
+        let subscription = self.subscriptions.get_mut(id).unwrap();
+        let sent = partitions
+            .iter()
+            .map(|key| (key.clone(), subscription.committed(key)))
+            .collect();
+        subscription.stream = Some(ActiveStream {
+            id: stream_id.clone(),
+            partitions,
+            sent,
+            batch_limit: parameters.batch_limit.unwrap_or(1).max(1),
+            max_uncommitted_events: parameters.max_uncommitted_events.unwrap_or(10).max(1),
+            uncommitted_since: None,
+        });
+        self.streams_opened += 1;
+
+        Ok(stream_id)
+    }
+
+    /// Returns the lines to send on the stream.
+    ///
+    /// Returns `None` if the stream is closed.
+    fn next_lines(&mut self, id: &str, stream_id: &str, keep_alive: bool) -> Option<Vec<String>> {
+        let event_types = &self.event_types;
+        let commit_timeout = self.config.commit_timeout;
+        let subscription = self.subscriptions.get_mut(id)?;
+
+        match subscription.stream {
+            Some(ref stream) if stream.id == stream_id => {}
+            _ => return None,
+        }
+
+        let timed_out = subscription
+            .stream
+            .as_ref()
+            .and_then(|stream| stream.uncommitted_since)
+            .map(|since| since.elapsed() > commit_timeout)
+            .unwrap_or(false);
+        if timed_out {
+            warn!("Fake Nakadi: Commit timeout on stream {}", stream_id);
+            subscription.stream = None;
+            return None;
+        }
+
+        let committed = &subscription.committed;
+        let stream = subscription.stream.as_mut()?;
+
+        let mut uncommitted: usize = stream
+            .sent
+            .iter()
+            .map(|(key, sent)| sent - committed.get(key).cloned().unwrap_or(0).min(*sent))
+            .sum();
+
+        let mut lines = Vec::new();
+        for key in &stream.partitions {
+            let events = match event_types
+                .get(&key.0)
+                .and_then(|et| et.partitions.get(key.1))
+            {
+                Some(events) => events,
+                None => continue,
+            };
+            let next = stream.sent[key];
+            if next >= events.len() || uncommitted >= stream.max_uncommitted_events {
+                continue;
+            }
+            let n = stream
+                .batch_limit
+                .min(events.len() - next)
+                .min(stream.max_uncommitted_events - uncommitted);
+            let line = json!({
+                "cursor": cursor_json(key, next + n),
+                "events": &events[next..next + n],
+            });
+            lines.push(line.to_string());
+            stream.sent.insert(key.clone(), next + n);
+            uncommitted += n;
+            if stream.uncommitted_since.is_none() {
+                stream.uncommitted_since = Some(Instant::now());
+            }
+        }
+
+        if lines.is_empty() && keep_alive {
+            for key in &stream.partitions {
+                let line = json!({ "cursor": cursor_json(key, stream.sent[key]) });
+                lines.push(line.to_string());
+            }
+        }
+
+        Some(lines)
+    }
+
+    fn close_stream(&mut self, id: &str, stream_id: &str) {
+        if let Some(subscription) = self.subscriptions.get_mut(id) {
+            if subscription.stream.as_ref().map(|s| s.id == stream_id) == Some(true) {
+                subscription.stream = None;
+            }
+        }
+    }
+
+    fn parse_cursor(&self, cursor: &SubscriptionCursor) -> Result<(PartitionKey, usize), Response> {
+        let invalid = || {
+            (
+                422,
+                problem(format!(
+                    "Invalid cursor {}:{}:{}",
+                    cursor.event_type, cursor.partition, cursor.offset
+                )),
+            )
+        };
+        let partition: usize = cursor.partition.0.parse().map_err(|_| invalid())?;
+        let key = (cursor.event_type.clone(), partition);
+        let next = parse_offset(&cursor.offset).ok_or_else(invalid)?;
+        if next > self.num_events(&key) {
+            return Err(invalid());
+        }
+        Ok((key, next))
+    }
+}
+
+/// Formats the index of the next event as the offset
+/// of the last event consumed.
+fn offset(next: usize) -> String {
+    if next == 0 {
+        "BEGIN".to_string()
+    } else {
+        format!("{:018}", next - 1)
+    }
+}
+
+/// Parses an offset into the index of the next event to consume
+fn parse_offset(offset: &str) -> Option<usize> {
+    if offset == "BEGIN" {
+        Some(0)
+    } else {
+        offset.parse::<usize>().ok().map(|o| o + 1)
+    }
+}
+
+fn cursor_json(key: &PartitionKey, next: usize) -> Value {
+    json!({
+        "partition": key.1.to_string(),
+        "offset": offset(next),
+        "event_type": key.0,
+        "cursor_token": Uuid::new_v4().to_string(),
+    })
+}
+
+fn problem<T: ToString>(detail: T) -> String {
+    json!({ "title": "Fake Nakadi", "detail": detail.to_string() }).to_string()
+}
+
+fn to_values<T: Serialize>(events: &[T]) -> Vec<Value> {
+    events
+        .iter()
+        .map(|e| serde_json::to_value(e).expect("Could not serialize event"))
+        .collect()
+}
+
+struct Request {
+    method: String,
+    path: Vec<String>,
+    query: HashMap<String, String>,
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
+}
+
+impl Request {
+    fn endpoint(&self) -> Option<Endpoint> {
+        let path: Vec<&str> = self.path.iter().map(String::as_str).collect();
+        let endpoint = match (self.method.as_str(), path.as_slice()) {
+            ("GET", ["subscriptions", _, "events"]) => Endpoint::Stream,
+            ("POST", ["subscriptions", _, "events"]) => Endpoint::Stream,
+            ("POST", ["subscriptions", _, "cursors"]) => Endpoint::Commit,
+            ("GET", ["subscriptions", _, "cursors"]) => Endpoint::GetCursors,
+            ("PATCH", ["subscriptions", _, "cursors"]) => Endpoint::ResetCursors,
+            ("GET", ["subscriptions", _, "stats"]) => Endpoint::Stats,
+            ("POST", ["subscriptions"]) => Endpoint::CreateSubscription,
+            ("DELETE", ["subscriptions", _]) => Endpoint::DeleteSubscription,
+            ("POST", ["event-types"]) => Endpoint::CreateEventType,
+            ("DELETE", ["event-types", _]) => Endpoint::DeleteEventType,
+            ("POST", ["event-types", _, "events"]) => Endpoint::Publish,
+            _ => return None,
+        };
+        Some(endpoint)
+    }
+}
+
+fn accept_loop(listener: TcpListener, state: Arc<Mutex<State>>, stop: Arc<AtomicBool>) {
+    for connection in listener.incoming() {
+        if stop.load(Ordering::SeqCst) {
+            break;
+        }
+
+        let connection = match connection {
+            Ok(connection) => connection,
+            Err(err) => {
+                warn!("Fake Nakadi: Could not accept connection: {}", err);
+                continue;
+            }
+        };
+
+        let state = state.clone();
+        let stop = stop.clone();
+        thread::spawn(move || {
+            if let Err(err) = handle_connection(connection, &state, &stop) {
+                debug!("Fake Nakadi: Connection failed: {}", err);
+            }
+        });
+    }
+}
+
+fn handle_connection(
+    connection: TcpStream,
+    state: &Mutex<State>,
+    stop: &AtomicBool,
+) -> Result<(), IoError> {
+    let request = read_request(&connection)?;
+
+    let endpoint = match request.endpoint() {
+        Some(endpoint) => endpoint,
+        None => return respond(&connection, 404, &problem("Not found")),
+    };
+
+    let fault = state
+        .lock()
+        .unwrap()
+        .faults
+        .get_mut(&endpoint)
+        .and_then(VecDeque::pop_front);
+    match fault {
+        Some(Fault::Status(status, body)) => return respond(&connection, status, &body),
+        Some(Fault::CloseConnection) => return connection.shutdown(Shutdown::Both),
+        Some(Fault::Delay(delay)) => thread::sleep(delay),
+        None => {}
+    }
+
+    if endpoint == Endpoint::Stream {
+        return stream(&connection, &request, state, stop);
+    }
+
+    let (status, body) = {
+        let mut state = state.lock().unwrap();
+        let name = request.path.get(1).map(String::as_str).unwrap_or("");
+        match endpoint {
+            Endpoint::Commit => {
+                let stream_id = request.headers.get("x-nakadi-streamid").map(String::as_str);
+                state.commit(name, stream_id, &request.body)
+            }
+            Endpoint::GetCursors => state.get_cursors(name),
+            Endpoint::ResetCursors => state.reset_cursors(name, &request.body),
+            Endpoint::Stats => state.stats(name),
+            Endpoint::Publish => state.publish(name, &request.body),
+            Endpoint::CreateEventType => state.create_event_type(&request.body),
+            Endpoint::DeleteEventType => state.delete_event_type(name),
+            Endpoint::CreateSubscription => state.create_subscription(&request.body),
+            Endpoint::DeleteSubscription => state.delete_subscription(name),
+            Endpoint::Stream => unreachable!("Streams are handled above"),
+        }
+    };
+
+    respond(&connection, status, &body)
+}
+
+fn stream(
+    connection: &TcpStream,
+    request: &Request,
+    state: &Mutex<State>,
+    stop: &AtomicBool,
+) -> Result<(), IoError> {
+    let id = &request.path[1];
+
+    let opened = state.lock().unwrap().open_stream(id, request);
+    let stream_id = match opened {
+        Ok(stream_id) => stream_id,
+        Err((status, body)) => return respond(connection, status, &body),
+    };
+
+    let keep_alive_interval = state.lock().unwrap().config.keep_alive_interval;
+
+    let mut writer = connection;
+    let result = write!(
+        writer,
+        "HTTP/1.1 200 OK\r\nContent-Type: application/x-json-stream\r\n\
+         X-Nakadi-StreamId: {}\r\nConnection: close\r\n\r\n",
+        stream_id
+    )
+    .and_then(|_| {
+        let mut last_sent = Instant::now();
+        while !stop.load(Ordering::SeqCst) {
+            let keep_alive = last_sent.elapsed() >= keep_alive_interval;
+            let lines = match state.lock().unwrap().next_lines(id, &stream_id, keep_alive) {
+                Some(lines) => lines,
+                None => break,
+            };
+
+            if lines.is_empty() {
+                thread::sleep(Duration::from_millis(10));
+                continue;
+            }
+
+            for line in lines {
+                writer.write_all(line.as_bytes())?;
+                writer.write_all(b"\n")?;
+            }
+            writer.flush()?;
+            last_sent = Instant::now();
+        }
+        Ok(())
+    });
+
+    state.lock().unwrap().close_stream(id, &stream_id);
+    let _ = connection.shutdown(Shutdown::Both);
+    result
+}
+
+fn read_request(connection: &TcpStream) -> Result<Request, IoError> {
+    let invalid = |msg: &str| IoError::new(ErrorKind::InvalidData, msg.to_string());
+
+    let mut reader = BufReader::new(connection);
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let method = parts
+        .next()
+        .ok_or_else(|| invalid("No method"))?
+        .to_string();
+    let target = parts.next().ok_or_else(|| invalid("No target"))?;
+
+    let mut headers = HashMap::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(invalid("Connection closed within headers"));
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(idx) = line.find(':') {
+            headers.insert(
+                line[..idx].trim().to_lowercase(),
+                line[idx + 1..].trim().to_string(),
+            );
+        }
+    }
+
+    let mut body = Vec::new();
+    if let Some(length) = headers.get("content-length") {
+        let length: usize = length.parse().map_err(|_| invalid("Invalid length"))?;
+        body.resize(length, 0);
+        reader.read_exact(&mut body)?;
+    } else if headers.get("transfer-encoding").map(String::as_str) == Some("chunked") {
+        loop {
+            let mut size_line = String::new();
+            reader.read_line(&mut size_line)?;
+            let size = usize::from_str_radix(size_line.trim(), 16)
+                .map_err(|_| invalid("Invalid chunk size"))?;
+            let mut chunk = vec![0; size + 2];
+            reader.read_exact(&mut chunk)?;
+            if size == 0 {
+                break;
+            }
+            body.extend_from_slice(&chunk[..size]);
+        }
+    }
+
+    let url = Url::parse(&format!("http://localhost{}", target))
+        .map_err(|err| invalid(&err.to_string()))?;
+
+    Ok(Request {
+        method,
+        path: url
+            .path_segments()
+            .map(|segments| {
+                segments
+                    .filter(|s| !s.is_empty())
+                    .map(ToString::to_string)
+                    .collect()
+            })
+            .unwrap_or_default(),
+        query: url.query_pairs().into_owned().collect(),
+        headers,
+        body,
+    })
+}
+
+fn respond(mut connection: &TcpStream, status: u16, body: &str) -> Result<(), IoError> {
+    let reason = StatusCode::from_u16(status)
+        .ok()
+        .and_then(|s| s.canonical_reason())
+        .unwrap_or("Unknown");
+    write!(
+        connection,
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        status,
+        reason,
+        body.len(),
+        body
+    )?;
+    connection.flush()
+}
+
+#[cfg(feature = "blocking")]
+#[test]
+fn streams_events_and_accepts_commits() {
+    use crate::api::{ApiClient, CommitStatus, ConfigBuilder as ApiConfigBuilder};
+    use crate::auth::NoAuthAccessTokenProvider;
+    use crate::metrics::DevNullMetricsCollector;
+    use crate::nakadi::model::FlowId;
+    use crate::streaming_client::{ConfigBuilder, StreamingClient};
+
+    let nakadi = FakeNakadi::start().unwrap();
+    nakadi.create_event_type("test_event", 2);
+    let subscription_id = nakadi.create_subscription("test_app", &["test_event"]);
+    nakadi.set_next_stream_ids(vec!["stream-1"]);
+    nakadi.publish(
+        "test_event",
+        &[json!({"a": 1}), json!({"a": 2}), json!({"a": 3})],
+    );
+
+    let streaming_client = ConfigBuilder::default()
+        .nakadi_host(nakadi.nakadi_host())
+        .batch_limit(5)
+        .build_client(NoAuthAccessTokenProvider, DevNullMetricsCollector)
+        .unwrap();
+    let api_client = ApiConfigBuilder::default()
+        .nakadi_host(nakadi.nakadi_host())
+        .build_client(NoAuthAccessTokenProvider)
+        .unwrap();
+
+    let (stream_id, mut lines) = streaming_client
+        .connect(&subscription_id, FlowId::default())
+        .unwrap();
+    assert_eq!(stream_id.0, "stream-1");
+    assert_eq!(
+        nakadi.active_stream(&subscription_id).map(|id| id.0),
+        Some(stream_id.0.clone())
+    );
+
+    let mut cursors = Vec::new();
+    let mut num_events = 0;
+    while num_events < 3 {
+        let line: Value = serde_json::from_slice(&lines.next().unwrap().unwrap().bytes).unwrap();
+        if let Some(events) = line["events"].as_array() {
+            num_events += events.len();
+            cursors.push(serde_json::to_vec(&line["cursor"]).unwrap());
+        }
+    }
+    assert_eq!(cursors.len(), 2);
+
+    let status = api_client
+        .commit_cursors(&subscription_id, &stream_id, &cursors, FlowId::default())
+        .unwrap();
+    match status {
+        CommitStatus::AllOffsetsIncreased => {}
+        other => panic!("Expected all offsets to be increased but got {:?}", other),
+    }
+
+    let committed: Vec<String> = nakadi
+        .committed_cursors(&subscription_id)
+        .into_iter()
+        .map(|c| c.offset)
+        .collect();
+    assert_eq!(committed, vec![offset(2), offset(1)]);
+    assert_eq!(nakadi.commits().len(), 1);
+}
+
+#[cfg(feature = "blocking")]
+#[test]
+fn injected_faults_happen_once() {
+    use crate::api::{
+        ApiClient, ConfigBuilder as ApiConfigBuilder, CreateSubscriptionError,
+        CreateSubscriptionStatus,
+    };
+    use crate::auth::NoAuthAccessTokenProvider;
+
+    let nakadi = FakeNakadi::start().unwrap();
+    nakadi.create_event_type("test_event", 1);
+    nakadi.inject_fault(
+        Endpoint::CreateSubscription,
+        Fault::Status(400, "Injected".to_string()),
+    );
+
+    let api_client = ApiConfigBuilder::default()
+        .nakadi_host(nakadi.nakadi_host())
+        .build_client(NoAuthAccessTokenProvider)
+        .unwrap();
+
+    let request = SubscriptionRequest {
+        owning_application: "test_app".to_string(),
+        event_types: vec!["test_event".to_string()],
+        read_from: None,
+    };
+
+    match api_client.create_subscription(&request) {
+        Err(CreateSubscriptionError::BadRequest(_)) => {}
+        other => panic!("Expected the injected fault but got {:?}", other),
+    }
+    match api_client.create_subscription(&request) {
+        Ok(CreateSubscriptionStatus::Created(_)) => {}
+        other => panic!("Expected a new subscription but got {:?}", other),
+    }
+    match api_client.create_subscription(&request) {
+        Ok(CreateSubscriptionStatus::AlreadyExists(_)) => {}
+        other => panic!("Expected an existing subscription but got {:?}", other),
+    }
+}
+
+#[cfg(feature = "blocking")]
+#[test]
+fn closes_streams_on_commit_timeout() {
+    use crate::auth::NoAuthAccessTokenProvider;
+    use crate::metrics::DevNullMetricsCollector;
+    use crate::nakadi::model::FlowId;
+    use crate::streaming_client::{ConfigBuilder, StreamingClient};
+
+    let nakadi = FakeNakadi::start_with(FakeNakadiConfig {
+        commit_timeout: Duration::from_millis(100),
+        ..FakeNakadiConfig::default()
+    })
+    .unwrap();
+    nakadi.create_event_type("test_event", 1);
+    let subscription_id = nakadi.create_subscription("test_app", &["test_event"]);
+    nakadi.publish("test_event", &[json!({"a": 1})]);
+
+    let streaming_client = ConfigBuilder::default()
+        .nakadi_host(nakadi.nakadi_host())
+        .build_client(NoAuthAccessTokenProvider, DevNullMetricsCollector)
+        .unwrap();
+
+    let (_, lines) = streaming_client
+        .connect(&subscription_id, FlowId::default())
+        .unwrap();
+
+    // The stream ends since the batch is never committed
+    assert_eq!(lines.filter_map(Result::ok).count(), 1);
+    assert!(nakadi.active_stream(&subscription_id).is_none());
+}
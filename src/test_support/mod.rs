@@ -0,0 +1,15 @@
+//! Support for testing applications using `Nakadion`
+//!
+//! `FakeNakadi` serves the Nakadi API over HTTP for testing
+//! against the clients provided by `Nakadion`. `ScriptedStreamingClient`
+//! and `RecordingApiClient` replace the clients in memory
+//! for deterministic tests without any sockets.
+//!
+//! This module is only available with the `test-support` feature enabled.
+pub mod api_client;
+pub mod fake_nakadi;
+pub mod streaming_client;
+
+pub use self::api_client::{CommitCall, RecordingApiClient};
+pub use self::fake_nakadi::{CommitRecord, Endpoint, FakeNakadi, FakeNakadiConfig, Fault};
+pub use self::streaming_client::{ScriptedLineIterator, ScriptedStream, ScriptedStreamingClient};
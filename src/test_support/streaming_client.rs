@@ -0,0 +1,239 @@
+//! A `StreamingClient` replaying scripted streams
+use std::collections::VecDeque;
+use std::io::{Error as IoError, ErrorKind};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use serde_json::{self, json, Value};
+
+use crate::nakadi::handler::SubscriptionCursor;
+use crate::nakadi::model::{FlowId, StreamId, SubscriptionId};
+use crate::nakadi::streaming_client::{ConnectError, LineResult, RawLine, StreamingClient};
+
+/// A single step of a `ScriptedStream`
+#[derive(Debug, Clone)]
+enum Step {
+    Line(Vec<u8>),
+    Wait(Duration),
+    Drop,
+}
+
+/// The lines a `ScriptedStreamingClient` delivers on a single stream
+///
+/// The stream ends once all lines were delivered
+/// as if Nakadi closed the connection.
+#[derive(Debug, Clone)]
+pub struct ScriptedStream {
+    stream_id: StreamId,
+    steps: Vec<Step>,
+}
+
+impl ScriptedStream {
+    /// Create an empty script for the stream with the given id
+    pub fn new<T: Into<String>>(stream_id: T) -> ScriptedStream {
+        ScriptedStream {
+            stream_id: StreamId::new(stream_id),
+            steps: Vec::new(),
+        }
+    }
+
+    /// Deliver a batch with the given events
+    ///
+    /// # Panics
+    ///
+    /// An event could not be serialized.
+    pub fn batch<T: Serialize>(self, cursor: &SubscriptionCursor, events: &[T]) -> Self {
+        let events: Vec<Value> = events
+            .iter()
+            .map(|e| serde_json::to_value(e).expect("Could not serialize event"))
+            .collect();
+        self.line(json!({ "cursor": cursor, "events": events }).to_string())
+    }
+
+    /// Deliver a keep alive line which has a cursor but no events
+    pub fn keep_alive(self, cursor: &SubscriptionCursor) -> Self {
+        self.line(json!({ "cursor": cursor }).to_string())
+    }
+
+    /// Deliver a line with debug information but no events
+    pub fn info<T: Into<String>>(self, cursor: &SubscriptionCursor, debug: T) -> Self {
+        self.line(json!({ "cursor": cursor, "info": { "debug": debug.into() } }).to_string())
+    }
+
+    /// Deliver the given bytes as a line
+    pub fn line<T: Into<Vec<u8>>>(mut self, line: T) -> Self {
+        self.steps.push(Step::Line(line.into()));
+        self
+    }
+
+    /// Wait before delivering the next line
+    pub fn wait(mut self, duration: Duration) -> Self {
+        self.steps.push(Step::Wait(duration));
+        self
+    }
+
+    /// Fail with an IO error as if the connection was lost.
+    ///
+    /// No further lines are delivered on this stream.
+    pub fn drop_connection(mut self) -> Self {
+        self.steps.push(Step::Drop);
+        self
+    }
+}
+
+/// What happens on a connect attempt
+enum Connect {
+    Stream(ScriptedStream),
+    Error(ConnectError),
+}
+
+/// A `StreamingClient` delivering the lines of `ScriptedStream`s
+///
+/// Each connect attempt takes the next scripted stream or
+/// connect error in the order they were added. Once the script
+/// is used up connect attempts fail with a `ConnectError::Conflict`
+/// as if all partitions were assigned to other consumers.
+///
+/// Clones share the same script.
+#[derive(Clone, Default)]
+pub struct ScriptedStreamingClient {
+    connects: Arc<Mutex<VecDeque<Connect>>>,
+    attempts: Arc<Mutex<Vec<SubscriptionId>>>,
+}
+
+impl ScriptedStreamingClient {
+    pub fn new() -> ScriptedStreamingClient {
+        ScriptedStreamingClient::default()
+    }
+
+    /// Connect to the given stream on a following connect attempt
+    pub fn push_stream(&self, stream: ScriptedStream) {
+        self.connects
+            .lock()
+            .unwrap()
+            .push_back(Connect::Stream(stream));
+    }
+
+    /// Fail a following connect attempt with the given error
+    pub fn push_connect_error(&self, err: ConnectError) {
+        self.connects.lock().unwrap().push_back(Connect::Error(err));
+    }
+
+    /// The subscriptions of all connect attempts so far
+    pub fn connect_attempts(&self) -> Vec<SubscriptionId> {
+        self.attempts.lock().unwrap().clone()
+    }
+
+    /// Returns true if all scripted connect attempts happened
+    pub fn is_exhausted(&self) -> bool {
+        self.connects.lock().unwrap().is_empty()
+    }
+}
+
+impl StreamingClient for ScriptedStreamingClient {
+    type LineIterator = ScriptedLineIterator;
+
+    fn connect(
+        &self,
+        subscription_id: &SubscriptionId,
+        flow_id: FlowId,
+    ) -> Result<(StreamId, ScriptedLineIterator), ConnectError> {
+        self.attempts.lock().unwrap().push(subscription_id.clone());
+
+        match self.connects.lock().unwrap().pop_front() {
+            Some(Connect::Stream(stream)) => Ok((
+                stream.stream_id,
+                ScriptedLineIterator {
+                    steps: stream.steps.into(),
+                },
+            )),
+            Some(Connect::Error(err)) => Err(err),
+            None => Err(ConnectError::Conflict(
+                "No more scripted streams".to_string(),
+                flow_id,
+            )),
+        }
+    }
+}
+
+/// Delivers the lines of a `ScriptedStream`
+pub struct ScriptedLineIterator {
+    steps: VecDeque<Step>,
+}
+
+impl Iterator for ScriptedLineIterator {
+    type Item = LineResult;
+
+    fn next(&mut self) -> Option<LineResult> {
+        loop {
+            match self.steps.pop_front()? {
+                Step::Line(bytes) => {
+                    return Some(Ok(RawLine {
+                        bytes,
+                        received_at: Instant::now(),
+                    }))
+                }
+                Step::Wait(duration) => thread::sleep(duration),
+                Step::Drop => {
+                    self.steps.clear();
+                    return Some(Err(IoError::new(
+                        ErrorKind::ConnectionReset,
+                        "Scripted connection drop",
+                    )));
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn replays_scripted_streams() {
+    use crate::nakadi::batch::BatchLine;
+    use crate::nakadi::model::PartitionId;
+
+    let cursor = SubscriptionCursor {
+        partition: PartitionId::new("0"),
+        offset: "0".to_string(),
+        event_type: "test_event".to_string(),
+    };
+
+    let client = ScriptedStreamingClient::new();
+    client.push_stream(
+        ScriptedStream::new("stream-1")
+            .keep_alive(&cursor)
+            .info(&cursor, "Stream started")
+            .batch(&cursor, &[json!({"a": 1})])
+            .drop_connection()
+            .batch(&cursor, &[json!({"a": 2})]),
+    );
+    client.push_connect_error(ConnectError::Connection("Injected".to_string()));
+
+    let subscription_id = SubscriptionId::new("subscription");
+
+    let (stream_id, lines) = client.connect(&subscription_id, FlowId::default()).unwrap();
+    assert_eq!(stream_id.0, "stream-1");
+
+    let lines: Vec<LineResult> = lines.collect();
+    assert_eq!(lines.len(), 4);
+    let parsed: Vec<BatchLine> = lines[..3]
+        .iter()
+        .map(|line| BatchLine::from_slice(&line.as_ref().unwrap().bytes).unwrap())
+        .collect();
+    assert!(parsed[0].is_keep_alive_line());
+    assert!(parsed[1].info().is_some());
+    assert_eq!(parsed[2].events(), Some(&b"[{\"a\":1}]"[..]));
+    assert!(lines[3].is_err());
+
+    match client.connect(&subscription_id, FlowId::default()) {
+        Err(ConnectError::Connection(_)) => {}
+        _ => panic!("Expected the scripted connect error"),
+    }
+    match client.connect(&subscription_id, FlowId::default()) {
+        Err(ConnectError::Conflict(_, _)) => {}
+        _ => panic!("Expected the script to be exhausted"),
+    }
+    assert_eq!(client.connect_attempts().len(), 3);
+    assert!(client.is_exhausted());
+}
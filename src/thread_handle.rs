@@ -0,0 +1,137 @@
+//! Waiting for background threads without polling
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// The handle of a background thread which can be waited
+/// for with a timeout and joined from a shared reference.
+///
+/// Finishing is signalled by dropping a guard moved into the
+/// thread so that a thread which panicked counts as finished, too.
+pub struct ThreadHandle<T = ()> {
+    name: String,
+    handle: Mutex<Option<thread::JoinHandle<T>>>,
+    done: Arc<Done>,
+}
+
+/// Whether the thread finished. All waiters are notified once it did.
+#[derive(Default)]
+struct Done {
+    finished: Mutex<bool>,
+    condvar: Condvar,
+}
+
+/// Marks the thread as finished when dropped
+struct DoneGuard(Arc<Done>);
+
+impl Drop for DoneGuard {
+    fn drop(&mut self) {
+        *self.0.finished.lock().unwrap() = true;
+        self.0.condvar.notify_all();
+    }
+}
+
+impl<T: Send + 'static> ThreadHandle<T> {
+    /// Spawn a new named thread running `f`
+    pub fn spawn<F>(name: String, f: F) -> ThreadHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let done = Arc::new(Done::default());
+        let done_guard = DoneGuard(done.clone());
+        let handle = thread::Builder::new()
+            .name(name.clone())
+            .spawn(move || {
+                // Dropped last so that the thread is only
+                // considered finished after `f` was dropped
+                let _done_guard = done_guard;
+                f()
+            })
+            .unwrap();
+
+        ThreadHandle {
+            name,
+            handle: Mutex::new(Some(handle)),
+            done,
+        }
+    }
+
+    /// Block until the thread finished or `timeout` elapsed.
+    ///
+    /// Returns true if the thread finished.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let finished = self.done.finished.lock().unwrap();
+        let (finished, _) = self
+            .done
+            .condvar
+            .wait_timeout_while(finished, timeout, |finished| !*finished)
+            .unwrap();
+        *finished
+    }
+
+    /// Block until the thread finished.
+    ///
+    /// Returns what the thread returned or `Err` with the name of
+    /// the thread if it panicked. Only the first caller gets the
+    /// outcome, all others get `None`.
+    pub fn join(&self) -> Option<Result<T, String>> {
+        let handle = self.handle.lock().unwrap().take();
+        if let Some(handle) = handle {
+            Some(handle.join().map_err(|_| {
+                warn!("Thread '{}' panicked", self.name);
+                self.name.clone()
+            }))
+        } else {
+            // Someone else is joining. Wait for the thread to finish.
+            let finished = self.done.finished.lock().unwrap();
+            let _finished = self
+                .done
+                .condvar
+                .wait_while(finished, |finished| !*finished)
+                .unwrap();
+            None
+        }
+    }
+}
+
+#[test]
+fn a_panicking_thread_counts_as_finished() {
+    let handle: ThreadHandle = ThreadHandle::spawn("panicking".to_string(), || {
+        thread::sleep(Duration::from_millis(50));
+        panic!("expected");
+    });
+
+    assert!(!handle.wait_timeout(Duration::from_millis(1)));
+    assert!(handle.wait_timeout(Duration::from_secs(5)));
+    assert_eq!(handle.join(), Some(Err("panicking".to_string())));
+    assert_eq!(handle.join(), None);
+    assert!(handle.wait_timeout(Duration::from_millis(1)));
+}
+
+#[test]
+fn concurrent_waiters_are_all_woken() {
+    let handle = Arc::new(ThreadHandle::spawn("sleeping".to_string(), || {
+        thread::sleep(Duration::from_millis(100));
+        1
+    }));
+
+    let waiters: Vec<_> = (0..4)
+        .map(|_| {
+            let handle = handle.clone();
+            thread::spawn(move || handle.wait_timeout(Duration::from_secs(5)))
+        })
+        .collect();
+    let joiners: Vec<_> = (0..2)
+        .map(|_| {
+            let handle = handle.clone();
+            thread::spawn(move || handle.join())
+        })
+        .collect();
+
+    for waiter in waiters {
+        assert!(waiter.join().unwrap());
+    }
+    let mut outcomes: Vec<_> = joiners.into_iter().map(|j| j.join().unwrap()).collect();
+    outcomes.sort();
+    assert_eq!(outcomes, vec![None, Some(Ok(1))]);
+}